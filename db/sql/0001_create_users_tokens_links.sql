@@ -0,0 +1,47 @@
+-- Ids keep the `table:id` form of SurrealDB record ids, and times are
+-- RFC 3339 strings in UTC so that they sort like the times themselves.
+
+CREATE TABLE users (
+    id TEXT PRIMARY KEY,
+    username TEXT NOT NULL UNIQUE,
+    password TEXT NOT NULL,
+    email TEXT,
+    totp_secret TEXT,
+    totp_pending_secret TEXT,
+    totp_last_step BIGINT
+);
+
+CREATE TABLE tokens (
+    id TEXT PRIMARY KEY,
+    token_hash TEXT NOT NULL UNIQUE,
+    name TEXT NOT NULL,
+    short_token TEXT NOT NULL,
+    user_id TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    -- JSON array of scope names
+    scopes TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    expires_at TEXT,
+    last_used_at TEXT,
+    last_used_ip TEXT
+);
+
+CREATE INDEX idx_tokens_user ON tokens (user_id);
+
+CREATE TABLE links (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    url TEXT NOT NULL,
+    canonical_url TEXT NOT NULL,
+    -- Lowercase host of `url`, for filtering by domain
+    domain TEXT,
+    title TEXT NOT NULL,
+    note TEXT NOT NULL,
+    bookmarked_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    -- JSON array of the ids of the link's tags, which are kept in SurrealDB
+    tags TEXT NOT NULL,
+    collection TEXT
+);
+
+CREATE INDEX idx_links_user_bookmarked_at ON links (user_id, bookmarked_at);
+CREATE INDEX idx_links_user_canonical_url ON links (user_id, canonical_url);
@@ -0,0 +1,3 @@
+-- Two requests saving the same URL at once must not both create a link
+DROP INDEX idx_links_user_canonical_url;
+CREATE UNIQUE INDEX idx_links_user_canonical_url ON links (user_id, canonical_url);
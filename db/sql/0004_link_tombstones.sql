@@ -0,0 +1,10 @@
+-- What the `link_deleted` event records in SurrealDB, so that devices hear
+-- about deleted links
+CREATE TABLE link_tombstones (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    link_id TEXT NOT NULL,
+    deleted_at TEXT NOT NULL
+);
+
+CREATE INDEX idx_link_tombstones_user_deleted_at ON link_tombstones (user_id, deleted_at);
@@ -0,0 +1,118 @@
+DEFINE TABLE user SCHEMAFULL;
+DEFINE FIELD username ON TABLE user TYPE string;
+DEFINE FIELD password ON TABLE user TYPE string;
+DEFINE FIELD email ON TABLE user TYPE option<string>;
+DEFINE FIELD totp_secret ON TABLE user TYPE option<string>;
+DEFINE FIELD totp_pending_secret ON TABLE user TYPE option<string>;
+DEFINE FIELD totp_last_step ON TABLE user TYPE option<int>;
+DEFINE INDEX idx_username ON TABLE user COLUMNS username UNIQUE;
+
+DEFINE TABLE token SCHEMAFULL;
+DEFINE FIELD token_hash ON TABLE token TYPE string;
+DEFINE FIELD name ON TABLE token TYPE string;
+DEFINE FIELD short_token ON TABLE token TYPE string;
+DEFINE FIELD user ON TABLE token TYPE record (user);
+DEFINE FIELD scopes ON TABLE token TYPE array;
+DEFINE FIELD scopes.* ON TABLE token TYPE string;
+DEFINE FIELD created_at ON TABLE token TYPE datetime DEFAULT time::now();
+DEFINE FIELD expires_at ON TABLE token TYPE option<datetime>;
+DEFINE FIELD last_used_at ON TABLE token TYPE option<datetime>;
+DEFINE FIELD last_used_ip ON TABLE token TYPE option<string>;
+DEFINE INDEX idx_hash ON TABLE token COLUMNS token_hash UNIQUE;
+DEFINE INDEX idx_user ON TABLE token COLUMNS user;
+-- Tokens created before scopes existed keep access to links, but not to tokens
+UPDATE token SET scopes = ["links:read", "links:write", "links:clear"] WHERE scopes = NONE;
+UPDATE token SET created_at = time::now() WHERE created_at = NONE;
+
+DEFINE TABLE session SCHEMAFULL;
+DEFINE FIELD user ON TABLE session TYPE record (user);
+DEFINE FIELD created_at ON TABLE session TYPE datetime DEFAULT time::now();
+DEFINE FIELD revoked_at ON TABLE session TYPE option<datetime>;
+DEFINE INDEX idx_user ON TABLE session COLUMNS user;
+
+DEFINE TABLE refresh_token SCHEMAFULL;
+DEFINE FIELD token_hash ON TABLE refresh_token TYPE string;
+DEFINE FIELD session ON TABLE refresh_token TYPE record (session);
+DEFINE FIELD expires_at ON TABLE refresh_token TYPE datetime;
+DEFINE FIELD used_at ON TABLE refresh_token TYPE option<datetime>;
+DEFINE INDEX idx_hash ON TABLE refresh_token COLUMNS token_hash UNIQUE;
+DEFINE INDEX idx_session ON TABLE refresh_token COLUMNS session;
+
+DEFINE TABLE password_reset SCHEMAFULL;
+DEFINE FIELD token_hash ON TABLE password_reset TYPE string;
+DEFINE FIELD user ON TABLE password_reset TYPE record (user);
+DEFINE FIELD expires_at ON TABLE password_reset TYPE datetime;
+DEFINE FIELD used_at ON TABLE password_reset TYPE option<datetime>;
+DEFINE INDEX idx_hash ON TABLE password_reset COLUMNS token_hash UNIQUE;
+DEFINE INDEX idx_user ON TABLE password_reset COLUMNS user;
+
+DEFINE TABLE mfa_challenge SCHEMAFULL;
+DEFINE FIELD token_hash ON TABLE mfa_challenge TYPE string;
+DEFINE FIELD user ON TABLE mfa_challenge TYPE record (user);
+DEFINE FIELD expires_at ON TABLE mfa_challenge TYPE datetime;
+DEFINE FIELD attempts ON TABLE mfa_challenge TYPE int DEFAULT 0;
+DEFINE FIELD used_at ON TABLE mfa_challenge TYPE option<datetime>;
+DEFINE INDEX idx_hash ON TABLE mfa_challenge COLUMNS token_hash UNIQUE;
+DEFINE INDEX idx_user ON TABLE mfa_challenge COLUMNS user;
+
+DEFINE TABLE recovery_code SCHEMAFULL;
+DEFINE FIELD code_hash ON TABLE recovery_code TYPE string;
+DEFINE FIELD user ON TABLE recovery_code TYPE record (user);
+DEFINE FIELD used_at ON TABLE recovery_code TYPE option<datetime>;
+DEFINE INDEX idx_user ON TABLE recovery_code COLUMNS user;
+
+DEFINE TABLE link SCHEMAFULL;
+DEFINE FIELD url ON TABLE link TYPE string;
+DEFINE FIELD canonical_url ON TABLE link TYPE string;
+DEFINE FIELD title ON TABLE link TYPE string;
+DEFINE FIELD note ON TABLE link TYPE string;
+DEFINE FIELD user ON TABLE link TYPE record (user);
+DEFINE FIELD bookmarked_at ON TABLE link TYPE datetime DEFAULT time::now();
+DEFINE FIELD updated_at ON TABLE link TYPE datetime VALUE time::now();
+DEFINE FIELD tags ON TABLE link TYPE array DEFAULT [];
+DEFINE FIELD tags.* ON TABLE link TYPE record (tag);
+DEFINE FIELD collection ON TABLE link TYPE option<record (collection)>;
+DEFINE INDEX idx_user ON TABLE link COLUMNS user;
+DEFINE INDEX idx_user_updated_at ON TABLE link COLUMNS user, updated_at;
+DEFINE INDEX idx_tags ON TABLE link COLUMNS tags;
+DEFINE INDEX idx_collection ON TABLE link COLUMNS collection;
+DEFINE ANALYZER link_text TOKENIZERS blank,class,punct FILTERS lowercase,ascii,snowball(english);
+DEFINE ANALYZER link_url TOKENIZERS blank,class,punct FILTERS lowercase;
+DEFINE INDEX idx_search_title ON TABLE link COLUMNS title SEARCH ANALYZER link_text BM25;
+DEFINE INDEX idx_search_note ON TABLE link COLUMNS note SEARCH ANALYZER link_text BM25;
+DEFINE INDEX idx_search_url ON TABLE link COLUMNS url SEARCH ANALYZER link_url BM25;
+-- Backfill fields for links saved before they existed
+UPDATE link SET tags = [] WHERE tags = NONE;
+UPDATE link WHERE updated_at = NONE;
+UPDATE link SET canonical_url = url WHERE canonical_url = NONE;
+DEFINE INDEX idx_user_canonical_url ON TABLE link COLUMNS user, canonical_url UNIQUE;
+DEFINE EVENT link_deleted ON TABLE link WHEN $event = "DELETE" THEN (
+    CREATE link_tombstone SET link = $before.id, user = $before.user, deleted_at = time::now()
+);
+
+DEFINE TABLE link_tombstone SCHEMAFULL;
+DEFINE FIELD link ON TABLE link_tombstone TYPE record (link);
+DEFINE FIELD user ON TABLE link_tombstone TYPE record (user);
+DEFINE FIELD deleted_at ON TABLE link_tombstone TYPE datetime DEFAULT time::now();
+DEFINE INDEX idx_user_deleted_at ON TABLE link_tombstone COLUMNS user, deleted_at;
+
+DEFINE TABLE tag SCHEMAFULL;
+DEFINE FIELD name ON TABLE tag TYPE string;
+DEFINE FIELD user ON TABLE tag TYPE record (user);
+DEFINE INDEX idx_user_name ON TABLE tag COLUMNS user, name UNIQUE;
+
+DEFINE TABLE collection SCHEMAFULL;
+DEFINE FIELD name ON TABLE collection TYPE string;
+DEFINE FIELD user ON TABLE collection TYPE record (user);
+DEFINE FIELD parent ON TABLE collection TYPE option<record (collection)>;
+DEFINE FIELD position ON TABLE collection TYPE int DEFAULT 0;
+DEFINE FIELD created_at ON TABLE collection TYPE datetime DEFAULT time::now();
+DEFINE INDEX idx_user ON TABLE collection COLUMNS user;
+
+DEFINE TABLE device SCHEMAFULL;
+DEFINE FIELD name ON TABLE device TYPE string;
+DEFINE FIELD user ON TABLE device TYPE record (user);
+DEFINE FIELD cursor ON TABLE device TYPE option<datetime>;
+DEFINE FIELD created_at ON TABLE device TYPE datetime DEFAULT time::now();
+DEFINE FIELD last_synced_at ON TABLE device TYPE option<datetime>;
+DEFINE INDEX idx_user ON TABLE device COLUMNS user;
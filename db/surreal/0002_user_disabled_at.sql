@@ -0,0 +1 @@
+DEFINE FIELD disabled_at ON TABLE user TYPE option<datetime>;
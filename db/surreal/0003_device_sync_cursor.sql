@@ -0,0 +1 @@
+DEFINE FIELD sync_cursor ON TABLE device TYPE option<string>;
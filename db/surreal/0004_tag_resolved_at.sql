@@ -0,0 +1,2 @@
+DEFINE FIELD resolved_at ON TABLE tag TYPE datetime DEFAULT time::now();
+UPDATE tag SET resolved_at = time::now() WHERE resolved_at = NONE;
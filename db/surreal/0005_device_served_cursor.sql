@@ -0,0 +1 @@
+DEFINE FIELD served_cursor ON TABLE device TYPE option<string>;
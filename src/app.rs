@@ -1,5 +1,6 @@
 use axum::{
-    http::Method,
+    extract::State,
+    http::{header, HeaderValue, Method},
     middleware,
     response::{IntoResponse, Response},
     routing::get,
@@ -14,14 +15,31 @@ use uuid::Uuid;
 use crate::{
     error::Error,
     middlewares,
-    routes::{auth, health_check, link_routes, token},
+    routes::{
+        account, auth, collection_routes, health_check, health_routes, import_routes, link_routes,
+        metrics, sync_routes, tag_routes, token,
+    },
     types::AppState,
 };
 
 pub fn get_app(state: &AppState) -> Router {
     let token_routes = token::routes(state.clone());
+    let sync_routes = sync_routes::routes(state.clone());
+    let tag_routes = tag_routes::routes(state.clone());
+    let collection_routes = collection_routes::routes(state.clone());
+    let import_routes = import_routes::routes(state.clone());
+    let account_routes = account::routes(state.clone());
     let api_routes = link_routes::routes(state.clone())
         .merge(token_routes)
+        .merge(sync_routes)
+        .merge(tag_routes)
+        .merge(collection_routes)
+        .merge(import_routes)
+        .merge(account_routes)
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            middlewares::rate_limit::mw_rate_limit_api,
+        ))
         .route_layer(middleware::from_fn(middlewares::auth::mw_require_auth));
 
     let auth_routes = auth::routes(state.clone());
@@ -29,7 +47,10 @@ pub fn get_app(state: &AppState) -> Router {
     Router::new()
         .merge(auth_routes)
         .nest("/api", api_routes)
-        .layer(middleware::map_response(main_response_mapper))
+        .layer(middleware::map_response_with_state(
+            state.clone(),
+            main_response_mapper,
+        ))
         .layer(middleware::from_fn_with_state(
             state.clone(),
             middlewares::auth::mw_ctx_resolver,
@@ -39,36 +60,57 @@ pub fn get_app(state: &AppState) -> Router {
         // start OpenTelemetry trace on incoming request
         .layer(OtelAxumLayer::default())
         .route("/health_check", get(health_check))
+        .merge(health_routes(state.clone()))
+        .merge(metrics::routes(state.clone()))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            middlewares::metrics::mw_metrics,
+        ))
         .layer(
             CorsLayer::new()
                 .allow_origin(Any)
                 .allow_headers(Any)
-                .allow_methods([Method::POST, Method::GET]),
+                .allow_methods([Method::POST, Method::GET, Method::PATCH, Method::DELETE]),
         )
 }
 
-async fn main_response_mapper(res: Response) -> Response {
+async fn main_response_mapper(State(app_state): State<AppState>, res: Response) -> Response {
     let uuid = Uuid::new_v4();
 
     // -- Get the eventual response error.
     let service_error = res.extensions().get::<Error>();
+    if let Some(service_error) = service_error {
+        app_state.metrics.record_error(service_error);
+    }
     let client_status_error = service_error.map(|se| se.client_status_and_error());
+    let client_data = service_error.and_then(|se| se.client_data());
+    let retry_after = service_error.and_then(|se| se.retry_after());
 
     // -- If client error, build the new reponse.
     let error_response = client_status_error
         .as_ref()
         .map(|(status_code, client_error)| {
-            let client_error_body = json!({
+            let mut client_error_body = json!({
                 "error": {
                     "type": client_error.as_ref(),
                     "req_uuid": uuid.to_string(),
                 }
             });
+            if let Some(data) = &client_data {
+                client_error_body["error"]["data"] = data.clone();
+            }
 
             error!("    ->> client_error_body: {client_error_body}");
 
             // Build the new response from the client_error_body
-            (*status_code, Json(client_error_body)).into_response()
+            let mut response = (*status_code, Json(client_error_body)).into_response();
+            if let Some(retry_after) = retry_after {
+                response
+                    .headers_mut()
+                    .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+            }
+
+            response
         });
 
     error_response.unwrap_or(res)
@@ -1,23 +1,57 @@
-use chrono::Utc;
+use chrono::{DateTime, Duration, Utc};
 use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rand::{rngs::OsRng, RngCore};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use surrealdb::sql::{thing, Datetime, Thing};
 use tracing::error;
 
 use crate::{
     error::{Error, Result},
-    types::User,
+    types::{
+        AppState, PasswordReset, PasswordResetDBResult, RefreshToken, RefreshTokenDBResult,
+        SessionDBResult, User,
+    },
 };
 
+/// A refresh token along with the session and user it belongs to. The user
+/// is loaded through `AppState::users`, since it may not be stored in
+/// SurrealDB.
+#[derive(Debug, Deserialize)]
+struct RefreshTokenLookup {
+    id: Thing,
+    session: Thing,
+    expires_at: DateTime<Utc>,
+    used_at: Option<DateTime<Utc>>,
+    session_revoked_at: Option<DateTime<Utc>>,
+    user_id: Thing,
+}
+
+/// Access tokens are short lived since they are only checked against the
+/// session store, not revoked on their own. Clients renew them with the
+/// refresh token.
+const ACCESS_TOKEN_TTL_MINUTES: i64 = 15;
+const REFRESH_TOKEN_TTL_DAYS: i64 = 30;
+const PASSWORD_RESET_TTL_MINUTES: i64 = 30;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
     pub username: String,
     pub exp: i64,
+    /// Id of the session the token was issued for.
+    pub jti: String,
+}
+
+/// The tokens handed to a client when signing in or refreshing.
+pub struct SessionTokens {
+    pub token: String,
+    pub refresh_token: String,
 }
 
-pub fn create_jwt(user: &User) -> Result<String> {
+pub fn create_jwt(user: &User, session_id: &Thing) -> Result<String> {
     let exp = Utc::now()
-        .checked_add_signed(chrono::Duration::hours(24))
+        .checked_add_signed(Duration::minutes(ACCESS_TOKEN_TTL_MINUTES))
         .expect("Failed generating timestamp")
         .timestamp();
 
@@ -25,6 +59,7 @@ pub fn create_jwt(user: &User) -> Result<String> {
         sub: user.id.to_string(),
         username: user.username.clone(),
         exp,
+        jti: session_id.to_string(),
     };
     let header = Header::new(Algorithm::HS512);
     let secret = std::env::var("JWT_ENCODING_SECRET").expect("Failed to get JWT encoding secret");
@@ -39,7 +74,9 @@ pub fn create_jwt(user: &User) -> Result<String> {
     })
 }
 
-pub fn validate_jwt(jwt: &str) -> Result<Claims> {
+/// Checks the signature and expiry of `jwt` and that its session hasn't been
+/// revoked.
+pub async fn validate_jwt(jwt: &str, app_state: &AppState) -> Result<Claims> {
     let secret = std::env::var("JWT_ENCODING_SECRET").expect("Failed to get JWT encoding secret");
     let decoded = decode::<Claims>(
         jwt,
@@ -56,5 +93,276 @@ pub fn validate_jwt(jwt: &str) -> Result<Claims> {
         return Err(Error::AuthExpired);
     }
 
-    Ok(decoded.claims)
+    let session_id = match thing(&decoded.claims.jti) {
+        Ok(session_id) if session_id.tb == "session" => session_id,
+        _ => return Err(Error::JWTValidationError),
+    };
+    let session = get_session(app_state, &session_id).await?;
+    match session {
+        Some(session)
+            if session.revoked_at.is_none() && session.user.to_string() == decoded.claims.sub =>
+        {
+            Ok(decoded.claims)
+        }
+        _ => Err(Error::SessionRevoked),
+    }
+}
+
+/// Starts a new session for the user and returns its first tokens.
+pub async fn create_session(app_state: &AppState, user: &User) -> Result<SessionTokens> {
+    let mut result = app_state
+        .db
+        .query("CREATE session SET user = $user_id;")
+        .bind(("user_id", &user.id))
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::CreateSessionFail
+        })?;
+    let session: Option<SessionDBResult> = result.take(0).map_err(|e| {
+        error!("Encountered error {:?}", e);
+        Error::CreateSessionFail
+    })?;
+    let session = session.ok_or(Error::CreateSessionFail)?;
+
+    issue_tokens(app_state, user, &session.id).await
+}
+
+/// Swaps a refresh token for new tokens. Refresh tokens can only be used
+/// once: using one again means it was leaked, so the whole session is revoked.
+pub async fn refresh_session(app_state: &AppState, refresh_token: &str) -> Result<SessionTokens> {
+    let mut result = app_state
+        .db
+        .query(
+            "SELECT id, session, expires_at, used_at, session.revoked_at AS session_revoked_at, \
+                session.user AS user_id \
+                FROM refresh_token WHERE token_hash = $token_hash;",
+        )
+        .bind(("token_hash", hash_secret(refresh_token)))
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::RefreshSessionFail
+        })?;
+    let stored: Option<RefreshTokenLookup> = result.take(0).map_err(|e| {
+        error!("Encountered error {:?}", e);
+        Error::RefreshSessionFail
+    })?;
+    let stored = stored.ok_or(Error::InvalidRefreshToken)?;
+
+    if stored.session_revoked_at.is_some() {
+        return Err(Error::SessionRevoked);
+    }
+    if stored.used_at.is_some() {
+        error!("Refresh token reused, revoking {}", stored.session);
+        revoke_session(app_state, &stored.session).await?;
+        return Err(Error::RefreshTokenReused);
+    }
+    if stored.expires_at < Utc::now() {
+        return Err(Error::AuthExpired);
+    }
+
+    // Only one of two requests racing with the same token gets to use it
+    let mut result = app_state
+        .db
+        .query("UPDATE $refresh_token_id SET used_at = time::now() WHERE used_at = NONE;")
+        .bind(("refresh_token_id", &stored.id))
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::RefreshSessionFail
+        })?;
+    let used: Option<RefreshTokenDBResult> = result.take(0).map_err(|e| {
+        error!("Encountered error {:?}", e);
+        Error::RefreshSessionFail
+    })?;
+    if used.is_none() {
+        revoke_session(app_state, &stored.session).await?;
+        return Err(Error::RefreshTokenReused);
+    }
+
+    let user = app_state
+        .users
+        .find_by_id(&stored.user_id)
+        .await?
+        .ok_or(Error::InvalidRefreshToken)?;
+    let user = User {
+        id: user.id,
+        username: user.username,
+    };
+
+    issue_tokens(app_state, &user, &stored.session).await
+}
+
+/// Revokes the session so that neither its access nor its refresh tokens
+/// work anymore.
+pub async fn revoke_session(app_state: &AppState, session_id: &Thing) -> Result<()> {
+    app_state
+        .db
+        .query("UPDATE $session_id SET revoked_at = time::now() WHERE revoked_at = NONE;")
+        .query("DELETE refresh_token WHERE session = $session_id;")
+        .bind(("session_id", session_id))
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::RevokeSessionFail
+        })?
+        .check()
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::RevokeSessionFail
+        })?;
+
+    Ok(())
+}
+
+/// Revokes all of the user's sessions but `except`, e.g. after their password
+/// changed.
+pub async fn revoke_user_sessions(
+    app_state: &AppState,
+    user_id: &Thing,
+    except: Option<&Thing>,
+) -> Result<()> {
+    app_state
+        .db
+        .query(
+            "UPDATE session SET revoked_at = time::now() \
+                WHERE user = $user_id AND revoked_at = NONE AND id != $except;",
+        )
+        .query("DELETE refresh_token WHERE session.user = $user_id AND session != $except;")
+        .bind(("user_id", user_id))
+        .bind(("except", except))
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::RevokeSessionFail
+        })?
+        .check()
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::RevokeSessionFail
+        })?;
+
+    Ok(())
+}
+
+/// Creates a single use password reset token for the user. Only its hash is
+/// stored, the token itself has to be sent to the user.
+pub async fn create_password_reset(app_state: &AppState, user_id: &Thing) -> Result<String> {
+    let token = generate_secret();
+    let expires_at = Utc::now()
+        .checked_add_signed(Duration::minutes(PASSWORD_RESET_TTL_MINUTES))
+        .expect("Failed generating timestamp");
+
+    let _: Vec<PasswordResetDBResult> = app_state
+        .db
+        .create("password_reset")
+        .content(PasswordReset {
+            token_hash: hash_secret(&token),
+            user: user_id.clone(),
+            expires_at: Datetime::from(expires_at),
+        })
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::ResetPasswordFail
+        })?;
+
+    Ok(token)
+}
+
+/// Uses up a password reset token and returns the user it was created for.
+/// All of the user's other reset tokens stop working as well.
+pub async fn consume_password_reset(app_state: &AppState, token: &str) -> Result<Thing> {
+    // Only one of two requests racing with the same token gets to use it
+    let mut result = app_state
+        .db
+        .query(
+            "UPDATE password_reset SET used_at = time::now() \
+                WHERE token_hash = $token_hash AND used_at = NONE AND expires_at > time::now();",
+        )
+        .bind(("token_hash", hash_secret(token)))
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::ResetPasswordFail
+        })?;
+    let reset: Option<PasswordResetDBResult> = result.take(0).map_err(|e| {
+        error!("Encountered error {:?}", e);
+        Error::ResetPasswordFail
+    })?;
+    let reset = reset.ok_or(Error::InvalidResetToken)?;
+
+    app_state
+        .db
+        .query("UPDATE password_reset SET used_at = time::now() WHERE user = $user_id AND used_at = NONE;")
+        .bind(("user_id", &reset.user))
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::ResetPasswordFail
+        })?;
+
+    Ok(reset.user)
+}
+
+async fn get_session(app_state: &AppState, session_id: &Thing) -> Result<Option<SessionDBResult>> {
+    let mut result = app_state
+        .db
+        .query("SELECT * FROM $session_id;")
+        .bind(("session_id", session_id))
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::GetSessionFail
+        })?;
+    let session: Option<SessionDBResult> = result.take(0).map_err(|e| {
+        error!("Encountered error {:?}", e);
+        Error::GetSessionFail
+    })?;
+
+    Ok(session)
+}
+
+async fn issue_tokens(
+    app_state: &AppState,
+    user: &User,
+    session_id: &Thing,
+) -> Result<SessionTokens> {
+    let refresh_token = generate_secret();
+    let expires_at = Utc::now()
+        .checked_add_signed(Duration::days(REFRESH_TOKEN_TTL_DAYS))
+        .expect("Failed generating timestamp");
+
+    let _: Vec<RefreshTokenDBResult> = app_state
+        .db
+        .create("refresh_token")
+        .content(RefreshToken {
+            token_hash: hash_secret(&refresh_token),
+            session: session_id.clone(),
+            expires_at: Datetime::from(expires_at),
+        })
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::CreateSessionFail
+        })?;
+
+    Ok(SessionTokens {
+        token: create_jwt(user, session_id)?,
+        refresh_token,
+    })
+}
+
+/// A random secret handed to clients, of which only the hash is stored.
+pub(crate) fn generate_secret() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bs58::encode(bytes).into_string()
+}
+
+pub(crate) fn hash_secret(secret: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(secret);
+    hex::encode(hasher.finalize())
 }
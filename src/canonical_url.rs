@@ -0,0 +1,98 @@
+//! Module for normalising URLs so that the same page saved from different
+//! places ends up with the same canonical URL.
+
+use url::Url;
+
+/// Query parameters that only track where a visit came from. A trailing `*`
+/// matches any parameter starting with what comes before it.
+pub const DEFAULT_TRACKING_PARAMS: &[&str] = &[
+    "utm_*", "fbclid", "gclid", "dclid", "gbraid", "wbraid", "msclkid", "yclid", "igshid",
+    "mc_cid", "mc_eid", "_hsenc", "_hsmi", "ref_src",
+];
+
+#[derive(Debug, Clone)]
+pub struct UrlCanonicalizer {
+    tracking_params: Vec<String>,
+}
+
+impl Default for UrlCanonicalizer {
+    fn default() -> Self {
+        Self::new(
+            DEFAULT_TRACKING_PARAMS
+                .iter()
+                .map(|param| param.to_string())
+                .collect(),
+        )
+    }
+}
+
+impl UrlCanonicalizer {
+    pub fn new(tracking_params: Vec<String>) -> Self {
+        Self {
+            tracking_params: tracking_params
+                .into_iter()
+                .map(|param| param.to_lowercase())
+                .collect(),
+        }
+    }
+
+    /// Returns the canonical form of `url`:
+    /// - `http` is upgraded to `https`
+    /// - the host is lowercased and a leading `www.` removed
+    /// - the fragment, tracking parameters and trailing slashes are dropped
+    /// - the remaining query parameters are sorted
+    ///
+    /// Strings that don't parse as an absolute URL are only trimmed.
+    pub fn canonicalize(&self, url: &str) -> String {
+        let url = url.trim();
+        let mut parsed = match Url::parse(url) {
+            Ok(parsed) => parsed,
+            Err(_) => return url.to_string(),
+        };
+
+        if !matches!(parsed.scheme(), "http" | "https") {
+            parsed.set_fragment(None);
+            return parsed.to_string();
+        }
+
+        if parsed.scheme() == "http" {
+            // Only fails for special to non-special scheme changes
+            let _ = parsed.set_scheme("https");
+        }
+        if let Some(host) = parsed.host_str() {
+            if let Some(host) = host.strip_prefix("www.") {
+                let host = host.to_string();
+                let _ = parsed.set_host(Some(&host));
+            }
+        }
+
+        parsed.set_fragment(None);
+
+        let mut query: Vec<(String, String)> = parsed
+            .query_pairs()
+            .filter(|(name, _)| !self.is_tracking_param(name))
+            .map(|(name, value)| (name.into_owned(), value.into_owned()))
+            .collect();
+        query.sort();
+        if query.is_empty() {
+            parsed.set_query(None);
+        } else {
+            parsed.query_pairs_mut().clear().extend_pairs(query);
+        }
+
+        let path = parsed.path().trim_end_matches('/').to_string();
+        parsed.set_path(if path.is_empty() { "/" } else { &path });
+
+        parsed.to_string()
+    }
+
+    fn is_tracking_param(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+        self.tracking_params
+            .iter()
+            .any(|param| match param.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == *param,
+            })
+    }
+}
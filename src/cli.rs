@@ -0,0 +1,273 @@
+//! Module for the subcommands of the `linkstowr` binary, so that operational
+//! tasks don't mean hand-writing SurrealQL against production. `serve` and
+//! `migrate` are run by `main`, the admin commands by `run`.
+
+use std::{fs, io, path::PathBuf};
+
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
+use surrealdb::sql::thing;
+
+use crate::{
+    auth::revoke_user_sessions,
+    configuration::DatabaseBackend,
+    ctx::Scope,
+    error::Error,
+    routes::{
+        account::export_user,
+        auth::{create_user, parse_email},
+        token::gen_pak,
+    },
+    types::{AppState, UserDBResult},
+};
+
+type CliResult = std::result::Result<(), Box<dyn std::error::Error>>;
+
+#[derive(Debug, Parser)]
+#[command(name = "linkstowr", about = "LinkStowr API server and admin commands")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Start the API server. This is the default.
+    Serve,
+    /// Apply pending database migrations and exit.
+    Migrate,
+    #[command(flatten)]
+    Admin(AdminCommand),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AdminCommand {
+    /// Manage users.
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Manage a user's API tokens.
+    #[command(subcommand)]
+    Token(TokenCommand),
+    /// Write everything stored about a user as JSON.
+    Export {
+        #[arg(long)]
+        user: String,
+        /// File to write to instead of stdout.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Export the configured SurrealDB database (`database.db` in
+    /// `database.ns`) to a file of SurrealQL statements. Other databases of
+    /// the namespace aren't included. Only available with the `surreal`
+    /// backend, since it leaves out what is stored in SQL.
+    Backup { path: PathBuf },
+    /// Import a file written by `backup` into the configured SurrealDB
+    /// database. Only available with the `surreal` backend.
+    Restore { path: PathBuf },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum UserCommand {
+    /// Create a user. The password is read from stdin.
+    Create {
+        username: String,
+        #[arg(long)]
+        email: Option<String>,
+    },
+    /// List all users.
+    List,
+    /// Stop a user from signing in. Their sessions are revoked and their API
+    /// tokens refused while they're disabled. Running servers keep accepting
+    /// tokens they validated recently for up to `auth.token_cache_ttl_secs`.
+    Disable { username: String },
+    /// Let a disabled user sign in and use their API tokens again.
+    Enable { username: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum TokenCommand {
+    /// Create an API token for a user and print it.
+    Issue {
+        #[arg(long)]
+        user: String,
+        #[arg(long)]
+        name: String,
+        /// Comma separated scopes, like `links:read,links:write`. Defaults to
+        /// the scopes of tokens created through the API.
+        #[arg(long, value_delimiter = ',', value_parser = parse_scope)]
+        scopes: Vec<Scope>,
+        /// RFC 3339 time when the token stops working.
+        #[arg(long)]
+        expires_at: Option<DateTime<Utc>>,
+    },
+    /// Delete one of a user's API tokens. Running servers keep accepting it
+    /// if they validated it recently, for up to `auth.token_cache_ttl_secs`.
+    Revoke {
+        #[arg(long)]
+        user: String,
+        /// Id of the token, like `token:abc`.
+        id: String,
+    },
+}
+
+fn parse_scope(scope: &str) -> std::result::Result<Scope, String> {
+    serde_json::from_value(scope.into()).map_err(|_| format!("unknown scope `{scope}`"))
+}
+
+/// Runs `command` against `app_state`, which stores users, tokens and links
+/// in `backend`.
+pub async fn run(
+    command: AdminCommand,
+    app_state: &AppState,
+    backend: DatabaseBackend,
+) -> CliResult {
+    match command {
+        AdminCommand::User(command) => run_user(command, app_state).await,
+        AdminCommand::Token(command) => run_token(command, app_state).await,
+        AdminCommand::Export { user, output } => {
+            let user = find_user(app_state, &user).await?;
+            let export = export_user(app_state, &user.id).await?;
+            let json = serde_json::to_string_pretty(&export)?;
+            match output {
+                Some(path) => fs::write(path, json)?,
+                None => println!("{json}"),
+            }
+
+            Ok(())
+        }
+        AdminCommand::Backup { path } => {
+            require_surreal(backend, "backup")?;
+            app_state.db.export(&path).await?;
+            eprintln!("Backed up the database to {}", path.display());
+
+            Ok(())
+        }
+        AdminCommand::Restore { path } => {
+            require_surreal(backend, "restore")?;
+            app_state.db.import(&path).await?;
+            eprintln!("Restored the database from {}", path.display());
+
+            Ok(())
+        }
+    }
+}
+
+/// SurrealDB exports only hold what is stored in SurrealDB, so with a SQL
+/// backend they would silently leave out users, tokens and links.
+fn require_surreal(backend: DatabaseBackend, command: &str) -> CliResult {
+    match backend {
+        DatabaseBackend::Surreal => Ok(()),
+        backend => Err(format!(
+            "`{command}` only covers SurrealDB, but users, tokens and links are stored in {backend:?}. Use the tools of that database as well."
+        )
+        .into()),
+    }
+}
+
+async fn run_user(command: UserCommand, app_state: &AppState) -> CliResult {
+    match command {
+        UserCommand::Create { username, email } => {
+            let email = email.as_deref().map(parse_email).transpose()?;
+            eprintln!("Password for {username}:");
+            let mut password = String::new();
+            io::stdin().read_line(&mut password)?;
+            let password = password.trim_end_matches(|c| c == '\r' || c == '\n');
+            if password.is_empty() {
+                return Err("The password can't be empty".into());
+            }
+
+            let user =
+                create_user(username, password.into(), email, app_state.users.as_ref()).await?;
+            println!("{}", user.id);
+        }
+        UserCommand::List => {
+            for user in app_state.users.list().await? {
+                let status = match (user.disabled_at, &user.totp_secret) {
+                    (Some(disabled_at), _) => format!("disabled since {disabled_at}"),
+                    (None, Some(_)) => "active, 2FA".into(),
+                    (None, None) => "active".into(),
+                };
+                println!(
+                    "{}\t{}\t{}\t{status}",
+                    user.id,
+                    user.username,
+                    user.email.as_deref().unwrap_or("-")
+                );
+            }
+        }
+        UserCommand::Disable { username } => {
+            let user = find_user(app_state, &username).await?;
+            // API tokens are refused by `disabled_at` alone, so that enabling
+            // the user brings them back
+            app_state
+                .users
+                .set_disabled_at(&user.id, Some(Utc::now()))
+                .await?;
+            revoke_user_sessions(app_state, &user.id, None).await?;
+            eprintln!("Disabled {username}");
+        }
+        UserCommand::Enable { username } => {
+            let user = find_user(app_state, &username).await?;
+            app_state.users.set_disabled_at(&user.id, None).await?;
+            eprintln!("Enabled {username}");
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_token(command: TokenCommand, app_state: &AppState) -> CliResult {
+    match command {
+        TokenCommand::Issue {
+            user,
+            name,
+            scopes,
+            expires_at,
+        } => {
+            let user = find_user(app_state, &user).await?;
+            if user.disabled_at.is_some() {
+                return Err(Error::AccountDisabled.into());
+            }
+            if expires_at.is_some_and(|expires_at| expires_at <= Utc::now()) {
+                return Err(Error::InvalidTokenExpiry.into());
+            }
+            let scopes = match scopes.is_empty() {
+                true => Scope::DEFAULT_TOKEN.to_vec(),
+                false => scopes,
+            };
+
+            let pak = gen_pak(app_state, &user.id.to_string(), &name, &scopes, expires_at).await?;
+            let token = pak.to_string();
+            println!("{token}");
+        }
+        TokenCommand::Revoke { user, id } => {
+            let user = find_user(app_state, &user).await?;
+            let token_id = match thing(&id) {
+                Ok(token_id) if token_id.tb == "token" => token_id,
+                _ => return Err(Error::InvalidTokenId.into()),
+            };
+            app_state
+                .tokens
+                .find_for_user(&token_id, &user.id)
+                .await?
+                .ok_or(Error::TokenNotFound)?;
+
+            app_state
+                .tokens
+                .delete_for_user(&token_id, &user.id)
+                .await?;
+            eprintln!("Revoked {id}");
+        }
+    }
+
+    Ok(())
+}
+
+async fn find_user(
+    app_state: &AppState,
+    username: &str,
+) -> std::result::Result<UserDBResult, Box<dyn std::error::Error>> {
+    let user = app_state.users.find_by_username(username).await?;
+
+    user.ok_or_else(|| format!("No user named `{username}`").into())
+}
@@ -1,13 +1,62 @@
+use serde::{Deserialize, Serialize};
+
 use crate::error::{Error, Result};
 
+/// What a request is allowed to do. Signed in users can do everything, API
+/// tokens only what they were created with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Scope {
+    /// Read links, tags and collections, sync devices and export links.
+    #[serde(rename = "links:read")]
+    LinksRead,
+    /// Create, update and delete links, tags and collections, and import links.
+    #[serde(rename = "links:write")]
+    LinksWrite,
+    /// Delete all of the user's links at once.
+    #[serde(rename = "links:clear")]
+    LinksClear,
+    /// Create, list and delete API tokens.
+    #[serde(rename = "tokens:manage")]
+    TokensManage,
+    /// Change the account's password and email.
+    #[serde(rename = "account:manage")]
+    AccountManage,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 5] = [
+        Scope::LinksRead,
+        Scope::LinksWrite,
+        Scope::LinksClear,
+        Scope::TokensManage,
+        Scope::AccountManage,
+    ];
+
+    /// Scopes of API tokens created without any. Everything but managing
+    /// tokens and the account, so a token can't be used to take either over.
+    pub const DEFAULT_TOKEN: [Scope; 3] = [Scope::LinksRead, Scope::LinksWrite, Scope::LinksClear];
+}
+
 #[derive(Clone, Debug)]
 pub struct Ctx {
     user_id: String,
+    scopes: Vec<Scope>,
+    /// Set when authenticated with a JWT rather than an API token.
+    session_id: Option<String>,
 }
 
 impl Ctx {
-    pub fn new(user_id: String) -> Self {
-        Self { user_id }
+    pub fn new(user_id: String, scopes: Vec<Scope>) -> Self {
+        Self {
+            user_id,
+            scopes,
+            session_id: None,
+        }
+    }
+
+    pub fn with_session_id(mut self, session_id: String) -> Self {
+        self.session_id = Some(session_id);
+        self
     }
 }
 
@@ -16,6 +65,22 @@ impl Ctx {
         &self.user_id
     }
 
+    pub fn scopes(&self) -> &[Scope] {
+        &self.scopes
+    }
+
+    pub fn session_id(&self) -> Option<&str> {
+        self.session_id.as_deref()
+    }
+
+    /// Fails with `Error::MissingScope` unless the request has `scope`.
+    pub fn require_scope(&self, scope: Scope) -> Result<()> {
+        match self.scopes.contains(&scope) {
+            true => Ok(()),
+            false => Err(Error::MissingScope(scope)),
+        }
+    }
+
     pub fn try_user_id_tuple(&self) -> Result<(&str, &str)> {
         let parts: Vec<&str> = self.user_id.split(':').collect();
 
@@ -4,37 +4,110 @@ use axum::{
 };
 use serde::Serialize;
 
+use crate::ctx::Scope;
+
 pub type Result<T> = core::result::Result<T, Error>;
 
 #[derive(Clone, Debug, Serialize, strum_macros::AsRefStr)]
 #[serde(tag = "type", content = "data")]
 pub enum Error {
     // Auth errors
+    AccountDisabled,
     AuthExpired,
     AuthFailCtxNotInRequestExt,
     InvalidAuthHeader,
     InvalidCredentials,
+    InvalidMfaChallenge,
+    InvalidMfaCode,
+    InvalidResetToken,
     InvalidToken,
     JWTTokenCreationError,
     JWTValidationError,
+    InvalidRefreshToken,
     MissingAuth,
+    MissingScope(Scope),
     PasswordConfirmMismatch,
+    /// Carries the number of seconds to wait before trying again.
+    RateLimited(u64),
+    RefreshTokenReused,
+    SessionRevoked,
+    TokenExpired,
     UsernameExists,
 
+    // Request errors
+    CollectionNotFound,
+    DeviceNotFound,
+    InvalidCollectionId,
+    InvalidCollectionName,
+    InvalidCollectionParent,
+    InvalidDeviceId,
+    InvalidEmail,
+    InvalidImportFile,
+    InvalidLinkId,
+    InvalidLinkPayload,
+    InvalidLinkUrl,
+    InvalidLinksCursor,
+    InvalidSearchQuery,
+    InvalidSyncCursor,
+    InvalidTagId,
+    InvalidTagName,
+    InvalidTokenExpiry,
+    InvalidTokenId,
+    LinkBatchTooLarge,
+    LinkExists(String),
+    LinkNotFound,
+    TagExists,
+    TagNotFound,
+    TokenNotFound,
+    TotpAlreadyEnabled,
+    TotpNotEnabled,
+    TotpNotPending,
+
     // Server errors
+    AckChangesFail,
+    ChangePasswordFail,
     ClearLinksFail,
+    CreateCollectionFail,
+    CreateDeviceFail,
     CreateLinkFail,
+    CreateSessionFail,
+    DeleteAccountFail,
+    DeleteCollectionFail,
+    DeleteDeviceFail,
+    DeleteLinkFail,
     DeleteTokenFail,
+    EnrollTotpFail,
+    ExportAccountFail,
+    GetChangesFail,
+    GetCollectionsFail,
+    GetDevicesFail,
+    GetLinkFail,
     GetLinksFail,
+    GetSessionFail,
     GetUsersFail,
+    GetTagsFail,
     GetTokensFail,
     InvalidDeleteToken,
+    MergeTagsFail,
+    MoveLinksFail,
+    RefreshSessionFail,
+    ResetPasswordFail,
+    ResolveTagsFail,
+    RevokeSessionFail,
+    SearchLinksFail,
+    SendEmailFail,
     SignInFail,
     SignUpFail,
     CtxCreationFail,
     MissingEnvVar,
     GenTokenFail,
     SplitUserIdFail,
+    UpdateAccountFail,
+    UpdateCollectionFail,
+    UpdateLinkFail,
+    UpdateTagFail,
+    UpdateTokenFail,
+    VerifyMfaFail,
 }
 
 impl core::fmt::Display for Error {
@@ -60,35 +133,123 @@ impl IntoResponse for Error {
 impl Error {
     pub fn client_status_and_error(&self) -> (StatusCode, ClientError) {
         match self {
-            Self::AuthExpired => (StatusCode::UNAUTHORIZED, ClientError::AUTH_EXPIRED),
-            Self::AuthFailCtxNotInRequestExt | Self::MissingAuth => {
-                (StatusCode::UNAUTHORIZED, ClientError::NO_AUTH)
+            Self::AuthExpired | Self::TokenExpired => {
+                (StatusCode::UNAUTHORIZED, ClientError::AUTH_EXPIRED)
             }
+            Self::AuthFailCtxNotInRequestExt
+            | Self::InvalidRefreshToken
+            | Self::MissingAuth
+            | Self::RefreshTokenReused
+            | Self::SessionRevoked => (StatusCode::UNAUTHORIZED, ClientError::NO_AUTH),
+            Self::AccountDisabled => (StatusCode::FORBIDDEN, ClientError::ACCOUNT_DISABLED),
+            Self::MissingScope(_) => (StatusCode::FORBIDDEN, ClientError::MISSING_SCOPE),
+            Self::RateLimited(_) => (StatusCode::TOO_MANY_REQUESTS, ClientError::RATE_LIMITED),
             Self::UsernameExists => (StatusCode::BAD_REQUEST, ClientError::USERNAME_EXISTS),
             Self::JWTValidationError
             | Self::PasswordConfirmMismatch
             | Self::InvalidAuthHeader
             | Self::InvalidToken
             | Self::InvalidCredentials
+            | Self::InvalidMfaChallenge
+            | Self::InvalidMfaCode
+            | Self::InvalidResetToken
             | Self::InvalidDeleteToken
             | Self::GenTokenFail => (StatusCode::BAD_REQUEST, ClientError::INVALID_AUTH),
-            Self::ClearLinksFail
+            Self::InvalidCollectionId
+            | Self::InvalidCollectionName
+            | Self::InvalidCollectionParent
+            | Self::InvalidDeviceId
+            | Self::InvalidEmail
+            | Self::InvalidImportFile
+            | Self::InvalidLinkId
+            | Self::InvalidLinkPayload
+            | Self::InvalidLinkUrl
+            | Self::InvalidLinksCursor
+            | Self::InvalidSearchQuery
+            | Self::LinkBatchTooLarge
+            | Self::InvalidSyncCursor
+            | Self::InvalidTagId
+            | Self::InvalidTagName
+            | Self::InvalidTokenExpiry
+            | Self::InvalidTokenId
+            | Self::TotpAlreadyEnabled
+            | Self::TotpNotEnabled
+            | Self::TotpNotPending => (StatusCode::BAD_REQUEST, ClientError::INVALID_PARAMS),
+            Self::CollectionNotFound
+            | Self::DeviceNotFound
+            | Self::LinkNotFound
+            | Self::TagNotFound
+            | Self::TokenNotFound => (StatusCode::NOT_FOUND, ClientError::NOT_FOUND),
+            Self::LinkExists(_) => (StatusCode::CONFLICT, ClientError::LINK_EXISTS),
+            Self::TagExists => (StatusCode::CONFLICT, ClientError::TAG_EXISTS),
+            Self::AckChangesFail
+            | Self::ChangePasswordFail
+            | Self::ClearLinksFail
+            | Self::CreateCollectionFail
+            | Self::CreateDeviceFail
             | Self::CreateLinkFail
+            | Self::CreateSessionFail
+            | Self::DeleteAccountFail
+            | Self::DeleteCollectionFail
+            | Self::DeleteDeviceFail
+            | Self::DeleteLinkFail
             | Self::DeleteTokenFail
+            | Self::EnrollTotpFail
+            | Self::ExportAccountFail
+            | Self::GetChangesFail
+            | Self::GetCollectionsFail
+            | Self::GetDevicesFail
+            | Self::GetLinkFail
             | Self::GetLinksFail
+            | Self::GetSessionFail
             | Self::GetUsersFail
+            | Self::GetTagsFail
             | Self::GetTokensFail
             | Self::JWTTokenCreationError
+            | Self::MergeTagsFail
+            | Self::MoveLinksFail
+            | Self::RefreshSessionFail
+            | Self::ResetPasswordFail
+            | Self::ResolveTagsFail
+            | Self::RevokeSessionFail
+            | Self::SearchLinksFail
+            | Self::SendEmailFail
             | Self::SignInFail
             | Self::SignUpFail
             | Self::CtxCreationFail
             | Self::MissingEnvVar
-            | Self::SplitUserIdFail => (
+            | Self::SplitUserIdFail
+            | Self::UpdateAccountFail
+            | Self::UpdateCollectionFail
+            | Self::UpdateLinkFail
+            | Self::UpdateTagFail
+            | Self::UpdateTokenFail
+            | Self::VerifyMfaFail => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 ClientError::SERVICE_ERROR,
             ),
         }
     }
+
+    /// Extra details that clients need to handle the error.
+    pub fn client_data(&self) -> Option<serde_json::Value> {
+        match self {
+            Self::LinkExists(id) => Some(serde_json::json!({ "id": id })),
+            Self::MissingScope(scope) => Some(serde_json::json!({ "scope": scope })),
+            Self::RateLimited(retry_after) => {
+                Some(serde_json::json!({ "retry_after": retry_after }))
+            }
+            _ => None,
+        }
+    }
+
+    /// Seconds the client should wait before retrying, sent as `Retry-After`.
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            Self::RateLimited(retry_after) => Some(*retry_after),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, strum_macros::AsRefStr)]
@@ -100,4 +261,11 @@ pub enum ClientError {
     AUTH_EXPIRED,
     SERVICE_ERROR,
     USERNAME_EXISTS,
+    INVALID_PARAMS,
+    NOT_FOUND,
+    TAG_EXISTS,
+    LINK_EXISTS,
+    MISSING_SCOPE,
+    RATE_LIMITED,
+    ACCOUNT_DISABLED,
 }
@@ -0,0 +1,159 @@
+//! Module for writing links out in formats other tools can read.
+//!
+//! Exports are streamed, so every format is written as a header, one chunk per
+//! link and a footer.
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::routes::link_routes::LinkResponse;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    /// The bookmark file every browser can import.
+    Netscape,
+    /// One JSON object per line.
+    Jsonl,
+    Csv,
+    /// A list of links that can be dropped into an Obsidian vault as a note.
+    Markdown,
+}
+
+const CSV_COLUMNS: [&str; 7] = [
+    "url",
+    "title",
+    "note",
+    "tags",
+    "collection",
+    "bookmarked_at",
+    "updated_at",
+];
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Netscape => "text/html; charset=utf-8",
+            Self::Jsonl => "application/x-ndjson",
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::Markdown => "text/markdown; charset=utf-8",
+        }
+    }
+
+    pub fn file_extension(&self) -> &'static str {
+        match self {
+            Self::Netscape => "html",
+            Self::Jsonl => "jsonl",
+            Self::Csv => "csv",
+            Self::Markdown => "md",
+        }
+    }
+
+    pub fn header(&self) -> String {
+        match self {
+            Self::Netscape => "<!DOCTYPE NETSCAPE-Bookmark-file-1>\n\
+                <META HTTP-EQUIV=\"Content-Type\" CONTENT=\"text/html; charset=UTF-8\">\n\
+                <TITLE>Bookmarks</TITLE>\n\
+                <H1>Bookmarks</H1>\n\
+                <DL><p>\n"
+                .to_string(),
+            Self::Jsonl => String::new(),
+            Self::Csv => csv_record(CSV_COLUMNS.iter().map(|column| column.to_string())),
+            Self::Markdown => format!(
+                "---\ntitle: LinkStowr links\nexported_at: {}\n---\n\n",
+                Utc::now().to_rfc3339()
+            ),
+        }
+    }
+
+    pub fn row(&self, link: &LinkResponse) -> String {
+        match self {
+            Self::Netscape => {
+                let mut row = format!(
+                    "    <DT><A HREF=\"{}\" ADD_DATE=\"{}\"",
+                    escape_html(&link.url),
+                    link.bookmarked_at.timestamp()
+                );
+                if let Some(updated_at) = link.updated_at {
+                    row.push_str(&format!(" LAST_MODIFIED=\"{}\"", updated_at.timestamp()));
+                }
+                if !link.tags.is_empty() {
+                    row.push_str(&format!(" TAGS=\"{}\"", escape_html(&link.tags.join(","))));
+                }
+                row.push_str(&format!(">{}</A>\n", escape_html(&link.title)));
+                if !link.note.trim().is_empty() {
+                    row.push_str(&format!("    <DD>{}\n", escape_html(link.note.trim())));
+                }
+                row
+            }
+            Self::Jsonl => serde_json::to_string(link).unwrap_or_default() + "\n",
+            Self::Csv => csv_record([
+                link.url.clone(),
+                link.title.clone(),
+                link.note.clone(),
+                link.tags.join(","),
+                link.collection.clone().unwrap_or_default(),
+                link.bookmarked_at.to_rfc3339(),
+                link.updated_at
+                    .map(|updated_at| updated_at.to_rfc3339())
+                    .unwrap_or_default(),
+            ]),
+            Self::Markdown => {
+                let title = match link.title.trim().is_empty() {
+                    true => link.url.as_str(),
+                    false => link.title.trim(),
+                };
+                let mut row = format!(
+                    "- [{}]({})",
+                    escape_markdown(title),
+                    escape_markdown_url(&link.url)
+                );
+                for tag in &link.tags {
+                    // Obsidian tags can't contain spaces
+                    row.push_str(&format!(" #{}", tag.replace(char::is_whitespace, "-")));
+                }
+                row.push_str(&format!(" ({})\n", link.bookmarked_at.format("%Y-%m-%d")));
+                for line in link.note.lines().filter(|line| !line.trim().is_empty()) {
+                    row.push_str(&format!("    {}\n", line.trim_end()));
+                }
+                row
+            }
+        }
+    }
+
+    pub fn footer(&self) -> String {
+        match self {
+            Self::Netscape => "</DL><p>\n".to_string(),
+            Self::Jsonl | Self::Csv | Self::Markdown => String::new(),
+        }
+    }
+}
+
+fn csv_record(fields: impl IntoIterator<Item = String>) -> String {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    // Writing to a Vec can't fail
+    let _ = writer.write_record(fields);
+    let bytes = writer.into_inner().unwrap_or_default();
+
+    String::from_utf8(bytes).unwrap_or_default()
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace(['\r', '\n'], " ")
+}
+
+fn escape_markdown(text: &str) -> String {
+    text.replace(['\r', '\n'], " ")
+        .replace('[', "\\[")
+        .replace(']', "\\]")
+}
+
+fn escape_markdown_url(url: &str) -> String {
+    url.replace(' ', "%20")
+        .replace('(', "%28")
+        .replace(')', "%29")
+}
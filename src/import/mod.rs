@@ -0,0 +1,76 @@
+//! Module containing the parsers for bookmark exports of browsers and other
+//! bookmarking services.
+//!
+//! Every parser turns an export into a list of `ImportedLink`s. Saving them is
+//! left to the import route so that they go through the same path as links
+//! created through the API.
+
+mod netscape;
+mod pinboard;
+mod pocket;
+mod raindrop;
+
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportFormat {
+    /// The bookmark file every browser exports.
+    Netscape,
+    PocketHtml,
+    PocketCsv,
+    Pinboard,
+    Raindrop,
+}
+
+/// A bookmark read from an export.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportedLink {
+    pub url: String,
+    pub title: String,
+    pub note: String,
+    pub tags: Vec<String>,
+    /// Names of the folders the bookmark was in, from the top level down.
+    pub folder: Vec<String>,
+    pub bookmarked_at: Option<DateTime<Utc>>,
+}
+
+/// An entry of an export that couldn't be read. Holds what went wrong.
+pub type ImportEntry = std::result::Result<ImportedLink, String>;
+
+/// Parses the export `input` in the given format. Fails only when the export
+/// as a whole can't be read; broken entries are returned as errors.
+pub fn parse(format: ImportFormat, input: &str) -> Result<Vec<ImportEntry>> {
+    match format {
+        // Pocket's HTML export is a plain list of links in the same format
+        ImportFormat::Netscape | ImportFormat::PocketHtml => Ok(netscape::parse(input)),
+        ImportFormat::PocketCsv => pocket::parse(input),
+        ImportFormat::Pinboard => pinboard::parse(input),
+        ImportFormat::Raindrop => raindrop::parse(input),
+    }
+}
+
+/// Reads a unix timestamp. Some exporters write milli- or microseconds instead
+/// of seconds, which is told apart by the size of the number.
+fn parse_unix_timestamp(value: &str) -> Option<DateTime<Utc>> {
+    let value: i64 = value.trim().parse().ok()?;
+
+    let timestamp = match value {
+        v if v > 100_000_000_000_000 => Utc.timestamp_millis_opt(v / 1000),
+        v if v > 100_000_000_000 => Utc.timestamp_millis_opt(v),
+        v => Utc.timestamp_opt(v, 0),
+    };
+
+    timestamp.single()
+}
+
+/// Splits a list on `separator`, dropping empty items.
+fn split_list(list: &str, separator: char) -> Vec<String> {
+    list.split(separator)
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
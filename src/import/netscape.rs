@@ -0,0 +1,164 @@
+//! Parser for the Netscape bookmark file format.
+//!
+//! Folders are `<H3>` headings followed by a `<DL>` list of their contents,
+//! bookmarks are `<A>` tags and an optional `<DD>` after a bookmark holds its
+//! description. The files are rarely well formed HTML, so they are read tag by
+//! tag instead of as a document.
+
+use lazy_regex::regex;
+
+use super::{parse_unix_timestamp, split_list, ImportEntry, ImportedLink};
+
+/// What the text up to the next tag belongs to.
+enum Reading {
+    Nothing,
+    Folder(String),
+    Title(ImportedLink),
+    Note,
+}
+
+pub fn parse(input: &str) -> Vec<ImportEntry> {
+    let mut entries: Vec<ImportEntry> = vec![];
+    // One item per open `<DL>`, holding the name of the folder it lists
+    let mut folders: Vec<Option<String>> = vec![];
+    let mut next_folder: Option<String> = None;
+    let mut reading = Reading::Nothing;
+    // Whether a `<DD>` now describes the last bookmark rather than a folder
+    let mut after_link = false;
+    let mut text_start = 0;
+
+    for tag in regex!(r"(?s)<(/?)([a-zA-Z0-9]+)([^>]*)>").captures_iter(input) {
+        let whole = tag.get(0).unwrap();
+        let text = &input[text_start..whole.start()];
+        text_start = whole.end();
+
+        match &mut reading {
+            Reading::Folder(name) | Reading::Title(ImportedLink { title: name, .. }) => {
+                name.push_str(text)
+            }
+            Reading::Note => {
+                let note = decode_entities(text.trim());
+                if let Some(Ok(link)) = entries.last_mut() {
+                    if !note.is_empty() {
+                        link.note = note;
+                    }
+                }
+                reading = Reading::Nothing;
+            }
+            Reading::Nothing => {}
+        }
+
+        let closing = &tag[1] == "/";
+        let name = tag[2].to_lowercase();
+        match (closing, name.as_str()) {
+            (false, "h3") => {
+                after_link = false;
+                reading = Reading::Folder(String::new());
+            }
+            (true, "h3") => {
+                if let Reading::Folder(name) = std::mem::replace(&mut reading, Reading::Nothing) {
+                    next_folder = Some(decode_entities(name.trim()));
+                }
+            }
+            (false, "dl") => {
+                after_link = false;
+                folders.push(next_folder.take());
+            }
+            (true, "dl") => {
+                after_link = false;
+                folders.pop();
+            }
+            (false, "a") => {
+                let attributes = attributes(&tag[3]);
+                let attribute = |name: &str| {
+                    attributes
+                        .iter()
+                        .find(|(key, _)| key == name)
+                        .map(|(_, value)| value.as_str())
+                };
+                reading = Reading::Title(ImportedLink {
+                    url: attribute("href").unwrap_or_default().trim().to_string(),
+                    tags: split_list(attribute("tags").unwrap_or_default(), ','),
+                    folder: folders.iter().flatten().cloned().collect(),
+                    bookmarked_at: attribute("add_date")
+                        .or(attribute("time_added"))
+                        .and_then(parse_unix_timestamp),
+                    ..Default::default()
+                });
+            }
+            (true, "a") => {
+                if let Reading::Title(mut link) = std::mem::replace(&mut reading, Reading::Nothing)
+                {
+                    link.title = decode_entities(link.title.trim());
+                    after_link = true;
+                    match link.url.is_empty() {
+                        true => {
+                            entries.push(Err(format!("Bookmark \"{}\" has no URL", link.title)))
+                        }
+                        false => entries.push(Ok(link)),
+                    }
+                }
+            }
+            (false, "dd") if after_link => reading = Reading::Note,
+            _ => {}
+        }
+    }
+
+    entries
+}
+
+/// Reads the attributes of a tag, with lowercased names.
+fn attributes(tag: &str) -> Vec<(String, String)> {
+    regex!(r#"([a-zA-Z_\-]+)\s*=\s*(?:"([^"]*)"|'([^']*)')"#)
+        .captures_iter(tag)
+        .map(|attribute| {
+            let value = attribute
+                .get(2)
+                .or(attribute.get(3))
+                .map(|value| value.as_str())
+                .unwrap_or_default();
+            (attribute[1].to_lowercase(), decode_entities(value))
+        })
+        .collect()
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let entity = rest.find(';').map(|end| (&rest[1..end], end));
+        let character = entity.and_then(|(entity, _)| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => match entity.strip_prefix("#x").or(entity.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+                None => entity
+                    .strip_prefix('#')
+                    .and_then(|code| code.parse().ok())
+                    .and_then(char::from_u32),
+            },
+        });
+
+        match (character, entity) {
+            (Some(character), Some((_, end))) => {
+                decoded.push(character);
+                rest = &rest[end + 1..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+
+    decoded
+}
@@ -0,0 +1,52 @@
+//! Parser for Pinboard's JSON export.
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::error;
+
+use super::{split_list, ImportEntry, ImportedLink};
+use crate::error::{Error, Result};
+
+#[derive(Debug, Deserialize)]
+struct PinboardPost {
+    href: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    extended: String,
+    time: Option<DateTime<Utc>>,
+    /// Tags separated by spaces.
+    #[serde(default)]
+    tags: String,
+}
+
+pub fn parse(input: &str) -> Result<Vec<ImportEntry>> {
+    let posts: Vec<Value> = serde_json::from_str(input).map_err(|e| {
+        error!("Encountered error {:?}", e);
+        Error::InvalidImportFile
+    })?;
+
+    let entries = posts
+        .into_iter()
+        .enumerate()
+        .map(|(i, post)| {
+            let post: PinboardPost = serde_json::from_value(post)
+                .map_err(|e| format!("Bookmark {} could not be read: {e}", i + 1))?;
+            if post.href.trim().is_empty() {
+                return Err(format!("Bookmark {} has no URL", i + 1));
+            }
+
+            Ok(ImportedLink {
+                url: post.href.trim().to_string(),
+                title: post.description.trim().to_string(),
+                note: post.extended.trim().to_string(),
+                tags: split_list(&post.tags, ' '),
+                folder: vec![],
+                bookmarked_at: post.time,
+            })
+        })
+        .collect();
+
+    Ok(entries)
+}
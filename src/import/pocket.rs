@@ -0,0 +1,51 @@
+//! Parser for Pocket's CSV export, with the columns
+//! `title,url,time_added,tags,status`.
+
+use serde::Deserialize;
+use tracing::error;
+
+use super::{parse_unix_timestamp, split_list, ImportEntry, ImportedLink};
+use crate::error::{Error, Result};
+
+#[derive(Debug, Deserialize)]
+struct PocketRow {
+    title: String,
+    url: String,
+    #[serde(default)]
+    time_added: String,
+    /// Tags separated by `|`.
+    #[serde(default)]
+    tags: String,
+}
+
+pub fn parse(input: &str) -> Result<Vec<ImportEntry>> {
+    let mut reader = csv::Reader::from_reader(input.as_bytes());
+    let headers = reader.headers().map_err(|e| {
+        error!("Encountered error {:?}", e);
+        Error::InvalidImportFile
+    })?;
+    if !headers.iter().any(|header| header == "url") {
+        return Err(Error::InvalidImportFile);
+    }
+
+    let entries = reader
+        .deserialize::<PocketRow>()
+        .enumerate()
+        .map(|(i, row)| {
+            let row = row.map_err(|e| format!("Row {} could not be read: {e}", i + 1))?;
+            if row.url.trim().is_empty() {
+                return Err(format!("Row {} has no URL", i + 1));
+            }
+
+            Ok(ImportedLink {
+                url: row.url.trim().to_string(),
+                title: row.title.trim().to_string(),
+                tags: split_list(&row.tags, '|'),
+                bookmarked_at: parse_unix_timestamp(&row.time_added),
+                ..Default::default()
+            })
+        })
+        .collect();
+
+    Ok(entries)
+}
@@ -0,0 +1,61 @@
+//! Parser for Raindrop.io's CSV export, with the columns
+//! `id,title,note,excerpt,url,folder,tags,created,cover,highlights,favorite`.
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use tracing::error;
+
+use super::{split_list, ImportEntry, ImportedLink};
+use crate::error::{Error, Result};
+
+#[derive(Debug, Deserialize)]
+struct RaindropRow {
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    note: String,
+    url: String,
+    /// Path of the collection, with nested ones separated by `/`.
+    #[serde(default)]
+    folder: String,
+    /// Tags separated by `,`.
+    #[serde(default)]
+    tags: String,
+    #[serde(default)]
+    created: String,
+}
+
+pub fn parse(input: &str) -> Result<Vec<ImportEntry>> {
+    let mut reader = csv::Reader::from_reader(input.as_bytes());
+    let headers = reader.headers().map_err(|e| {
+        error!("Encountered error {:?}", e);
+        Error::InvalidImportFile
+    })?;
+    if !headers.iter().any(|header| header == "url") {
+        return Err(Error::InvalidImportFile);
+    }
+
+    let entries = reader
+        .deserialize::<RaindropRow>()
+        .enumerate()
+        .map(|(i, row)| {
+            let row = row.map_err(|e| format!("Row {} could not be read: {e}", i + 1))?;
+            if row.url.trim().is_empty() {
+                return Err(format!("Row {} has no URL", i + 1));
+            }
+
+            Ok(ImportedLink {
+                url: row.url.trim().to_string(),
+                title: row.title.trim().to_string(),
+                note: row.note.trim().to_string(),
+                tags: split_list(&row.tags, ','),
+                folder: split_list(&row.folder, '/'),
+                bookmarked_at: DateTime::parse_from_rfc3339(row.created.trim())
+                    .ok()
+                    .map(|created| created.with_timezone(&Utc)),
+            })
+        })
+        .collect();
+
+    Ok(entries)
+}
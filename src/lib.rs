@@ -1,10 +1,23 @@
 pub mod app;
 pub mod auth;
+pub mod canonical_url;
+pub mod cli;
 pub mod configuration;
 pub mod ctx;
 pub mod error;
+pub mod export;
+pub mod import;
+pub mod mailer;
+pub mod metrics;
+pub mod mfa;
 pub mod middlewares;
+pub mod migrations;
 pub mod prefixed_api_key;
+pub mod rate_limit;
+pub mod repository;
 pub mod routes;
+pub mod search;
 pub mod telemetry;
+pub mod token_cache;
+pub mod totp;
 pub mod types;
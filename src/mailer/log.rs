@@ -0,0 +1,60 @@
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::error::{Error, Result};
+
+use super::{Email, Mailer};
+
+/// Logs emails instead of sending them. When given an outbox directory, each
+/// email is also written there as its own file.
+#[derive(Debug, Clone, Default)]
+pub struct LogMailer {
+    outbox_dir: Option<PathBuf>,
+}
+
+impl LogMailer {
+    pub fn new(outbox_dir: Option<PathBuf>) -> Self {
+        Self { outbox_dir }
+    }
+}
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, email: Email) -> Result<()> {
+        info!(to = %email.to, subject = %email.subject, "Sending email:\n{}", email.body);
+
+        let Some(outbox_dir) = &self.outbox_dir else {
+            return Ok(());
+        };
+        let path = outbox_dir.join(format!(
+            "{}-{}.eml",
+            Utc::now().timestamp_millis(),
+            Uuid::new_v4()
+        ));
+        let contents = format!(
+            "To: {}\nSubject: {}\n\n{}\n",
+            email.to, email.subject, email.body
+        );
+
+        tokio::fs::create_dir_all(outbox_dir).await.map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::SendEmailFail
+        })?;
+        // Written next to it first, so that the outbox never has half an email
+        let partial_path = path.with_extension("eml.partial");
+        tokio::fs::write(&partial_path, contents)
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::SendEmailFail
+            })?;
+        tokio::fs::rename(&partial_path, &path).await.map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::SendEmailFail
+        })
+    }
+}
@@ -0,0 +1,45 @@
+//! Module for sending emails to users, e.g. password reset tokens.
+//!
+//! Emails go through the `Mailer` trait so that the transport can be picked in
+//! the configuration: SMTP in production, and a mailer that only logs emails
+//! (and optionally writes them to an outbox directory) for local testing.
+
+mod log;
+mod smtp;
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::{
+    configuration::{MailerKind, MailerSettings},
+    error::Result,
+};
+
+pub use self::log::LogMailer;
+pub use smtp::SmtpMailer;
+
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Email) -> Result<()>;
+}
+
+pub fn from_settings(settings: &MailerSettings) -> Arc<dyn Mailer> {
+    match settings.kind {
+        MailerKind::Log => Arc::new(LogMailer::new(settings.outbox_dir.clone())),
+        MailerKind::Smtp => {
+            let smtp = settings
+                .smtp
+                .as_ref()
+                .expect("Missing SMTP settings for the smtp mailer");
+            Arc::new(SmtpMailer::new(smtp, &settings.from).expect("Failed to set up SMTP mailer"))
+        }
+    }
+}
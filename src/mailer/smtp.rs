@@ -0,0 +1,61 @@
+use async_trait::async_trait;
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use tracing::error;
+
+use crate::{
+    configuration::SmtpSettings,
+    error::{Error, Result},
+};
+
+use super::{Email, Mailer};
+
+/// Sends emails through an SMTP relay over STARTTLS.
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn new(settings: &SmtpSettings, from: &str) -> std::result::Result<Self, String> {
+        let from = from.parse::<Mailbox>().map_err(|e| e.to_string())?;
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&settings.host)
+            .map_err(|e| e.to_string())?
+            .port(settings.port)
+            .credentials(Credentials::new(
+                settings.username.clone(),
+                settings.password.clone(),
+            ))
+            .build();
+
+        Ok(Self { transport, from })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: Email) -> Result<()> {
+        let to = email.to.parse::<Mailbox>().map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::SendEmailFail
+        })?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(email.subject)
+            .body(email.body)
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::SendEmailFail
+            })?;
+
+        self.transport.send(message).await.map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::SendEmailFail
+        })?;
+
+        Ok(())
+    }
+}
@@ -1,8 +1,17 @@
+use std::{net::SocketAddr, sync::atomic::Ordering, time::Duration};
+
+use clap::Parser;
 use dotenv::dotenv;
 use linkstowr::{
     app::get_app,
-    configuration::{get_configuration, get_environment, Environment, Settings},
-    telemetry::init_subscribers,
+    canonical_url::UrlCanonicalizer,
+    cli::{self, Cli, Command},
+    configuration::{
+        get_configuration, get_environment, DatabaseBackend, DatabaseSettings, Environment,
+        Settings,
+    },
+    mailer, migrations,
+    telemetry::{init_cli_subscriber, init_subscribers},
     types::AppState,
 };
 use surrealdb::{engine::any::Any, opt::auth::Root, Surreal};
@@ -10,21 +19,54 @@ use tracing::info;
 
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
+    let command = Cli::parse().command.unwrap_or(Command::Serve);
+
     let environment = get_environment();
-    // We need to initialize the OTEL env vars in local before initializing subscribers
+    // We need to initialize the env vars in local before reading the configuration
     if let Environment::Local = environment {
         dotenv().ok();
     }
 
-    init_subscribers().expect("Unable to init tracing subscribers");
-
     let configuration = get_configuration().expect("Failed to read configuration.");
 
+    match command {
+        Command::Serve => {
+            init_subscribers(&configuration.telemetry).expect("Unable to init tracing subscribers")
+        }
+        _ => init_cli_subscriber(),
+    }
+
     let db = get_db(&configuration).await;
+    let url_canonicalizer = UrlCanonicalizer::new(configuration.links.tracking_params.clone());
+    migrations::run(&db, &url_canonicalizer)
+        .await
+        .unwrap_or_else(|e| panic!("Could not migrate the database: {e}"));
+
+    let state = AppState::new(db)
+        .with_link_settings(&configuration.links)
+        .with_auth_settings(&configuration.auth)
+        .with_mailer(mailer::from_settings(&configuration.mailer))
+        .with_rate_limit_settings(&configuration.rate_limit)
+        .with_proxy_settings(&configuration.proxy)
+        .with_metrics_settings(&configuration.metrics);
+    let state = use_database_backend(state, &configuration.database).await;
+
+    match command {
+        Command::Serve => serve(&state, &configuration).await,
+        Command::Migrate => eprintln!("The database is up to date"),
+        Command::Admin(command) => {
+            if let Err(e) = cli::run(command, &state, configuration.database.backend).await {
+                eprintln!("Error: {e}");
+                std::process::exit(1);
+            }
+        }
+    }
 
-    let state = AppState::new(db);
+    Ok(())
+}
 
-    let app = get_app(&state);
+async fn serve(state: &AppState, configuration: &Settings) {
+    let app = get_app(state);
 
     let address = format!(
         "{}:{}",
@@ -32,12 +74,13 @@ async fn main() -> Result<(), std::io::Error> {
     );
     info!("->> LISTENING on {address}\n");
     axum::Server::bind(&address.parse().unwrap())
-        .serve(app.into_make_service())
-        .with_graceful_shutdown(shutdown_signal())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown_signal(
+            state,
+            Duration::from_secs(configuration.application.shutdown_delay_secs),
+        ))
         .await
         .unwrap();
-
-    Ok(())
 }
 
 #[tracing::instrument(
@@ -50,15 +93,18 @@ async fn main() -> Result<(), std::io::Error> {
 )]
 async fn get_db(configuration: &Settings) -> Surreal<Any> {
     let connection_string = configuration.database.get_connection_string();
-    let db = surrealdb::engine::any::connect(connection_string)
+    let db = surrealdb::engine::any::connect(&connection_string)
+        .await
+        .unwrap_or_else(|e| panic!("Could not connect to SurrealDB at {connection_string}: {e}"));
+    // The embedded store has no users to sign in as
+    if !configuration.database.is_embedded() {
+        db.signin(Root {
+            username: &configuration.database.username,
+            password: &configuration.database.password,
+        })
         .await
-        .expect("Could not connect to SurrealDB");
-    db.signin(Root {
-        username: &configuration.database.username,
-        password: &configuration.database.password,
-    })
-    .await
-    .expect("Could not sign into SurrealDB");
+        .expect("Could not sign into SurrealDB");
+    }
 
     db.use_ns(&configuration.database.ns)
         .use_db(&configuration.database.db)
@@ -73,7 +119,45 @@ async fn get_db(configuration: &Settings) -> Surreal<Any> {
     db
 }
 
-async fn shutdown_signal() {
+/// Moves users, tokens and links to the SQL database when one is configured.
+#[cfg(feature = "sql")]
+async fn use_database_backend(state: AppState, settings: &DatabaseSettings) -> AppState {
+    use linkstowr::repository::sql::{
+        connect, SqlLinkRepository, SqlTokenRepository, SqlUserRepository,
+    };
+    use std::sync::Arc;
+
+    if settings.backend == DatabaseBackend::Surreal {
+        return state;
+    }
+
+    let pool = connect(settings.backend, settings.url.as_deref())
+        .await
+        .expect("Could not connect to the SQL database");
+    info!("Storing users, tokens and links in {:?}", settings.backend);
+
+    let db = state.db.clone();
+    state
+        .with_repositories(
+            Arc::new(SqlUserRepository::new(pool.clone())),
+            Arc::new(SqlTokenRepository::new(pool.clone())),
+            Arc::new(SqlLinkRepository::new(pool.clone(), db)),
+        )
+        .with_sql_pool(pool)
+}
+
+#[cfg(not(feature = "sql"))]
+async fn use_database_backend(state: AppState, settings: &DatabaseSettings) -> AppState {
+    assert!(
+        settings.backend == DatabaseBackend::Surreal,
+        "The {:?} backend needs linkstowr to be built with the `sqlite` or `postgres` feature",
+        settings.backend
+    );
+
+    state
+}
+
+async fn shutdown_signal(state: &AppState, delay: Duration) {
     let ctrl_c = async {
         tokio::signal::ctrl_c()
             .await
@@ -97,5 +181,7 @@ async fn shutdown_signal() {
     }
 
     tracing::warn!("signal received, starting graceful shutdown");
+    state.shutting_down.store(true, Ordering::Relaxed);
+    tokio::time::sleep(delay).await;
     opentelemetry::global::shutdown_tracer_provider();
 }
@@ -0,0 +1,165 @@
+//! Module for the Prometheus metrics served on `/metrics`: request rate,
+//! errors and latency per route, latency of the storage behind the
+//! repositories, and a few business counters.
+
+use std::time::Duration;
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+
+use crate::{error::Error, token_cache::TokenCacheStats};
+
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    errors: IntCounterVec,
+    db_query_duration: HistogramVec,
+    links_created: IntCounter,
+    tokens_validated: IntCounterVec,
+    token_cache_hits: IntGauge,
+    token_cache_misses: IntGauge,
+    token_cache_entries: IntGauge,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests handled"),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time taken to handle HTTP requests",
+            ),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let errors = IntCounterVec::new(
+            Opts::new("app_errors_total", "Errors returned to clients, by variant"),
+            &["error"],
+        )
+        .unwrap();
+        let db_query_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "db_query_duration_seconds",
+                "Time taken by repository operations",
+            )
+            .buckets(vec![
+                0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+            ]),
+            &["repository", "operation"],
+        )
+        .unwrap();
+        let links_created =
+            IntCounter::new("links_created_total", "Links created, including imports").unwrap();
+        let tokens_validated = IntCounterVec::new(
+            Opts::new("api_tokens_validated_total", "API token validations"),
+            &["result"],
+        )
+        .unwrap();
+        let token_cache_hits = IntGauge::new(
+            "token_cache_hits",
+            "API token validations served from cache",
+        )
+        .unwrap();
+        let token_cache_misses = IntGauge::new(
+            "token_cache_misses",
+            "API token validations that had to look the token up",
+        )
+        .unwrap();
+        let token_cache_entries =
+            IntGauge::new("token_cache_entries", "API tokens in the validation cache").unwrap();
+
+        registry.register(Box::new(http_requests.clone())).unwrap();
+        registry
+            .register(Box::new(http_request_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(errors.clone())).unwrap();
+        registry
+            .register(Box::new(db_query_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(links_created.clone())).unwrap();
+        registry
+            .register(Box::new(tokens_validated.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(token_cache_hits.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(token_cache_misses.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(token_cache_entries.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            http_requests,
+            http_request_duration,
+            errors,
+            db_query_duration,
+            links_created,
+            tokens_validated,
+            token_cache_hits,
+            token_cache_misses,
+            token_cache_entries,
+        }
+    }
+
+    /// `route` is the matched route, like `/api/links/:id`, so that the number
+    /// of series doesn't grow with ids.
+    pub fn record_request(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+        let status = status.to_string();
+        let labels = [method, route, status.as_str()];
+        self.http_requests.with_label_values(&labels).inc();
+        self.http_request_duration
+            .with_label_values(&labels)
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn record_error(&self, error: &Error) {
+        self.errors.with_label_values(&[error.as_ref()]).inc();
+    }
+
+    pub fn record_db_query(&self, repository: &str, operation: &str, elapsed: Duration) {
+        self.db_query_duration
+            .with_label_values(&[repository, operation])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn record_links_created(&self, count: usize) {
+        self.links_created.inc_by(count as u64);
+    }
+
+    /// `result` is `valid`, `expired` or `invalid`.
+    pub fn record_token_validation(&self, result: &str) {
+        self.tokens_validated.with_label_values(&[result]).inc();
+    }
+
+    /// All metrics in the Prometheus text format.
+    pub fn render(&self, token_cache: &TokenCacheStats) -> String {
+        self.token_cache_hits.set(token_cache.hits as i64);
+        self.token_cache_misses.set(token_cache.misses as i64);
+        self.token_cache_entries.set(token_cache.entries as i64);
+
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("Failed to encode metrics");
+
+        String::from_utf8(buffer).expect("Metrics are valid UTF-8")
+    }
+}
@@ -0,0 +1,224 @@
+//! Module for two-factor authentication: the challenges that stand between
+//! the password check and a session, and the recovery codes that replace TOTP
+//! codes when the authenticator is lost.
+
+use chrono::{Duration, Utc};
+use rand::{rngs::OsRng, RngCore};
+use surrealdb::sql::{Datetime, Thing};
+use tracing::error;
+
+use crate::{
+    auth::{generate_secret, hash_secret},
+    error::{Error, Result},
+    totp,
+    types::{
+        AppState, MfaChallenge, MfaChallengeDBResult, RecoveryCode, RecoveryCodeDBResult,
+        UserDBResult,
+    },
+};
+
+const CHALLENGE_TTL_MINUTES: i64 = 5;
+/// Codes that can be tried per challenge before the password has to be entered
+/// again.
+const MAX_CHALLENGE_ATTEMPTS: i64 = 5;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Creates a challenge for a user whose password checked out. Only its hash
+/// is stored, the token itself goes back to the client.
+pub async fn create_challenge(app_state: &AppState, user_id: &Thing) -> Result<String> {
+    let token = generate_secret();
+    let expires_at = Utc::now()
+        .checked_add_signed(Duration::minutes(CHALLENGE_TTL_MINUTES))
+        .expect("Failed generating timestamp");
+
+    let _: Vec<MfaChallengeDBResult> = app_state
+        .db
+        .create("mfa_challenge")
+        .content(MfaChallenge {
+            token_hash: hash_secret(&token),
+            user: user_id.clone(),
+            expires_at: Datetime::from(expires_at),
+        })
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::VerifyMfaFail
+        })?;
+
+    Ok(token)
+}
+
+/// Returns the challenge if it can still be completed.
+pub async fn get_challenge(app_state: &AppState, token: &str) -> Result<MfaChallengeDBResult> {
+    let mut result = app_state
+        .db
+        .query("SELECT * FROM mfa_challenge WHERE token_hash = $token_hash;")
+        .bind(("token_hash", hash_secret(token)))
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::VerifyMfaFail
+        })?;
+    let challenge: Option<MfaChallengeDBResult> = result.take(0).map_err(|e| {
+        error!("Encountered error {:?}", e);
+        Error::VerifyMfaFail
+    })?;
+
+    match challenge {
+        Some(challenge)
+            if challenge.used_at.is_none()
+                && challenge.expires_at > Utc::now()
+                && challenge.attempts < MAX_CHALLENGE_ATTEMPTS =>
+        {
+            Ok(challenge)
+        }
+        _ => Err(Error::InvalidMfaChallenge),
+    }
+}
+
+/// Counts an attempt at completing the challenge before the code is checked,
+/// so that concurrent requests can't get more attempts than allowed. Fails
+/// once they are all used up.
+pub async fn use_attempt(app_state: &AppState, challenge_id: &Thing) -> Result<()> {
+    let mut result = app_state
+        .db
+        .query(
+            "UPDATE $challenge_id SET attempts += 1
+                WHERE attempts < $max_attempts AND used_at = NONE AND expires_at > time::now()
+                RETURN AFTER;",
+        )
+        .bind(("challenge_id", challenge_id))
+        .bind(("max_attempts", MAX_CHALLENGE_ATTEMPTS))
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::VerifyMfaFail
+        })?;
+    let challenge: Option<MfaChallengeDBResult> = result.take(0).map_err(|e| {
+        error!("Encountered error {:?}", e);
+        Error::VerifyMfaFail
+    })?;
+
+    challenge.map(|_| ()).ok_or(Error::InvalidMfaChallenge)
+}
+
+/// Marks the challenge as used. Fails if another request got to it first.
+pub async fn complete_challenge(app_state: &AppState, challenge_id: &Thing) -> Result<()> {
+    let mut result = app_state
+        .db
+        .query("UPDATE $challenge_id SET used_at = time::now() WHERE used_at = NONE;")
+        .bind(("challenge_id", challenge_id))
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::VerifyMfaFail
+        })?;
+    let completed: Option<MfaChallengeDBResult> = result.take(0).map_err(|e| {
+        error!("Encountered error {:?}", e);
+        Error::VerifyMfaFail
+    })?;
+
+    completed.map(|_| ()).ok_or(Error::InvalidMfaChallenge)
+}
+
+/// Checks a TOTP code against `secret` and records its time step for the
+/// user, so that the same code can't be used twice.
+pub async fn check_totp_code(
+    app_state: &AppState,
+    user: &UserDBResult,
+    secret: &str,
+    code: &str,
+) -> Result<bool> {
+    let step = match totp::verify(secret, code, Utc::now().timestamp()) {
+        Some(step) => step,
+        None => return Ok(false),
+    };
+    if user
+        .totp_last_step
+        .is_some_and(|last_step| step <= last_step)
+    {
+        return Ok(false);
+    }
+
+    app_state.users.record_totp_step(&user.id, step).await
+}
+
+/// Replaces the user's recovery codes with new ones and returns them. They
+/// can't be shown again since only their hashes are stored.
+pub async fn replace_recovery_codes(app_state: &AppState, user_id: &Thing) -> Result<Vec<String>> {
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| generate_recovery_code())
+        .collect();
+
+    let mut query = app_state
+        .db
+        .query("BEGIN TRANSACTION;")
+        .query("DELETE recovery_code WHERE user = $user_id;")
+        .bind(("user_id", user_id));
+    for (index, code) in codes.iter().enumerate() {
+        query = query
+            .query(format!("CREATE recovery_code CONTENT $code_{index};"))
+            .bind((
+                format!("code_{index}"),
+                RecoveryCode {
+                    code_hash: hash_secret(&normalize_recovery_code(code)),
+                    user: user_id.clone(),
+                },
+            ));
+    }
+    query
+        .query("COMMIT TRANSACTION;")
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::EnrollTotpFail
+        })?
+        .check()
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::EnrollTotpFail
+        })?;
+
+    Ok(codes)
+}
+
+/// Uses up one of the user's recovery codes. Returns `false` if it doesn't
+/// match any unused code.
+pub async fn use_recovery_code(app_state: &AppState, user_id: &Thing, code: &str) -> Result<bool> {
+    let mut result = app_state
+        .db
+        .query(
+            "UPDATE recovery_code SET used_at = time::now() \
+                WHERE user = $user_id AND code_hash = $code_hash AND used_at = NONE;",
+        )
+        .bind(("user_id", user_id))
+        .bind(("code_hash", hash_secret(&normalize_recovery_code(code))))
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::VerifyMfaFail
+        })?;
+    let used: Vec<RecoveryCodeDBResult> = result.take(0).map_err(|e| {
+        error!("Encountered error {:?}", e);
+        Error::VerifyMfaFail
+    })?;
+
+    Ok(!used.is_empty())
+}
+
+/// Codes look like `1a2b3-c4d5e` so that they are easy to copy down.
+fn generate_recovery_code() -> String {
+    let mut bytes = [0u8; 5];
+    OsRng.fill_bytes(&mut bytes);
+    let code = hex::encode(bytes);
+
+    format!("{}-{}", &code[..5], &code[5..])
+}
+
+/// Codes are accepted with or without the dash and in any case.
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
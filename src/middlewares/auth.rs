@@ -1,21 +1,29 @@
+use std::net::SocketAddr;
+
 use async_trait::async_trait;
 use axum::{
-    extract::{FromRequestParts, State},
+    extract::{ConnectInfo, FromRequestParts, State},
     http::{header::AUTHORIZATION, request::Parts, HeaderMap, HeaderValue, Request},
     middleware::Next,
     response::Response,
 };
+use chrono::{Duration, Utc};
 use lazy_regex::regex_captures;
 use tracing::error;
 
 use crate::{
-    auth::validate_jwt,
-    ctx::Ctx,
+    auth::{validate_jwt, Claims},
+    configuration::ProxySettings,
+    ctx::{Ctx, Scope},
     error::{Error, Result},
     prefixed_api_key::PrefixedApiKey,
-    types::{AppState, Token},
+    token_cache::CachedToken,
+    types::{AppState, TokenDBResult},
 };
 
+/// How long to wait before recording the use of an API token again.
+const TOKEN_USAGE_UPDATE_INTERVAL_SECS: i64 = 300;
+
 #[tracing::instrument(skip(ctx, req, next))]
 pub async fn mw_require_auth<B>(
     ctx: Result<Ctx>,
@@ -34,26 +42,79 @@ pub async fn mw_ctx_resolver<B>(
     mut req: Request<B>,
     next: Next<B>,
 ) -> Result<Response> {
+    let client_ip = get_client_ip(&app_state.proxy, &req);
     // We need to get the Ctx as Result<Ctx> because it may not always be set
-    let result_ctx = get_result_ctx(app_state, headers).await;
+    let result_ctx = get_result_ctx(app_state, headers, client_ip.clone()).await;
 
     req.extensions_mut().insert(result_ctx);
+    req.extensions_mut().insert(ClientIp(client_ip));
 
     Ok(next.run(req).await)
 }
 
-async fn get_result_ctx(app_state: State<AppState>, headers: HeaderMap) -> Result<Ctx> {
+/// The address of the client as found by `get_client_ip`, for handlers.
+#[derive(Clone, Debug)]
+pub struct ClientIp(pub Option<String>);
+
+/// The address of the client. It is only taken from the header set by the
+/// proxy in front of the app when the request comes from a trusted proxy,
+/// since anyone can send that header.
+pub fn get_client_ip<B>(proxy: &ProxySettings, req: &Request<B>) -> Option<String> {
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(address)| address.ip());
+    let trusted = proxy.trusted_proxies.is_empty()
+        || peer.map_or(false, |peer| proxy.trusted_proxies.contains(&peer));
+
+    // Proxies append to `X-Forwarded-For`, so the last entry is the one the
+    // trusted proxy added.
+    let forwarded = proxy
+        .client_ip_header
+        .as_deref()
+        .filter(|_| trusted)
+        .and_then(|header| req.headers().get(header))
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.rsplit(',').next())
+        .map(|ip| ip.trim().to_string())
+        .filter(|ip| !ip.is_empty());
+
+    forwarded.or_else(|| peer.map(|peer| peer.to_string()))
+}
+
+async fn get_result_ctx(
+    app_state: State<AppState>,
+    headers: HeaderMap,
+    client_ip: Option<String>,
+) -> Result<Ctx> {
     let auth_header = headers.get(AUTHORIZATION);
     let token_header = headers.get("X-Api-Token");
 
-    let user_id = match (auth_header, token_header) {
+    match (auth_header, token_header) {
         // Prefer to use the Authorization header if it is available
-        (Some(auth_header), _) => get_user_from_auth_header(auth_header),
-        (_, Some(token_header)) => get_user_from_token_header(token_header, &app_state).await,
+        (Some(auth_header), _) => {
+            let claims = get_claims_from_auth_header(auth_header, &app_state).await?;
+            Ok(Ctx::new(claims.sub, Scope::ALL.to_vec()).with_session_id(claims.jti))
+        }
+        (_, Some(token_header)) => {
+            // Guessing tokens is limited by the IP of the requests that fail
+            let limiter = &app_state.rate_limits.invalid_token_ip;
+            if let Some(client_ip) = &client_ip {
+                limiter.peek(client_ip)?;
+            }
+            let (user_id, scopes) =
+                get_user_from_token_header(token_header, &app_state, client_ip.clone())
+                    .await
+                    .map_err(|e| {
+                        if let (Error::InvalidToken, Some(client_ip)) = (&e, &client_ip) {
+                            let _ = limiter.check(client_ip);
+                        }
+                        e
+                    })?;
+            Ok(Ctx::new(user_id, scopes))
+        }
         (_, _) => Err(Error::MissingAuth),
-    }?;
-
-    Ok(Ctx::new(user_id))
+    }
 }
 
 // region:    --- Ctx Extractor
@@ -72,56 +133,95 @@ impl<S: Send + Sync> FromRequestParts<S> for Ctx {
 
 // endregion: --- Ctx Extractor
 
-fn get_user_from_auth_header(header: &HeaderValue) -> Result<String> {
+async fn get_claims_from_auth_header(header: &HeaderValue, app_state: &AppState) -> Result<Claims> {
     let auth_header = std::str::from_utf8(header.as_bytes())
         .ok()
         .ok_or(Error::MissingAuth)?;
     let pattern = regex_captures!(r#"^Bearer (.+)"#, auth_header);
 
-    let user = match pattern {
-        Some((_, bearer_token)) => validate_jwt(bearer_token),
+    match pattern {
+        Some((_, bearer_token)) => validate_jwt(bearer_token, app_state).await,
         None => Err(Error::InvalidAuthHeader),
-    }?;
-
-    Ok(user.sub)
+    }
 }
 
-async fn get_user_from_token_header(header: &HeaderValue, app_state: &AppState) -> Result<String> {
+/// Returns the id of the token's user and the scopes it was created with.
+async fn get_user_from_token_header(
+    header: &HeaderValue,
+    app_state: &AppState,
+    client_ip: Option<String>,
+) -> Result<(String, Vec<Scope>)> {
     let token = std::str::from_utf8(header.as_bytes())
         .ok()
         .ok_or(Error::MissingAuth)?;
 
-    let user_id = validate_api_token(token, app_state).await?;
+    let result = validate_api_token(token, app_state, client_ip).await;
+    app_state.metrics.record_token_validation(match &result {
+        Ok(_) => "valid",
+        Err(Error::TokenExpired) => "expired",
+        Err(_) => "invalid",
+    });
 
-    Ok(user_id)
+    result
 }
 
-async fn validate_api_token(token: &str, app_state: &AppState) -> Result<String> {
+async fn validate_api_token(
+    token: &str,
+    app_state: &AppState,
+    client_ip: Option<String>,
+) -> Result<(String, Vec<Scope>)> {
     let pak: PrefixedApiKey = token.try_into().map_err(|e| {
         error!("Encountered error {:?}", e);
         Error::InvalidToken
     })?;
     let hash = pak.long_token_hashed();
 
-    let mut result = app_state
-        .db
-        .query("SELECT * FROM token WHERE token_hash = $token_hash")
-        .bind(("token_hash", hash))
-        .await
-        .map_err(|e| {
-            error!("Encountered error {:?}", e);
-            Error::InvalidToken
-        })?;
-    let token: Option<Token> = result.take(0).map_err(|e| {
-        error!("Encountered error {:?}", e);
+    let token = match app_state.token_cache.get(&hash) {
+        Some(token) => token,
+        None => {
+            let token: CachedToken = get_token_by_hash(&hash, app_state).await?.into();
+            app_state.token_cache.insert(hash.clone(), token.clone());
+            token
+        }
+    };
+    let now = Utc::now();
+    if token.expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err(Error::TokenExpired);
+    }
+
+    // Usage is only recorded every so often so that requests don't all write,
+    // even from clients switching between addresses. The address recorded is
+    // the one of the request that does the write.
+    let is_stale = token.last_used_at.map_or(true, |last_used_at| {
+        now - last_used_at > Duration::seconds(TOKEN_USAGE_UPDATE_INTERVAL_SECS)
+    });
+    if is_stale {
+        let client_ip = client_ip.or(token.last_used_ip);
+        let result = app_state
+            .tokens
+            .record_use(&token.id, client_ip.as_deref())
+            .await;
+        // Not being able to record usage shouldn't fail the request
+        if result.is_ok() {
+            app_state.token_cache.record_use(&hash, now, client_ip);
+        }
+    }
+
+    Ok((token.user.to_string(), token.scopes))
+}
+
+/// Tokens of disabled or deleted accounts are refused.
+async fn get_token_by_hash(hash: &str, app_state: &AppState) -> Result<TokenDBResult> {
+    let token = app_state.tokens.find_by_hash(hash).await?;
+    let token = token.ok_or_else(|| {
+        error!("Invalid API token passed in");
         Error::InvalidToken
     })?;
 
-    match token {
-        Some(token) => Ok(token.user.to_string()),
-        None => {
-            error!("Invalid API token passed in");
-            Err(Error::InvalidToken)
-        }
+    let user = app_state.users.find_by_id(&token.user).await?;
+    match user {
+        Some(user) if user.disabled_at.is_some() => Err(Error::AccountDisabled),
+        Some(_) => Ok(token),
+        None => Err(Error::InvalidToken),
     }
 }
@@ -0,0 +1,36 @@
+use std::time::Instant;
+
+use axum::{
+    extract::{MatchedPath, State},
+    http::Request,
+    middleware::Next,
+    response::Response,
+};
+
+use crate::types::AppState;
+
+/// Records the rate, status and latency of requests by matched route.
+pub async fn mw_metrics<B>(
+    State(app_state): State<AppState>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let started_at = Instant::now();
+    let method = req.method().to_string();
+    // Requests that match no route share a series, whatever their path
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", |path| path.as_str())
+        .to_string();
+
+    let response = next.run(req).await;
+    app_state.metrics.record_request(
+        &method,
+        &route,
+        response.status().as_u16(),
+        started_at.elapsed(),
+    );
+
+    response
+}
@@ -0,0 +1,32 @@
+use axum::{extract::State, http::Request, middleware::Next, response::Response};
+
+use crate::{ctx::Ctx, error::Result, middlewares::auth::get_client_ip, types::AppState};
+
+/// Limits the routes that check credentials by the IP they are called from.
+#[tracing::instrument(skip(app_state, req, next))]
+pub async fn mw_rate_limit_auth<B>(
+    State(app_state): State<AppState>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Result<Response> {
+    if let Some(client_ip) = get_client_ip(&app_state.proxy, &req) {
+        app_state.rate_limits.auth_ip.check(&client_ip)?;
+    }
+
+    Ok(next.run(req).await)
+}
+
+/// Limits `/api/*` requests by user, once they are authenticated.
+#[tracing::instrument(skip(app_state, ctx, req, next))]
+pub async fn mw_rate_limit_api<B>(
+    State(app_state): State<AppState>,
+    ctx: Result<Ctx>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Result<Response> {
+    if let Ok(ctx) = ctx {
+        app_state.rate_limits.api_user.check(ctx.user_id())?;
+    }
+
+    Ok(next.run(req).await)
+}
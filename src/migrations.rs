@@ -0,0 +1,352 @@
+//! Module for the SurrealDB schema migrations in `db/surreal`. They are
+//! embedded in the binary and applied in order, each in its own transaction,
+//! and the `migration` table records which ones a database has had along
+//! with their checksums.
+//!
+//! Applied migrations must never change: a new migration is added instead.
+//!
+//! Data changes that SurrealQL can't express are done by a `Backfill` in Rust
+//! right before the statements of their migration.
+
+use std::{collections::HashMap, fmt};
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use surrealdb::sql::Thing;
+use tracing::info;
+
+use crate::{canonical_url::UrlCanonicalizer, types::DB};
+
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub statements: &'static str,
+    /// Runs before `statements`, outside of their transaction, so it has to be
+    /// safe to run again if they fail.
+    pub backfill: Option<Backfill>,
+}
+
+impl Migration {
+    pub fn checksum(&self) -> String {
+        hex::encode(Sha256::digest(self.statements.as_bytes()))
+    }
+}
+
+/// All migrations, ordered by version. The file of version `n` is
+/// `db/surreal/<n padded to 4 digits>_<name>.sql`.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial_schema",
+        statements: include_str!("../db/surreal/0001_initial_schema.sql"),
+        backfill: Some(Backfill::CanonicalUrls),
+    },
+    Migration {
+        version: 2,
+        name: "user_disabled_at",
+        statements: include_str!("../db/surreal/0002_user_disabled_at.sql"),
+        backfill: None,
+    },
+    Migration {
+        version: 3,
+        name: "device_sync_cursor",
+        statements: include_str!("../db/surreal/0003_device_sync_cursor.sql"),
+        backfill: None,
+    },
+    Migration {
+        version: 4,
+        name: "tag_resolved_at",
+        statements: include_str!("../db/surreal/0004_tag_resolved_at.sql"),
+        backfill: None,
+    },
+    Migration {
+        version: 5,
+        name: "device_served_cursor",
+        statements: include_str!("../db/surreal/0005_device_served_cursor.sql"),
+        backfill: None,
+    },
+];
+
+const MIGRATION_TABLE: &str = "
+DEFINE TABLE migration SCHEMAFULL;
+DEFINE FIELD version ON TABLE migration TYPE int;
+DEFINE FIELD name ON TABLE migration TYPE string;
+DEFINE FIELD checksum ON TABLE migration TYPE string;
+DEFINE FIELD applied_at ON TABLE migration TYPE datetime DEFAULT time::now();
+DEFINE INDEX idx_version ON TABLE migration COLUMNS version UNIQUE;
+";
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Db(surrealdb::Error),
+    /// An applied migration was changed afterwards.
+    ChecksumMismatch {
+        version: i64,
+        name: String,
+    },
+    /// The database was migrated by a newer build that has this migration.
+    Unknown {
+        version: i64,
+        name: String,
+    },
+}
+
+impl std::error::Error for MigrationError {}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Db(e) => write!(fmt, "{e}"),
+            Self::ChecksumMismatch { version, name } => write!(
+                fmt,
+                "migration {version} ({name}) was changed after it was applied"
+            ),
+            Self::Unknown { version, name } => write!(
+                fmt,
+                "migration {version} ({name}) was applied but isn't known to this build"
+            ),
+        }
+    }
+}
+
+impl From<surrealdb::Error> for MigrationError {
+    fn from(e: surrealdb::Error) -> Self {
+        Self::Db(e)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AppliedMigration {
+    pub version: i64,
+    pub name: String,
+    pub checksum: String,
+    pub applied_at: DateTime<Utc>,
+}
+
+/// Migrations recorded in the database, ordered by version.
+pub async fn applied(db: &DB) -> Result<Vec<AppliedMigration>, MigrationError> {
+    db.query(MIGRATION_TABLE).await?.check()?;
+    let mut result = db
+        .query("SELECT * FROM migration ORDER BY version;")
+        .await?;
+
+    Ok(result.take(0)?)
+}
+
+/// Checks the migrations the database already had and applies the missing
+/// ones. Returns the versions that were applied.
+#[tracing::instrument(name = "Migrating the database", skip(db, url_canonicalizer))]
+pub async fn run(
+    db: &DB,
+    url_canonicalizer: &UrlCanonicalizer,
+) -> Result<Vec<i64>, MigrationError> {
+    let applied = applied(db).await?;
+    for migration in &applied {
+        match MIGRATIONS.iter().find(|m| m.version == migration.version) {
+            Some(known) if known.checksum() == migration.checksum => {}
+            Some(_) => {
+                return Err(MigrationError::ChecksumMismatch {
+                    version: migration.version,
+                    name: migration.name.clone(),
+                })
+            }
+            None => {
+                return Err(MigrationError::Unknown {
+                    version: migration.version,
+                    name: migration.name.clone(),
+                })
+            }
+        }
+    }
+
+    let mut versions = vec![];
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|m| !applied.iter().any(|a| a.version == m.version))
+    {
+        if let Some(backfill) = migration.backfill {
+            backfill.run(db, url_canonicalizer).await?;
+        }
+
+        let statement = format!(
+            "BEGIN TRANSACTION;\n{}\nCREATE migration CONTENT {{ version: $version, name: $name, checksum: $checksum }};\nCOMMIT TRANSACTION;",
+            migration.statements
+        );
+        db.query(statement)
+            .bind(("version", migration.version))
+            .bind(("name", migration.name))
+            .bind(("checksum", migration.checksum()))
+            .await?
+            .check()?;
+        info!(
+            "Applied migration {} ({})",
+            migration.version, migration.name
+        );
+
+        versions.push(migration.version);
+    }
+
+    Ok(versions)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Backfill {
+    /// Sets the `canonical_url` of the links saved before it existed and
+    /// merges the links that turn out to be duplicates, so that the unique
+    /// index on it can be defined.
+    CanonicalUrls,
+}
+
+/// Number of links loaded at a time by the `CanonicalUrls` backfill.
+const BACKFILL_BATCH_SIZE: usize = 1000;
+
+/// The link fields the `CanonicalUrls` backfill writes, as the initial schema
+/// defines them. Databases from before it have a SCHEMAFULL `link` table
+/// without them, which would silently drop the backfilled values.
+const BACKFILL_LINK_FIELDS: &str = "
+DEFINE FIELD canonical_url ON TABLE link TYPE string;
+DEFINE FIELD tags ON TABLE link TYPE array DEFAULT [];
+DEFINE FIELD tags.* ON TABLE link TYPE record (tag);
+DEFINE FIELD collection ON TABLE link TYPE option<record (collection)>;
+";
+
+#[derive(Debug, Deserialize)]
+struct LinkOwner {
+    user: Thing,
+}
+
+/// A link as it may have been stored before the initial schema.
+#[derive(Debug, Deserialize)]
+struct BackfillLink {
+    id: Thing,
+    url: String,
+    canonical_url: Option<String>,
+    note: Option<String>,
+    tags: Option<Vec<Thing>>,
+    collection: Option<Thing>,
+    bookmarked_at: Option<DateTime<Utc>>,
+}
+
+impl Backfill {
+    async fn run(
+        self,
+        db: &DB,
+        url_canonicalizer: &UrlCanonicalizer,
+    ) -> Result<(), MigrationError> {
+        match self {
+            Self::CanonicalUrls => backfill_canonical_urls(db, url_canonicalizer).await,
+        }
+    }
+}
+
+#[tracing::instrument(name = "Backfilling canonical URLs", skip(db, url_canonicalizer))]
+async fn backfill_canonical_urls(
+    db: &DB,
+    url_canonicalizer: &UrlCanonicalizer,
+) -> Result<(), MigrationError> {
+    db.query(BACKFILL_LINK_FIELDS).await?.check()?;
+
+    // Links are only duplicates of links of the same user, so only one user's
+    // links are loaded at a time.
+    let mut result = db.query("SELECT user FROM link GROUP BY user;").await?;
+    let owners: Vec<LinkOwner> = result.take(0)?;
+    let mut merged = 0;
+    for owner in owners {
+        merged += backfill_user_canonical_urls(db, url_canonicalizer, &owner.user).await?;
+    }
+
+    if merged > 0 {
+        info!("Merged {merged} duplicate links");
+    }
+
+    Ok(())
+}
+
+/// Backfills the links of one user and returns how many were merged.
+async fn backfill_user_canonical_urls(
+    db: &DB,
+    url_canonicalizer: &UrlCanonicalizer,
+    user: &Thing,
+) -> Result<usize, MigrationError> {
+    let mut duplicates: HashMap<String, Vec<BackfillLink>> = HashMap::new();
+    let mut start = 0;
+    loop {
+        let mut result = db
+            .query(
+                "SELECT id, url, canonical_url, note, tags, collection, bookmarked_at \
+                    FROM link WHERE user = $user ORDER BY id LIMIT $limit START $start;",
+            )
+            .bind(("user", user))
+            .bind(("limit", BACKFILL_BATCH_SIZE))
+            .bind(("start", start))
+            .await?;
+        let links: Vec<BackfillLink> = result.take(0)?;
+        let loaded = links.len();
+        start += loaded;
+
+        for link in links {
+            let canonical_url = url_canonicalizer.canonicalize(&link.url);
+            duplicates.entry(canonical_url).or_default().push(link);
+        }
+
+        if loaded < BACKFILL_BATCH_SIZE {
+            break;
+        }
+    }
+
+    let mut merged = 0;
+    for (canonical_url, mut links) in duplicates {
+        if links.len() == 1 && links[0].canonical_url.as_ref() == Some(&canonical_url) {
+            continue;
+        }
+
+        // The first saved link is kept, with the notes, tags and collection of
+        // the others added to it.
+        links.sort_by(|a, b| {
+            (a.bookmarked_at, a.id.to_string()).cmp(&(b.bookmarked_at, b.id.to_string()))
+        });
+        let mut links = links.into_iter();
+        let Some(kept) = links.next() else {
+            continue;
+        };
+        let mut note = kept.note.unwrap_or_default();
+        let mut tags = kept.tags.unwrap_or_default();
+        let mut collection = kept.collection;
+        let mut removed = vec![];
+        for duplicate in links {
+            let duplicate_note = duplicate.note.unwrap_or_default();
+            if !duplicate_note.is_empty() && !note.contains(&duplicate_note) {
+                if !note.is_empty() {
+                    note.push_str("\n\n");
+                }
+                note.push_str(&duplicate_note);
+            }
+            for tag in duplicate.tags.unwrap_or_default() {
+                if !tags.contains(&tag) {
+                    tags.push(tag);
+                }
+            }
+            collection = collection.or(duplicate.collection);
+            removed.push(duplicate.id);
+        }
+        merged += removed.len();
+
+        db.query(
+            "BEGIN TRANSACTION;
+            UPDATE $link_id SET canonical_url = $canonical_url, note = $note, tags = $tags, collection = $collection;
+            DELETE link WHERE id IN $removed;
+            COMMIT TRANSACTION;",
+        )
+        .bind(("link_id", kept.id))
+        .bind(("canonical_url", canonical_url))
+        .bind(("note", note))
+        .bind(("tags", tags))
+        .bind(("collection", collection))
+        .bind(("removed", removed))
+        .await?
+        .check()?;
+    }
+
+    Ok(merged)
+}
@@ -0,0 +1,187 @@
+//! In-process rate limiting: token buckets for requests and progressive
+//! lockout of accounts after repeated failed sign ins.
+//!
+//! State is kept per instance, so the limits apply to each instance on its
+//! own.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    configuration::RateLimitSettings,
+    error::{Error, Result},
+};
+
+/// Past this many tracked keys, idle ones are forgotten.
+const MAX_TRACKED_KEYS: usize = 100_000;
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// Token buckets keyed on e.g. an IP address or a user id. Each bucket holds
+/// up to `burst` tokens and refills at `per_minute` tokens a minute.
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<String, Bucket>>,
+    burst: f64,
+    refill_per_sec: f64,
+}
+
+impl RateLimiter {
+    pub fn new(burst: u32, per_minute: u32) -> Self {
+        Self {
+            buckets: Mutex::new(HashMap::new()),
+            burst: burst.max(1) as f64,
+            refill_per_sec: per_minute.max(1) as f64 / 60.0,
+        }
+    }
+
+    /// Takes a token from the bucket of `key`, or fails with
+    /// `Error::RateLimited` when it is empty.
+    pub fn check(&self, key: &str) -> Result<()> {
+        self.take(key, 1.0)
+    }
+
+    /// Fails like `check` but without taking a token, for limits that are
+    /// only charged when something fails.
+    pub fn peek(&self, key: &str) -> Result<()> {
+        self.take(key, 0.0)
+    }
+
+    fn take(&self, key: &str, cost: f64) -> Result<()> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let now = Instant::now();
+        if buckets.len() >= MAX_TRACKED_KEYS && !buckets.contains_key(key) {
+            let refill_time = Duration::from_secs_f64(self.burst / self.refill_per_sec);
+            buckets.retain(|_, bucket| now.duration_since(bucket.updated_at) < refill_time);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated_at: now,
+        });
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.refill_per_sec).min(self.burst);
+        bucket.updated_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= cost;
+            Ok(())
+        } else {
+            let retry_after = ((1.0 - bucket.tokens) / self.refill_per_sec).ceil() as u64;
+            Err(Error::RateLimited(retry_after.max(1)))
+        }
+    }
+}
+
+struct Failures {
+    count: u32,
+    locked_until: Option<Instant>,
+}
+
+/// Locks usernames out of signing in from an address after repeated failures
+/// from it. Every failure past the threshold doubles the lockout, up to a
+/// maximum. Failures from one address don't lock the owner of the account out
+/// everywhere else.
+pub struct LoginLockout {
+    failures: Mutex<HashMap<(String, String), Failures>>,
+    threshold: u32,
+    base: Duration,
+    max: Duration,
+}
+
+impl LoginLockout {
+    pub fn new(threshold: u32, base: Duration, max: Duration) -> Self {
+        Self {
+            failures: Mutex::new(HashMap::new()),
+            threshold: threshold.max(1),
+            base,
+            max,
+        }
+    }
+
+    /// Fails with `Error::RateLimited` while `username` is locked out from
+    /// `client_ip`.
+    pub fn check(&self, username: &str, client_ip: Option<&str>) -> Result<()> {
+        let failures = self.failures.lock().unwrap();
+        let locked_until = failures
+            .get(&key(username, client_ip))
+            .and_then(|failures| failures.locked_until);
+
+        match locked_until {
+            Some(locked_until) if locked_until > Instant::now() => {
+                let retry_after = (locked_until - Instant::now()).as_secs_f64().ceil() as u64;
+                Err(Error::RateLimited(retry_after.max(1)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn record_failure(&self, username: &str, client_ip: Option<&str>) {
+        let mut failures = self.failures.lock().unwrap();
+        let now = Instant::now();
+        let key = key(username, client_ip);
+        if failures.len() >= MAX_TRACKED_KEYS && !failures.contains_key(&key) {
+            failures.retain(|_, failures| failures.locked_until.is_some_and(|until| until > now));
+        }
+
+        let entry = failures.entry(key).or_insert(Failures {
+            count: 0,
+            locked_until: None,
+        });
+        entry.count += 1;
+        if entry.count >= self.threshold {
+            let doublings = (entry.count - self.threshold).min(16);
+            let lockout = self.base.saturating_mul(1 << doublings).min(self.max);
+            entry.locked_until = Some(now + lockout);
+        }
+    }
+
+    pub fn record_success(&self, username: &str, client_ip: Option<&str>) {
+        self.failures
+            .lock()
+            .unwrap()
+            .remove(&key(username, client_ip));
+    }
+}
+
+/// Requests without a known address share a key.
+fn key(username: &str, client_ip: Option<&str>) -> (String, String) {
+    (
+        username.to_string(),
+        client_ip.unwrap_or_default().to_string(),
+    )
+}
+
+/// All of the app's limits.
+pub struct RateLimits {
+    /// Attempts at the sign in, sign up and password reset routes per IP.
+    pub auth_ip: RateLimiter,
+    /// Sign in attempts per username, whatever IP they come from.
+    pub auth_username: RateLimiter,
+    /// Requests with an invalid API token per IP.
+    pub invalid_token_ip: RateLimiter,
+    /// Requests to `/api/*` per user.
+    pub api_user: RateLimiter,
+    pub lockout: LoginLockout,
+}
+
+impl RateLimits {
+    pub fn new(settings: &RateLimitSettings) -> Self {
+        Self {
+            auth_ip: RateLimiter::new(settings.auth_burst, settings.auth_per_minute),
+            auth_username: RateLimiter::new(settings.username_burst, settings.username_per_minute),
+            invalid_token_ip: RateLimiter::new(settings.auth_burst, settings.auth_per_minute),
+            api_user: RateLimiter::new(settings.api_burst, settings.api_per_minute),
+            lockout: LoginLockout::new(
+                settings.lockout_threshold,
+                Duration::from_secs(settings.lockout_base_secs),
+                Duration::from_secs(settings.lockout_max_secs),
+            ),
+        }
+    }
+}
@@ -0,0 +1,796 @@
+//! Repositories that keep everything in memory. They behave like the SurrealDB
+//! ones, except that there are no `tag` records to look names up in, so links
+//! come back without `tag_names` and filtering on tags matches nothing.
+
+use std::{cmp::Ordering, collections::HashMap, sync::Mutex};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use surrealdb::sql::Thing;
+
+use super::{
+    new_id, ChangePosition, LinkChanges, LinkMerge, LinkQuery, LinkRepository, LinkSortValue,
+    ScoredLink, TokenRepository, UserRepository,
+};
+use crate::{
+    error::{Error, Result},
+    routes::link_routes::SortOrder,
+    search::SearchQuery,
+    types::{
+        CreateUserContent, Link, LinkDBResult, LinkTombstone, Token, TokenDBResult, User,
+        UserDBResult,
+    },
+};
+
+#[derive(Default)]
+pub struct MemoryUserRepository {
+    users: Mutex<Vec<UserDBResult>>,
+}
+
+impl MemoryUserRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl UserRepository for MemoryUserRepository {
+    async fn find_by_id(&self, user_id: &Thing) -> Result<Option<UserDBResult>> {
+        let users = self.users.lock().unwrap();
+
+        Ok(users.iter().find(|user| &user.id == user_id).cloned())
+    }
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<UserDBResult>> {
+        let users = self.users.lock().unwrap();
+
+        Ok(users.iter().find(|user| user.username == username).cloned())
+    }
+
+    async fn list(&self) -> Result<Vec<UserDBResult>> {
+        let mut users = self.users.lock().unwrap().clone();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+
+        Ok(users)
+    }
+
+    async fn create(&self, user: CreateUserContent) -> Result<User> {
+        let mut users = self.users.lock().unwrap();
+        if users
+            .iter()
+            .any(|existing| existing.username == user.username)
+        {
+            return Err(Error::UsernameExists);
+        }
+
+        let user = UserDBResult {
+            id: new_id("user"),
+            username: user.username,
+            password: user.password,
+            email: user.email,
+            totp_secret: None,
+            totp_pending_secret: None,
+            totp_last_step: None,
+            disabled_at: None,
+        };
+        let created = User {
+            id: user.id.clone(),
+            username: user.username.clone(),
+        };
+        users.push(user);
+
+        Ok(created)
+    }
+
+    async fn update_password(&self, user_id: &Thing, password_hash: &str) -> Result<()> {
+        let mut users = self.users.lock().unwrap();
+        if let Some(user) = users.iter_mut().find(|user| &user.id == user_id) {
+            user.password = password_hash.to_string();
+        }
+
+        Ok(())
+    }
+
+    async fn update_email(&self, user_id: &Thing, email: &str) -> Result<()> {
+        let mut users = self.users.lock().unwrap();
+        if let Some(user) = users.iter_mut().find(|user| &user.id == user_id) {
+            user.email = Some(email.to_string());
+        }
+
+        Ok(())
+    }
+
+    async fn set_totp_pending_secret(&self, user_id: &Thing, secret: &str) -> Result<()> {
+        let mut users = self.users.lock().unwrap();
+        if let Some(user) = users.iter_mut().find(|user| &user.id == user_id) {
+            user.totp_pending_secret = Some(secret.to_string());
+        }
+
+        Ok(())
+    }
+
+    async fn set_disabled_at(
+        &self,
+        user_id: &Thing,
+        disabled_at: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        let mut users = self.users.lock().unwrap();
+        if let Some(user) = users.iter_mut().find(|user| &user.id == user_id) {
+            user.disabled_at = disabled_at;
+        }
+
+        Ok(())
+    }
+
+    async fn enable_totp(&self, user_id: &Thing, secret: &str) -> Result<()> {
+        let mut users = self.users.lock().unwrap();
+        if let Some(user) = users.iter_mut().find(|user| &user.id == user_id) {
+            user.totp_secret = Some(secret.to_string());
+            user.totp_pending_secret = None;
+        }
+
+        Ok(())
+    }
+
+    async fn disable_totp(&self, user_id: &Thing) -> Result<()> {
+        let mut users = self.users.lock().unwrap();
+        if let Some(user) = users.iter_mut().find(|user| &user.id == user_id) {
+            user.totp_secret = None;
+            user.totp_pending_secret = None;
+            user.totp_last_step = None;
+        }
+
+        Ok(())
+    }
+
+    async fn record_totp_step(&self, user_id: &Thing, step: i64) -> Result<bool> {
+        let mut users = self.users.lock().unwrap();
+        match users.iter_mut().find(|user| &user.id == user_id) {
+            Some(user)
+                if user
+                    .totp_last_step
+                    .map_or(true, |last_step| last_step < step) =>
+            {
+                user.totp_last_step = Some(step);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// The memory repositories don't share their state, so the user's tokens
+    /// and links are left to go away with the process.
+    async fn delete(&self, user_id: &Thing) -> Result<()> {
+        let mut users = self.users.lock().unwrap();
+        users.retain(|user| &user.id != user_id);
+
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryTokenRepository {
+    tokens: Mutex<Vec<TokenDBResult>>,
+}
+
+impl MemoryTokenRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl TokenRepository for MemoryTokenRepository {
+    async fn create(&self, token: Token) -> Result<TokenDBResult> {
+        let token = TokenDBResult {
+            id: new_id("token"),
+            token_hash: token.token_hash,
+            name: token.name,
+            short_token: token.short_token,
+            user: token.user,
+            scopes: token.scopes,
+            created_at: Utc::now(),
+            expires_at: token.expires_at.map(|expires_at| expires_at.0),
+            last_used_at: None,
+            last_used_ip: None,
+        };
+        self.tokens.lock().unwrap().push(token.clone());
+
+        Ok(token)
+    }
+
+    async fn find_by_hash(&self, token_hash: &str) -> Result<Option<TokenDBResult>> {
+        let tokens = self.tokens.lock().unwrap();
+
+        Ok(tokens
+            .iter()
+            .find(|token| token.token_hash == token_hash)
+            .cloned())
+    }
+
+    async fn find_for_user(
+        &self,
+        token_id: &Thing,
+        user_id: &Thing,
+    ) -> Result<Option<TokenDBResult>> {
+        let tokens = self.tokens.lock().unwrap();
+
+        Ok(tokens
+            .iter()
+            .find(|token| &token.id == token_id && &token.user == user_id)
+            .cloned())
+    }
+
+    async fn list_for_user(&self, user_id: &Thing) -> Result<Vec<TokenDBResult>> {
+        let tokens = self.tokens.lock().unwrap();
+
+        // Tokens are kept in the order they were created in
+        Ok(tokens
+            .iter()
+            .filter(|token| &token.user == user_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn delete_for_user(&self, token_id: &Thing, user_id: &Thing) -> Result<()> {
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.retain(|token| !(&token.id == token_id && &token.user == user_id));
+
+        Ok(())
+    }
+
+    async fn record_use(&self, token_id: &Thing, ip: Option<&str>) -> Result<()> {
+        let mut tokens = self.tokens.lock().unwrap();
+        if let Some(token) = tokens.iter_mut().find(|token| &token.id == token_id) {
+            token.last_used_at = Some(Utc::now());
+            token.last_used_ip = ip.map(String::from);
+        }
+
+        Ok(())
+    }
+
+    async fn set_expiry(&self, token_id: &Thing, expires_at: DateTime<Utc>) -> Result<()> {
+        let mut tokens = self.tokens.lock().unwrap();
+        if let Some(token) = tokens.iter_mut().find(|token| &token.id == token_id) {
+            token.expires_at = Some(expires_at);
+        }
+
+        Ok(())
+    }
+}
+
+struct StoredLink {
+    id: Thing,
+    link: Link,
+    updated_at: DateTime<Utc>,
+}
+
+impl StoredLink {
+    fn to_result(&self) -> LinkDBResult {
+        LinkDBResult {
+            id: self.id.clone(),
+            url: self.link.url.clone(),
+            canonical_url: self.link.canonical_url.clone(),
+            title: self.link.title.clone(),
+            note: self.link.note.clone(),
+            bookmarked_at: self.link.bookmarked_at.0,
+            updated_at: Some(self.updated_at),
+            user: self.link.user.clone(),
+            collection: self.link.collection.clone(),
+            tag_names: vec![],
+        }
+    }
+
+    fn domain(&self) -> Option<String> {
+        url::Url::parse(&self.link.url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_lowercase))
+    }
+
+    fn matches(&self, query: &LinkQuery) -> bool {
+        let bookmarked_at = self.link.bookmarked_at.0;
+
+        query.from.map_or(true, |from| bookmarked_at >= from)
+            && query.to.map_or(true, |to| bookmarked_at < to)
+            && query.domain.as_ref().map_or(true, |domain| {
+                self.domain()
+                    .is_some_and(|host| host == *domain || host == format!("www.{domain}"))
+            })
+            && query.tags.is_empty()
+            && query.collection.as_ref().map_or(true, |collection| {
+                self.link.collection.as_ref() == Some(collection)
+            })
+            && query
+                .has_note
+                .map_or(true, |has_note| has_note != self.link.note.is_empty())
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryLinkRepository {
+    links: Mutex<Vec<StoredLink>>,
+    tombstones: Mutex<Vec<LinkTombstone>>,
+}
+
+impl MemoryLinkRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Removes the user's links that `remove` picks, recording their
+    /// deletion like the `link_deleted` event.
+    fn remove_links(
+        &self,
+        user_id: &Thing,
+        remove: impl Fn(&StoredLink) -> bool,
+    ) -> Vec<StoredLink> {
+        let mut links = self.links.lock().unwrap();
+        let (removed, kept): (Vec<StoredLink>, Vec<StoredLink>) = links
+            .drain(..)
+            .partition(|stored| &stored.link.user == user_id && remove(stored));
+        *links = kept;
+
+        let now = Utc::now();
+        self.tombstones
+            .lock()
+            .unwrap()
+            .extend(removed.iter().map(|stored| LinkTombstone {
+                id: new_id("link_tombstone"),
+                link: stored.id.clone(),
+                user: user_id.clone(),
+                deleted_at: now,
+            }));
+
+        removed
+    }
+}
+
+/// Whether a change at (`at`, `id`) comes after `position`.
+fn is_after(at: DateTime<Utc>, id: &Thing, position: Option<&ChangePosition>) -> bool {
+    let Some(position) = position else {
+        return true;
+    };
+
+    match &position.id {
+        Some(position_id) => {
+            at > position.at || (at == position.at && id.to_string() > position_id.to_string())
+        }
+        None => at > position.at,
+    }
+}
+
+/// Orders changes by time and then id.
+fn compare_changes(a: (DateTime<Utc>, &Thing), b: (DateTime<Utc>, &Thing)) -> Ordering {
+    a.0.cmp(&b.0)
+        .then_with(|| a.1.to_string().cmp(&b.1.to_string()))
+}
+
+/// Compares links by sort value and then id, like the `ORDER BY` of the
+/// SurrealDB repository.
+fn compare_links(a: &(LinkSortValue, String), b: &(LinkSortValue, String)) -> Ordering {
+    a.0.partial_cmp(&b.0)
+        .unwrap_or(Ordering::Equal)
+        .then_with(|| a.1.cmp(&b.1))
+}
+
+#[async_trait]
+impl LinkRepository for MemoryLinkRepository {
+    async fn get(&self, user_id: &Thing, link_id: &Thing) -> Result<Option<LinkDBResult>> {
+        let links = self.links.lock().unwrap();
+
+        Ok(links
+            .iter()
+            .find(|stored| &stored.id == link_id && &stored.link.user == user_id)
+            .map(StoredLink::to_result))
+    }
+
+    async fn find_by_canonical_url(
+        &self,
+        user_id: &Thing,
+        canonical_url: &str,
+        exclude: Option<&Thing>,
+    ) -> Result<Option<LinkDBResult>> {
+        let links = self.links.lock().unwrap();
+
+        Ok(links
+            .iter()
+            .find(|stored| {
+                &stored.link.user == user_id
+                    && stored.link.canonical_url == canonical_url
+                    && Some(&stored.id) != exclude
+            })
+            .map(StoredLink::to_result))
+    }
+
+    async fn find_by_canonical_urls(
+        &self,
+        user_id: &Thing,
+        canonical_urls: &[String],
+    ) -> Result<Vec<LinkDBResult>> {
+        let links = self.links.lock().unwrap();
+
+        Ok(links
+            .iter()
+            .filter(|stored| {
+                &stored.link.user == user_id && canonical_urls.contains(&stored.link.canonical_url)
+            })
+            .map(StoredLink::to_result)
+            .collect())
+    }
+
+    async fn list(&self, user_id: &Thing, query: &LinkQuery) -> Result<Vec<LinkDBResult>> {
+        let links = self.links.lock().unwrap();
+
+        let mut matching: Vec<((LinkSortValue, String), LinkDBResult)> = links
+            .iter()
+            .filter(|stored| &stored.link.user == user_id && stored.matches(query))
+            .map(|stored| {
+                let link = stored.to_result();
+                let key = (LinkSortValue::of(&link, query.sort), link.id.to_string());
+                (key, link)
+            })
+            .collect();
+        matching.sort_by(|(a, _), (b, _)| match query.order {
+            SortOrder::Asc => compare_links(a, b),
+            SortOrder::Desc => compare_links(b, a),
+        });
+
+        let after = query
+            .after
+            .as_ref()
+            .map(|after| (after.value.clone(), after.id.to_string()));
+
+        Ok(matching
+            .into_iter()
+            .filter(|(key, _)| match (&after, query.order) {
+                (Some(after), SortOrder::Asc) => compare_links(key, after) == Ordering::Greater,
+                (Some(after), SortOrder::Desc) => compare_links(key, after) == Ordering::Less,
+                (None, _) => true,
+            })
+            .take(query.limit)
+            .map(|(_, link)| link)
+            .collect())
+    }
+
+    async fn create(&self, link: Link) -> Result<LinkDBResult> {
+        let mut created = self.create_many(vec![link]).await?;
+
+        created.pop().ok_or(Error::CreateLinkFail)
+    }
+
+    async fn create_many(&self, links: Vec<Link>) -> Result<Vec<LinkDBResult>> {
+        let now = Utc::now();
+        let mut stored_links = self.links.lock().unwrap();
+
+        // Like the unique index of the other backends
+        for link in &links {
+            if let Some(existing) = stored_links.iter().find(|stored| {
+                stored.link.user == link.user && stored.link.canonical_url == link.canonical_url
+            }) {
+                return Err(Error::LinkExists(existing.id.to_string()));
+            }
+        }
+
+        Ok(links
+            .into_iter()
+            .map(|link| {
+                let stored = StoredLink {
+                    id: new_id("link"),
+                    link,
+                    updated_at: now,
+                };
+                let created = stored.to_result();
+                stored_links.push(stored);
+                created
+            })
+            .collect())
+    }
+
+    async fn merge(
+        &self,
+        user_id: &Thing,
+        link_id: &Thing,
+        merge: LinkMerge,
+    ) -> Result<Option<LinkDBResult>> {
+        let mut links = self.links.lock().unwrap();
+        let Some(stored) = links
+            .iter_mut()
+            .find(|stored| &stored.id == link_id && &stored.link.user == user_id)
+        else {
+            return Ok(None);
+        };
+
+        let now = Utc::now();
+        stored.link.title = merge.title;
+        stored.link.note = merge.note;
+        stored.link.bookmarked_at = now.into();
+        for tag in merge.tags {
+            if !stored.link.tags.contains(&tag) {
+                stored.link.tags.push(tag);
+            }
+        }
+        stored.link.collection = merge.collection;
+        stored.updated_at = now;
+
+        Ok(Some(stored.to_result()))
+    }
+
+    async fn update(
+        &self,
+        user_id: &Thing,
+        link_id: &Thing,
+        changes: LinkChanges,
+    ) -> Result<Option<LinkDBResult>> {
+        let mut links = self.links.lock().unwrap();
+        if let Some(canonical_url) = &changes.canonical_url {
+            if let Some(existing) = links.iter().find(|stored| {
+                &stored.link.user == user_id
+                    && &stored.link.canonical_url == canonical_url
+                    && &stored.id != link_id
+            }) {
+                return Err(Error::LinkExists(existing.id.to_string()));
+            }
+        }
+        let Some(stored) = links
+            .iter_mut()
+            .find(|stored| &stored.id == link_id && &stored.link.user == user_id)
+        else {
+            return Ok(None);
+        };
+
+        if let Some(url) = changes.url {
+            stored.link.url = url;
+        }
+        if let Some(canonical_url) = changes.canonical_url {
+            stored.link.canonical_url = canonical_url;
+        }
+        if let Some(title) = changes.title {
+            stored.link.title = title;
+        }
+        if let Some(note) = changes.note {
+            stored.link.note = note;
+        }
+        if let Some(tags) = changes.tags {
+            stored.link.tags = tags;
+        }
+        if let Some(collection) = changes.collection {
+            stored.link.collection = collection;
+        }
+        stored.updated_at = Utc::now();
+
+        Ok(Some(stored.to_result()))
+    }
+
+    async fn delete(&self, user_id: &Thing, link_id: &Thing) -> Result<Option<LinkDBResult>> {
+        let removed = self.remove_links(user_id, |stored| &stored.id == link_id);
+
+        Ok(removed.first().map(StoredLink::to_result))
+    }
+
+    async fn clear(&self, user_id: &Thing) -> Result<()> {
+        self.remove_links(user_id, |_| true);
+
+        Ok(())
+    }
+
+    async fn search(
+        &self,
+        user_id: &Thing,
+        query: &SearchQuery,
+        start: usize,
+        limit: usize,
+    ) -> Result<Vec<ScoredLink>> {
+        let links = self.links.lock().unwrap();
+        let index_terms = query.index_terms();
+
+        // Terms are matched as substrings and scored by the fields they are
+        // in, which is close enough to the full text index without one.
+        let mut candidates: Vec<ScoredLink> = links
+            .iter()
+            .filter(|stored| &stored.link.user == user_id)
+            .filter_map(|stored| {
+                let fields = [&stored.link.title, &stored.link.note, &stored.link.url]
+                    .map(|field| field.to_lowercase());
+                let mut score = 0;
+                for term in index_terms.iter().chain(query.prefixes.iter()) {
+                    let found = fields.iter().filter(|field| field.contains(term)).count();
+                    if found == 0 {
+                        return None;
+                    }
+                    score += found;
+                }
+
+                Some(ScoredLink {
+                    link: stored.to_result(),
+                    score: score as f64,
+                })
+            })
+            .collect();
+        candidates.sort_by(|a, b| {
+            b.score
+                .partial_cmp(&a.score)
+                .unwrap_or(Ordering::Equal)
+                .then_with(|| b.link.bookmarked_at.cmp(&a.link.bookmarked_at))
+                .then_with(|| b.link.id.to_string().cmp(&a.link.id.to_string()))
+        });
+
+        Ok(candidates.into_iter().skip(start).take(limit).collect())
+    }
+
+    async fn list_changed(
+        &self,
+        user_id: &Thing,
+        after: Option<&ChangePosition>,
+        limit: usize,
+    ) -> Result<Vec<LinkDBResult>> {
+        let links = self.links.lock().unwrap();
+
+        let mut changed: Vec<&StoredLink> = links
+            .iter()
+            .filter(|stored| {
+                &stored.link.user == user_id && is_after(stored.updated_at, &stored.id, after)
+            })
+            .collect();
+        changed.sort_by(|a, b| compare_changes((a.updated_at, &a.id), (b.updated_at, &b.id)));
+
+        Ok(changed
+            .into_iter()
+            .take(limit)
+            .map(StoredLink::to_result)
+            .collect())
+    }
+
+    async fn list_deleted(
+        &self,
+        user_id: &Thing,
+        after: Option<&ChangePosition>,
+        limit: usize,
+    ) -> Result<Vec<LinkTombstone>> {
+        let tombstones = self.tombstones.lock().unwrap();
+
+        let mut deleted: Vec<&LinkTombstone> = tombstones
+            .iter()
+            .filter(|tombstone| {
+                &tombstone.user == user_id && is_after(tombstone.deleted_at, &tombstone.id, after)
+            })
+            .collect();
+        deleted.sort_by(|a, b| compare_changes((a.deleted_at, &a.id), (b.deleted_at, &b.id)));
+
+        Ok(deleted.into_iter().take(limit).cloned().collect())
+    }
+
+    async fn prune_tombstones(&self, user_id: &Thing, up_to: &ChangePosition) -> Result<()> {
+        let mut tombstones = self.tombstones.lock().unwrap();
+        tombstones.retain(|tombstone| {
+            &tombstone.user != user_id || is_after(tombstone.deleted_at, &tombstone.id, Some(up_to))
+        });
+
+        Ok(())
+    }
+
+    async fn count_by_tag(&self, user_id: &Thing) -> Result<HashMap<String, u64>> {
+        let links = self.links.lock().unwrap();
+
+        let mut counts = HashMap::new();
+        for stored in links.iter().filter(|stored| &stored.link.user == user_id) {
+            for tag in &stored.link.tags {
+                *counts.entry(tag.to_string()).or_insert(0) += 1;
+            }
+        }
+
+        Ok(counts)
+    }
+
+    async fn tags_in_use(&self, user_id: &Thing, tags: &[Thing]) -> Result<Vec<Thing>> {
+        let links = self.links.lock().unwrap();
+
+        Ok(tags
+            .iter()
+            .filter(|tag| {
+                links
+                    .iter()
+                    .any(|stored| &stored.link.user == user_id && stored.link.tags.contains(tag))
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn touch_tagged(&self, user_id: &Thing, tag_id: &Thing) -> Result<()> {
+        let mut links = self.links.lock().unwrap();
+        let now = Utc::now();
+        for stored in links
+            .iter_mut()
+            .filter(|stored| &stored.link.user == user_id && stored.link.tags.contains(tag_id))
+        {
+            stored.updated_at = now;
+        }
+
+        Ok(())
+    }
+
+    async fn replace_tag(&self, user_id: &Thing, source: &Thing, target: &Thing) -> Result<()> {
+        let mut links = self.links.lock().unwrap();
+        let now = Utc::now();
+        for stored in links
+            .iter_mut()
+            .filter(|stored| &stored.link.user == user_id && stored.link.tags.contains(source))
+        {
+            stored.link.tags.retain(|tag| tag != source);
+            if !stored.link.tags.contains(target) {
+                stored.link.tags.push(target.clone());
+            }
+            stored.updated_at = now;
+        }
+
+        Ok(())
+    }
+
+    async fn count_by_collection(&self, user_id: &Thing) -> Result<HashMap<String, u64>> {
+        let links = self.links.lock().unwrap();
+
+        let mut counts = HashMap::new();
+        for stored in links.iter().filter(|stored| &stored.link.user == user_id) {
+            if let Some(collection) = &stored.link.collection {
+                *counts.entry(collection.to_string()).or_insert(0) += 1;
+            }
+        }
+
+        Ok(counts)
+    }
+
+    async fn move_collections(
+        &self,
+        user_id: &Thing,
+        collections: &[Thing],
+        target: Option<&Thing>,
+    ) -> Result<()> {
+        let mut links = self.links.lock().unwrap();
+        let now = Utc::now();
+        for stored in links.iter_mut().filter(|stored| {
+            &stored.link.user == user_id
+                && stored
+                    .link
+                    .collection
+                    .as_ref()
+                    .is_some_and(|collection| collections.contains(collection))
+        }) {
+            stored.link.collection = target.cloned();
+            stored.updated_at = now;
+        }
+
+        Ok(())
+    }
+
+    async fn set_collection(
+        &self,
+        user_id: &Thing,
+        link_ids: &[Thing],
+        collection: &Thing,
+    ) -> Result<usize> {
+        let mut links = self.links.lock().unwrap();
+        let now = Utc::now();
+        let mut moved = 0;
+        for stored in links
+            .iter_mut()
+            .filter(|stored| &stored.link.user == user_id && link_ids.contains(&stored.id))
+        {
+            stored.link.collection = Some(collection.clone());
+            stored.updated_at = now;
+            moved += 1;
+        }
+
+        Ok(moved)
+    }
+
+    async fn delete_in_collections(&self, user_id: &Thing, collections: &[Thing]) -> Result<()> {
+        self.remove_links(user_id, |stored| {
+            stored
+                .link
+                .collection
+                .as_ref()
+                .is_some_and(|collection| collections.contains(collection))
+        });
+
+        Ok(())
+    }
+}
@@ -0,0 +1,360 @@
+//! Repositories that time every operation of the ones they wrap, for the
+//! `db_query_duration_seconds` metric, and count created links.
+
+use std::{collections::HashMap, future::Future, sync::Arc, time::Instant};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use surrealdb::sql::Thing;
+
+use super::{
+    ChangePosition, LinkChanges, LinkMerge, LinkQuery, LinkRepository, ScoredLink, TokenRepository,
+    UserRepository,
+};
+use crate::{
+    error::Result,
+    metrics::Metrics,
+    search::SearchQuery,
+    types::{
+        CreateUserContent, Link, LinkDBResult, LinkTombstone, Token, TokenDBResult, User,
+        UserDBResult,
+    },
+};
+
+pub struct Metered<R: ?Sized> {
+    inner: Arc<R>,
+    metrics: Arc<Metrics>,
+    repository: &'static str,
+}
+
+impl<R: ?Sized> Metered<R> {
+    /// `repository` labels the operations, like `users`.
+    pub fn new(inner: Arc<R>, metrics: Arc<Metrics>, repository: &'static str) -> Self {
+        Self {
+            inner,
+            metrics,
+            repository,
+        }
+    }
+
+    async fn time<T>(&self, operation: &str, future: impl Future<Output = T>) -> T {
+        let started_at = Instant::now();
+        let output = future.await;
+        self.metrics
+            .record_db_query(self.repository, operation, started_at.elapsed());
+
+        output
+    }
+}
+
+#[async_trait]
+impl UserRepository for Metered<dyn UserRepository> {
+    async fn find_by_id(&self, user_id: &Thing) -> Result<Option<UserDBResult>> {
+        self.time("find_by_id", self.inner.find_by_id(user_id))
+            .await
+    }
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<UserDBResult>> {
+        self.time("find_by_username", self.inner.find_by_username(username))
+            .await
+    }
+
+    async fn list(&self) -> Result<Vec<UserDBResult>> {
+        self.time("list", self.inner.list()).await
+    }
+
+    async fn create(&self, user: CreateUserContent) -> Result<User> {
+        self.time("create", self.inner.create(user)).await
+    }
+
+    async fn update_password(&self, user_id: &Thing, password_hash: &str) -> Result<()> {
+        self.time(
+            "update_password",
+            self.inner.update_password(user_id, password_hash),
+        )
+        .await
+    }
+
+    async fn update_email(&self, user_id: &Thing, email: &str) -> Result<()> {
+        self.time("update_email", self.inner.update_email(user_id, email))
+            .await
+    }
+
+    async fn set_disabled_at(
+        &self,
+        user_id: &Thing,
+        disabled_at: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        self.time(
+            "set_disabled_at",
+            self.inner.set_disabled_at(user_id, disabled_at),
+        )
+        .await
+    }
+
+    async fn set_totp_pending_secret(&self, user_id: &Thing, secret: &str) -> Result<()> {
+        self.time(
+            "set_totp_pending_secret",
+            self.inner.set_totp_pending_secret(user_id, secret),
+        )
+        .await
+    }
+
+    async fn enable_totp(&self, user_id: &Thing, secret: &str) -> Result<()> {
+        self.time("enable_totp", self.inner.enable_totp(user_id, secret))
+            .await
+    }
+
+    async fn disable_totp(&self, user_id: &Thing) -> Result<()> {
+        self.time("disable_totp", self.inner.disable_totp(user_id))
+            .await
+    }
+
+    async fn record_totp_step(&self, user_id: &Thing, step: i64) -> Result<bool> {
+        self.time(
+            "record_totp_step",
+            self.inner.record_totp_step(user_id, step),
+        )
+        .await
+    }
+
+    async fn delete(&self, user_id: &Thing) -> Result<()> {
+        self.time("delete", self.inner.delete(user_id)).await
+    }
+}
+
+#[async_trait]
+impl TokenRepository for Metered<dyn TokenRepository> {
+    async fn create(&self, token: Token) -> Result<TokenDBResult> {
+        self.time("create", self.inner.create(token)).await
+    }
+
+    async fn find_by_hash(&self, token_hash: &str) -> Result<Option<TokenDBResult>> {
+        self.time("find_by_hash", self.inner.find_by_hash(token_hash))
+            .await
+    }
+
+    async fn find_for_user(
+        &self,
+        token_id: &Thing,
+        user_id: &Thing,
+    ) -> Result<Option<TokenDBResult>> {
+        self.time("find_for_user", self.inner.find_for_user(token_id, user_id))
+            .await
+    }
+
+    async fn list_for_user(&self, user_id: &Thing) -> Result<Vec<TokenDBResult>> {
+        self.time("list_for_user", self.inner.list_for_user(user_id))
+            .await
+    }
+
+    async fn delete_for_user(&self, token_id: &Thing, user_id: &Thing) -> Result<()> {
+        self.time(
+            "delete_for_user",
+            self.inner.delete_for_user(token_id, user_id),
+        )
+        .await
+    }
+
+    async fn record_use(&self, token_id: &Thing, ip: Option<&str>) -> Result<()> {
+        self.time("record_use", self.inner.record_use(token_id, ip))
+            .await
+    }
+
+    async fn set_expiry(&self, token_id: &Thing, expires_at: DateTime<Utc>) -> Result<()> {
+        self.time("set_expiry", self.inner.set_expiry(token_id, expires_at))
+            .await
+    }
+}
+
+#[async_trait]
+impl LinkRepository for Metered<dyn LinkRepository> {
+    async fn get(&self, user_id: &Thing, link_id: &Thing) -> Result<Option<LinkDBResult>> {
+        self.time("get", self.inner.get(user_id, link_id)).await
+    }
+
+    async fn find_by_canonical_url(
+        &self,
+        user_id: &Thing,
+        canonical_url: &str,
+        exclude: Option<&Thing>,
+    ) -> Result<Option<LinkDBResult>> {
+        self.time(
+            "find_by_canonical_url",
+            self.inner
+                .find_by_canonical_url(user_id, canonical_url, exclude),
+        )
+        .await
+    }
+
+    async fn find_by_canonical_urls(
+        &self,
+        user_id: &Thing,
+        canonical_urls: &[String],
+    ) -> Result<Vec<LinkDBResult>> {
+        self.time(
+            "find_by_canonical_urls",
+            self.inner.find_by_canonical_urls(user_id, canonical_urls),
+        )
+        .await
+    }
+
+    async fn list(&self, user_id: &Thing, query: &LinkQuery) -> Result<Vec<LinkDBResult>> {
+        self.time("list", self.inner.list(user_id, query)).await
+    }
+
+    async fn create(&self, link: Link) -> Result<LinkDBResult> {
+        let created = self.time("create", self.inner.create(link)).await?;
+        self.metrics.record_links_created(1);
+
+        Ok(created)
+    }
+
+    async fn create_many(&self, links: Vec<Link>) -> Result<Vec<LinkDBResult>> {
+        let created = self
+            .time("create_many", self.inner.create_many(links))
+            .await?;
+        self.metrics.record_links_created(created.len());
+
+        Ok(created)
+    }
+
+    async fn merge(
+        &self,
+        user_id: &Thing,
+        link_id: &Thing,
+        merge: LinkMerge,
+    ) -> Result<Option<LinkDBResult>> {
+        self.time("merge", self.inner.merge(user_id, link_id, merge))
+            .await
+    }
+
+    async fn update(
+        &self,
+        user_id: &Thing,
+        link_id: &Thing,
+        changes: LinkChanges,
+    ) -> Result<Option<LinkDBResult>> {
+        self.time("update", self.inner.update(user_id, link_id, changes))
+            .await
+    }
+
+    async fn delete(&self, user_id: &Thing, link_id: &Thing) -> Result<Option<LinkDBResult>> {
+        self.time("delete", self.inner.delete(user_id, link_id))
+            .await
+    }
+
+    async fn clear(&self, user_id: &Thing) -> Result<()> {
+        self.time("clear", self.inner.clear(user_id)).await
+    }
+
+    async fn search(
+        &self,
+        user_id: &Thing,
+        query: &SearchQuery,
+        start: usize,
+        limit: usize,
+    ) -> Result<Vec<ScoredLink>> {
+        self.time("search", self.inner.search(user_id, query, start, limit))
+            .await
+    }
+
+    async fn list_changed(
+        &self,
+        user_id: &Thing,
+        after: Option<&ChangePosition>,
+        limit: usize,
+    ) -> Result<Vec<LinkDBResult>> {
+        self.time(
+            "list_changed",
+            self.inner.list_changed(user_id, after, limit),
+        )
+        .await
+    }
+
+    async fn list_deleted(
+        &self,
+        user_id: &Thing,
+        after: Option<&ChangePosition>,
+        limit: usize,
+    ) -> Result<Vec<LinkTombstone>> {
+        self.time(
+            "list_deleted",
+            self.inner.list_deleted(user_id, after, limit),
+        )
+        .await
+    }
+
+    async fn prune_tombstones(&self, user_id: &Thing, up_to: &ChangePosition) -> Result<()> {
+        self.time(
+            "prune_tombstones",
+            self.inner.prune_tombstones(user_id, up_to),
+        )
+        .await
+    }
+
+    async fn count_by_tag(&self, user_id: &Thing) -> Result<HashMap<String, u64>> {
+        self.time("count_by_tag", self.inner.count_by_tag(user_id))
+            .await
+    }
+
+    async fn tags_in_use(&self, user_id: &Thing, tags: &[Thing]) -> Result<Vec<Thing>> {
+        self.time("tags_in_use", self.inner.tags_in_use(user_id, tags))
+            .await
+    }
+
+    async fn touch_tagged(&self, user_id: &Thing, tag_id: &Thing) -> Result<()> {
+        self.time("touch_tagged", self.inner.touch_tagged(user_id, tag_id))
+            .await
+    }
+
+    async fn replace_tag(&self, user_id: &Thing, source: &Thing, target: &Thing) -> Result<()> {
+        self.time(
+            "replace_tag",
+            self.inner.replace_tag(user_id, source, target),
+        )
+        .await
+    }
+
+    async fn count_by_collection(&self, user_id: &Thing) -> Result<HashMap<String, u64>> {
+        self.time(
+            "count_by_collection",
+            self.inner.count_by_collection(user_id),
+        )
+        .await
+    }
+
+    async fn move_collections(
+        &self,
+        user_id: &Thing,
+        collections: &[Thing],
+        target: Option<&Thing>,
+    ) -> Result<()> {
+        self.time(
+            "move_collections",
+            self.inner.move_collections(user_id, collections, target),
+        )
+        .await
+    }
+
+    async fn set_collection(
+        &self,
+        user_id: &Thing,
+        link_ids: &[Thing],
+        collection: &Thing,
+    ) -> Result<usize> {
+        self.time(
+            "set_collection",
+            self.inner.set_collection(user_id, link_ids, collection),
+        )
+        .await
+    }
+
+    async fn delete_in_collections(&self, user_id: &Thing, collections: &[Thing]) -> Result<()> {
+        self.time(
+            "delete_in_collections",
+            self.inner.delete_in_collections(user_id, collections),
+        )
+        .await
+    }
+}
@@ -0,0 +1,339 @@
+//! Storage of users, API tokens and links behind traits, so that handlers
+//! don't build queries themselves and can run without a database.
+//!
+//! `surreal` holds the implementations used by default, `memory` pure
+//! in-memory ones and `sql` (behind the `sqlite` and `postgres` features)
+//! ones for SQLite and Postgres. `AppState` wraps whichever are used in
+//! `Metered`. Everything else is still read and written through
+//! `AppState::db`, so handlers never query the `link` table themselves.
+
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use surrealdb::sql::{Id, Thing};
+
+use crate::{
+    error::{Error, Result},
+    routes::link_routes::{LinkSort, SortOrder},
+    search::SearchQuery,
+    types::{
+        CreateUserContent, Link, LinkDBResult, LinkTombstone, Token, TokenDBResult, User,
+        UserDBResult,
+    },
+};
+
+mod memory;
+mod metered;
+#[cfg(feature = "sql")]
+pub mod sql;
+mod surreal;
+
+pub use memory::{MemoryLinkRepository, MemoryTokenRepository, MemoryUserRepository};
+pub use metered::Metered;
+pub use surreal::{SurrealLinkRepository, SurrealTokenRepository, SurrealUserRepository};
+
+/// Id for a new record of `table` in stores that don't generate their own.
+fn new_id(table: &str) -> Thing {
+    Thing {
+        tb: table.to_string(),
+        id: Id::rand(),
+    }
+}
+
+/// The error for links that another request saved in the meantime, which the
+/// unique index on their canonical URL rejected. `fallback` is used when the
+/// other link can't be found.
+async fn already_saved(
+    links: &dyn LinkRepository,
+    user_id: &Thing,
+    canonical_urls: &[String],
+    exclude: Option<&Thing>,
+    fallback: Error,
+) -> Error {
+    match links.find_by_canonical_urls(user_id, canonical_urls).await {
+        Ok(existing) => existing
+            .into_iter()
+            .find(|existing| Some(&existing.id) != exclude)
+            .map(|existing| Error::LinkExists(existing.id.to_string()))
+            .unwrap_or(fallback),
+        Err(e) => e,
+    }
+}
+
+#[async_trait]
+pub trait UserRepository: Send + Sync {
+    async fn find_by_id(&self, user_id: &Thing) -> Result<Option<UserDBResult>>;
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<UserDBResult>>;
+
+    /// All users, ordered by username.
+    async fn list(&self) -> Result<Vec<UserDBResult>>;
+
+    /// Fails with `Error::UsernameExists` when the username is taken.
+    async fn create(&self, user: CreateUserContent) -> Result<User>;
+
+    async fn update_password(&self, user_id: &Thing, password_hash: &str) -> Result<()>;
+
+    async fn update_email(&self, user_id: &Thing, email: &str) -> Result<()>;
+
+    /// Disables the account when `disabled_at` is set, enables it otherwise.
+    async fn set_disabled_at(
+        &self,
+        user_id: &Thing,
+        disabled_at: Option<DateTime<Utc>>,
+    ) -> Result<()>;
+
+    /// Stores a TOTP secret that waits to be confirmed with a first code.
+    async fn set_totp_pending_secret(&self, user_id: &Thing, secret: &str) -> Result<()>;
+
+    /// Turns the pending secret into the one codes are checked against.
+    async fn enable_totp(&self, user_id: &Thing, secret: &str) -> Result<()>;
+
+    async fn disable_totp(&self, user_id: &Thing) -> Result<()>;
+
+    /// Records the time step of an accepted TOTP code. Returns `false` when
+    /// the same or a later step was already recorded, so that a code can only
+    /// be used once.
+    async fn record_totp_step(&self, user_id: &Thing, step: i64) -> Result<bool>;
+
+    /// Deletes the user along with their API tokens, links and link
+    /// tombstones, in one transaction where they are stored together.
+    async fn delete(&self, user_id: &Thing) -> Result<()>;
+}
+
+#[async_trait]
+pub trait TokenRepository: Send + Sync {
+    async fn create(&self, token: Token) -> Result<TokenDBResult>;
+
+    async fn find_by_hash(&self, token_hash: &str) -> Result<Option<TokenDBResult>>;
+
+    async fn find_for_user(
+        &self,
+        token_id: &Thing,
+        user_id: &Thing,
+    ) -> Result<Option<TokenDBResult>>;
+
+    /// The user's tokens, oldest first.
+    async fn list_for_user(&self, user_id: &Thing) -> Result<Vec<TokenDBResult>>;
+
+    /// Deleting a token that doesn't exist or belongs to someone else is a
+    /// no-op.
+    async fn delete_for_user(&self, token_id: &Thing, user_id: &Thing) -> Result<()>;
+
+    /// Records that the token was just used, from `ip` if known.
+    async fn record_use(&self, token_id: &Thing, ip: Option<&str>) -> Result<()>;
+
+    async fn set_expiry(&self, token_id: &Thing, expires_at: DateTime<Utc>) -> Result<()>;
+}
+
+/// Which of a user's links to list, and in what order.
+#[derive(Debug, Clone, Default)]
+pub struct LinkQuery {
+    pub sort: LinkSort,
+    pub order: SortOrder,
+    /// Only links bookmarked at or after this time.
+    pub from: Option<DateTime<Utc>>,
+    /// Only links bookmarked before this time.
+    pub to: Option<DateTime<Utc>>,
+    /// Lowercase domain, matched with or without a leading `www.`.
+    pub domain: Option<String>,
+    /// Normalized tag names. Only links with all of them are listed.
+    pub tags: Vec<String>,
+    pub collection: Option<Thing>,
+    pub has_note: Option<bool>,
+    /// Only links that come after this one in the sort order.
+    pub after: Option<LinkPosition>,
+    pub limit: usize,
+}
+
+/// Where a link sits in the sort order of a `LinkQuery`.
+#[derive(Debug, Clone)]
+pub struct LinkPosition {
+    pub value: LinkSortValue,
+    pub id: Thing,
+}
+
+impl LinkPosition {
+    pub fn of(link: &LinkDBResult, sort: LinkSort) -> Self {
+        Self {
+            value: LinkSortValue::of(link, sort),
+            id: link.id.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub enum LinkSortValue {
+    BookmarkedAt(DateTime<Utc>),
+    Title(String),
+}
+
+impl LinkSortValue {
+    pub fn of(link: &LinkDBResult, sort: LinkSort) -> Self {
+        match sort {
+            LinkSort::BookmarkedAt => Self::BookmarkedAt(link.bookmarked_at),
+            LinkSort::Title => Self::Title(link.title.clone()),
+        }
+    }
+}
+
+/// What saving a link again changes. `tags` are added to the link's tags.
+#[derive(Debug, Clone)]
+pub struct LinkMerge {
+    pub title: String,
+    pub note: String,
+    pub tags: Vec<Thing>,
+    pub collection: Option<Thing>,
+}
+
+/// Fields to change on a link. Fields that are `None` are left as they are.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LinkChanges {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub canonical_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<Thing>>,
+    /// `Some(None)` takes the link out of its collection.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub collection: Option<Option<Thing>>,
+}
+
+/// A point in the changes to a user's links, which are ordered by (time, id).
+/// Without an id it is past every change at `at`.
+#[derive(Debug, Clone)]
+pub struct ChangePosition {
+    pub at: DateTime<Utc>,
+    pub id: Option<Thing>,
+}
+
+/// A link found by `LinkRepository::search`, with how well it matches.
+#[derive(Debug, Clone)]
+pub struct ScoredLink {
+    pub link: LinkDBResult,
+    pub score: f64,
+}
+
+/// Links are always looked up together with their user, so that users can
+/// only ever see and change their own.
+#[async_trait]
+pub trait LinkRepository: Send + Sync {
+    async fn get(&self, user_id: &Thing, link_id: &Thing) -> Result<Option<LinkDBResult>>;
+
+    /// The user's link saved under `canonical_url`, other than `exclude`.
+    async fn find_by_canonical_url(
+        &self,
+        user_id: &Thing,
+        canonical_url: &str,
+        exclude: Option<&Thing>,
+    ) -> Result<Option<LinkDBResult>>;
+
+    /// The user's links saved under any of `canonical_urls`.
+    async fn find_by_canonical_urls(
+        &self,
+        user_id: &Thing,
+        canonical_urls: &[String],
+    ) -> Result<Vec<LinkDBResult>>;
+
+    async fn list(&self, user_id: &Thing, query: &LinkQuery) -> Result<Vec<LinkDBResult>>;
+
+    async fn create(&self, link: Link) -> Result<LinkDBResult>;
+
+    /// Creates all of the links or none of them, returning them in order.
+    async fn create_many(&self, links: Vec<Link>) -> Result<Vec<LinkDBResult>>;
+
+    /// Bumps the link to the top with the changes of saving it again.
+    async fn merge(
+        &self,
+        user_id: &Thing,
+        link_id: &Thing,
+        merge: LinkMerge,
+    ) -> Result<Option<LinkDBResult>>;
+
+    async fn update(
+        &self,
+        user_id: &Thing,
+        link_id: &Thing,
+        changes: LinkChanges,
+    ) -> Result<Option<LinkDBResult>>;
+
+    /// Returns the link as it was before it was deleted.
+    async fn delete(&self, user_id: &Thing, link_id: &Thing) -> Result<Option<LinkDBResult>>;
+
+    /// Deletes all of the user's links.
+    async fn clear(&self, user_id: &Thing) -> Result<()>;
+
+    /// Candidates for `query`, best first, which have every index term in
+    /// one of their fields. Phrases and prefixes are left to the caller.
+    async fn search(
+        &self,
+        user_id: &Thing,
+        query: &SearchQuery,
+        start: usize,
+        limit: usize,
+    ) -> Result<Vec<ScoredLink>>;
+
+    /// Links created or changed after `after`, ordered by (`updated_at`, id).
+    async fn list_changed(
+        &self,
+        user_id: &Thing,
+        after: Option<&ChangePosition>,
+        limit: usize,
+    ) -> Result<Vec<LinkDBResult>>;
+
+    /// Tombstones of links deleted after `after`, ordered by (`deleted_at`,
+    /// id).
+    async fn list_deleted(
+        &self,
+        user_id: &Thing,
+        after: Option<&ChangePosition>,
+        limit: usize,
+    ) -> Result<Vec<LinkTombstone>>;
+
+    /// Drops the tombstones up to and including `up_to`.
+    async fn prune_tombstones(&self, user_id: &Thing, up_to: &ChangePosition) -> Result<()>;
+
+    /// How many of the user's links have each tag, by tag id.
+    async fn count_by_tag(&self, user_id: &Thing) -> Result<HashMap<String, u64>>;
+
+    /// The ones of `tags` that any of the user's links have.
+    async fn tags_in_use(&self, user_id: &Thing, tags: &[Thing]) -> Result<Vec<Thing>>;
+
+    /// Marks the links with the tag as changed, so that devices sync its new
+    /// name.
+    async fn touch_tagged(&self, user_id: &Thing, tag_id: &Thing) -> Result<()>;
+
+    /// Gives the links with `source` the `target` tag instead.
+    async fn replace_tag(&self, user_id: &Thing, source: &Thing, target: &Thing) -> Result<()>;
+
+    /// How many of the user's links are in each collection, by collection id.
+    async fn count_by_collection(&self, user_id: &Thing) -> Result<HashMap<String, u64>>;
+
+    /// Moves the links of any of `collections` to `target`, or out of any
+    /// collection.
+    async fn move_collections(
+        &self,
+        user_id: &Thing,
+        collections: &[Thing],
+        target: Option<&Thing>,
+    ) -> Result<()>;
+
+    /// Puts the given links in the collection and returns how many of them
+    /// the user has.
+    async fn set_collection(
+        &self,
+        user_id: &Thing,
+        link_ids: &[Thing],
+        collection: &Thing,
+    ) -> Result<usize>;
+
+    /// Deletes the links of any of `collections`.
+    async fn delete_in_collections(&self, user_id: &Thing, collections: &[Thing]) -> Result<()>;
+}
@@ -0,0 +1,1437 @@
+//! Repositories backed by SQLite or Postgres. They go through sqlx's `Any`
+//! driver, so the same queries run on both, and the schema is created by the
+//! migrations in `db/sql`, which are embedded in the binary.
+//!
+//! Tags and collections are still kept in SurrealDB. Links only store the ids
+//! of their tags, and their names are looked up there.
+//!
+//! Search matches terms with `LIKE` on the lowercased fields rather than a
+//! full text index. SQLite's `LOWER` only folds ASCII letters, so there other
+//! letters only match in the case they were written in.
+
+use std::{collections::HashMap, fmt::Debug, sync::Arc};
+
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use sqlx::{
+    any::{install_default_drivers, AnyPoolOptions},
+    migrate::Migrator,
+    Any, AnyPool, FromRow, Transaction,
+};
+use surrealdb::sql::{thing, Thing};
+use tracing::error;
+
+use super::{
+    already_saved, new_id, ChangePosition, LinkChanges, LinkMerge, LinkQuery, LinkRepository,
+    LinkSortValue, ScoredLink, TokenRepository, UserRepository,
+};
+use crate::{
+    configuration::DatabaseBackend,
+    error::{Error, Result},
+    routes::link_routes::{LinkSort, SortOrder},
+    search::SearchQuery,
+    types::{
+        CreateUserContent, Link, LinkDBResult, LinkTombstone, TagDBResult, Token, TokenDBResult,
+        User, UserDBResult, DB,
+    },
+};
+
+static MIGRATOR: Migrator = sqlx::migrate!("./db/sql");
+
+const DEFAULT_SQLITE_URL: &str = "sqlite://linkstowr.db?mode=rwc";
+
+/// Connects to the database of `backend` and brings its schema up to date.
+pub async fn connect(
+    backend: DatabaseBackend,
+    url: Option<&str>,
+) -> std::result::Result<AnyPool, sqlx::Error> {
+    let (url, scheme) = match backend {
+        DatabaseBackend::Sqlite => (url.unwrap_or(DEFAULT_SQLITE_URL), "sqlite:"),
+        DatabaseBackend::Postgres => (url.unwrap_or_default(), "postgres"),
+        DatabaseBackend::Surreal => {
+            return Err(sqlx::Error::Configuration(
+                "SurrealDB is not a SQL backend".into(),
+            ))
+        }
+    };
+    if !url.starts_with(scheme) {
+        return Err(sqlx::Error::Configuration(
+            format!("`database.url` must be a {scheme} URL for the {backend:?} backend").into(),
+        ));
+    }
+
+    install_default_drivers();
+    let pool = AnyPoolOptions::new().connect(url).await?;
+    MIGRATOR.run(&pool).await?;
+
+    Ok(pool)
+}
+
+/// Whether `e` comes from a unique index, which for links is the one on
+/// their canonical URL.
+fn is_unique_violation(e: &sqlx::Error) -> bool {
+    matches!(e, sqlx::Error::Database(e) if e.is_unique_violation())
+}
+
+/// Logs `e` and turns it into `error`.
+fn fail<E: Debug>(error: Error) -> impl FnOnce(E) -> Error {
+    move |e| {
+        error!("Encountered error {:?}", e);
+        error
+    }
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+fn parse_time(time: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(time)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|e| error!("Encountered invalid time {time}: {:?}", e))
+        .ok()
+}
+
+fn parse_optional_time(time: Option<&str>) -> Option<Option<DateTime<Utc>>> {
+    match time {
+        Some(time) => parse_time(time).map(Some),
+        None => Some(None),
+    }
+}
+
+fn parse_id(id: &str) -> Option<Thing> {
+    thing(id)
+        .map_err(|e| error!("Encountered invalid id {id}: {:?}", e))
+        .ok()
+}
+
+/// Collects the values bound to `$1`, `$2`, … while a statement is built.
+#[derive(Default)]
+struct Args(Vec<String>);
+
+impl Args {
+    /// Adds `value` and returns its placeholder.
+    fn push(&mut self, value: String) -> String {
+        self.0.push(value);
+        format!("${}", self.0.len())
+    }
+
+    /// Adds every one of `values` and returns their placeholders as a list
+    /// for `IN`.
+    fn push_all<'a>(&mut self, values: impl IntoIterator<Item = &'a Thing>) -> String {
+        let placeholders: Vec<String> = values
+            .into_iter()
+            .map(|value| self.push(value.to_string()))
+            .collect();
+
+        format!("({})", placeholders.join(", "))
+    }
+}
+
+#[derive(FromRow)]
+struct UserRow {
+    id: String,
+    username: String,
+    password: String,
+    email: Option<String>,
+    totp_secret: Option<String>,
+    totp_pending_secret: Option<String>,
+    totp_last_step: Option<i64>,
+    disabled_at: Option<String>,
+}
+
+impl UserRow {
+    fn into_user(self) -> Option<UserDBResult> {
+        Some(UserDBResult {
+            id: parse_id(&self.id)?,
+            username: self.username,
+            password: self.password,
+            email: self.email,
+            totp_secret: self.totp_secret,
+            totp_pending_secret: self.totp_pending_secret,
+            totp_last_step: self.totp_last_step,
+            disabled_at: parse_optional_time(self.disabled_at.as_deref())?,
+        })
+    }
+}
+
+pub struct SqlUserRepository {
+    pool: AnyPool,
+}
+
+impl SqlUserRepository {
+    pub fn new(pool: AnyPool) -> Self {
+        Self { pool }
+    }
+
+    async fn find(&self, column: &str, value: &str) -> Result<Option<UserDBResult>> {
+        let statement = format!("SELECT * FROM users WHERE {column} = $1");
+        let row: Option<UserRow> = sqlx::query_as(&statement)
+            .bind(value)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(fail(Error::GetUsersFail))?;
+
+        row.map(|row| row.into_user().ok_or(Error::GetUsersFail))
+            .transpose()
+    }
+
+    async fn execute(&self, statement: &str, args: Vec<String>, error: Error) -> Result<()> {
+        let mut query = sqlx::query(statement);
+        for arg in args {
+            query = query.bind(arg);
+        }
+        query.execute(&self.pool).await.map_err(fail(error))?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl UserRepository for SqlUserRepository {
+    async fn find_by_id(&self, user_id: &Thing) -> Result<Option<UserDBResult>> {
+        self.find("id", &user_id.to_string()).await
+    }
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<UserDBResult>> {
+        self.find("username", username).await
+    }
+
+    async fn list(&self) -> Result<Vec<UserDBResult>> {
+        let rows: Vec<UserRow> = sqlx::query_as("SELECT * FROM users ORDER BY username")
+            .fetch_all(&self.pool)
+            .await
+            .map_err(fail(Error::GetUsersFail))?;
+
+        rows.into_iter()
+            .map(|row| row.into_user().ok_or(Error::GetUsersFail))
+            .collect()
+    }
+
+    async fn create(&self, user: CreateUserContent) -> Result<User> {
+        if self.find_by_username(&user.username).await?.is_some() {
+            return Err(Error::UsernameExists);
+        }
+
+        let id = new_id("user");
+        sqlx::query("INSERT INTO users (id, username, password, email) VALUES ($1, $2, $3, $4)")
+            .bind(id.to_string())
+            .bind(&user.username)
+            .bind(&user.password)
+            .bind(user.email)
+            .execute(&self.pool)
+            .await
+            .map_err(fail(Error::SignUpFail))?;
+
+        Ok(User {
+            id,
+            username: user.username,
+        })
+    }
+
+    async fn update_password(&self, user_id: &Thing, password_hash: &str) -> Result<()> {
+        self.execute(
+            "UPDATE users SET password = $2 WHERE id = $1",
+            vec![user_id.to_string(), password_hash.into()],
+            Error::ChangePasswordFail,
+        )
+        .await
+    }
+
+    async fn update_email(&self, user_id: &Thing, email: &str) -> Result<()> {
+        self.execute(
+            "UPDATE users SET email = $2 WHERE id = $1",
+            vec![user_id.to_string(), email.into()],
+            Error::UpdateAccountFail,
+        )
+        .await
+    }
+
+    async fn set_disabled_at(
+        &self,
+        user_id: &Thing,
+        disabled_at: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        sqlx::query("UPDATE users SET disabled_at = $2 WHERE id = $1")
+            .bind(user_id.to_string())
+            .bind(disabled_at.map(format_time))
+            .execute(&self.pool)
+            .await
+            .map_err(fail(Error::UpdateAccountFail))?;
+
+        Ok(())
+    }
+
+    async fn set_totp_pending_secret(&self, user_id: &Thing, secret: &str) -> Result<()> {
+        self.execute(
+            "UPDATE users SET totp_pending_secret = $2 WHERE id = $1",
+            vec![user_id.to_string(), secret.into()],
+            Error::EnrollTotpFail,
+        )
+        .await
+    }
+
+    async fn enable_totp(&self, user_id: &Thing, secret: &str) -> Result<()> {
+        self.execute(
+            "UPDATE users SET totp_secret = $2, totp_pending_secret = NULL WHERE id = $1",
+            vec![user_id.to_string(), secret.into()],
+            Error::EnrollTotpFail,
+        )
+        .await
+    }
+
+    async fn disable_totp(&self, user_id: &Thing) -> Result<()> {
+        self.execute(
+            "UPDATE users SET totp_secret = NULL, totp_pending_secret = NULL, totp_last_step = NULL WHERE id = $1",
+            vec![user_id.to_string()],
+            Error::UpdateAccountFail,
+        )
+        .await
+    }
+
+    async fn record_totp_step(&self, user_id: &Thing, step: i64) -> Result<bool> {
+        // Conditional, so that two requests with the same code can't both win
+        let result = sqlx::query(
+            "UPDATE users SET totp_last_step = $2 WHERE id = $1 AND (totp_last_step IS NULL OR totp_last_step < $2)",
+        )
+        .bind(user_id.to_string())
+        .bind(step)
+        .execute(&self.pool)
+        .await
+        .map_err(fail(Error::VerifyMfaFail))?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn delete(&self, user_id: &Thing) -> Result<()> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(fail(Error::DeleteAccountFail))?;
+        for statement in [
+            "DELETE FROM tokens WHERE user_id = $1",
+            "DELETE FROM links WHERE user_id = $1",
+            "DELETE FROM link_tombstones WHERE user_id = $1",
+            "DELETE FROM users WHERE id = $1",
+        ] {
+            sqlx::query(statement)
+                .bind(user_id.to_string())
+                .execute(&mut *tx)
+                .await
+                .map_err(fail(Error::DeleteAccountFail))?;
+        }
+        tx.commit().await.map_err(fail(Error::DeleteAccountFail))?;
+
+        Ok(())
+    }
+}
+
+#[derive(FromRow)]
+struct TokenRow {
+    id: String,
+    token_hash: String,
+    name: String,
+    short_token: String,
+    user_id: String,
+    scopes: String,
+    created_at: String,
+    expires_at: Option<String>,
+    last_used_at: Option<String>,
+    last_used_ip: Option<String>,
+}
+
+impl TokenRow {
+    fn into_token(self) -> Option<TokenDBResult> {
+        Some(TokenDBResult {
+            id: parse_id(&self.id)?,
+            token_hash: self.token_hash,
+            name: self.name,
+            short_token: self.short_token,
+            user: parse_id(&self.user_id)?,
+            scopes: serde_json::from_str(&self.scopes)
+                .map_err(|e| error!("Encountered invalid scopes {}: {:?}", self.scopes, e))
+                .ok()?,
+            created_at: parse_time(&self.created_at)?,
+            expires_at: parse_optional_time(self.expires_at.as_deref())?,
+            last_used_at: parse_optional_time(self.last_used_at.as_deref())?,
+            last_used_ip: self.last_used_ip,
+        })
+    }
+}
+
+pub struct SqlTokenRepository {
+    pool: AnyPool,
+}
+
+impl SqlTokenRepository {
+    pub fn new(pool: AnyPool) -> Self {
+        Self { pool }
+    }
+
+    /// The tokens matching `condition`, oldest first.
+    async fn find(
+        &self,
+        condition: &str,
+        args: Vec<String>,
+        error: Error,
+    ) -> Result<Vec<TokenDBResult>> {
+        let statement = format!("SELECT * FROM tokens WHERE {condition} ORDER BY created_at, id");
+        let mut query = sqlx::query_as(&statement);
+        for arg in args {
+            query = query.bind(arg);
+        }
+        let rows: Vec<TokenRow> = query
+            .fetch_all(&self.pool)
+            .await
+            .map_err(fail(error.clone()))?;
+
+        rows.into_iter()
+            .map(|row| row.into_token().ok_or_else(|| error.clone()))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl TokenRepository for SqlTokenRepository {
+    async fn create(&self, token: Token) -> Result<TokenDBResult> {
+        let token = TokenDBResult {
+            id: new_id("token"),
+            token_hash: token.token_hash,
+            name: token.name,
+            short_token: token.short_token,
+            user: token.user,
+            scopes: token.scopes,
+            created_at: Utc::now(),
+            expires_at: token.expires_at.map(|expires_at| expires_at.0),
+            last_used_at: None,
+            last_used_ip: None,
+        };
+
+        sqlx::query(
+            "INSERT INTO tokens (id, token_hash, name, short_token, user_id, scopes, created_at, expires_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+        )
+        .bind(token.id.to_string())
+        .bind(&token.token_hash)
+        .bind(&token.name)
+        .bind(&token.short_token)
+        .bind(token.user.to_string())
+        .bind(serde_json::to_string(&token.scopes).map_err(fail(Error::GenTokenFail))?)
+        .bind(format_time(token.created_at))
+        .bind(token.expires_at.map(format_time))
+        .execute(&self.pool)
+        .await
+        .map_err(fail(Error::GenTokenFail))?;
+
+        Ok(token)
+    }
+
+    async fn find_by_hash(&self, token_hash: &str) -> Result<Option<TokenDBResult>> {
+        let tokens = self
+            .find(
+                "token_hash = $1",
+                vec![token_hash.into()],
+                Error::InvalidToken,
+            )
+            .await?;
+
+        Ok(tokens.into_iter().next())
+    }
+
+    async fn find_for_user(
+        &self,
+        token_id: &Thing,
+        user_id: &Thing,
+    ) -> Result<Option<TokenDBResult>> {
+        let tokens = self
+            .find(
+                "id = $1 AND user_id = $2",
+                vec![token_id.to_string(), user_id.to_string()],
+                Error::GetTokensFail,
+            )
+            .await?;
+
+        Ok(tokens.into_iter().next())
+    }
+
+    async fn list_for_user(&self, user_id: &Thing) -> Result<Vec<TokenDBResult>> {
+        self.find(
+            "user_id = $1",
+            vec![user_id.to_string()],
+            Error::GetTokensFail,
+        )
+        .await
+    }
+
+    async fn delete_for_user(&self, token_id: &Thing, user_id: &Thing) -> Result<()> {
+        sqlx::query("DELETE FROM tokens WHERE id = $1 AND user_id = $2")
+            .bind(token_id.to_string())
+            .bind(user_id.to_string())
+            .execute(&self.pool)
+            .await
+            .map_err(fail(Error::DeleteTokenFail))?;
+
+        Ok(())
+    }
+
+    async fn record_use(&self, token_id: &Thing, ip: Option<&str>) -> Result<()> {
+        sqlx::query("UPDATE tokens SET last_used_at = $2, last_used_ip = $3 WHERE id = $1")
+            .bind(token_id.to_string())
+            .bind(format_time(Utc::now()))
+            .bind(ip)
+            .execute(&self.pool)
+            .await
+            .map_err(fail(Error::UpdateTokenFail))?;
+
+        Ok(())
+    }
+
+    async fn set_expiry(&self, token_id: &Thing, expires_at: DateTime<Utc>) -> Result<()> {
+        sqlx::query("UPDATE tokens SET expires_at = $2 WHERE id = $1")
+            .bind(token_id.to_string())
+            .bind(format_time(expires_at))
+            .execute(&self.pool)
+            .await
+            .map_err(fail(Error::UpdateTokenFail))?;
+
+        Ok(())
+    }
+}
+
+#[derive(FromRow)]
+struct LinkRow {
+    id: String,
+    user_id: String,
+    url: String,
+    canonical_url: String,
+    domain: Option<String>,
+    title: String,
+    note: String,
+    bookmarked_at: String,
+    updated_at: String,
+    /// JSON array of tag ids.
+    tags: String,
+    collection: Option<String>,
+}
+
+impl LinkRow {
+    fn new(link: Link, now: &str) -> Self {
+        Self {
+            id: new_id("link").to_string(),
+            user_id: link.user.to_string(),
+            domain: domain_of(&link.url),
+            url: link.url,
+            canonical_url: link.canonical_url,
+            title: link.title,
+            note: link.note,
+            bookmarked_at: format_time(link.bookmarked_at.0),
+            updated_at: now.to_string(),
+            tags: tags_json(&link.tags),
+            collection: link.collection.map(|collection| collection.to_string()),
+        }
+    }
+
+    fn tag_ids(&self) -> Option<Vec<Thing>> {
+        let tags: Vec<String> = serde_json::from_str(&self.tags)
+            .map_err(|e| error!("Encountered invalid tags {}: {:?}", self.tags, e))
+            .ok()?;
+
+        tags.iter().map(|tag| parse_id(tag)).collect()
+    }
+
+    /// The link without its tag names, and the ids of its tags.
+    fn into_link(self) -> Option<(LinkDBResult, Vec<Thing>)> {
+        let tag_ids = self.tag_ids()?;
+        let link = LinkDBResult {
+            id: parse_id(&self.id)?,
+            url: self.url,
+            canonical_url: self.canonical_url,
+            title: self.title,
+            note: self.note,
+            bookmarked_at: parse_time(&self.bookmarked_at)?,
+            updated_at: Some(parse_time(&self.updated_at)?),
+            user: parse_id(&self.user_id)?,
+            collection: match self.collection {
+                Some(collection) => Some(parse_id(&collection)?),
+                None => None,
+            },
+            tag_names: vec![],
+        };
+
+        Some((link, tag_ids))
+    }
+}
+
+#[derive(FromRow)]
+struct ScoredLinkRow {
+    #[sqlx(flatten)]
+    link: LinkRow,
+    score: i64,
+}
+
+#[derive(FromRow)]
+struct TombstoneRow {
+    id: String,
+    user_id: String,
+    link_id: String,
+    deleted_at: String,
+}
+
+impl TombstoneRow {
+    fn into_tombstone(self) -> Option<LinkTombstone> {
+        Some(LinkTombstone {
+            id: parse_id(&self.id)?,
+            link: parse_id(&self.link_id)?,
+            user: parse_id(&self.user_id)?,
+            deleted_at: parse_time(&self.deleted_at)?,
+        })
+    }
+}
+
+/// `LIKE` pattern matching links with the tag in their JSON array of tags.
+fn tag_pattern(tag_id: &Thing) -> String {
+    let tag_id = serde_json::to_string(&tag_id.to_string()).expect("A string is always valid JSON");
+
+    format!("%{tag_id}%")
+}
+
+/// Condition matching the changes in `field` after `position`, with its time
+/// and id added to `args`.
+fn after_condition(position: &ChangePosition, field: &str, args: &mut Args) -> String {
+    let at = args.push(format_time(position.at));
+    match &position.id {
+        Some(id) => {
+            let id = args.push(id.to_string());
+            format!("({field} > {at} OR ({field} = {at} AND id > {id}))")
+        }
+        None => format!("{field} > {at}"),
+    }
+}
+
+/// Deletes the links matching `condition` and records their tombstones, like
+/// the `link_deleted` event does in SurrealDB. Tombstones take the key of
+/// their link, which is never used again.
+async fn delete_links_where(
+    tx: &mut Transaction<'_, Any>,
+    condition: &str,
+    mut args: Args,
+) -> std::result::Result<(), sqlx::Error> {
+    let delete = format!("DELETE FROM links WHERE {condition}");
+    let mut delete_query = sqlx::query(&delete);
+    for arg in &args.0 {
+        delete_query = delete_query.bind(arg.clone());
+    }
+
+    let deleted_at = args.push(format_time(Utc::now()));
+    let insert = format!(
+        "INSERT INTO link_tombstones (id, user_id, link_id, deleted_at) SELECT 'link_tombstone:' || SUBSTR(id, 6), user_id, id, {deleted_at} FROM links WHERE {condition}"
+    );
+    let mut insert_query = sqlx::query(&insert);
+    for arg in args.0 {
+        insert_query = insert_query.bind(arg);
+    }
+
+    insert_query.execute(&mut **tx).await?;
+    delete_query.execute(&mut **tx).await?;
+
+    Ok(())
+}
+
+fn domain_of(url: &str) -> Option<String> {
+    url::Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_lowercase))
+}
+
+fn tags_json(tags: &[Thing]) -> String {
+    let tags: Vec<String> = tags.iter().map(Thing::to_string).collect();
+    serde_json::to_string(&tags).expect("A list of strings is always valid JSON")
+}
+
+/// Writes all of the changeable fields of `row` back.
+async fn save_link_row(
+    tx: &mut Transaction<'_, Any>,
+    row: &LinkRow,
+) -> std::result::Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE links SET url = $2, canonical_url = $3, domain = $4, title = $5, note = $6, bookmarked_at = $7, updated_at = $8, tags = $9, collection = $10 WHERE id = $1",
+    )
+    .bind(row.id.as_str())
+    .bind(row.url.as_str())
+    .bind(row.canonical_url.as_str())
+    .bind(row.domain.as_deref())
+    .bind(row.title.as_str())
+    .bind(row.note.as_str())
+    .bind(row.bookmarked_at.as_str())
+    .bind(row.updated_at.as_str())
+    .bind(row.tags.as_str())
+    .bind(row.collection.as_deref())
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+pub struct SqlLinkRepository {
+    pool: AnyPool,
+    /// Where tags are looked up.
+    db: Arc<DB>,
+}
+
+impl SqlLinkRepository {
+    pub fn new(pool: AnyPool, db: Arc<DB>) -> Self {
+        Self { pool, db }
+    }
+
+    async fn fetch(&self, statement: &str, args: Args, error: Error) -> Result<Vec<LinkDBResult>> {
+        let mut query = sqlx::query_as(statement);
+        for arg in args.0 {
+            query = query.bind(arg);
+        }
+        let rows: Vec<LinkRow> = query
+            .fetch_all(&self.pool)
+            .await
+            .map_err(fail(error.clone()))?;
+
+        self.with_tag_names(rows, error).await
+    }
+
+    async fn fetch_one(
+        &self,
+        user_id: &Thing,
+        link_id: &Thing,
+        tx: &mut Transaction<'_, Any>,
+    ) -> std::result::Result<Option<LinkRow>, sqlx::Error> {
+        sqlx::query_as("SELECT * FROM links WHERE id = $1 AND user_id = $2")
+            .bind(link_id.to_string())
+            .bind(user_id.to_string())
+            .fetch_optional(&mut **tx)
+            .await
+    }
+
+    /// Turns rows into links, with the names of their tags.
+    async fn with_tag_names(&self, rows: Vec<LinkRow>, error: Error) -> Result<Vec<LinkDBResult>> {
+        let links: Vec<(LinkDBResult, Vec<Thing>)> = rows
+            .into_iter()
+            .map(LinkRow::into_link)
+            .collect::<Option<_>>()
+            .ok_or_else(|| error.clone())?;
+
+        let tag_ids: Vec<&Thing> = links.iter().flat_map(|(_, tag_ids)| tag_ids).collect();
+        let tag_names: HashMap<String, String> = if tag_ids.is_empty() {
+            HashMap::new()
+        } else {
+            let mut result = self
+                .db
+                .query("SELECT * FROM $tag_ids;")
+                .bind(("tag_ids", tag_ids))
+                .await
+                .map_err(fail(error.clone()))?;
+            let tags: Vec<TagDBResult> = result.take(0).map_err(fail(error))?;
+
+            tags.into_iter()
+                .map(|tag| (tag.id.to_string(), tag.name))
+                .collect()
+        };
+
+        Ok(links
+            .into_iter()
+            .map(|(mut link, tag_ids)| {
+                link.tag_names = tag_ids
+                    .iter()
+                    .filter_map(|tag_id| tag_names.get(&tag_id.to_string()).cloned())
+                    .collect();
+                link
+            })
+            .collect())
+    }
+
+    /// Ids of the user's tags named `names`, or `None` when one of them
+    /// doesn't exist.
+    async fn find_tag_ids(&self, user_id: &Thing, names: &[String]) -> Result<Option<Vec<Thing>>> {
+        let mut result = self
+            .db
+            .query("SELECT VALUE id FROM tag WHERE user = $user_id AND name IN $names;")
+            .bind(("user_id", user_id))
+            .bind(("names", names))
+            .await
+            .map_err(fail(Error::GetLinksFail))?;
+        let tag_ids: Vec<Thing> = result.take(0).map_err(fail(Error::GetLinksFail))?;
+
+        Ok((tag_ids.len() == names.len()).then_some(tag_ids))
+    }
+}
+
+#[async_trait]
+impl LinkRepository for SqlLinkRepository {
+    async fn get(&self, user_id: &Thing, link_id: &Thing) -> Result<Option<LinkDBResult>> {
+        let mut args = Args::default();
+        let statement = format!(
+            "SELECT * FROM links WHERE id = {} AND user_id = {}",
+            args.push(link_id.to_string()),
+            args.push(user_id.to_string())
+        );
+        let links = self.fetch(&statement, args, Error::GetLinkFail).await?;
+
+        Ok(links.into_iter().next())
+    }
+
+    async fn find_by_canonical_url(
+        &self,
+        user_id: &Thing,
+        canonical_url: &str,
+        exclude: Option<&Thing>,
+    ) -> Result<Option<LinkDBResult>> {
+        let mut args = Args::default();
+        let statement = format!(
+            "SELECT * FROM links WHERE user_id = {} AND canonical_url = {} AND id <> {} LIMIT 1",
+            args.push(user_id.to_string()),
+            args.push(canonical_url.into()),
+            args.push(exclude.map(Thing::to_string).unwrap_or_default())
+        );
+        let links = self.fetch(&statement, args, Error::GetLinkFail).await?;
+
+        Ok(links.into_iter().next())
+    }
+
+    async fn find_by_canonical_urls(
+        &self,
+        user_id: &Thing,
+        canonical_urls: &[String],
+    ) -> Result<Vec<LinkDBResult>> {
+        if canonical_urls.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut args = Args::default();
+        let user = args.push(user_id.to_string());
+        let placeholders: Vec<String> = canonical_urls
+            .iter()
+            .map(|canonical_url| args.push(canonical_url.clone()))
+            .collect();
+        let statement = format!(
+            "SELECT * FROM links WHERE user_id = {user} AND canonical_url IN ({})",
+            placeholders.join(", ")
+        );
+
+        self.fetch(&statement, args, Error::GetLinksFail).await
+    }
+
+    async fn list(&self, user_id: &Thing, query: &LinkQuery) -> Result<Vec<LinkDBResult>> {
+        let (sort_field, order, cmp) = match (query.sort, query.order) {
+            (LinkSort::BookmarkedAt, SortOrder::Asc) => ("bookmarked_at", "ASC", ">"),
+            (LinkSort::BookmarkedAt, SortOrder::Desc) => ("bookmarked_at", "DESC", "<"),
+            (LinkSort::Title, SortOrder::Asc) => ("title", "ASC", ">"),
+            (LinkSort::Title, SortOrder::Desc) => ("title", "DESC", "<"),
+        };
+
+        let mut args = Args::default();
+        let mut conditions = vec![format!("user_id = {}", args.push(user_id.to_string()))];
+        if let Some(from) = query.from {
+            conditions.push(format!("bookmarked_at >= {}", args.push(format_time(from))));
+        }
+        if let Some(to) = query.to {
+            conditions.push(format!("bookmarked_at < {}", args.push(format_time(to))));
+        }
+        if let Some(domain) = &query.domain {
+            let bare = args.push(domain.clone());
+            let www = args.push(format!("www.{domain}"));
+            conditions.push(format!("domain IN ({bare}, {www})"));
+        }
+        if !query.tags.is_empty() {
+            let Some(tag_ids) = self.find_tag_ids(user_id, &query.tags).await? else {
+                // A tag the user doesn't have can't be on any of their links
+                return Ok(vec![]);
+            };
+            for tag_id in tag_ids {
+                conditions.push(format!("tags LIKE {}", args.push(tag_pattern(&tag_id))));
+            }
+        }
+        if let Some(collection) = &query.collection {
+            conditions.push(format!(
+                "collection = {}",
+                args.push(collection.to_string())
+            ));
+        }
+        match query.has_note {
+            Some(true) => conditions.push("note <> ''".into()),
+            Some(false) => conditions.push("note = ''".into()),
+            None => {}
+        }
+        // Keyset pagination: continue right after the (sort value, id) of the
+        // given link.
+        if let Some(after) = &query.after {
+            let value = args.push(match &after.value {
+                LinkSortValue::BookmarkedAt(bookmarked_at) => format_time(*bookmarked_at),
+                LinkSortValue::Title(title) => title.clone(),
+            });
+            let id = args.push(after.id.to_string());
+            conditions.push(format!(
+                "({sort_field} {cmp} {value} OR ({sort_field} = {value} AND id {cmp} {id}))"
+            ));
+        }
+
+        let statement = format!(
+            "SELECT * FROM links WHERE {} ORDER BY {sort_field} {order}, id {order} LIMIT {}",
+            conditions.join(" AND "),
+            query.limit
+        );
+
+        self.fetch(&statement, args, Error::GetLinksFail).await
+    }
+
+    async fn create(&self, link: Link) -> Result<LinkDBResult> {
+        let created = self.create_many(vec![link]).await?;
+
+        created.into_iter().next().ok_or(Error::CreateLinkFail)
+    }
+
+    async fn create_many(&self, links: Vec<Link>) -> Result<Vec<LinkDBResult>> {
+        let now = format_time(Utc::now());
+        let rows: Vec<LinkRow> = links
+            .into_iter()
+            .map(|link| LinkRow::new(link, &now))
+            .collect();
+
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(fail(Error::CreateLinkFail))?;
+        for row in &rows {
+            let inserted = sqlx::query(
+                "INSERT INTO links (id, user_id, url, canonical_url, domain, title, note, bookmarked_at, updated_at, tags, collection) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
+            )
+            .bind(row.id.as_str())
+            .bind(row.user_id.as_str())
+            .bind(row.url.as_str())
+            .bind(row.canonical_url.as_str())
+            .bind(row.domain.as_deref())
+            .bind(row.title.as_str())
+            .bind(row.note.as_str())
+            .bind(row.bookmarked_at.as_str())
+            .bind(row.updated_at.as_str())
+            .bind(row.tags.as_str())
+            .bind(row.collection.as_deref())
+            .execute(&mut *tx)
+            .await;
+            match inserted {
+                Ok(_) => {}
+                Err(e) if is_unique_violation(&e) => {
+                    drop(tx);
+                    let canonical_urls: Vec<String> =
+                        rows.iter().map(|row| row.canonical_url.clone()).collect();
+                    return Err(already_saved(
+                        self,
+                        &parse_id(&row.user_id).ok_or(Error::CreateLinkFail)?,
+                        &canonical_urls,
+                        None,
+                        Error::CreateLinkFail,
+                    )
+                    .await);
+                }
+                Err(e) => return Err(fail(Error::CreateLinkFail)(e)),
+            }
+        }
+        tx.commit().await.map_err(fail(Error::CreateLinkFail))?;
+
+        self.with_tag_names(rows, Error::CreateLinkFail).await
+    }
+
+    async fn merge(
+        &self,
+        user_id: &Thing,
+        link_id: &Thing,
+        merge: LinkMerge,
+    ) -> Result<Option<LinkDBResult>> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(fail(Error::UpdateLinkFail))?;
+        let row = self
+            .fetch_one(user_id, link_id, &mut tx)
+            .await
+            .map_err(fail(Error::UpdateLinkFail))?;
+        let Some(mut row) = row else {
+            return Ok(None);
+        };
+
+        let mut tags = row.tag_ids().ok_or(Error::UpdateLinkFail)?;
+        for tag in merge.tags {
+            if !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+        let now = format_time(Utc::now());
+        row.title = merge.title;
+        row.note = merge.note;
+        row.tags = tags_json(&tags);
+        row.collection = merge.collection.map(|collection| collection.to_string());
+        row.bookmarked_at = now.clone();
+        row.updated_at = now;
+
+        save_link_row(&mut tx, &row)
+            .await
+            .map_err(fail(Error::UpdateLinkFail))?;
+        tx.commit().await.map_err(fail(Error::UpdateLinkFail))?;
+
+        let links = self
+            .with_tag_names(vec![row], Error::UpdateLinkFail)
+            .await?;
+
+        Ok(links.into_iter().next())
+    }
+
+    async fn update(
+        &self,
+        user_id: &Thing,
+        link_id: &Thing,
+        changes: LinkChanges,
+    ) -> Result<Option<LinkDBResult>> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(fail(Error::UpdateLinkFail))?;
+        let row = self
+            .fetch_one(user_id, link_id, &mut tx)
+            .await
+            .map_err(fail(Error::UpdateLinkFail))?;
+        let Some(mut row) = row else {
+            return Ok(None);
+        };
+
+        if let Some(url) = changes.url {
+            row.domain = domain_of(&url);
+            row.url = url;
+        }
+        if let Some(canonical_url) = changes.canonical_url {
+            row.canonical_url = canonical_url;
+        }
+        if let Some(title) = changes.title {
+            row.title = title;
+        }
+        if let Some(note) = changes.note {
+            row.note = note;
+        }
+        if let Some(tags) = changes.tags {
+            row.tags = tags_json(&tags);
+        }
+        if let Some(collection) = changes.collection {
+            row.collection = collection.map(|collection| collection.to_string());
+        }
+        row.updated_at = format_time(Utc::now());
+
+        match save_link_row(&mut tx, &row).await {
+            Ok(_) => {}
+            Err(e) if is_unique_violation(&e) => {
+                drop(tx);
+                return Err(already_saved(
+                    self,
+                    user_id,
+                    &[row.canonical_url],
+                    Some(link_id),
+                    Error::UpdateLinkFail,
+                )
+                .await);
+            }
+            Err(e) => return Err(fail(Error::UpdateLinkFail)(e)),
+        }
+        tx.commit().await.map_err(fail(Error::UpdateLinkFail))?;
+
+        let links = self
+            .with_tag_names(vec![row], Error::UpdateLinkFail)
+            .await?;
+
+        Ok(links.into_iter().next())
+    }
+
+    async fn delete(&self, user_id: &Thing, link_id: &Thing) -> Result<Option<LinkDBResult>> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(fail(Error::DeleteLinkFail))?;
+        let row = self
+            .fetch_one(user_id, link_id, &mut tx)
+            .await
+            .map_err(fail(Error::DeleteLinkFail))?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+
+        let mut args = Args::default();
+        let condition = format!("id = {}", args.push(row.id.clone()));
+        delete_links_where(&mut tx, &condition, args)
+            .await
+            .map_err(fail(Error::DeleteLinkFail))?;
+        tx.commit().await.map_err(fail(Error::DeleteLinkFail))?;
+
+        let links = self
+            .with_tag_names(vec![row], Error::DeleteLinkFail)
+            .await?;
+
+        Ok(links.into_iter().next())
+    }
+
+    async fn clear(&self, user_id: &Thing) -> Result<()> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(fail(Error::ClearLinksFail))?;
+        let mut args = Args::default();
+        let condition = format!("user_id = {}", args.push(user_id.to_string()));
+        delete_links_where(&mut tx, &condition, args)
+            .await
+            .map_err(fail(Error::ClearLinksFail))?;
+        tx.commit().await.map_err(fail(Error::ClearLinksFail))?;
+
+        Ok(())
+    }
+
+    async fn search(
+        &self,
+        user_id: &Thing,
+        query: &SearchQuery,
+        start: usize,
+        limit: usize,
+    ) -> Result<Vec<ScoredLink>> {
+        // Every term has to be in one of the fields, and scores a point for
+        // each field it is in.
+        let mut args = Args::default();
+        let mut conditions = vec![format!("user_id = {}", args.push(user_id.to_string()))];
+        let mut scores = vec![];
+        for term in query.index_terms() {
+            let pattern = args.push(format!("%{term}%"));
+            let fields =
+                ["title", "note", "url"].map(|field| format!("LOWER({field}) LIKE {pattern}"));
+            conditions.push(format!("({})", fields.join(" OR ")));
+            scores.extend(fields.map(|field| format!("CASE WHEN {field} THEN 1 ELSE 0 END")));
+        }
+        let score = if scores.is_empty() {
+            "0".to_string()
+        } else {
+            scores.join(" + ")
+        };
+        // Prefixes narrow down the candidates by substring and are checked
+        // properly by the caller.
+        for prefix in &query.prefixes {
+            conditions.push(format!(
+                "LOWER(title || ' ' || note || ' ' || url) LIKE {}",
+                args.push(format!("%{prefix}%"))
+            ));
+        }
+
+        let statement = format!(
+            "SELECT *, CAST({score} AS BIGINT) AS score FROM links WHERE {} ORDER BY score DESC, bookmarked_at DESC, id DESC LIMIT {limit} OFFSET {start}",
+            conditions.join(" AND ")
+        );
+        let mut query = sqlx::query_as(&statement);
+        for arg in args.0 {
+            query = query.bind(arg);
+        }
+        let rows: Vec<ScoredLinkRow> = query
+            .fetch_all(&self.pool)
+            .await
+            .map_err(fail(Error::SearchLinksFail))?;
+
+        let scores: Vec<i64> = rows.iter().map(|row| row.score).collect();
+        let links = self
+            .with_tag_names(
+                rows.into_iter().map(|row| row.link).collect(),
+                Error::SearchLinksFail,
+            )
+            .await?;
+
+        Ok(links
+            .into_iter()
+            .zip(scores)
+            .map(|(link, score)| ScoredLink {
+                link,
+                score: score as f64,
+            })
+            .collect())
+    }
+
+    async fn list_changed(
+        &self,
+        user_id: &Thing,
+        after: Option<&ChangePosition>,
+        limit: usize,
+    ) -> Result<Vec<LinkDBResult>> {
+        let mut args = Args::default();
+        let mut conditions = vec![format!("user_id = {}", args.push(user_id.to_string()))];
+        if let Some(after) = after {
+            conditions.push(after_condition(after, "updated_at", &mut args));
+        }
+        let statement = format!(
+            "SELECT * FROM links WHERE {} ORDER BY updated_at ASC, id ASC LIMIT {limit}",
+            conditions.join(" AND ")
+        );
+
+        self.fetch(&statement, args, Error::GetChangesFail).await
+    }
+
+    async fn list_deleted(
+        &self,
+        user_id: &Thing,
+        after: Option<&ChangePosition>,
+        limit: usize,
+    ) -> Result<Vec<LinkTombstone>> {
+        let mut args = Args::default();
+        let mut conditions = vec![format!("user_id = {}", args.push(user_id.to_string()))];
+        if let Some(after) = after {
+            conditions.push(after_condition(after, "deleted_at", &mut args));
+        }
+        let statement = format!(
+            "SELECT * FROM link_tombstones WHERE {} ORDER BY deleted_at ASC, id ASC LIMIT {limit}",
+            conditions.join(" AND ")
+        );
+        let mut query = sqlx::query_as(&statement);
+        for arg in args.0 {
+            query = query.bind(arg);
+        }
+        let rows: Vec<TombstoneRow> = query
+            .fetch_all(&self.pool)
+            .await
+            .map_err(fail(Error::GetChangesFail))?;
+
+        rows.into_iter()
+            .map(TombstoneRow::into_tombstone)
+            .collect::<Option<_>>()
+            .ok_or(Error::GetChangesFail)
+    }
+
+    async fn prune_tombstones(&self, user_id: &Thing, up_to: &ChangePosition) -> Result<()> {
+        let mut args = Args::default();
+        let user = args.push(user_id.to_string());
+        let at = args.push(format_time(up_to.at));
+        let condition = match &up_to.id {
+            Some(id) => {
+                let id = args.push(id.to_string());
+                format!("(deleted_at < {at} OR (deleted_at = {at} AND id <= {id}))")
+            }
+            None => format!("deleted_at <= {at}"),
+        };
+        let statement =
+            format!("DELETE FROM link_tombstones WHERE user_id = {user} AND {condition}");
+        let mut query = sqlx::query(&statement);
+        for arg in args.0 {
+            query = query.bind(arg);
+        }
+        query
+            .execute(&self.pool)
+            .await
+            .map_err(fail(Error::AckChangesFail))?;
+
+        Ok(())
+    }
+
+    async fn count_by_tag(&self, user_id: &Thing) -> Result<HashMap<String, u64>> {
+        let tags: Vec<String> = sqlx::query_scalar("SELECT tags FROM links WHERE user_id = $1")
+            .bind(user_id.to_string())
+            .fetch_all(&self.pool)
+            .await
+            .map_err(fail(Error::GetTagsFail))?;
+
+        let mut counts = HashMap::new();
+        for tags in tags {
+            let tags: Vec<String> =
+                serde_json::from_str(&tags).map_err(fail(Error::GetTagsFail))?;
+            for tag in tags {
+                *counts.entry(tag).or_insert(0) += 1;
+            }
+        }
+
+        Ok(counts)
+    }
+
+    async fn tags_in_use(&self, user_id: &Thing, tags: &[Thing]) -> Result<Vec<Thing>> {
+        if tags.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut args = Args::default();
+        let user = args.push(user_id.to_string());
+        let patterns: Vec<String> = tags
+            .iter()
+            .map(|tag| format!("tags LIKE {}", args.push(tag_pattern(tag))))
+            .collect();
+        let statement = format!(
+            "SELECT tags FROM links WHERE user_id = {user} AND ({})",
+            patterns.join(" OR ")
+        );
+        let mut query = sqlx::query_scalar(&statement);
+        for arg in args.0 {
+            query = query.bind(arg);
+        }
+        let link_tags: Vec<String> = query
+            .fetch_all(&self.pool)
+            .await
+            .map_err(fail(Error::GetTagsFail))?;
+
+        let mut used: Vec<Thing> = vec![];
+        for link_tags in link_tags {
+            let link_tags: Vec<String> =
+                serde_json::from_str(&link_tags).map_err(fail(Error::GetTagsFail))?;
+            for tag in tags {
+                if link_tags.contains(&tag.to_string()) && !used.contains(tag) {
+                    used.push(tag.clone());
+                }
+            }
+        }
+
+        Ok(used)
+    }
+
+    async fn touch_tagged(&self, user_id: &Thing, tag_id: &Thing) -> Result<()> {
+        sqlx::query("UPDATE links SET updated_at = $1 WHERE user_id = $2 AND tags LIKE $3")
+            .bind(format_time(Utc::now()))
+            .bind(user_id.to_string())
+            .bind(tag_pattern(tag_id))
+            .execute(&self.pool)
+            .await
+            .map_err(fail(Error::UpdateTagFail))?;
+
+        Ok(())
+    }
+
+    async fn replace_tag(&self, user_id: &Thing, source: &Thing, target: &Thing) -> Result<()> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(fail(Error::MergeTagsFail))?;
+        let rows: Vec<LinkRow> =
+            sqlx::query_as("SELECT * FROM links WHERE user_id = $1 AND tags LIKE $2")
+                .bind(user_id.to_string())
+                .bind(tag_pattern(source))
+                .fetch_all(&mut *tx)
+                .await
+                .map_err(fail(Error::MergeTagsFail))?;
+
+        let now = format_time(Utc::now());
+        for mut row in rows {
+            let mut tags = row.tag_ids().ok_or(Error::MergeTagsFail)?;
+            tags.retain(|tag| tag != source);
+            if !tags.contains(target) {
+                tags.push(target.clone());
+            }
+            row.tags = tags_json(&tags);
+            row.updated_at = now.clone();
+
+            save_link_row(&mut tx, &row)
+                .await
+                .map_err(fail(Error::MergeTagsFail))?;
+        }
+        tx.commit().await.map_err(fail(Error::MergeTagsFail))?;
+
+        Ok(())
+    }
+
+    async fn count_by_collection(&self, user_id: &Thing) -> Result<HashMap<String, u64>> {
+        let counts: Vec<(String, i64)> = sqlx::query_as(
+            "SELECT collection, COUNT(*) FROM links WHERE user_id = $1 AND collection IS NOT NULL GROUP BY collection",
+        )
+        .bind(user_id.to_string())
+        .fetch_all(&self.pool)
+        .await
+        .map_err(fail(Error::GetCollectionsFail))?;
+
+        Ok(counts
+            .into_iter()
+            .map(|(collection, count)| (collection, count as u64))
+            .collect())
+    }
+
+    async fn move_collections(
+        &self,
+        user_id: &Thing,
+        collections: &[Thing],
+        target: Option<&Thing>,
+    ) -> Result<()> {
+        if collections.is_empty() {
+            return Ok(());
+        }
+
+        let mut args = Args::default();
+        let target = match target {
+            Some(target) => args.push(target.to_string()),
+            None => "NULL".to_string(),
+        };
+        let now = args.push(format_time(Utc::now()));
+        let user = args.push(user_id.to_string());
+        let statement = format!(
+            "UPDATE links SET collection = {target}, updated_at = {now} WHERE user_id = {user} AND collection IN {}",
+            args.push_all(collections)
+        );
+        let mut query = sqlx::query(&statement);
+        for arg in args.0 {
+            query = query.bind(arg);
+        }
+        query
+            .execute(&self.pool)
+            .await
+            .map_err(fail(Error::DeleteCollectionFail))?;
+
+        Ok(())
+    }
+
+    async fn set_collection(
+        &self,
+        user_id: &Thing,
+        link_ids: &[Thing],
+        collection: &Thing,
+    ) -> Result<usize> {
+        if link_ids.is_empty() {
+            return Ok(0);
+        }
+
+        let mut args = Args::default();
+        let collection = args.push(collection.to_string());
+        let now = args.push(format_time(Utc::now()));
+        let user = args.push(user_id.to_string());
+        let statement = format!(
+            "UPDATE links SET collection = {collection}, updated_at = {now} WHERE user_id = {user} AND id IN {}",
+            args.push_all(link_ids)
+        );
+        let mut query = sqlx::query(&statement);
+        for arg in args.0 {
+            query = query.bind(arg);
+        }
+        let result = query
+            .execute(&self.pool)
+            .await
+            .map_err(fail(Error::MoveLinksFail))?;
+
+        Ok(result.rows_affected() as usize)
+    }
+
+    async fn delete_in_collections(&self, user_id: &Thing, collections: &[Thing]) -> Result<()> {
+        if collections.is_empty() {
+            return Ok(());
+        }
+
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(fail(Error::DeleteCollectionFail))?;
+        let mut args = Args::default();
+        let user = args.push(user_id.to_string());
+        let condition = format!(
+            "user_id = {user} AND collection IN {}",
+            args.push_all(collections)
+        );
+        delete_links_where(&mut tx, &condition, args)
+            .await
+            .map_err(fail(Error::DeleteCollectionFail))?;
+        tx.commit()
+            .await
+            .map_err(fail(Error::DeleteCollectionFail))?;
+
+        Ok(())
+    }
+}
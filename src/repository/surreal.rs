@@ -0,0 +1,1103 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use surrealdb::{
+    engine::any::Any,
+    method::Query,
+    sql::{self, Datetime, Thing},
+};
+use tracing::error;
+
+use super::{
+    already_saved, ChangePosition, LinkChanges, LinkMerge, LinkQuery, LinkRepository,
+    LinkSortValue, ScoredLink, TokenRepository, UserRepository,
+};
+use crate::{
+    error::{Error, Result},
+    routes::link_routes::{LinkSort, SortOrder},
+    search::SearchQuery,
+    types::{
+        CreateUserContent, Link, LinkDBResult, LinkTombstone, Token, TokenDBResult, User,
+        UserDBResult, DB, LINK_FIELDS,
+    },
+};
+
+pub struct SurrealUserRepository {
+    db: Arc<DB>,
+}
+
+impl SurrealUserRepository {
+    pub fn new(db: Arc<DB>) -> Self {
+        Self { db }
+    }
+}
+
+#[async_trait]
+impl UserRepository for SurrealUserRepository {
+    async fn find_by_id(&self, user_id: &Thing) -> Result<Option<UserDBResult>> {
+        let mut result = self
+            .db
+            .query("SELECT * FROM $user_id;")
+            .bind(("user_id", user_id))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::GetUsersFail
+            })?;
+
+        result.take(0).map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::GetUsersFail
+        })
+    }
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<UserDBResult>> {
+        let mut result = self
+            .db
+            .query("SELECT * FROM user WHERE username = $username;")
+            .bind(("username", username))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::GetUsersFail
+            })?;
+
+        result.take(0).map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::GetUsersFail
+        })
+    }
+
+    async fn list(&self) -> Result<Vec<UserDBResult>> {
+        let mut result = self
+            .db
+            .query("SELECT * FROM user ORDER BY username ASC;")
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::GetUsersFail
+            })?;
+
+        result.take(0).map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::GetUsersFail
+        })
+    }
+
+    async fn create(&self, user: CreateUserContent) -> Result<User> {
+        if self.find_by_username(&user.username).await?.is_some() {
+            return Err(Error::UsernameExists);
+        }
+
+        let created: Vec<User> = self.db.create("user").content(user).await.map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::SignUpFail
+        })?;
+
+        created.into_iter().next().ok_or(Error::SignUpFail)
+    }
+
+    async fn update_password(&self, user_id: &Thing, password_hash: &str) -> Result<()> {
+        self.db
+            .query("UPDATE $user_id SET password = $password;")
+            .bind(("user_id", user_id))
+            .bind(("password", password_hash))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::ChangePasswordFail
+            })?
+            .check()
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::ChangePasswordFail
+            })?;
+
+        Ok(())
+    }
+
+    async fn update_email(&self, user_id: &Thing, email: &str) -> Result<()> {
+        self.db
+            .query("UPDATE $user_id SET email = $email;")
+            .bind(("user_id", user_id))
+            .bind(("email", email))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::UpdateAccountFail
+            })?
+            .check()
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::UpdateAccountFail
+            })?;
+
+        Ok(())
+    }
+
+    async fn set_disabled_at(
+        &self,
+        user_id: &Thing,
+        disabled_at: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        self.db
+            .query("UPDATE $user_id SET disabled_at = $disabled_at;")
+            .bind(("user_id", user_id))
+            .bind(("disabled_at", disabled_at.map(Datetime::from)))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::UpdateAccountFail
+            })?
+            .check()
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::UpdateAccountFail
+            })?;
+
+        Ok(())
+    }
+
+    async fn set_totp_pending_secret(&self, user_id: &Thing, secret: &str) -> Result<()> {
+        self.db
+            .query("UPDATE $user_id SET totp_pending_secret = $secret;")
+            .bind(("user_id", user_id))
+            .bind(("secret", secret))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::EnrollTotpFail
+            })?
+            .check()
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::EnrollTotpFail
+            })?;
+
+        Ok(())
+    }
+
+    async fn enable_totp(&self, user_id: &Thing, secret: &str) -> Result<()> {
+        self.db
+            .query("UPDATE $user_id SET totp_secret = $secret, totp_pending_secret = NONE;")
+            .bind(("user_id", user_id))
+            .bind(("secret", secret))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::EnrollTotpFail
+            })?
+            .check()
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::EnrollTotpFail
+            })?;
+
+        Ok(())
+    }
+
+    async fn disable_totp(&self, user_id: &Thing) -> Result<()> {
+        self.db
+            .query(
+                "UPDATE $user_id SET totp_secret = NONE, totp_pending_secret = NONE, totp_last_step = NONE;",
+            )
+            .bind(("user_id", user_id))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::UpdateAccountFail
+            })?
+            .check()
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::UpdateAccountFail
+            })?;
+
+        Ok(())
+    }
+
+    async fn record_totp_step(&self, user_id: &Thing, step: i64) -> Result<bool> {
+        // Only one of two requests racing with the same step gets to record it
+        let mut result = self
+            .db
+            .query(
+                "UPDATE $user_id SET totp_last_step = $step \
+                    WHERE totp_last_step = NONE OR totp_last_step < $step;",
+            )
+            .bind(("user_id", user_id))
+            .bind(("step", step))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::VerifyMfaFail
+            })?;
+        let updated: Option<UserDBResult> = result.take(0).map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::VerifyMfaFail
+        })?;
+
+        Ok(updated.is_some())
+    }
+
+    async fn delete(&self, user_id: &Thing) -> Result<()> {
+        // Deleting the links records tombstones, which go right after
+        execute(
+            self.db
+                .query(
+                    "BEGIN TRANSACTION;
+                    DELETE token WHERE user = $user_id;
+                    DELETE link WHERE user = $user_id;
+                    DELETE link_tombstone WHERE user = $user_id;
+                    DELETE $user_id;
+                    COMMIT TRANSACTION;",
+                )
+                .bind(("user_id", user_id)),
+            Error::DeleteAccountFail,
+        )
+        .await
+    }
+}
+
+pub struct SurrealTokenRepository {
+    db: Arc<DB>,
+}
+
+impl SurrealTokenRepository {
+    pub fn new(db: Arc<DB>) -> Self {
+        Self { db }
+    }
+}
+
+#[async_trait]
+impl TokenRepository for SurrealTokenRepository {
+    async fn create(&self, token: Token) -> Result<TokenDBResult> {
+        let created: Vec<TokenDBResult> =
+            self.db.create("token").content(token).await.map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::GenTokenFail
+            })?;
+
+        created.into_iter().next().ok_or(Error::GenTokenFail)
+    }
+
+    async fn find_by_hash(&self, token_hash: &str) -> Result<Option<TokenDBResult>> {
+        let mut result = self
+            .db
+            .query("SELECT * FROM token WHERE token_hash = $token_hash;")
+            .bind(("token_hash", token_hash))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::InvalidToken
+            })?;
+
+        result.take(0).map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::InvalidToken
+        })
+    }
+
+    async fn find_for_user(
+        &self,
+        token_id: &Thing,
+        user_id: &Thing,
+    ) -> Result<Option<TokenDBResult>> {
+        let mut result = self
+            .db
+            .query("SELECT * FROM token WHERE id = $token_id AND user = $user_id;")
+            .bind(("token_id", token_id))
+            .bind(("user_id", user_id))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::GetTokensFail
+            })?;
+
+        result.take(0).map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::GetTokensFail
+        })
+    }
+
+    async fn list_for_user(&self, user_id: &Thing) -> Result<Vec<TokenDBResult>> {
+        let mut result = self
+            .db
+            .query("SELECT * FROM token WHERE user = $user_id ORDER BY created_at ASC;")
+            .bind(("user_id", user_id))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::GetTokensFail
+            })?;
+
+        result.take(0).map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::GetTokensFail
+        })
+    }
+
+    async fn delete_for_user(&self, token_id: &Thing, user_id: &Thing) -> Result<()> {
+        self.db
+            .query("DELETE token WHERE id = $token_id AND user = $user_id;")
+            .bind(("token_id", token_id))
+            .bind(("user_id", user_id))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::DeleteTokenFail
+            })?
+            .check()
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::DeleteTokenFail
+            })?;
+
+        Ok(())
+    }
+
+    async fn record_use(&self, token_id: &Thing, ip: Option<&str>) -> Result<()> {
+        self.db
+            .query("UPDATE $token_id SET last_used_at = time::now(), last_used_ip = $client_ip;")
+            .bind(("token_id", token_id))
+            .bind(("client_ip", ip))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::UpdateTokenFail
+            })?;
+
+        Ok(())
+    }
+
+    async fn set_expiry(&self, token_id: &Thing, expires_at: DateTime<Utc>) -> Result<()> {
+        self.db
+            .query("UPDATE $token_id SET expires_at = $expires_at;")
+            .bind(("token_id", token_id))
+            .bind(("expires_at", Datetime::from(expires_at)))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::UpdateTokenFail
+            })?
+            .check()
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::UpdateTokenFail
+            })?;
+
+        Ok(())
+    }
+}
+
+pub struct SurrealLinkRepository {
+    db: Arc<DB>,
+}
+
+impl SurrealLinkRepository {
+    pub fn new(db: Arc<DB>) -> Self {
+        Self { db }
+    }
+
+    /// The number of links of each record from `SELECT id, … AS count`.
+    async fn counts(
+        &self,
+        statement: &str,
+        user_id: &Thing,
+        error: Error,
+    ) -> Result<HashMap<String, u64>> {
+        let mut result = self
+            .db
+            .query(statement)
+            .bind(("user_id", user_id))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                error.clone()
+            })?;
+        let counts: Vec<CountDBResult> = result.take(0).map_err(|e| {
+            error!("Encountered error {:?}", e);
+            error
+        })?;
+
+        Ok(counts
+            .into_iter()
+            .map(|count| (count.id.to_string(), count.count))
+            .collect())
+    }
+}
+
+/// Runs statements whose results aren't needed.
+async fn execute(query: Query<'_, Any>, error: Error) -> Result<()> {
+    query
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            error.clone()
+        })?
+        .check()
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            error
+        })?;
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct CountDBResult {
+    id: Thing,
+    count: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchDBResult {
+    #[serde(flatten)]
+    link: LinkDBResult,
+    score: f64,
+}
+
+/// Condition matching the changes after `position` in (`field`, id) order,
+/// with its time and id bound to `$after_at` and `$after_id`.
+fn after_condition(position: &ChangePosition, field: &str) -> String {
+    match position.id {
+        Some(_) => format!("({field} > $after_at OR ({field} = $after_at AND id > $after_id))"),
+        None => format!("{field} > $after_at"),
+    }
+}
+
+/// Whether the error comes from the unique index on the canonical URL.
+fn is_duplicate_link(e: &surrealdb::Error) -> bool {
+    e.to_string().contains("idx_user_canonical_url")
+}
+
+impl From<LinkSortValue> for sql::Value {
+    fn from(value: LinkSortValue) -> Self {
+        match value {
+            LinkSortValue::BookmarkedAt(bookmarked_at) => {
+                sql::Value::from(Datetime::from(bookmarked_at))
+            }
+            LinkSortValue::Title(title) => sql::Value::from(title),
+        }
+    }
+}
+
+#[async_trait]
+impl LinkRepository for SurrealLinkRepository {
+    async fn get(&self, user_id: &Thing, link_id: &Thing) -> Result<Option<LinkDBResult>> {
+        let mut result = self
+            .db
+            .query(format!(
+                "SELECT {LINK_FIELDS} FROM link WHERE id = $link_id AND user = $user_id;"
+            ))
+            .bind(("link_id", link_id))
+            .bind(("user_id", user_id))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::GetLinkFail
+            })?;
+
+        result.take(0).map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::GetLinkFail
+        })
+    }
+
+    async fn find_by_canonical_url(
+        &self,
+        user_id: &Thing,
+        canonical_url: &str,
+        exclude: Option<&Thing>,
+    ) -> Result<Option<LinkDBResult>> {
+        let mut result = self
+            .db
+            .query(format!(
+                "SELECT {LINK_FIELDS} FROM link WHERE user = $user_id AND canonical_url = $canonical_url AND id != $exclude;"
+            ))
+            .bind(("user_id", user_id))
+            .bind(("canonical_url", canonical_url))
+            .bind(("exclude", exclude))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::GetLinkFail
+            })?;
+
+        result.take(0).map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::GetLinkFail
+        })
+    }
+
+    async fn find_by_canonical_urls(
+        &self,
+        user_id: &Thing,
+        canonical_urls: &[String],
+    ) -> Result<Vec<LinkDBResult>> {
+        let mut result = self
+            .db
+            .query(format!(
+                "SELECT {LINK_FIELDS} FROM link WHERE user = $user_id AND canonical_url IN $canonical_urls;"
+            ))
+            .bind(("user_id", user_id))
+            .bind(("canonical_urls", canonical_urls))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::GetLinksFail
+            })?;
+
+        result.take(0).map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::GetLinksFail
+        })
+    }
+
+    async fn list(&self, user_id: &Thing, query: &LinkQuery) -> Result<Vec<LinkDBResult>> {
+        let (sort_field, order, cmp) = match (query.sort, query.order) {
+            (LinkSort::BookmarkedAt, SortOrder::Asc) => ("bookmarked_at", "ASC", ">"),
+            (LinkSort::BookmarkedAt, SortOrder::Desc) => ("bookmarked_at", "DESC", "<"),
+            (LinkSort::Title, SortOrder::Asc) => ("title", "ASC", ">"),
+            (LinkSort::Title, SortOrder::Desc) => ("title", "DESC", "<"),
+        };
+
+        let mut conditions = vec!["user = $user_id".to_string()];
+        if query.from.is_some() {
+            conditions.push("bookmarked_at >= $from".into());
+        }
+        if query.to.is_some() {
+            conditions.push("bookmarked_at < $to".into());
+        }
+        if query.domain.is_some() {
+            conditions.push("parse::url::domain(url) IN [$domain, \"www.\" + $domain]".into());
+        }
+        if !query.tags.is_empty() {
+            conditions.push("tags.name CONTAINSALL $tags".into());
+        }
+        if query.collection.is_some() {
+            conditions.push("collection = $collection".into());
+        }
+        match query.has_note {
+            Some(true) => conditions.push("note != \"\"".into()),
+            Some(false) => conditions.push("note = \"\"".into()),
+            None => {}
+        }
+        // Keyset pagination: continue right after the (sort value, id) of the
+        // given link.
+        if query.after.is_some() {
+            conditions.push(format!(
+                "({sort_field} {cmp} $cursor_value OR ({sort_field} = $cursor_value AND id {cmp} $cursor_id))"
+            ));
+        }
+
+        let statement = format!(
+            "SELECT {LINK_FIELDS} FROM link WHERE {} ORDER BY {sort_field} {order}, id {order} LIMIT $limit;",
+            conditions.join(" AND ")
+        );
+
+        let mut result = self
+            .db
+            .query(statement)
+            .bind(("user_id", user_id))
+            .bind(("from", query.from.map(Datetime::from)))
+            .bind(("to", query.to.map(Datetime::from)))
+            .bind(("domain", &query.domain))
+            .bind(("tags", &query.tags))
+            .bind(("collection", &query.collection))
+            .bind((
+                "cursor_value",
+                query
+                    .after
+                    .as_ref()
+                    .map(|after| sql::Value::from(after.value.clone())),
+            ))
+            .bind(("cursor_id", query.after.as_ref().map(|after| &after.id)))
+            .bind(("limit", query.limit))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::GetLinksFail
+            })?;
+
+        result.take(0).map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::GetLinksFail
+        })
+    }
+
+    async fn create(&self, link: Link) -> Result<LinkDBResult> {
+        let user_id = link.user.clone();
+        let canonical_url = link.canonical_url.clone();
+        let created: Vec<LinkDBResult> = match self.db.create("link").content(link).await {
+            Ok(created) => created,
+            Err(e) if is_duplicate_link(&e) => {
+                return Err(already_saved(
+                    self,
+                    &user_id,
+                    &[canonical_url],
+                    None,
+                    Error::CreateLinkFail,
+                )
+                .await)
+            }
+            Err(e) => {
+                error!("Encountered error {:?}", e);
+                return Err(Error::CreateLinkFail);
+            }
+        };
+
+        created.into_iter().next().ok_or(Error::CreateLinkFail)
+    }
+
+    async fn create_many(&self, links: Vec<Link>) -> Result<Vec<LinkDBResult>> {
+        if links.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let statements: String = (0..links.len())
+            .map(|index| format!("CREATE link CONTENT $link_{index};"))
+            .collect();
+        let count = links.len();
+        let user_id = links[0].user.clone();
+        let canonical_urls: Vec<String> = links
+            .iter()
+            .map(|link| link.canonical_url.clone())
+            .collect();
+        let mut query = self.db.query(format!(
+            "BEGIN TRANSACTION; {statements} COMMIT TRANSACTION;"
+        ));
+        for (index, link) in links.into_iter().enumerate() {
+            query = query.bind((format!("link_{index}"), link));
+        }
+        let mut result = query.await.map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::CreateLinkFail
+        })?;
+
+        // A failed statement makes every other statement of the transaction
+        // fail too, so all of the errors are looked at to find out why.
+        let mut created = Vec::with_capacity(count);
+        let mut errors = vec![];
+        for index in 0..count {
+            match result.take::<Option<LinkDBResult>>(index) {
+                Ok(link) => created.extend(link),
+                Err(e) => errors.push(e),
+            }
+        }
+        if errors.iter().any(is_duplicate_link) {
+            return Err(already_saved(
+                self,
+                &user_id,
+                &canonical_urls,
+                None,
+                Error::CreateLinkFail,
+            )
+            .await);
+        }
+        if !errors.is_empty() || created.len() != count {
+            error!("Encountered errors {:?}", errors);
+            return Err(Error::CreateLinkFail);
+        }
+
+        Ok(created)
+    }
+
+    async fn merge(
+        &self,
+        user_id: &Thing,
+        link_id: &Thing,
+        merge: LinkMerge,
+    ) -> Result<Option<LinkDBResult>> {
+        let mut result = self
+            .db
+            .query(
+                "UPDATE $link_id SET title = $title, note = $note, bookmarked_at = time::now(), \
+                    tags = array::union(tags, $tags), collection = $collection WHERE user = $user_id;",
+            )
+            .bind(("link_id", link_id))
+            .bind(("title", merge.title))
+            .bind(("note", merge.note))
+            .bind(("tags", merge.tags))
+            .bind(("collection", merge.collection))
+            .bind(("user_id", user_id))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::UpdateLinkFail
+            })?;
+
+        result.take(0).map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::UpdateLinkFail
+        })
+    }
+
+    async fn update(
+        &self,
+        user_id: &Thing,
+        link_id: &Thing,
+        changes: LinkChanges,
+    ) -> Result<Option<LinkDBResult>> {
+        // Only the fields that are set are merged into the record. The WHERE
+        // clause makes sure users can only update their own links.
+        let canonical_url = changes.canonical_url.clone();
+        let mut result = self
+            .db
+            .query(format!(
+                "UPDATE $link_id MERGE $data WHERE user = $user_id;
+                SELECT {LINK_FIELDS} FROM link WHERE id = $link_id AND user = $user_id;"
+            ))
+            .bind(("link_id", link_id))
+            .bind(("data", changes))
+            .bind(("user_id", user_id))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::UpdateLinkFail
+            })?;
+
+        match result.take::<sql::Value>(0) {
+            Ok(_) => {}
+            Err(e) if is_duplicate_link(&e) => {
+                let canonical_urls: Vec<String> = canonical_url.into_iter().collect();
+                return Err(already_saved(
+                    self,
+                    user_id,
+                    &canonical_urls,
+                    Some(link_id),
+                    Error::UpdateLinkFail,
+                )
+                .await);
+            }
+            Err(e) => {
+                error!("Encountered error {:?}", e);
+                return Err(Error::UpdateLinkFail);
+            }
+        }
+
+        result.take(1).map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::UpdateLinkFail
+        })
+    }
+
+    async fn delete(&self, user_id: &Thing, link_id: &Thing) -> Result<Option<LinkDBResult>> {
+        let mut result = self
+            .db
+            .query("DELETE $link_id WHERE user = $user_id RETURN BEFORE;")
+            .bind(("link_id", link_id))
+            .bind(("user_id", user_id))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::DeleteLinkFail
+            })?;
+
+        result.take(0).map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::DeleteLinkFail
+        })
+    }
+
+    async fn clear(&self, user_id: &Thing) -> Result<()> {
+        self.db
+            .query("DELETE link WHERE user = $user_id;")
+            .bind(("user_id", user_id))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::ClearLinksFail
+            })?
+            .check()
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::ClearLinksFail
+            })?;
+
+        Ok(())
+    }
+
+    async fn search(
+        &self,
+        user_id: &Thing,
+        query: &SearchQuery,
+        start: usize,
+        limit: usize,
+    ) -> Result<Vec<ScoredLink>> {
+        // Every term has to be in one of the fields, each field having its own
+        // match reference so that the scores can be added up.
+        let index_terms = query.index_terms();
+        let mut conditions = vec!["user = $user_id".to_string()];
+        let mut scores = vec![];
+        for i in 0..index_terms.len() {
+            let [title, note, url] = [3 * i + 1, 3 * i + 2, 3 * i + 3];
+            conditions.push(format!(
+                "(title @{title}@ $terms[{i}] OR note @{note}@ $terms[{i}] OR url @{url}@ $terms[{i}])"
+            ));
+            scores.extend(
+                [title, note, url].map(|reference| format!("(search::score({reference}) ?? 0)")),
+            );
+        }
+        let score = if scores.is_empty() {
+            "0".to_string()
+        } else {
+            scores.join(" + ")
+        };
+        // The index only knows whole words, so prefixes narrow down the
+        // candidates by substring and are checked properly by the caller.
+        for i in 0..query.prefixes.len() {
+            conditions.push(format!(
+                "string::contains(string::lowercase(title + \" \" + note + \" \" + url), $prefixes[{i}])"
+            ));
+        }
+
+        let statement = format!(
+            "SELECT {LINK_FIELDS}, {score} AS score FROM link WHERE {} \
+                ORDER BY score DESC, bookmarked_at DESC, id DESC LIMIT $limit START $start;",
+            conditions.join(" AND ")
+        );
+        let mut result = self
+            .db
+            .query(statement)
+            .bind(("user_id", user_id))
+            .bind(("terms", &index_terms))
+            .bind(("prefixes", &query.prefixes))
+            .bind(("limit", limit))
+            .bind(("start", start))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::SearchLinksFail
+            })?;
+
+        let candidates: Vec<SearchDBResult> = result.take(0).map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::SearchLinksFail
+        })?;
+
+        Ok(candidates
+            .into_iter()
+            .map(|candidate| ScoredLink {
+                link: candidate.link,
+                score: candidate.score,
+            })
+            .collect())
+    }
+
+    async fn list_changed(
+        &self,
+        user_id: &Thing,
+        after: Option<&ChangePosition>,
+        limit: usize,
+    ) -> Result<Vec<LinkDBResult>> {
+        let mut conditions = vec!["user = $user_id".to_string()];
+        conditions.extend(after.map(|after| after_condition(after, "updated_at")));
+        let statement = format!(
+            "SELECT {LINK_FIELDS} FROM link WHERE {} ORDER BY updated_at ASC, id ASC LIMIT $limit;",
+            conditions.join(" AND ")
+        );
+
+        let mut result = self
+            .db
+            .query(statement)
+            .bind(("user_id", user_id))
+            .bind(("after_at", after.map(|after| Datetime::from(after.at))))
+            .bind(("after_id", after.and_then(|after| after.id.as_ref())))
+            .bind(("limit", limit))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::GetChangesFail
+            })?;
+
+        result.take(0).map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::GetChangesFail
+        })
+    }
+
+    async fn list_deleted(
+        &self,
+        user_id: &Thing,
+        after: Option<&ChangePosition>,
+        limit: usize,
+    ) -> Result<Vec<LinkTombstone>> {
+        let mut conditions = vec!["user = $user_id".to_string()];
+        conditions.extend(after.map(|after| after_condition(after, "deleted_at")));
+        let statement = format!(
+            "SELECT * FROM link_tombstone WHERE {} ORDER BY deleted_at ASC, id ASC LIMIT $limit;",
+            conditions.join(" AND ")
+        );
+
+        let mut result = self
+            .db
+            .query(statement)
+            .bind(("user_id", user_id))
+            .bind(("after_at", after.map(|after| Datetime::from(after.at))))
+            .bind(("after_id", after.and_then(|after| after.id.as_ref())))
+            .bind(("limit", limit))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::GetChangesFail
+            })?;
+
+        result.take(0).map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::GetChangesFail
+        })
+    }
+
+    async fn prune_tombstones(&self, user_id: &Thing, up_to: &ChangePosition) -> Result<()> {
+        let condition = match up_to.id {
+            Some(_) => "(deleted_at < $at OR (deleted_at = $at AND id <= $id))",
+            None => "deleted_at <= $at",
+        };
+
+        execute(
+            self.db
+                .query(format!(
+                    "DELETE link_tombstone WHERE user = $user_id AND {condition};"
+                ))
+                .bind(("user_id", user_id))
+                .bind(("at", Datetime::from(up_to.at)))
+                .bind(("id", &up_to.id)),
+            Error::AckChangesFail,
+        )
+        .await
+    }
+
+    async fn count_by_tag(&self, user_id: &Thing) -> Result<HashMap<String, u64>> {
+        self.counts(
+            "SELECT id, array::len((SELECT VALUE id FROM link WHERE user = $parent.user AND tags CONTAINS $parent.id)) AS count \
+                FROM tag WHERE user = $user_id;",
+            user_id,
+            Error::GetTagsFail,
+        )
+        .await
+    }
+
+    async fn tags_in_use(&self, user_id: &Thing, tags: &[Thing]) -> Result<Vec<Thing>> {
+        let mut result = self
+            .db
+            .query("SELECT VALUE tags FROM link WHERE user = $user_id AND tags CONTAINSANY $tags;")
+            .bind(("user_id", user_id))
+            .bind(("tags", tags))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::GetTagsFail
+            })?;
+        let link_tags: Vec<Vec<Thing>> = result.take(0).map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::GetTagsFail
+        })?;
+
+        Ok(tags
+            .iter()
+            .filter(|tag| link_tags.iter().any(|link_tags| link_tags.contains(tag)))
+            .cloned()
+            .collect())
+    }
+
+    async fn touch_tagged(&self, user_id: &Thing, tag_id: &Thing) -> Result<()> {
+        // Setting the tags to themselves moves `updated_at`
+        execute(
+            self.db
+                .query(
+                    "UPDATE link SET tags = tags WHERE user = $user_id AND tags CONTAINS $tag_id;",
+                )
+                .bind(("user_id", user_id))
+                .bind(("tag_id", tag_id)),
+            Error::UpdateTagFail,
+        )
+        .await
+    }
+
+    async fn replace_tag(&self, user_id: &Thing, source: &Thing, target: &Thing) -> Result<()> {
+        execute(
+            self.db
+                .query(
+                    "BEGIN TRANSACTION;
+                    UPDATE link SET tags = array::add(tags, $target) WHERE user = $user_id AND tags CONTAINS $source;
+                    UPDATE link SET tags -= $source WHERE user = $user_id AND tags CONTAINS $source;
+                    COMMIT TRANSACTION;",
+                )
+                .bind(("user_id", user_id))
+                .bind(("source", source))
+                .bind(("target", target)),
+            Error::MergeTagsFail,
+        )
+        .await
+    }
+
+    async fn count_by_collection(&self, user_id: &Thing) -> Result<HashMap<String, u64>> {
+        self.counts(
+            "SELECT id, array::len((SELECT VALUE id FROM link WHERE user = $parent.user AND collection = $parent.id)) AS count \
+                FROM collection WHERE user = $user_id;",
+            user_id,
+            Error::GetCollectionsFail,
+        )
+        .await
+    }
+
+    async fn move_collections(
+        &self,
+        user_id: &Thing,
+        collections: &[Thing],
+        target: Option<&Thing>,
+    ) -> Result<()> {
+        execute(
+            self.db
+                .query(
+                    "UPDATE link SET collection = $target WHERE user = $user_id AND collection IN $collections;",
+                )
+                .bind(("user_id", user_id))
+                .bind(("collections", collections))
+                .bind(("target", target)),
+            Error::DeleteCollectionFail,
+        )
+        .await
+    }
+
+    async fn set_collection(
+        &self,
+        user_id: &Thing,
+        link_ids: &[Thing],
+        collection: &Thing,
+    ) -> Result<usize> {
+        let mut result = self
+            .db
+            .query(
+                "UPDATE link SET collection = $collection_id WHERE user = $user_id AND id IN $links;",
+            )
+            .bind(("collection_id", collection))
+            .bind(("links", link_ids))
+            .bind(("user_id", user_id))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::MoveLinksFail
+            })?;
+
+        let moved: Vec<LinkDBResult> = result.take(0).map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::MoveLinksFail
+        })?;
+
+        Ok(moved.len())
+    }
+
+    async fn delete_in_collections(&self, user_id: &Thing, collections: &[Thing]) -> Result<()> {
+        execute(
+            self.db
+                .query("DELETE link WHERE user = $user_id AND collection IN $collections;")
+                .bind(("user_id", user_id))
+                .bind(("collections", collections)),
+            Error::DeleteCollectionFail,
+        )
+        .await
+    }
+}
@@ -0,0 +1,534 @@
+use axum::{
+    extract::State,
+    http::header,
+    response::IntoResponse,
+    routing::{delete, get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use surrealdb::sql::{thing, Thing};
+use tracing::error;
+
+use crate::auth::revoke_user_sessions;
+use crate::ctx::{Ctx, Scope};
+use crate::error::{Error, Result};
+use crate::repository::{LinkPosition, LinkQuery};
+use crate::routes::auth::{parse_email, update_password, verify_password};
+use crate::routes::link_routes::{LinkResponse, EXPORT_PAGE_SIZE};
+use crate::routes::sync_routes::DeviceResponse;
+use crate::types::{
+    AppState, CollectionDBResult, DeviceDBResult, LinkDBResult, SessionDBResult, TagDBResult,
+    UserDBResult,
+};
+use crate::{mfa, totp};
+
+/// Tables whose records belong to a user through their `user` field. Tables
+/// holding per-user data need to be listed here so that they are deleted
+/// along with the account. Links and their tombstones are deleted through
+/// `LinkRepository` instead.
+pub const USER_TABLES: &[&str] = &[
+    "tag",
+    "collection",
+    "device",
+    "token",
+    "session",
+    "password_reset",
+    "mfa_challenge",
+    "recovery_code",
+];
+
+/// Shown next to the account in authenticator apps.
+const TOTP_ISSUER: &str = "LinkStowr";
+
+pub fn routes(state: AppState) -> Router {
+    Router::new()
+        .route("/account", delete(delete_account))
+        .route("/account/export", get(export_account))
+        .route("/account/password", post(change_password))
+        .route("/account/email", post(change_email))
+        .route("/account/totp", post(enroll_totp).delete(disable_totp))
+        .route("/account/totp/confirm", post(confirm_totp))
+        .with_state(state)
+}
+
+#[derive(Debug, Deserialize)]
+struct ChangePasswordPayload {
+    current_password: String,
+    password: String,
+    password_confirm: String,
+}
+
+/// Changes the user's password and signs them out everywhere but in the
+/// session that made the change.
+#[tracing::instrument(
+    name = "Changing password",
+    skip(ctx, app_state, payload),
+    fields(
+        user_id = %ctx.user_id(),
+    )
+)]
+async fn change_password(
+    ctx: Ctx,
+    State(app_state): State<AppState>,
+    Json(payload): Json<ChangePasswordPayload>,
+) -> Result<Json<Value>> {
+    ctx.require_scope(Scope::AccountManage)?;
+
+    if payload.password != payload.password_confirm {
+        return Err(Error::PasswordConfirmMismatch);
+    }
+
+    let user = check_current_password(&ctx, &app_state, &payload.current_password).await?;
+    update_password(&app_state, &user.id, &payload.password).await?;
+
+    let session_id = ctx
+        .session_id()
+        .and_then(|session_id| thing(session_id).ok());
+    revoke_user_sessions(&app_state, &user.id, session_id.as_ref()).await?;
+
+    let body = Json(json!({
+        "result": {
+            "success": true,
+        }
+    }));
+
+    Ok(body)
+}
+
+#[derive(Debug, Deserialize)]
+struct ChangeEmailPayload {
+    current_password: String,
+    email: String,
+}
+
+/// Sets the email that password reset tokens are sent to.
+#[tracing::instrument(
+    name = "Changing email",
+    skip(ctx, app_state, payload),
+    fields(
+        user_id = %ctx.user_id(),
+    )
+)]
+async fn change_email(
+    ctx: Ctx,
+    State(app_state): State<AppState>,
+    Json(payload): Json<ChangeEmailPayload>,
+) -> Result<Json<Value>> {
+    ctx.require_scope(Scope::AccountManage)?;
+
+    let email = parse_email(&payload.email)?;
+    let user = check_current_password(&ctx, &app_state, &payload.current_password).await?;
+
+    app_state.users.update_email(&user.id, &email).await?;
+
+    let body = Json(json!({
+        "result": {
+            "success": true,
+        }
+    }));
+
+    Ok(body)
+}
+
+#[derive(Debug, Deserialize)]
+struct PasswordPayload {
+    password: String,
+}
+
+/// Deletes the user along with everything stored for them.
+#[tracing::instrument(
+    name = "Deleting account",
+    skip(ctx, app_state, payload),
+    fields(
+        user_id = %ctx.user_id(),
+    )
+)]
+async fn delete_account(
+    ctx: Ctx,
+    State(app_state): State<AppState>,
+    Json(payload): Json<PasswordPayload>,
+) -> Result<Json<Value>> {
+    ctx.require_scope(Scope::AccountManage)?;
+
+    let user = check_current_password(&ctx, &app_state, &payload.password).await?;
+
+    // The user, their tokens and links may be stored apart from the rest, so
+    // each store is cleaned up in its own transaction. The account is
+    // disabled first so that a failure part way can't leave it usable.
+    app_state
+        .users
+        .set_disabled_at(&user.id, Some(Utc::now()))
+        .await?;
+    app_state.token_cache.invalidate_user(&user.id);
+
+    // Refresh tokens belong to the user through their session
+    let mut statements = vec![
+        "BEGIN TRANSACTION;".to_string(),
+        "DELETE refresh_token WHERE session.user = $user_id;".to_string(),
+    ];
+    statements.extend(
+        USER_TABLES
+            .iter()
+            .map(|table| format!("DELETE {table} WHERE user = $user_id;")),
+    );
+    statements.push("COMMIT TRANSACTION;".into());
+
+    app_state
+        .db
+        .query(statements.join("\n"))
+        .bind(("user_id", &user.id))
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::DeleteAccountFail
+        })?
+        .check()
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::DeleteAccountFail
+        })?;
+    app_state.users.delete(&user.id).await?;
+
+    let body = Json(json!({
+        "result": {
+            "success": true,
+        }
+    }));
+
+    Ok(body)
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AccountExport {
+    pub exported_at: DateTime<Utc>,
+    pub account: ExportedAccount,
+    pub links: Vec<LinkResponse>,
+    pub tags: Vec<ExportedTag>,
+    pub collections: Vec<ExportedCollection>,
+    pub devices: Vec<DeviceResponse>,
+    pub tokens: Vec<ExportedToken>,
+    pub sessions: Vec<ExportedSession>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExportedAccount {
+    pub id: String,
+    pub username: String,
+    pub email: Option<String>,
+    pub totp_enabled: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExportedTag {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExportedCollection {
+    pub id: String,
+    pub name: String,
+    pub parent: Option<String>,
+    pub position: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+/// API tokens without their hashes, which are of no use to the user.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExportedToken {
+    pub id: String,
+    pub name: String,
+    pub short_token: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub last_used_ip: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExportedSession {
+    pub id: String,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// Returns everything stored about the user as a single JSON document.
+/// Secrets like the password hash and the TOTP secret are left out.
+#[tracing::instrument(
+    name = "Exporting account",
+    skip(ctx, app_state),
+    fields(
+        user_id = %ctx.user_id(),
+    )
+)]
+async fn export_account(ctx: Ctx, State(app_state): State<AppState>) -> Result<impl IntoResponse> {
+    ctx.require_scope(Scope::AccountManage)?;
+
+    let user_id: Thing = thing(ctx.user_id()).map_err(|_| Error::SplitUserIdFail)?;
+    let export = export_user(&app_state, &user_id).await?;
+
+    let headers = [(
+        header::CONTENT_DISPOSITION,
+        "attachment; filename=\"linkstowr-account.json\"",
+    )];
+
+    Ok((headers, Json(export)))
+}
+
+/// Everything stored about the user, as returned by `GET /api/account/export`.
+pub async fn export_user(app_state: &AppState, user_id: &Thing) -> Result<AccountExport> {
+    let user = app_state
+        .users
+        .find_by_id(user_id)
+        .await?
+        .ok_or(Error::ExportAccountFail)?;
+    let links = get_all_links(app_state, user_id).await?;
+    let tokens = app_state.tokens.list_for_user(user_id).await?;
+    let mut result = app_state
+        .db
+        .query("SELECT * FROM tag WHERE user = $user_id ORDER BY name ASC;")
+        .query("SELECT * FROM collection WHERE user = $user_id ORDER BY position ASC;")
+        .query("SELECT * FROM device WHERE user = $user_id ORDER BY created_at ASC;")
+        .query("SELECT * FROM session WHERE user = $user_id ORDER BY created_at ASC;")
+        .bind(("user_id", user_id))
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::ExportAccountFail
+        })?;
+
+    let map_err = |e: surrealdb::Error| {
+        error!("Encountered error {:?}", e);
+        Error::ExportAccountFail
+    };
+    let tags: Vec<TagDBResult> = result.take(0).map_err(map_err)?;
+    let collections: Vec<CollectionDBResult> = result.take(1).map_err(map_err)?;
+    let devices: Vec<DeviceDBResult> = result.take(2).map_err(map_err)?;
+    let sessions: Vec<SessionDBResult> = result.take(3).map_err(map_err)?;
+
+    Ok(AccountExport {
+        exported_at: Utc::now(),
+        account: ExportedAccount {
+            id: user.id.to_string(),
+            username: user.username,
+            email: user.email,
+            totp_enabled: user.totp_secret.is_some(),
+        },
+        links: links.into_iter().map(LinkResponse::from).collect(),
+        tags: tags
+            .into_iter()
+            .map(|tag| ExportedTag {
+                id: tag.id.to_string(),
+                name: tag.name,
+            })
+            .collect(),
+        collections: collections
+            .into_iter()
+            .map(|collection| ExportedCollection {
+                id: collection.id.to_string(),
+                name: collection.name,
+                parent: collection.parent.map(|parent| parent.to_string()),
+                position: collection.position,
+                created_at: collection.created_at,
+            })
+            .collect(),
+        devices: devices.into_iter().map(DeviceResponse::from).collect(),
+        tokens: tokens
+            .into_iter()
+            .map(|token| ExportedToken {
+                id: token.id.to_string(),
+                name: token.name,
+                short_token: token.short_token,
+                scopes: token.scopes,
+                created_at: token.created_at,
+                expires_at: token.expires_at,
+                last_used_at: token.last_used_at,
+                last_used_ip: token.last_used_ip,
+            })
+            .collect(),
+        sessions: sessions
+            .into_iter()
+            .map(|session| ExportedSession {
+                id: session.id.to_string(),
+                created_at: session.created_at,
+                revoked_at: session.revoked_at,
+            })
+            .collect(),
+    })
+}
+
+/// Loads all of the user's links, most recently bookmarked first.
+async fn get_all_links(app_state: &AppState, user_id: &Thing) -> Result<Vec<LinkDBResult>> {
+    let mut links = vec![];
+    let mut query = LinkQuery {
+        limit: EXPORT_PAGE_SIZE,
+        ..Default::default()
+    };
+    loop {
+        let page = app_state.links.list(user_id, &query).await?;
+        let is_last_page = page.len() < EXPORT_PAGE_SIZE;
+        query.after = page.last().map(|link| LinkPosition::of(link, query.sort));
+        links.extend(page);
+        if is_last_page {
+            return Ok(links);
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TotpEnrollmentResponse {
+    /// Base32 secret, for typing into an authenticator app.
+    pub secret: String,
+    /// `otpauth://` URI to show as a QR code.
+    pub otpauth_uri: String,
+}
+
+/// Starts enabling two-factor authentication. It only takes effect once
+/// confirmed with a first code from the authenticator app.
+#[tracing::instrument(
+    name = "Enrolling TOTP",
+    skip(ctx, app_state, payload),
+    fields(
+        user_id = %ctx.user_id(),
+    )
+)]
+async fn enroll_totp(
+    ctx: Ctx,
+    State(app_state): State<AppState>,
+    Json(payload): Json<PasswordPayload>,
+) -> Result<Json<TotpEnrollmentResponse>> {
+    ctx.require_scope(Scope::AccountManage)?;
+
+    let user = check_current_password(&ctx, &app_state, &payload.password).await?;
+    if user.totp_secret.is_some() {
+        return Err(Error::TotpAlreadyEnabled);
+    }
+
+    let secret = totp::generate_secret();
+    app_state
+        .users
+        .set_totp_pending_secret(&user.id, &secret)
+        .await?;
+
+    let body = Json(TotpEnrollmentResponse {
+        otpauth_uri: totp::otpauth_uri(TOTP_ISSUER, &user.username, &secret),
+        secret,
+    });
+
+    Ok(body)
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfirmTotpPayload {
+    code: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RecoveryCodesResponse {
+    pub recovery_codes: Vec<String>,
+}
+
+/// Enables two-factor authentication once the first code checks out, and
+/// hands out recovery codes.
+#[tracing::instrument(
+    name = "Confirming TOTP",
+    skip(ctx, app_state, payload),
+    fields(
+        user_id = %ctx.user_id(),
+    )
+)]
+async fn confirm_totp(
+    ctx: Ctx,
+    State(app_state): State<AppState>,
+    Json(payload): Json<ConfirmTotpPayload>,
+) -> Result<Json<RecoveryCodesResponse>> {
+    ctx.require_scope(Scope::AccountManage)?;
+
+    let user = get_user(&ctx, &app_state).await?;
+    if user.totp_secret.is_some() {
+        return Err(Error::TotpAlreadyEnabled);
+    }
+    let secret = user
+        .totp_pending_secret
+        .clone()
+        .ok_or(Error::TotpNotPending)?;
+    if !mfa::check_totp_code(&app_state, &user, &secret, &payload.code).await? {
+        return Err(Error::InvalidMfaCode);
+    }
+
+    app_state.users.enable_totp(&user.id, &secret).await?;
+    let recovery_codes = mfa::replace_recovery_codes(&app_state, &user.id).await?;
+
+    Ok(Json(RecoveryCodesResponse { recovery_codes }))
+}
+
+#[tracing::instrument(
+    name = "Disabling TOTP",
+    skip(ctx, app_state, payload),
+    fields(
+        user_id = %ctx.user_id(),
+    )
+)]
+async fn disable_totp(
+    ctx: Ctx,
+    State(app_state): State<AppState>,
+    Json(payload): Json<PasswordPayload>,
+) -> Result<Json<Value>> {
+    ctx.require_scope(Scope::AccountManage)?;
+
+    let user = check_current_password(&ctx, &app_state, &payload.password).await?;
+    if user.totp_secret.is_none() {
+        return Err(Error::TotpNotEnabled);
+    }
+
+    app_state.users.disable_totp(&user.id).await?;
+    app_state
+        .db
+        .query("DELETE recovery_code WHERE user = $user_id;")
+        .bind(("user_id", &user.id))
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::UpdateAccountFail
+        })?
+        .check()
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::UpdateAccountFail
+        })?;
+
+    let body = Json(json!({
+        "result": {
+            "success": true,
+        }
+    }));
+
+    Ok(body)
+}
+
+async fn get_user(ctx: &Ctx, app_state: &AppState) -> Result<UserDBResult> {
+    let user_id: Thing = thing(ctx.user_id()).map_err(|_| Error::SplitUserIdFail)?;
+    let user = app_state.users.find_by_id(&user_id).await?;
+
+    user.ok_or(Error::GetUsersFail)
+}
+
+/// Changes to the account need the current password, so that a stolen token
+/// or an unattended browser isn't enough to take it over.
+async fn check_current_password(
+    ctx: &Ctx,
+    app_state: &AppState,
+    password: &str,
+) -> Result<UserDBResult> {
+    let user = get_user(ctx, app_state).await?;
+
+    verify_password(password, &user.password).map_err(|e| {
+        error!("Failed with error {e:?}");
+        Error::InvalidCredentials
+    })?;
+
+    Ok(user)
+}
@@ -1,25 +1,46 @@
-use std::sync::Arc;
-
 use argon2::password_hash::rand_core::OsRng;
 use argon2::password_hash::SaltString;
 use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
 use axum::http::header::AUTHORIZATION;
 use axum::http::HeaderMap;
+use axum::middleware;
 use axum::routing::get;
-use axum::{extract::State, routing::post, Json, Router};
+use axum::{extract::State, routing::post, Extension, Json, Router};
 use lazy_regex::regex_captures;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use tracing::error;
+use surrealdb::sql::{thing, Thing};
+use tracing::{error, info};
 
-use crate::auth::{create_jwt, validate_jwt};
+use crate::auth::{
+    consume_password_reset, create_password_reset, create_session, refresh_session, revoke_session,
+    revoke_user_sessions, validate_jwt, Claims,
+};
 use crate::error::{Error, Result};
-use crate::types::{AppState, CreateUserContent, User, UserDBResult, DB};
+use crate::mailer::Email;
+use crate::mfa;
+use crate::middlewares::auth::ClientIp;
+use crate::middlewares::rate_limit::mw_rate_limit_auth;
+use crate::repository::UserRepository;
+use crate::types::{AppState, CreateUserContent, User, UserDBResult};
 
 pub fn routes(state: AppState) -> Router {
-    Router::new()
+    // Routes that check credentials, which are rate limited by IP
+    let credential_routes = Router::new()
         .route("/signin", post(signin))
+        .route("/signin/mfa", post(signin_mfa))
         .route("/signup", post(signup))
+        .route("/password/forgot", post(forgot_password))
+        .route("/password/reset", post(reset_password))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            mw_rate_limit_auth,
+        ));
+
+    Router::new()
+        .merge(credential_routes)
+        .route("/refresh", post(refresh))
+        .route("/logout", post(logout))
         .route("/me", get(get_user_info))
         .with_state(state)
 }
@@ -35,43 +56,74 @@ pub struct UserResponse {
     // Making these public for test assertions
     pub id: String,
     pub username: String,
-    token: String,
+    pub token: String,
+    pub refresh_token: String,
+}
+
+/// Returned by `signin` instead of a session when the user has two-factor
+/// authentication enabled. The challenge token is exchanged for a session
+/// together with a TOTP or recovery code at `/signin/mfa`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MfaChallengeResponse {
+    pub mfa_required: bool,
+    pub challenge_token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SigninResponse {
+    Session(UserResponse),
+    MfaRequired(MfaChallengeResponse),
 }
 
 async fn signin(
     State(app_state): State<AppState>,
+    Extension(ClientIp(client_ip)): Extension<ClientIp>,
     Json(payload): Json<SigninPayload>,
-) -> Result<Json<UserResponse>> {
-    let mut result = app_state
-        .db
-        .query("SELECT * FROM user WHERE username = $username")
-        .bind(("username", payload.username))
-        .await
-        .map_err(|e| {
-            error!("Encountered error {:?}", e);
-            Error::SignInFail
-        })?;
+) -> Result<Json<SigninResponse>> {
+    // Checked before anything else since hashing the password is expensive
+    let rate_limits = &app_state.rate_limits;
+    let client_ip = client_ip.as_deref();
+    rate_limits.lockout.check(&payload.username, client_ip)?;
+    rate_limits.auth_username.check(&payload.username)?;
 
-    let user: Option<UserDBResult> = result.take(0).map_err(|e| {
-        error!("Encountered error {:?}", e);
-        Error::SignInFail
+    let user = app_state.users.find_by_username(&payload.username).await?;
+    let user = user.ok_or_else(|| {
+        rate_limits
+            .lockout
+            .record_failure(&payload.username, client_ip);
+        Error::InvalidCredentials
     })?;
-    let user = user.ok_or(Error::InvalidCredentials)?;
 
     let parsed_hash = PasswordHash::new(&user.password).map_err(|e| {
         error!("Encountered error {:?}", e);
         Error::SignInFail
     })?;
-    match Argon2::default().verify_password(payload.password.as_bytes(), &parsed_hash) {
+    let verified = Argon2::default().verify_password(payload.password.as_bytes(), &parsed_hash);
+    match &verified {
+        Ok(_) => rate_limits
+            .lockout
+            .record_success(&payload.username, client_ip),
+        Err(_) => rate_limits
+            .lockout
+            .record_failure(&payload.username, client_ip),
+    }
+    match verified {
+        Ok(_) if user.disabled_at.is_some() => Err(Error::AccountDisabled),
+        Ok(_) if user.totp_secret.is_some() => {
+            let challenge_token = mfa::create_challenge(&app_state, &user.id).await?;
+
+            let body = Json(SigninResponse::MfaRequired(MfaChallengeResponse {
+                mfa_required: true,
+                challenge_token,
+            }));
+
+            Ok(body)
+        }
         Ok(_) => {
             let user: User = user.into();
-            let token = create_jwt(&user)?;
-
-            let body = Json(UserResponse {
-                id: user.id.to_string(),
-                username: user.username.clone(),
-                token,
-            });
+            let session = start_session(&app_state, &user).await?;
+            let body = Json(SigninResponse::Session(session));
 
             Ok(body)
         }
@@ -82,11 +134,64 @@ async fn signin(
     }
 }
 
+#[derive(Debug, Deserialize)]
+struct SigninMfaPayload {
+    challenge_token: String,
+    code: Option<String>,
+    recovery_code: Option<String>,
+}
+
+/// Second step of signing in with two-factor authentication enabled.
+async fn signin_mfa(
+    State(app_state): State<AppState>,
+    Json(payload): Json<SigninMfaPayload>,
+) -> Result<Json<UserResponse>> {
+    let challenge = mfa::get_challenge(&app_state, &payload.challenge_token).await?;
+    mfa::use_attempt(&app_state, &challenge.id).await?;
+
+    let user = app_state.users.find_by_id(&challenge.user).await?;
+    let user = user.ok_or(Error::InvalidMfaChallenge)?;
+    if user.disabled_at.is_some() {
+        return Err(Error::AccountDisabled);
+    }
+    let secret = user.totp_secret.clone().ok_or(Error::InvalidMfaChallenge)?;
+
+    let verified = match (&payload.code, &payload.recovery_code) {
+        (Some(code), _) => mfa::check_totp_code(&app_state, &user, &secret, code).await?,
+        (_, Some(recovery_code)) => {
+            mfa::use_recovery_code(&app_state, &user.id, recovery_code).await?
+        }
+        (None, None) => false,
+    };
+    if !verified {
+        return Err(Error::InvalidMfaCode);
+    }
+    mfa::complete_challenge(&app_state, &challenge.id).await?;
+
+    let user: User = user.into();
+    let body = Json(start_session(&app_state, &user).await?);
+
+    Ok(body)
+}
+
+async fn start_session(app_state: &AppState, user: &User) -> Result<UserResponse> {
+    let tokens = create_session(app_state, user).await?;
+
+    Ok(UserResponse {
+        id: user.id.to_string(),
+        username: user.username.clone(),
+        token: tokens.token,
+        refresh_token: tokens.refresh_token,
+    })
+}
+
 #[derive(Debug, Deserialize)]
 struct SignupPayload {
     username: String,
     password: String,
     password_confirm: String,
+    /// Needed to reset the password.
+    email: Option<String>,
 }
 
 async fn signup(
@@ -96,76 +201,219 @@ async fn signup(
     if payload.password != payload.password_confirm {
         return Err(Error::PasswordConfirmMismatch);
     }
-    let mut result = app_state
-        .db
-        .query("SELECT * FROM user WHERE username = $username")
-        .bind(("username", &payload.username))
-        .await
-        .map_err(|e| {
-            error!("Encountered error {:?}", e);
-            Error::SignUpFail
-        })?;
+    let email = payload.email.as_deref().map(parse_email).transpose()?;
+
+    // Fails if the username is taken
+    let user = create_user(
+        payload.username,
+        payload.password,
+        email,
+        app_state.users.as_ref(),
+    )
+    .await?;
+    let body = Json(start_session(&app_state, &user).await?);
+
+    Ok(body)
+}
 
-    let user: Option<UserDBResult> = result.take(0).map_err(|e| {
+pub async fn create_user(
+    username: String,
+    password: String,
+    email: Option<String>,
+    users: &dyn UserRepository,
+) -> Result<User> {
+    let password_hash = hash_password(&password).map_err(|e| {
         error!("Encountered error {:?}", e);
         Error::SignUpFail
     })?;
 
-    if user.is_some() {
-        return Err(Error::UsernameExists);
+    users
+        .create(CreateUserContent {
+            username,
+            password: password_hash,
+            email,
+        })
+        .await
+}
+
+pub fn hash_password(password: &str) -> argon2::password_hash::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let password_hash = Argon2::default().hash_password(password.as_bytes(), &salt)?;
+
+    Ok(password_hash.to_string())
+}
+
+pub fn verify_password(password: &str, password_hash: &str) -> argon2::password_hash::Result<()> {
+    let parsed_hash = PasswordHash::new(password_hash)?;
+
+    Argon2::default().verify_password(password.as_bytes(), &parsed_hash)
+}
+
+/// Trims `email` and checks that it looks like an email address. Whether it
+/// works is only known once something is sent to it.
+pub fn parse_email(email: &str) -> Result<String> {
+    let email = email.trim();
+    let (local, domain) = email.split_once('@').unwrap_or_default();
+    let valid = !local.is_empty()
+        && domain.contains('.')
+        && !domain.contains('@')
+        && !email.contains(char::is_whitespace)
+        && email.len() <= 254;
+
+    match valid {
+        true => Ok(email.to_string()),
+        false => Err(Error::InvalidEmail),
     }
+}
+
+#[derive(Debug, Deserialize)]
+struct RefreshPayload {
+    refresh_token: String,
+}
 
-    let user = create_user(payload.username, payload.password, app_state.db).await?;
-    let token = create_jwt(&user)?;
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefreshResponse {
+    pub token: String,
+    pub refresh_token: String,
+}
 
-    let body = Json(UserResponse {
-        id: user.id.to_string(),
-        username: user.username,
-        token,
+async fn refresh(
+    State(app_state): State<AppState>,
+    Json(payload): Json<RefreshPayload>,
+) -> Result<Json<RefreshResponse>> {
+    let tokens = refresh_session(&app_state, &payload.refresh_token).await?;
+
+    let body = Json(RefreshResponse {
+        token: tokens.token,
+        refresh_token: tokens.refresh_token,
     });
 
     Ok(body)
 }
 
-pub async fn create_user(username: String, password: String, db: Arc<DB>) -> Result<User> {
-    let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
-    let password_hash = argon2
-        .hash_password(&password.into_bytes(), &salt)
-        .map_err(|e| {
-            error!("Encountered error {:?}", e);
-            Error::SignUpFail
-        })?
-        .to_string();
-
-    let result: Vec<User> = db
-        .create("user")
-        .content(CreateUserContent {
-            username,
-            password: password_hash,
-        })
-        .await
-        .map_err(|e| {
-            error!("Encountered error {:?}", e);
-            Error::SignUpFail
-        })?;
-    let user = result.first().ok_or(Error::SignUpFail)?.to_owned();
+async fn logout(State(app_state): State<AppState>, headers: HeaderMap) -> Result<Json<Value>> {
+    let claims = get_bearer_claims(&headers, &app_state).await?;
+    let session_id = thing(&claims.jti).map_err(|_| Error::JWTValidationError)?;
 
-    Ok(user)
+    revoke_session(&app_state, &session_id).await?;
+
+    let body = Json(json!({
+        "result": {
+            "success": true,
+        }
+    }));
+
+    Ok(body)
 }
 
-async fn get_user_info(headers: HeaderMap) -> Result<Json<Value>> {
-    let auth_header = headers.get(AUTHORIZATION).ok_or(Error::InvalidAuthHeader)?;
+#[derive(Debug, Deserialize)]
+struct ForgotPasswordPayload {
+    username: String,
+}
 
-    let auth_header = std::str::from_utf8(auth_header.as_bytes())
-        .ok()
-        .ok_or(Error::InvalidAuthHeader)?;
-    let pattern = regex_captures!(r#"^Bearer (.+)"#, auth_header);
+/// Emails a password reset token to the user. Always succeeds, and sends the
+/// email after responding, so that neither the response nor how long it takes
+/// tell which usernames exist.
+async fn forgot_password(
+    State(app_state): State<AppState>,
+    Json(payload): Json<ForgotPasswordPayload>,
+) -> Result<Json<Value>> {
+    tokio::spawn(async move {
+        if let Err(e) = send_password_reset(&app_state, &payload.username).await {
+            error!("Could not send the password reset: {:?}", e);
+        }
+    });
 
-    let claims = match pattern {
-        Some((_, bearer_token)) => validate_jwt(bearer_token),
-        None => Err(Error::InvalidAuthHeader),
-    }?;
+    let body = Json(json!({
+        "result": {
+            "success": true,
+        }
+    }));
+
+    Ok(body)
+}
+
+async fn send_password_reset(app_state: &AppState, username: &str) -> Result<()> {
+    let user = app_state.users.find_by_username(username).await?;
+
+    match user {
+        Some(UserDBResult {
+            id,
+            email: Some(email),
+            ..
+        }) => {
+            let token = create_password_reset(app_state, &id).await?;
+            let link = app_state
+                .password_reset_url
+                .as_ref()
+                .map(|url| format!("\n\n{url}?token={token}"))
+                .unwrap_or_default();
+            app_state
+                .mailer
+                .send(Email {
+                    to: email,
+                    subject: "Reset your LinkStowr password".into(),
+                    body: format!(
+                        "Use this token to reset the password of {username}: {token}{link}\n\n\
+                        It expires in 30 minutes. If you didn't ask for it you can ignore this email."
+                    ),
+                })
+                .await?;
+        }
+        Some(_) => info!("User has no email to send the password reset to"),
+        None => info!("Password reset requested for unknown user"),
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct ResetPasswordPayload {
+    token: String,
+    password: String,
+    password_confirm: String,
+}
+
+/// Sets a new password with a reset token and signs the user out everywhere.
+async fn reset_password(
+    State(app_state): State<AppState>,
+    Json(payload): Json<ResetPasswordPayload>,
+) -> Result<Json<Value>> {
+    if payload.password != payload.password_confirm {
+        return Err(Error::PasswordConfirmMismatch);
+    }
+
+    let user_id = consume_password_reset(&app_state, &payload.token).await?;
+    update_password(&app_state, &user_id, &payload.password).await?;
+    revoke_user_sessions(&app_state, &user_id, None).await?;
+
+    let body = Json(json!({
+        "result": {
+            "success": true,
+        }
+    }));
+
+    Ok(body)
+}
+
+/// Stores a new hash for the user's password.
+pub async fn update_password(app_state: &AppState, user_id: &Thing, password: &str) -> Result<()> {
+    let password_hash = hash_password(password).map_err(|e| {
+        error!("Encountered error {:?}", e);
+        Error::ChangePasswordFail
+    })?;
+
+    app_state
+        .users
+        .update_password(user_id, &password_hash)
+        .await
+}
+
+async fn get_user_info(
+    State(app_state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>> {
+    let claims = get_bearer_claims(&headers, &app_state).await?;
 
     let body = Json(json!({
         "id": claims.sub,
@@ -174,3 +422,17 @@ async fn get_user_info(headers: HeaderMap) -> Result<Json<Value>> {
 
     Ok(body)
 }
+
+async fn get_bearer_claims(headers: &HeaderMap, app_state: &AppState) -> Result<Claims> {
+    let auth_header = headers.get(AUTHORIZATION).ok_or(Error::InvalidAuthHeader)?;
+
+    let auth_header = std::str::from_utf8(auth_header.as_bytes())
+        .ok()
+        .ok_or(Error::InvalidAuthHeader)?;
+    let pattern = regex_captures!(r#"^Bearer (.+)"#, auth_header);
+
+    match pattern {
+        Some((_, bearer_token)) => validate_jwt(bearer_token, app_state).await,
+        None => Err(Error::InvalidAuthHeader),
+    }
+}
@@ -0,0 +1,534 @@
+use axum::{
+    extract::{Path, Query, State},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use surrealdb::sql::{thing, Thing};
+use tracing::error;
+
+use crate::{
+    ctx::{Ctx, Scope},
+    error::{Error, Result},
+    types::{AppState, Collection, CollectionDBResult},
+};
+
+pub fn routes(state: AppState) -> Router {
+    Router::new()
+        .route("/collections", post(create_collection).get(get_collections))
+        .route(
+            "/collections/:id",
+            get(get_collection)
+                .patch(update_collection)
+                .delete(delete_collection),
+        )
+        .route("/collections/:id/links", post(move_links_to_collection))
+        .with_state(state)
+}
+
+fn parse_collection_id(collection_id: &str) -> Result<Thing> {
+    match thing(collection_id) {
+        Ok(collection_id) if collection_id.tb == "collection" => Ok(collection_id),
+        _ => Err(Error::InvalidCollectionId),
+    }
+}
+
+/// Parses a collection id and makes sure the collection belongs to the user.
+pub async fn check_collection(
+    app_state: &AppState,
+    user_id: &str,
+    collection_id: &str,
+) -> Result<Thing> {
+    let collection_id = parse_collection_id(collection_id)?;
+    let collections = get_user_collections(app_state, user_id).await?;
+
+    match collections.iter().any(|c| c.id == collection_id) {
+        true => Ok(collection_id),
+        false => Err(Error::CollectionNotFound),
+    }
+}
+
+/// Finds the user's collection at `path`, a list of names from the top level
+/// down, creating any collections along it that don't exist yet.
+pub async fn resolve_collection_path(
+    app_state: &AppState,
+    user_id: &str,
+    path: &[String],
+) -> Result<Option<Thing>> {
+    let mut collections = get_user_collections(app_state, user_id).await?;
+    let mut parent: Option<Thing> = None;
+
+    for name in path
+        .iter()
+        .map(|name| name.trim())
+        .filter(|name| !name.is_empty())
+    {
+        let existing = collections
+            .iter()
+            .find(|c| c.parent == parent && c.name == name)
+            .map(|c| c.id.clone());
+        if let Some(existing) = existing {
+            parent = Some(existing);
+            continue;
+        }
+
+        let siblings = collections.iter().filter(|c| c.parent == parent).count();
+        let created: Vec<CollectionDBResult> = app_state
+            .db
+            .create("collection")
+            .content(Collection {
+                name: name.to_string(),
+                user: thing(user_id).expect("Failed to convert ctx user_id to thing"),
+                parent: parent.clone(),
+                position: siblings as i64,
+            })
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::CreateCollectionFail
+            })?;
+        let created = created
+            .into_iter()
+            .next()
+            .ok_or(Error::CreateCollectionFail)?;
+
+        parent = Some(created.id.clone());
+        collections.push(created);
+    }
+
+    Ok(parent)
+}
+
+async fn get_user_collections(
+    app_state: &AppState,
+    user_id: &str,
+) -> Result<Vec<CollectionDBResult>> {
+    let user_id = thing(user_id).unwrap();
+    let mut result = app_state
+        .db
+        .query("SELECT * FROM collection WHERE user = $user_id ORDER BY position ASC, name ASC;")
+        .bind(("user_id", &user_id))
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::GetCollectionsFail
+        })?;
+
+    let mut collections: Vec<CollectionDBResult> = result.take(0).map_err(|e| {
+        error!("Encountered error {:?}", e);
+        Error::GetCollectionsFail
+    })?;
+
+    let counts = app_state.links.count_by_collection(&user_id).await?;
+    for collection in &mut collections {
+        collection.link_count = counts.get(&collection.id.to_string()).copied().unwrap_or(0);
+    }
+
+    Ok(collections)
+}
+
+/// Returns the ids of every collection nested anywhere below `collection_id`.
+fn descendants(collections: &[CollectionDBResult], collection_id: &Thing) -> Vec<Thing> {
+    let mut found = vec![];
+    let mut stack = vec![collection_id.clone()];
+
+    while let Some(parent) = stack.pop() {
+        for child in collections
+            .iter()
+            .filter(|c| c.parent.as_ref() == Some(&parent))
+        {
+            found.push(child.id.clone());
+            stack.push(child.id.clone());
+        }
+    }
+
+    found
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CollectionResponse {
+    pub id: String,
+    pub name: String,
+    pub parent: Option<String>,
+    pub position: i64,
+    pub link_count: u64,
+    pub children: Vec<CollectionResponse>,
+}
+
+/// Builds the tree below `parent`, with siblings kept in their stored order.
+fn build_tree(
+    collections: &[CollectionDBResult],
+    parent: Option<&Thing>,
+) -> Vec<CollectionResponse> {
+    collections
+        .iter()
+        .filter(|c| c.parent.as_ref() == parent)
+        .map(|c| CollectionResponse {
+            id: c.id.to_string(),
+            name: c.name.clone(),
+            parent: c.parent.as_ref().map(|parent| parent.to_string()),
+            position: c.position,
+            link_count: c.link_count,
+            children: build_tree(collections, Some(&c.id)),
+        })
+        .collect()
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateCollectionPayload {
+    name: String,
+    parent: Option<String>,
+    /// Defaults to the end of the parent's children.
+    position: Option<i64>,
+}
+
+#[tracing::instrument(
+    name = "Creating a collection",
+    skip(ctx, app_state),
+    fields(
+        user_id = %ctx.user_id(),
+    )
+)]
+async fn create_collection(
+    ctx: Ctx,
+    State(app_state): State<AppState>,
+    Json(payload): Json<CreateCollectionPayload>,
+) -> Result<Json<CollectionResponse>> {
+    ctx.require_scope(Scope::LinksWrite)?;
+
+    let name = payload.name.trim().to_string();
+    if name.is_empty() {
+        return Err(Error::InvalidCollectionName);
+    }
+
+    let collections = get_user_collections(&app_state, ctx.user_id()).await?;
+    let parent = match payload.parent {
+        Some(parent) => {
+            let parent = parse_collection_id(&parent)?;
+            if !collections.iter().any(|c| c.id == parent) {
+                return Err(Error::CollectionNotFound);
+            }
+            Some(parent)
+        }
+        None => None,
+    };
+    let siblings = collections.iter().filter(|c| c.parent == parent).count();
+
+    let created: Vec<CollectionDBResult> = app_state
+        .db
+        .create("collection")
+        .content(Collection {
+            name,
+            user: thing(ctx.user_id()).expect("Failed to convert ctx user_id to thing"),
+            parent,
+            position: payload.position.unwrap_or(siblings as i64),
+        })
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::CreateCollectionFail
+        })?;
+
+    let created = created
+        .into_iter()
+        .next()
+        .ok_or(Error::CreateCollectionFail)?;
+
+    let body = Json(CollectionResponse {
+        id: created.id.to_string(),
+        name: created.name,
+        parent: created.parent.map(|parent| parent.to_string()),
+        position: created.position,
+        link_count: 0,
+        children: vec![],
+    });
+
+    Ok(body)
+}
+
+#[tracing::instrument(
+    name = "Getting collections",
+    skip(ctx, app_state),
+    fields(
+        user_id = %ctx.user_id(),
+    )
+)]
+async fn get_collections(
+    ctx: Ctx,
+    State(app_state): State<AppState>,
+) -> Result<Json<Vec<CollectionResponse>>> {
+    ctx.require_scope(Scope::LinksRead)?;
+
+    let collections = get_user_collections(&app_state, ctx.user_id()).await?;
+
+    Ok(Json(build_tree(&collections, None)))
+}
+
+#[tracing::instrument(
+    name = "Getting a collection",
+    skip(ctx, app_state),
+    fields(
+        user_id = %ctx.user_id(),
+    )
+)]
+async fn get_collection(
+    ctx: Ctx,
+    State(app_state): State<AppState>,
+    Path(collection_id): Path<String>,
+) -> Result<Json<CollectionResponse>> {
+    ctx.require_scope(Scope::LinksRead)?;
+
+    let collection_id = parse_collection_id(&collection_id)?;
+    let collections = get_user_collections(&app_state, ctx.user_id()).await?;
+    let collection = collections
+        .iter()
+        .find(|c| c.id == collection_id)
+        .ok_or(Error::CollectionNotFound)?;
+
+    let body = Json(CollectionResponse {
+        id: collection.id.to_string(),
+        name: collection.name.clone(),
+        parent: collection.parent.as_ref().map(|parent| parent.to_string()),
+        position: collection.position,
+        link_count: collection.link_count,
+        children: build_tree(&collections, Some(&collection.id)),
+    });
+
+    Ok(body)
+}
+
+#[derive(Debug, Deserialize)]
+struct UpdateCollectionPayload {
+    name: Option<String>,
+    /// `null` moves the collection to the top level.
+    #[serde(default, with = "::serde_with::rust::double_option")]
+    parent: Option<Option<String>>,
+    position: Option<i64>,
+}
+
+#[tracing::instrument(
+    name = "Updating a collection",
+    skip(ctx, app_state),
+    fields(
+        user_id = %ctx.user_id(),
+    )
+)]
+async fn update_collection(
+    ctx: Ctx,
+    State(app_state): State<AppState>,
+    Path(collection_id): Path<String>,
+    Json(payload): Json<UpdateCollectionPayload>,
+) -> Result<Json<CollectionResponse>> {
+    ctx.require_scope(Scope::LinksWrite)?;
+
+    let collection_id = parse_collection_id(&collection_id)?;
+    let collections = get_user_collections(&app_state, ctx.user_id()).await?;
+    let collection = collections
+        .iter()
+        .find(|c| c.id == collection_id)
+        .ok_or(Error::CollectionNotFound)?;
+
+    let name = match payload.name {
+        Some(name) if name.trim().is_empty() => return Err(Error::InvalidCollectionName),
+        Some(name) => name.trim().to_string(),
+        None => collection.name.clone(),
+    };
+    let parent = match payload.parent {
+        Some(Some(parent)) => {
+            let parent = parse_collection_id(&parent)?;
+            if !collections.iter().any(|c| c.id == parent) {
+                return Err(Error::CollectionNotFound);
+            }
+            // A collection can't be moved inside itself or one of its children
+            if parent == collection_id
+                || descendants(&collections, &collection_id).contains(&parent)
+            {
+                return Err(Error::InvalidCollectionParent);
+            }
+            Some(parent)
+        }
+        Some(None) => None,
+        None => collection.parent.clone(),
+    };
+    let position = payload.position.unwrap_or(collection.position);
+
+    let mut result = app_state
+        .db
+        .query(
+            "UPDATE $collection_id SET name = $name, parent = $parent, position = $position \
+                WHERE user = $user_id;",
+        )
+        .bind(("collection_id", &collection_id))
+        .bind(("name", name))
+        .bind(("parent", parent))
+        .bind(("position", position))
+        .bind(("user_id", thing(ctx.user_id()).unwrap()))
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::UpdateCollectionFail
+        })?;
+
+    let updated: Option<CollectionDBResult> = result.take(0).map_err(|e| {
+        error!("Encountered error {:?}", e);
+        Error::UpdateCollectionFail
+    })?;
+    let updated = updated.ok_or(Error::CollectionNotFound)?;
+
+    let body = Json(CollectionResponse {
+        id: updated.id.to_string(),
+        name: updated.name,
+        parent: updated.parent.map(|parent| parent.to_string()),
+        position: updated.position,
+        link_count: collection.link_count,
+        children: build_tree(&collections, Some(&collection_id)),
+    });
+
+    Ok(body)
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum DeleteCollectionMode {
+    /// Move the links to `target` and the child collections to the parent.
+    #[default]
+    Move,
+    /// Delete the links and every collection nested below as well.
+    Cascade,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeleteCollectionParams {
+    #[serde(default)]
+    mode: DeleteCollectionMode,
+    /// Where to move the links to. Defaults to the deleted collection's parent,
+    /// or out of any collection for a top level collection.
+    target: Option<String>,
+}
+
+#[tracing::instrument(
+    name = "Deleting a collection",
+    skip(ctx, app_state),
+    fields(
+        user_id = %ctx.user_id(),
+    )
+)]
+async fn delete_collection(
+    ctx: Ctx,
+    State(app_state): State<AppState>,
+    Path(collection_id): Path<String>,
+    Query(params): Query<DeleteCollectionParams>,
+) -> Result<Json<Value>> {
+    ctx.require_scope(Scope::LinksWrite)?;
+
+    let collection_id = parse_collection_id(&collection_id)?;
+    let collections = get_user_collections(&app_state, ctx.user_id()).await?;
+    let collection = collections
+        .iter()
+        .find(|c| c.id == collection_id)
+        .ok_or(Error::CollectionNotFound)?;
+
+    let target = match params.target {
+        Some(target) => {
+            let target = parse_collection_id(&target)?;
+            if target == collection_id || !collections.iter().any(|c| c.id == target) {
+                return Err(Error::InvalidCollectionParent);
+            }
+            Some(target)
+        }
+        None => collection.parent.clone(),
+    };
+    let mut to_delete = descendants(&collections, &collection_id);
+    to_delete.push(collection_id.clone());
+
+    // The links go first, so that a failure leaves the collections in place
+    // and the delete can be retried.
+    let user_id = thing(ctx.user_id()).unwrap();
+    let query = match params.mode {
+        DeleteCollectionMode::Move => {
+            app_state
+                .links
+                .move_collections(&user_id, &[collection_id.clone()], target.as_ref())
+                .await?;
+
+            "BEGIN TRANSACTION;
+            UPDATE collection SET parent = $parent WHERE user = $user_id AND parent = $collection_id;
+            DELETE $collection_id WHERE user = $user_id;
+            COMMIT TRANSACTION;"
+        }
+        DeleteCollectionMode::Cascade => {
+            app_state
+                .links
+                .delete_in_collections(&user_id, &to_delete)
+                .await?;
+
+            "DELETE collection WHERE user = $user_id AND id IN $collections;"
+        }
+    };
+
+    app_state
+        .db
+        .query(query)
+        .bind(("collection_id", &collection_id))
+        .bind(("collections", to_delete))
+        .bind(("parent", &collection.parent))
+        .bind(("user_id", user_id))
+        .await
+        .and_then(|response| response.check())
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::DeleteCollectionFail
+        })?;
+
+    let body = Json(json!({
+        "success": true,
+    }));
+
+    Ok(body)
+}
+
+#[derive(Debug, Deserialize)]
+struct MoveLinksPayload {
+    links: Vec<String>,
+}
+
+#[tracing::instrument(
+    name = "Moving links to a collection",
+    skip(ctx, app_state, payload),
+    fields(
+        user_id = %ctx.user_id(),
+    )
+)]
+async fn move_links_to_collection(
+    ctx: Ctx,
+    State(app_state): State<AppState>,
+    Path(collection_id): Path<String>,
+    Json(payload): Json<MoveLinksPayload>,
+) -> Result<Json<Value>> {
+    ctx.require_scope(Scope::LinksWrite)?;
+
+    let collection_id = check_collection(&app_state, ctx.user_id(), &collection_id).await?;
+    let links = payload
+        .links
+        .iter()
+        .map(|link_id| match thing(link_id) {
+            Ok(link_id) if link_id.tb == "link" => Ok(link_id),
+            _ => Err(Error::InvalidLinkId),
+        })
+        .collect::<Result<Vec<Thing>>>()?;
+
+    let moved = app_state
+        .links
+        .set_collection(&thing(ctx.user_id()).unwrap(), &links, &collection_id)
+        .await?;
+
+    let body = Json(json!({
+        "result": {
+            "moved": moved,
+            "success": true,
+        }
+    }));
+
+    Ok(body)
+}
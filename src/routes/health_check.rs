@@ -1,5 +1,153 @@
-use axum::{http::StatusCode, response::IntoResponse};
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    future::Future,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse, routing::get, Json, Router};
+use serde::Serialize;
+use tracing::error;
+
+use crate::types::AppState;
+
+/// How long the readiness probe waits for each dependency.
+const DEPENDENCY_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub fn health_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/health/live", get(live))
+        .route("/health/ready", get(ready))
+        .with_state(state)
+}
 
 pub async fn health_check() -> impl IntoResponse {
     StatusCode::OK
 }
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthStatus {
+    Ok,
+    Unavailable,
+    ShuttingDown,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    /// Set from `GIT_COMMIT_SHA` at build time.
+    pub commit: Option<&'static str>,
+}
+
+const BUILD_INFO: BuildInfo = BuildInfo {
+    version: env!("CARGO_PKG_VERSION"),
+    commit: option_env!("GIT_COMMIT_SHA"),
+};
+
+#[derive(Debug, Serialize)]
+pub struct DependencyHealth {
+    pub status: HealthStatus,
+    pub latency_ms: u128,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthResponse {
+    pub status: HealthStatus,
+    pub build: BuildInfo,
+    pub dependencies: BTreeMap<&'static str, DependencyHealth>,
+}
+
+/// Whether the process is up, whatever the state of its dependencies.
+async fn live() -> impl IntoResponse {
+    Json(HealthResponse {
+        status: HealthStatus::Ok,
+        build: BUILD_INFO,
+        dependencies: BTreeMap::new(),
+    })
+}
+
+/// Whether the instance should get traffic: 503 once it is shutting down or
+/// when a dependency doesn't answer.
+#[tracing::instrument(skip(app_state))]
+async fn ready(State(app_state): State<AppState>) -> impl IntoResponse {
+    let mut dependencies = BTreeMap::new();
+    dependencies.insert("surrealdb", check_surrealdb(&app_state).await);
+    #[cfg(feature = "sql")]
+    if let Some(pool) = &app_state.sql_pool {
+        dependencies.insert("sql", check_sql(pool).await);
+    }
+
+    let status = if app_state.shutting_down.load(Ordering::Relaxed) {
+        HealthStatus::ShuttingDown
+    } else if dependencies
+        .values()
+        .all(|dependency| dependency.status == HealthStatus::Ok)
+    {
+        HealthStatus::Ok
+    } else {
+        HealthStatus::Unavailable
+    };
+    let code = match status {
+        HealthStatus::Ok => StatusCode::OK,
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    (
+        code,
+        Json(HealthResponse {
+            status,
+            build: BUILD_INFO,
+            dependencies,
+        }),
+    )
+}
+
+/// Runs `query` within `DEPENDENCY_TIMEOUT`. Errors are only logged, since
+/// anyone can call the probe.
+async fn check_dependency<E: Debug>(
+    query: impl Future<Output = Result<(), E>>,
+) -> DependencyHealth {
+    let started_at = Instant::now();
+    let result = tokio::time::timeout(DEPENDENCY_TIMEOUT, query).await;
+
+    let error = match result {
+        Ok(Ok(_)) => None,
+        Ok(Err(e)) => {
+            error!("Encountered error {:?}", e);
+            Some("query failed".to_string())
+        }
+        Err(_) => Some(format!(
+            "no answer within {}s",
+            DEPENDENCY_TIMEOUT.as_secs()
+        )),
+    };
+
+    DependencyHealth {
+        status: match error {
+            None => HealthStatus::Ok,
+            Some(_) => HealthStatus::Unavailable,
+        },
+        latency_ms: started_at.elapsed().as_millis(),
+        error,
+    }
+}
+
+async fn check_surrealdb(app_state: &AppState) -> DependencyHealth {
+    check_dependency(async {
+        app_state.db.query("RETURN true;").await?.check()?;
+        Ok::<_, surrealdb::Error>(())
+    })
+    .await
+}
+
+#[cfg(feature = "sql")]
+async fn check_sql(pool: &sqlx::AnyPool) -> DependencyHealth {
+    check_dependency(async {
+        sqlx::query("SELECT 1").execute(pool).await?;
+        Ok::<_, sqlx::Error>(())
+    })
+    .await
+}
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{DefaultBodyLimit, Query, State},
+    http::StatusCode,
+    routing::post,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{thing, Thing};
+use tracing::error;
+
+use crate::{
+    ctx::{Ctx, Scope},
+    error::Result,
+    import::{self, ImportFormat},
+    routes::{
+        collection_routes::resolve_collection_path,
+        link_routes::{save_links_with_tags, BatchItemStatus, NewLink},
+        tag_routes::{remove_unused_tags, resolve_tag_names, ResolvedTags},
+    },
+    types::{AppState, LinkPayload, OnDuplicate},
+};
+
+/// Exports of a few thousand bookmarks go well past axum's default limit.
+const MAX_IMPORT_SIZE: usize = 20 * 1024 * 1024;
+
+pub fn routes(state: AppState) -> Router {
+    Router::new()
+        .route("/import", post(import_links))
+        .layer(DefaultBodyLimit::max(MAX_IMPORT_SIZE))
+        .with_state(state)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportParams {
+    pub format: ImportFormat,
+}
+
+/// Imports never change links that were already saved: entries with the
+/// canonical URL of a saved link, or of an earlier entry, are skipped whatever
+/// `on_duplicate` would say, so importing the same file twice is harmless.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ImportReport {
+    pub imported: usize,
+    /// Entries that were already saved, or repeated within the export.
+    pub skipped: usize,
+    pub failed: usize,
+    pub failures: Vec<ImportFailure>,
+    /// Client error type of the failure that stopped the import part way.
+    /// The entries counted above were handled, the ones after weren't.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ImportFailure {
+    pub url: Option<String>,
+    pub error: String,
+}
+
+#[tracing::instrument(
+    name = "Importing links",
+    skip(ctx, app_state, body),
+    fields(
+        user_id = %ctx.user_id(),
+    )
+)]
+async fn import_links(
+    ctx: Ctx,
+    State(app_state): State<AppState>,
+    Query(params): Query<ImportParams>,
+    body: String,
+) -> Result<(StatusCode, Json<ImportReport>)> {
+    ctx.require_scope(Scope::LinksWrite)?;
+
+    let entries = import::parse(params.format, &body)?;
+    let mut report = ImportReport::default();
+
+    let mut links: Vec<NewLink> = Vec::with_capacity(entries.len());
+    let mut folders: Vec<Vec<String>> = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let link = match entry {
+            Ok(link) => link,
+            Err(error) => {
+                report.failed += 1;
+                report.failures.push(ImportFailure { url: None, error });
+                continue;
+            }
+        };
+
+        links.push(NewLink {
+            payload: LinkPayload {
+                url: link.url,
+                title: link.title,
+                note: link.note,
+                tags: link.tags,
+                collection: None,
+                on_duplicate: OnDuplicate::Reject,
+            },
+            bookmarked_at: link.bookmarked_at,
+        });
+        folders.push(link.folder);
+    }
+
+    // The tags of the whole file are resolved at once, and the ones only used by
+    // skipped entries are removed at the end.
+    let names = links.iter().flat_map(|link| &link.payload.tags);
+    let tags = resolve_tag_names(&app_state, ctx.user_id(), names).await?;
+    let saved = save_in_chunks(
+        &app_state,
+        ctx.user_id(),
+        links,
+        folders,
+        &tags,
+        &mut report,
+    )
+    .await;
+    remove_unused_tags(&app_state, ctx.user_id(), &tags).await;
+
+    // The chunks saved before a failure stay saved, so the client gets to
+    // know which entries those were
+    let status = match saved {
+        Ok(()) => StatusCode::OK,
+        Err(e) => {
+            error!("Encountered error {:?}", e);
+            let (status, client_error) = e.client_status_and_error();
+            report.error = Some(client_error.as_ref().to_string());
+            status
+        }
+    };
+
+    Ok((status, Json(report)))
+}
+
+/// Saves the links in chunks to keep each transaction to the size of a batch
+/// request. Folders become collections once a link in them is saved, so
+/// skipped and failed entries don't leave empty ones behind.
+async fn save_in_chunks(
+    app_state: &AppState,
+    user_id: &str,
+    links: Vec<NewLink>,
+    folders: Vec<Vec<String>>,
+    tags: &ResolvedTags,
+    report: &mut ImportReport,
+) -> Result<()> {
+    let user = thing(user_id).expect("Failed to convert ctx user_id to thing");
+    let mut collections: HashMap<Vec<String>, Option<Thing>> = HashMap::new();
+    let mut links = links.into_iter().peekable();
+    let mut folders = folders.into_iter();
+    while links.peek().is_some() {
+        let chunk: Vec<_> = links
+            .by_ref()
+            .take(app_state.max_link_batch_size.max(1))
+            .map(Ok)
+            .collect();
+        let chunk_folders: Vec<Vec<String>> = folders.by_ref().take(chunk.len()).collect();
+
+        // Folders in the order they're first seen, for the collection positions
+        let mut created: Vec<(Vec<String>, Vec<Thing>)> = vec![];
+        let results = save_links_with_tags(app_state, user_id, chunk, tags).await?;
+        for (result, folder) in results.into_iter().zip(chunk_folders) {
+            match result.status {
+                BatchItemStatus::Created => {
+                    report.imported += 1;
+                    if let Some(id) = result.id.and_then(|id| thing(&id).ok()) {
+                        match created.iter_mut().find(|(seen, _)| seen == &folder) {
+                            Some((_, link_ids)) => link_ids.push(id),
+                            None => created.push((folder, vec![id])),
+                        }
+                    }
+                }
+                BatchItemStatus::Duplicate => report.skipped += 1,
+                BatchItemStatus::Invalid => {
+                    report.failed += 1;
+                    report.failures.push(ImportFailure {
+                        url: result.url,
+                        error: result.error.unwrap_or_default(),
+                    });
+                }
+            }
+        }
+
+        for (folder, link_ids) in created {
+            if !collections.contains_key(&folder) {
+                let collection = resolve_collection_path(app_state, user_id, &folder).await?;
+                collections.insert(folder.clone(), collection);
+            }
+            if let Some(collection) = &collections[&folder] {
+                app_state
+                    .links
+                    .set_collection(&user, &link_ids, collection)
+                    .await?;
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -1,23 +1,55 @@
-use axum::{extract::State, routing::post, Json, Router};
+use std::collections::HashMap;
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use surrealdb::sql::{thing, Datetime};
-use tracing::error;
+use surrealdb::sql::{thing, Datetime, Thing};
 
 use crate::{
-    ctx::Ctx,
+    ctx::{Ctx, Scope},
     error::{Error, Result},
-    types::{AppState, Link, LinkPayload},
+    export::ExportFormat,
+    repository::{LinkChanges, LinkMerge, LinkPosition, LinkQuery, LinkSortValue},
+    routes::{
+        collection_routes::check_collection,
+        tag_routes::{
+            normalize_tag, remove_unused_tags, resolve_tag_names, resolve_tags, ResolvedTags,
+        },
+    },
+    search::SearchQuery,
+    types::{AppState, Link, LinkDBResult, LinkPayload, OnDuplicate},
 };
 
 pub fn routes(state: AppState) -> Router {
     Router::new()
         .route("/links", post(create_link).get(get_links))
+        .route("/links/batch", post(create_links_batch))
         .route("/links/clear", post(clear_links))
+        .route("/links/export", get(export_links))
+        .route("/links/search", get(search_links))
+        .route(
+            "/links/:id",
+            get(get_link).patch(update_link).delete(delete_link),
+        )
         .with_state(state)
 }
 
+/// Parses a link id of the form `link:<id>` as returned in `LinkResponse`.
+fn parse_link_id(link_id: &str) -> Result<Thing> {
+    match thing(link_id) {
+        Ok(link_id) if link_id.tb == "link" => Ok(link_id),
+        _ => Err(Error::InvalidLinkId),
+    }
+}
+
 #[tracing::instrument(
     name = "Creating a link",
     skip(ctx, app_state),
@@ -30,40 +62,542 @@ async fn create_link(
     State(app_state): State<AppState>,
     Json(payload): Json<LinkPayload>,
 ) -> Result<Json<Value>> {
-    let created: Vec<Link> = app_state
-        .db
-        .create("link")
-        .content(Link {
+    ctx.require_scope(Scope::LinksWrite)?;
+
+    let tags = resolve_tags(&app_state, ctx.user_id(), &payload.tags).await?;
+    let collection = match &payload.collection {
+        Some(collection) => Some(check_collection(&app_state, ctx.user_id(), collection).await?),
+        None => None,
+    };
+    let canonical_url = app_state.url_canonicalizer.canonicalize(&payload.url);
+
+    if let Some(existing) = find_duplicate(&app_state, ctx.user_id(), &canonical_url, None).await? {
+        if payload.on_duplicate == OnDuplicate::Reject {
+            return Err(Error::LinkExists(existing.id.to_string()));
+        }
+
+        let merged = merge_duplicate(
+            &app_state,
+            ctx.user_id(),
+            existing,
+            &payload,
+            tags,
+            collection,
+        )
+        .await?;
+
+        return Ok(Json(json!({
+            "result": {
+                "id": merged.id.to_string(),
+                "url": merged.url,
+                "success": true,
+                "duplicate": true,
+            }
+        })));
+    }
+
+    let created = app_state
+        .links
+        .create(Link {
             url: payload.url.clone(),
+            canonical_url,
             title: payload.title.clone(),
             note: payload.note.clone(),
             bookmarked_at: Datetime::from(Utc::now()),
             user: thing(ctx.user_id()).expect("Failed to convert ctx user_id to thing"),
+            tags,
+            collection,
         })
-        .await
-        .map_err(|e| {
-            error!("Encountered error {:?}", e);
-            Error::CreateLinkFail
-        })?;
-
-    let created = created.first().ok_or(Error::CreateLinkFail)?;
+        .await?;
 
     let body = Json(json!({
         "result": {
+            "id": created.id.to_string(),
             "url": created.url,
             "success": true,
+            "duplicate": false,
         }
     }));
 
     Ok(body)
 }
 
+/// Finds the user's link saved under `canonical_url`, other than `exclude`.
+async fn find_duplicate(
+    app_state: &AppState,
+    user_id: &str,
+    canonical_url: &str,
+    exclude: Option<&Thing>,
+) -> Result<Option<LinkDBResult>> {
+    let user_id = thing(user_id).expect("Failed to convert ctx user_id to thing");
+
+    app_state
+        .links
+        .find_by_canonical_url(&user_id, canonical_url, exclude)
+        .await
+}
+
+/// Saving a link again bumps it to the top and adds to it. The existing note
+/// and collection are only replaced when new ones were given.
+async fn merge_duplicate(
+    app_state: &AppState,
+    user_id: &str,
+    existing: LinkDBResult,
+    payload: &LinkPayload,
+    tags: Vec<Thing>,
+    collection: Option<Thing>,
+) -> Result<LinkDBResult> {
+    let note = match payload.note.trim().is_empty() {
+        true => existing.note,
+        false => payload.note.clone(),
+    };
+
+    let user_id = thing(user_id).expect("Failed to convert ctx user_id to thing");
+    let merge = LinkMerge {
+        title: payload.title.clone(),
+        note,
+        tags,
+        collection: collection.or(existing.collection),
+    };
+    let merged = app_state.links.merge(&user_id, &existing.id, merge).await?;
+
+    merged.ok_or(Error::LinkNotFound)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchItemStatus {
+    Created,
+    /// The link was already saved. It is left as it was.
+    Duplicate,
+    Invalid,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BatchItemResult {
+    pub status: BatchItemStatus,
+    /// Id of the created link, or of the saved link for duplicates.
+    pub id: Option<String>,
+    pub url: Option<String>,
+    /// Client error type explaining why an invalid item was skipped.
+    pub error: Option<String>,
+}
+
+impl BatchItemResult {
+    pub fn invalid(url: Option<String>, error: Error) -> Self {
+        Self {
+            status: BatchItemStatus::Invalid,
+            id: None,
+            url,
+            error: Some(error.client_status_and_error().1.as_ref().to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BatchResponse {
+    /// One result per submitted item, in the same order.
+    pub results: Vec<BatchItemResult>,
+}
+
+/// A link to save with `save_links`.
+pub struct NewLink {
+    pub payload: LinkPayload,
+    /// When the link was first bookmarked. Defaults to now.
+    pub bookmarked_at: Option<DateTime<Utc>>,
+}
+
+/// A batch item as it moves through `save_links`.
+enum BatchItem {
+    /// Waiting to be checked, along with its canonical URL.
+    Pending(NewLink, String),
+    /// Created as, or a repeat of, the link at `index` in the transaction.
+    InBatch {
+        status: BatchItemStatus,
+        index: usize,
+        url: String,
+    },
+    Done(BatchItemResult),
+}
+
+#[tracing::instrument(
+    name = "Creating a batch of links",
+    skip(ctx, app_state, items),
+    fields(
+        user_id = %ctx.user_id(),
+        batch_size = items.len(),
+    )
+)]
+async fn create_links_batch(
+    ctx: Ctx,
+    State(app_state): State<AppState>,
+    Json(items): Json<Vec<Value>>,
+) -> Result<Json<BatchResponse>> {
+    ctx.require_scope(Scope::LinksWrite)?;
+
+    if items.len() > app_state.max_link_batch_size {
+        return Err(Error::LinkBatchTooLarge);
+    }
+
+    // Items are parsed one by one so that a single bad tab doesn't fail the batch
+    let items = items
+        .into_iter()
+        .map(|item| {
+            let url = item.get("url").and_then(Value::as_str).map(String::from);
+            serde_json::from_value::<LinkPayload>(item)
+                .map(|payload| NewLink {
+                    payload,
+                    bookmarked_at: None,
+                })
+                .map_err(|_| BatchItemResult::invalid(url, Error::InvalidLinkPayload))
+        })
+        .collect();
+
+    let results = save_links(&app_state, ctx.user_id(), items).await?;
+
+    Ok(Json(BatchResponse { results }))
+}
+
+/// Saves links for the user in a single transaction and returns one result
+/// per item, in order. Items that already failed are passed through as they
+/// are. Links that were saved before are left untouched and reported as
+/// duplicates, whatever their `on_duplicate`.
+///
+/// The tags of all items are resolved at once, and the ones that were created
+/// but didn't end up on a link are removed again.
+pub async fn save_links(
+    app_state: &AppState,
+    user_id: &str,
+    items: Vec<std::result::Result<NewLink, BatchItemResult>>,
+) -> Result<Vec<BatchItemResult>> {
+    let names = items
+        .iter()
+        .filter_map(|item| item.as_ref().ok())
+        .flat_map(|link| &link.payload.tags);
+    let tags = resolve_tag_names(app_state, user_id, names).await?;
+
+    let results = save_links_with_tags(app_state, user_id, items, &tags).await;
+    remove_unused_tags(app_state, user_id, &tags).await;
+
+    results
+}
+
+/// `save_links` with the tags of the items already resolved. The caller
+/// removes the ones that end up unused.
+pub async fn save_links_with_tags(
+    app_state: &AppState,
+    user_id: &str,
+    items: Vec<std::result::Result<NewLink, BatchItemResult>>,
+    tags: &ResolvedTags,
+) -> Result<Vec<BatchItemResult>> {
+    let user = thing(user_id).expect("Failed to convert ctx user_id to thing");
+
+    let mut batch: Vec<BatchItem> = items
+        .into_iter()
+        .map(|item| match item {
+            Ok(link) if link.payload.url.trim().is_empty() => BatchItem::Done(
+                BatchItemResult::invalid(Some(link.payload.url), Error::InvalidLinkUrl),
+            ),
+            Ok(link) => {
+                let canonical_url = app_state.url_canonicalizer.canonicalize(&link.payload.url);
+                BatchItem::Pending(link, canonical_url)
+            }
+            Err(result) => BatchItem::Done(result),
+        })
+        .collect();
+
+    let canonical_urls: Vec<String> = batch
+        .iter()
+        .filter_map(|item| match item {
+            BatchItem::Pending(_, canonical_url) => Some(canonical_url.clone()),
+            _ => None,
+        })
+        .collect();
+    let saved: HashMap<String, String> = app_state
+        .links
+        .find_by_canonical_urls(&user, &canonical_urls)
+        .await?
+        .into_iter()
+        .map(|link| (link.canonical_url, link.id.to_string()))
+        .collect();
+
+    let mut links: Vec<Link> = vec![];
+    let mut in_batch: HashMap<String, usize> = HashMap::new();
+    let mut collections: HashMap<String, Result<Thing>> = HashMap::new();
+    for item in batch.iter_mut() {
+        let BatchItem::Pending(link, canonical_url) = item else {
+            continue;
+        };
+        let payload = &link.payload;
+
+        if let Some(id) = saved.get(canonical_url) {
+            *item = BatchItem::Done(BatchItemResult {
+                status: BatchItemStatus::Duplicate,
+                id: Some(id.clone()),
+                url: Some(payload.url.clone()),
+                error: None,
+            });
+            continue;
+        }
+        // Repeated links within the batch point at the first one
+        if let Some(index) = in_batch.get(canonical_url) {
+            *item = BatchItem::InBatch {
+                status: BatchItemStatus::Duplicate,
+                index: *index,
+                url: payload.url.clone(),
+            };
+            continue;
+        }
+
+        let collection = match &payload.collection {
+            Some(collection) => {
+                if !collections.contains_key(collection) {
+                    let checked = check_collection(app_state, user_id, collection).await;
+                    collections.insert(collection.clone(), checked);
+                }
+                match &collections[collection] {
+                    Ok(collection) => Some(collection.clone()),
+                    Err(e) => {
+                        *item = BatchItem::Done(BatchItemResult::invalid(
+                            Some(payload.url.clone()),
+                            e.clone(),
+                        ));
+                        continue;
+                    }
+                }
+            }
+            None => None,
+        };
+        let index = links.len();
+        in_batch.insert(canonical_url.clone(), index);
+        links.push(Link {
+            url: payload.url.clone(),
+            canonical_url: canonical_url.clone(),
+            title: payload.title.clone(),
+            note: payload.note.clone(),
+            bookmarked_at: Datetime::from(link.bookmarked_at.unwrap_or_else(Utc::now)),
+            user: user.clone(),
+            tags: tags.ids(&payload.tags),
+            collection,
+        });
+        *item = BatchItem::InBatch {
+            status: BatchItemStatus::Created,
+            index,
+            url: payload.url.clone(),
+        };
+    }
+
+    let created = app_state.links.create_many(links).await?;
+
+    let results = batch
+        .into_iter()
+        .map(|item| match item {
+            BatchItem::InBatch { status, index, url } => BatchItemResult {
+                status,
+                id: Some(created[index].id.to_string()),
+                url: Some(url),
+                error: None,
+            },
+            BatchItem::Done(result) => result,
+            BatchItem::Pending(..) => unreachable!("Every pending item is resolved above"),
+        })
+        .collect();
+
+    Ok(results)
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct LinkResponse {
+    pub id: String,
     pub url: String,
     pub title: String,
     pub note: String,
     pub bookmarked_at: DateTime<Utc>,
+    pub updated_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub collection: Option<String>,
+}
+
+impl From<LinkDBResult> for LinkResponse {
+    fn from(link: LinkDBResult) -> Self {
+        Self {
+            id: link.id.to_string(),
+            url: link.url,
+            title: link.title,
+            note: link.note,
+            bookmarked_at: link.bookmarked_at,
+            updated_at: link.updated_at,
+            tags: link.tag_names,
+            collection: link.collection.map(|collection| collection.to_string()),
+        }
+    }
+}
+
+const DEFAULT_LINKS_LIMIT: usize = 100;
+const MAX_LINKS_LIMIT: usize = 1000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkSort {
+    #[default]
+    BookmarkedAt,
+    Title,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct LinksParams {
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+    #[serde(default)]
+    pub sort: LinkSort,
+    #[serde(default)]
+    pub order: SortOrder,
+    /// Only include links bookmarked at or after this time.
+    pub from: Option<DateTime<Utc>>,
+    /// Only include links bookmarked before this time.
+    pub to: Option<DateTime<Utc>>,
+    /// Only include links on this domain, ignoring a leading `www.`.
+    pub domain: Option<String>,
+    pub has_note: Option<bool>,
+    /// Comma separated tag names. Only links with all of them are included.
+    pub tags: Option<String>,
+    /// Only include links directly in this collection.
+    pub collection: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LinksPage {
+    pub links: Vec<LinkResponse>,
+    /// Pass this back as `cursor` to get the next page. `None` on the last page.
+    pub next_cursor: Option<String>,
+}
+
+/// Position of the last link of a page. It is handed to clients as an opaque
+/// base58 string and only makes sense with the same sort and order.
+#[derive(Debug, Deserialize, Serialize)]
+struct LinksCursor {
+    sort: LinkSort,
+    order: SortOrder,
+    value: String,
+    id: String,
+}
+
+impl LinksCursor {
+    fn from_link(link: &LinkDBResult, sort: LinkSort, order: SortOrder) -> Self {
+        let value = match sort {
+            LinkSort::BookmarkedAt => link.bookmarked_at.to_rfc3339(),
+            LinkSort::Title => link.title.clone(),
+        };
+
+        Self {
+            sort,
+            order,
+            value,
+            id: link.id.to_string(),
+        }
+    }
+
+    fn encode(&self) -> String {
+        bs58::encode(serde_json::to_vec(self).unwrap_or_default()).into_string()
+    }
+
+    fn decode(cursor: &str) -> Result<Self> {
+        bs58::decode(cursor)
+            .into_vec()
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .ok_or(Error::InvalidLinksCursor)
+    }
+}
+
+/// Number of links loaded from the DB at a time while exporting.
+pub const EXPORT_PAGE_SIZE: usize = 500;
+
+#[derive(Debug, Deserialize)]
+pub struct ExportParams {
+    pub format: ExportFormat,
+}
+
+#[tracing::instrument(
+    name = "Exporting links",
+    skip(ctx, app_state),
+    fields(
+        user_id = %ctx.user_id(),
+    )
+)]
+async fn export_links(
+    ctx: Ctx,
+    State(app_state): State<AppState>,
+    Query(params): Query<ExportParams>,
+) -> Result<Response> {
+    ctx.require_scope(Scope::LinksRead)?;
+
+    let format = params.format;
+    let user = thing(ctx.user_id()).expect("Failed to convert ctx user_id to thing");
+
+    // The links are written to the response one page at a time as they're
+    // loaded, so the whole export never has to be held in memory.
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        if sender
+            .send_data(Bytes::from(format.header()))
+            .await
+            .is_err()
+        {
+            return;
+        }
+
+        let mut query = LinkQuery {
+            limit: EXPORT_PAGE_SIZE,
+            ..Default::default()
+        };
+        loop {
+            let links = match app_state.links.list(&user, &query).await {
+                Ok(links) => links,
+                Err(_) => {
+                    sender.abort();
+                    return;
+                }
+            };
+            let is_last_page = links.len() < EXPORT_PAGE_SIZE;
+            query.after = links.last().map(|link| LinkPosition::of(link, query.sort));
+
+            let chunk: String = links
+                .into_iter()
+                .map(|link| format.row(&LinkResponse::from(link)))
+                .collect();
+            if sender.send_data(Bytes::from(chunk)).await.is_err() {
+                return;
+            }
+            if is_last_page {
+                break;
+            }
+        }
+
+        let _ = sender.send_data(Bytes::from(format.footer())).await;
+    });
+
+    let headers = [
+        (header::CONTENT_TYPE, format.content_type().to_string()),
+        (
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"linkstowr-links.{}\"",
+                format.file_extension()
+            ),
+        ),
+    ];
+
+    Ok((headers, body).into_response())
 }
 
 #[tracing::instrument(
@@ -73,55 +607,331 @@ pub struct LinkResponse {
         user_id = %ctx.user_id(),
     )
 )]
-async fn get_links(ctx: Ctx, State(app_state): State<AppState>) -> Result<Json<Vec<LinkResponse>>> {
-    let mut result = app_state
-        .db
-        .query("SELECT * FROM link WHERE user.id = $user_id;")
-        .bind(("user_id", thing(ctx.user_id()).unwrap()))
-        .await
-        .map_err(|e| {
-            error!("Encountered error {:?}", e);
-            Error::GetLinksFail
-        })?;
+async fn get_links(
+    ctx: Ctx,
+    State(app_state): State<AppState>,
+    Query(params): Query<LinksParams>,
+) -> Result<Json<LinksPage>> {
+    ctx.require_scope(Scope::LinksRead)?;
+
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_LINKS_LIMIT)
+        .clamp(1, MAX_LINKS_LIMIT);
+    let tags: Vec<String> = params
+        .tags
+        .as_deref()
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(normalize_tag)
+        .collect();
+    let collection = match &params.collection {
+        Some(collection) => Some(check_collection(&app_state, ctx.user_id(), collection).await?),
+        None => None,
+    };
+
+    // Keyset pagination: continue right after the (sort value, id) of the last
+    // link on the previous page.
+    let cursor = params
+        .cursor
+        .as_deref()
+        .map(LinksCursor::decode)
+        .transpose()?;
+    let after = match cursor {
+        Some(cursor) => {
+            if cursor.sort != params.sort || cursor.order != params.order {
+                return Err(Error::InvalidLinksCursor);
+            }
+            let value = match cursor.sort {
+                LinkSort::BookmarkedAt => {
+                    let bookmarked_at = DateTime::parse_from_rfc3339(&cursor.value)
+                        .map_err(|_| Error::InvalidLinksCursor)?
+                        .with_timezone(&Utc);
+                    LinkSortValue::BookmarkedAt(bookmarked_at)
+                }
+                LinkSort::Title => LinkSortValue::Title(cursor.value),
+            };
+            let id = parse_link_id(&cursor.id).map_err(|_| Error::InvalidLinksCursor)?;
+            Some(LinkPosition { value, id })
+        }
+        None => None,
+    };
 
-    let links: Vec<LinkResponse> = result.take(0).map_err(|e| {
-        error!("Encountered error {:?}", e);
-        Error::GetLinksFail
-    })?;
+    let query = LinkQuery {
+        sort: params.sort,
+        order: params.order,
+        from: params.from,
+        to: params.to,
+        domain: params.domain.map(|domain| domain.to_lowercase()),
+        tags,
+        collection,
+        has_note: params.has_note,
+        after,
+        limit: limit + 1,
+    };
+    let user_id = thing(ctx.user_id()).expect("Failed to convert ctx user_id to thing");
+    let mut links = app_state.links.list(&user_id, &query).await?;
 
-    let body = Json(links);
+    // One extra link was fetched to know whether there is another page
+    let next_cursor = if links.len() > limit {
+        links.truncate(limit);
+        links
+            .last()
+            .map(|link| LinksCursor::from_link(link, params.sort, params.order).encode())
+    } else {
+        None
+    };
+
+    let body = Json(LinksPage {
+        links: links.into_iter().map(LinkResponse::from).collect(),
+        next_cursor,
+    });
 
     Ok(body)
 }
 
+const DEFAULT_SEARCH_LIMIT: usize = 20;
+const MAX_SEARCH_LIMIT: usize = 100;
+/// Phrases and prefixes are checked after loading the candidates, so they are
+/// loaded in batches of this many times the limit until the page is full.
+const SEARCH_CANDIDATES_FACTOR: usize = 5;
+
+#[derive(Debug, Deserialize)]
+pub struct SearchParams {
+    /// Words to search for. `"quoted words"` must appear as a phrase and
+    /// `word*` matches any word starting with `word`.
+    pub q: String,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SearchHighlights {
+    /// The full title with the matches wrapped in `<mark>`.
+    pub title: String,
+    /// Part of the note around the first match, if the note matches.
+    pub note: Option<String>,
+    /// The URL with the matches wrapped in `<mark>`, if the URL matches.
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SearchResult {
+    pub link: LinkResponse,
+    pub score: f64,
+    pub highlights: SearchHighlights,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SearchResults {
+    pub results: Vec<SearchResult>,
+}
+
 #[tracing::instrument(
-    name = "Clearing links",
+    name = "Searching links",
     skip(ctx, app_state),
     fields(
         user_id = %ctx.user_id(),
     )
 )]
-async fn clear_links(ctx: Ctx, State(app_state): State<AppState>) -> Result<Json<Value>> {
-    let mut result = app_state
-        .db
-        .query("DELETE link WHERE user.id = $user_id;")
-        .bind(("user_id", ctx.user_id()))
-        .await
-        .map_err(|e| {
-            error!("Encountered error {:?}", e);
-            Error::ClearLinksFail
-        })?;
+async fn search_links(
+    ctx: Ctx,
+    State(app_state): State<AppState>,
+    Query(params): Query<SearchParams>,
+) -> Result<Json<SearchResults>> {
+    ctx.require_scope(Scope::LinksRead)?;
 
-    let deleted: surrealdb::Result<Vec<Link>> = result.take(0);
+    let search_query = SearchQuery::parse(&params.q);
+    if search_query.is_empty() {
+        return Err(Error::InvalidSearchQuery);
+    }
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .clamp(1, MAX_SEARCH_LIMIT);
 
-    match deleted {
-        Ok(_) => {
-            let body = Json(json!({
-                "success": true,
-            }));
+    let user_id = thing(ctx.user_id()).expect("Failed to convert ctx user_id to thing");
+    let batch_size = limit * SEARCH_CANDIDATES_FACTOR;
+    let mut results = vec![];
+    let mut start = 0;
+    while results.len() < limit {
+        let candidates = app_state
+            .links
+            .search(&user_id, &search_query, start, batch_size)
+            .await?;
+        let exhausted = candidates.len() < batch_size;
+        let remaining = limit - results.len();
+        start += candidates.len();
 
-            Ok(body)
+        results.extend(
+            candidates
+                .into_iter()
+                .filter(|candidate| {
+                    let link = &candidate.link;
+                    search_query.matches(&[&link.title, &link.note, &link.url])
+                })
+                .take(remaining)
+                .map(|candidate| {
+                    let highlights = SearchHighlights {
+                        title: search_query.highlight(&candidate.link.title),
+                        note: search_query.snippet(&candidate.link.note),
+                        url: search_query
+                            .snippet(&candidate.link.url)
+                            .map(|_| search_query.highlight(&candidate.link.url)),
+                    };
+
+                    SearchResult {
+                        link: candidate.link.into(),
+                        score: candidate.score,
+                        highlights,
+                    }
+                }),
+        );
+
+        if exhausted {
+            break;
         }
-        Err(_) => Err(Error::ClearLinksFail),
     }
+
+    Ok(Json(SearchResults { results }))
+}
+
+#[tracing::instrument(
+    name = "Getting a link",
+    skip(ctx, app_state),
+    fields(
+        user_id = %ctx.user_id(),
+    )
+)]
+async fn get_link(
+    ctx: Ctx,
+    State(app_state): State<AppState>,
+    Path(link_id): Path<String>,
+) -> Result<Json<LinkResponse>> {
+    ctx.require_scope(Scope::LinksRead)?;
+
+    let link_id = parse_link_id(&link_id)?;
+
+    let user_id = thing(ctx.user_id()).expect("Failed to convert ctx user_id to thing");
+    let link = app_state.links.get(&user_id, &link_id).await?;
+    let link = link.ok_or(Error::LinkNotFound)?;
+
+    Ok(Json(link.into()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateLinkPayload {
+    pub url: Option<String>,
+    pub title: Option<String>,
+    pub note: Option<String>,
+    /// Replaces all of the link's tags when present.
+    pub tags: Option<Vec<String>>,
+    /// `null` takes the link out of its collection.
+    #[serde(default, with = "::serde_with::rust::double_option")]
+    pub collection: Option<Option<String>>,
+}
+
+#[tracing::instrument(
+    name = "Updating a link",
+    skip(ctx, app_state, payload),
+    fields(
+        user_id = %ctx.user_id(),
+    )
+)]
+async fn update_link(
+    ctx: Ctx,
+    State(app_state): State<AppState>,
+    Path(link_id): Path<String>,
+    Json(payload): Json<UpdateLinkPayload>,
+) -> Result<Json<LinkResponse>> {
+    ctx.require_scope(Scope::LinksWrite)?;
+
+    let link_id = parse_link_id(&link_id)?;
+
+    let tags = match payload.tags {
+        Some(tags) => Some(resolve_tags(&app_state, ctx.user_id(), &tags).await?),
+        None => None,
+    };
+    let collection = match payload.collection {
+        Some(Some(collection)) => Some(Some(
+            check_collection(&app_state, ctx.user_id(), &collection).await?,
+        )),
+        Some(None) => Some(None),
+        None => None,
+    };
+    let canonical_url = match &payload.url {
+        Some(url) => {
+            let canonical_url = app_state.url_canonicalizer.canonicalize(url);
+            if let Some(existing) =
+                find_duplicate(&app_state, ctx.user_id(), &canonical_url, Some(&link_id)).await?
+            {
+                return Err(Error::LinkExists(existing.id.to_string()));
+            }
+            Some(canonical_url)
+        }
+        None => None,
+    };
+    let changes = LinkChanges {
+        url: payload.url,
+        canonical_url,
+        title: payload.title,
+        note: payload.note,
+        tags,
+        collection,
+    };
+
+    let user_id = thing(ctx.user_id()).expect("Failed to convert ctx user_id to thing");
+    let updated = app_state.links.update(&user_id, &link_id, changes).await?;
+    let updated = updated.ok_or(Error::LinkNotFound)?;
+
+    Ok(Json(updated.into()))
+}
+
+#[tracing::instrument(
+    name = "Deleting a link",
+    skip(ctx, app_state),
+    fields(
+        user_id = %ctx.user_id(),
+    )
+)]
+async fn delete_link(
+    ctx: Ctx,
+    State(app_state): State<AppState>,
+    Path(link_id): Path<String>,
+) -> Result<Json<Value>> {
+    ctx.require_scope(Scope::LinksWrite)?;
+
+    let link_id = parse_link_id(&link_id)?;
+
+    let user_id = thing(ctx.user_id()).expect("Failed to convert ctx user_id to thing");
+    let deleted = app_state.links.delete(&user_id, &link_id).await?;
+    let deleted = deleted.ok_or(Error::LinkNotFound)?;
+
+    let body = Json(json!({
+        "result": {
+            "id": deleted.id.to_string(),
+            "success": true,
+        }
+    }));
+
+    Ok(body)
+}
+
+#[tracing::instrument(
+    name = "Clearing links",
+    skip(ctx, app_state),
+    fields(
+        user_id = %ctx.user_id(),
+    )
+)]
+async fn clear_links(ctx: Ctx, State(app_state): State<AppState>) -> Result<Json<Value>> {
+    ctx.require_scope(Scope::LinksClear)?;
+
+    let user_id = thing(ctx.user_id()).expect("Failed to convert ctx user_id to thing");
+    app_state.links.clear(&user_id).await?;
+
+    let body = Json(json!({
+        "success": true,
+    }));
+
+    Ok(body)
 }
@@ -0,0 +1,42 @@
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use sha2::{Digest, Sha256};
+
+use crate::types::AppState;
+
+/// Only served when a token to call it with is configured.
+pub fn routes(state: AppState) -> Router {
+    if state.metrics_token.is_none() {
+        return Router::new();
+    }
+
+    Router::new()
+        .route("/metrics", get(metrics))
+        .with_state(state)
+}
+
+/// All metrics in the Prometheus text format.
+async fn metrics(
+    State(app_state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, StatusCode> {
+    let bearer_token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match (bearer_token, &app_state.metrics_token) {
+        // Comparing digests doesn't take longer the more of the token matches
+        (Some(given), Some(expected))
+            if Sha256::digest(given.as_bytes()) == Sha256::digest(expected.as_bytes()) => {}
+        _ => return Err(StatusCode::UNAUTHORIZED),
+    }
+
+    let body = app_state.metrics.render(&app_state.token_cache.stats());
+
+    Ok(([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body))
+}
@@ -1,6 +1,12 @@
+pub mod account;
 pub mod auth;
+pub mod collection_routes;
 mod health_check;
+pub mod import_routes;
 pub mod link_routes;
+pub mod metrics;
+pub mod sync_routes;
+pub mod tag_routes;
 pub mod token;
 
 pub use health_check::*;
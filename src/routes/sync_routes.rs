@@ -0,0 +1,514 @@
+//! Per-device sync protocol.
+//!
+//! Each client registers a device and keeps a cursor, which is the position in
+//! (`updated_at`, id) order of the last batch of changes it has acknowledged.
+//! Links are never removed as part of syncing; deletions are reported through
+//! the tombstones the link repository records whenever a link is deleted.
+
+use axum::{
+    extract::{Path, Query, State},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use surrealdb::sql::{thing, Datetime, Thing};
+use tracing::error;
+
+use crate::{
+    ctx::{Ctx, Scope},
+    error::{Error, Result},
+    repository,
+    routes::link_routes::LinkResponse,
+    types::{AppState, Device, DeviceDBResult, LinkDBResult},
+};
+
+const DEFAULT_CHANGES_LIMIT: usize = 500;
+const MAX_CHANGES_LIMIT: usize = 1000;
+
+pub fn routes(state: AppState) -> Router {
+    Router::new()
+        .route("/devices", post(create_device).get(get_devices))
+        .route("/devices/:id", delete(delete_device))
+        .route("/devices/:id/changes", get(get_changes))
+        .route("/devices/:id/ack", post(ack_changes))
+        .with_state(state)
+}
+
+fn parse_device_id(device_id: &str) -> Result<Thing> {
+    match thing(device_id) {
+        Ok(device_id) if device_id.tb == "device" => Ok(device_id),
+        _ => Err(Error::InvalidDeviceId),
+    }
+}
+
+async fn get_device_for_user(
+    app_state: &AppState,
+    device_id: Thing,
+    user_id: &str,
+) -> Result<DeviceDBResult> {
+    let mut result = app_state
+        .db
+        .query("SELECT * FROM device WHERE id = $device_id AND user = $user_id;")
+        .bind(("device_id", device_id))
+        .bind(("user_id", thing(user_id).unwrap()))
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::GetDevicesFail
+        })?;
+
+    let device: Option<DeviceDBResult> = result.take(0).map_err(|e| {
+        error!("Encountered error {:?}", e);
+        Error::GetDevicesFail
+    })?;
+
+    device.ok_or(Error::DeviceNotFound)
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateDevicePayload {
+    name: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DeviceResponse {
+    pub id: String,
+    pub name: String,
+    pub cursor: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub last_synced_at: Option<DateTime<Utc>>,
+}
+
+impl From<DeviceDBResult> for DeviceResponse {
+    fn from(device: DeviceDBResult) -> Self {
+        Self {
+            id: device.id.to_string(),
+            name: device.name,
+            cursor: device.cursor,
+            created_at: device.created_at,
+            last_synced_at: device.last_synced_at,
+        }
+    }
+}
+
+#[tracing::instrument(
+    name = "Registering a device",
+    skip(ctx, app_state),
+    fields(
+        user_id = %ctx.user_id(),
+    )
+)]
+async fn create_device(
+    ctx: Ctx,
+    State(app_state): State<AppState>,
+    Json(payload): Json<CreateDevicePayload>,
+) -> Result<Json<DeviceResponse>> {
+    ctx.require_scope(Scope::LinksWrite)?;
+
+    let created: Vec<DeviceDBResult> = app_state
+        .db
+        .create("device")
+        .content(Device {
+            name: payload.name,
+            user: thing(ctx.user_id()).expect("Failed to convert ctx user_id to thing"),
+        })
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::CreateDeviceFail
+        })?;
+
+    let created = created.into_iter().next().ok_or(Error::CreateDeviceFail)?;
+
+    Ok(Json(created.into()))
+}
+
+#[tracing::instrument(
+    name = "Getting devices",
+    skip(ctx, app_state),
+    fields(
+        user_id = %ctx.user_id(),
+    )
+)]
+async fn get_devices(
+    ctx: Ctx,
+    State(app_state): State<AppState>,
+) -> Result<Json<Vec<DeviceResponse>>> {
+    ctx.require_scope(Scope::LinksRead)?;
+
+    let mut result = app_state
+        .db
+        .query("SELECT * FROM device WHERE user = $user_id ORDER BY created_at ASC;")
+        .bind(("user_id", thing(ctx.user_id()).unwrap()))
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::GetDevicesFail
+        })?;
+
+    let devices: Vec<DeviceDBResult> = result.take(0).map_err(|e| {
+        error!("Encountered error {:?}", e);
+        Error::GetDevicesFail
+    })?;
+
+    Ok(Json(
+        devices.into_iter().map(DeviceResponse::from).collect(),
+    ))
+}
+
+#[tracing::instrument(
+    name = "Deleting a device",
+    skip(ctx, app_state),
+    fields(
+        user_id = %ctx.user_id(),
+    )
+)]
+async fn delete_device(
+    ctx: Ctx,
+    State(app_state): State<AppState>,
+    Path(device_id): Path<String>,
+) -> Result<Json<Value>> {
+    ctx.require_scope(Scope::LinksWrite)?;
+
+    let device_id = parse_device_id(&device_id)?;
+
+    let mut result = app_state
+        .db
+        .query("DELETE $device_id WHERE user = $user_id RETURN BEFORE;")
+        .bind(("device_id", device_id))
+        .bind(("user_id", thing(ctx.user_id()).unwrap()))
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::DeleteDeviceFail
+        })?;
+
+    let deleted: Option<DeviceDBResult> = result.take(0).map_err(|e| {
+        error!("Encountered error {:?}", e);
+        Error::DeleteDeviceFail
+    })?;
+    deleted.ok_or(Error::DeviceNotFound)?;
+
+    let body = Json(json!({
+        "success": true,
+    }));
+
+    Ok(body)
+}
+
+#[derive(Debug, Deserialize)]
+struct ChangesParams {
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChangesResponse {
+    /// Links created or updated since the device cursor.
+    pub links: Vec<LinkResponse>,
+    /// Ids of links deleted since the device cursor.
+    pub deleted: Vec<String>,
+    /// The cursor to acknowledge once this batch has been stored by the client.
+    pub cursor: String,
+    /// Whether more changes are available past `cursor`.
+    pub has_more: bool,
+}
+
+/// How far a device got in the links and tombstones, each ordered by
+/// (timestamp, id). It is handed to clients as an opaque base58 string.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct SyncCursor {
+    links: Option<ChangePosition>,
+    deleted: Option<ChangePosition>,
+}
+
+/// The last change a device has seen. Without an id every change at `at` has
+/// been seen, which is what cursors from before ids were recorded mean.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+struct ChangePosition {
+    at: DateTime<Utc>,
+    id: Option<String>,
+}
+
+impl ChangePosition {
+    fn new(at: DateTime<Utc>, id: &Thing) -> Self {
+        Self {
+            at,
+            id: Some(id.to_string()),
+        }
+    }
+
+    fn watermark(at: DateTime<Utc>) -> Self {
+        Self { at, id: None }
+    }
+
+    /// Orders positions by how far they got, a position without an id being
+    /// past every id at the same time.
+    fn sort_key(&self) -> (DateTime<Utc>, bool, Option<&str>) {
+        (self.at, self.id.is_none(), self.id.as_deref())
+    }
+
+    /// The position as the link repository takes it, failing on ids that
+    /// clients have tampered with.
+    fn parse(&self) -> Result<repository::ChangePosition> {
+        let id = self
+            .id
+            .as_deref()
+            .map(|id| thing(id).map_err(|_| Error::InvalidSyncCursor))
+            .transpose()?;
+
+        Ok(repository::ChangePosition { at: self.at, id })
+    }
+}
+
+impl SyncCursor {
+    /// Where the device is at. Devices that acknowledged before cursors had ids
+    /// only have a watermark, and a device that has never synced gets every
+    /// link but no deletions from before it was registered.
+    fn of_device(device: &DeviceDBResult) -> Result<Self> {
+        if let Some(sync_cursor) = &device.sync_cursor {
+            return Self::decode(sync_cursor);
+        }
+
+        Ok(Self {
+            links: device.cursor.map(ChangePosition::watermark),
+            deleted: Some(ChangePosition::watermark(
+                device.cursor.unwrap_or(device.created_at),
+            )),
+        })
+    }
+
+    /// Whether the cursor got no further than `limit` in both the links and
+    /// the tombstones.
+    fn is_within(&self, limit: &SyncCursor) -> bool {
+        fn within(position: &Option<ChangePosition>, limit: &Option<ChangePosition>) -> bool {
+            match (position, limit) {
+                (None, _) => true,
+                (Some(_), None) => false,
+                (Some(position), Some(limit)) => position.sort_key() <= limit.sort_key(),
+            }
+        }
+
+        within(&self.links, &limit.links) && within(&self.deleted, &limit.deleted)
+    }
+
+    /// The most recent time the cursor has reached, shown on the device.
+    fn latest(&self) -> Option<DateTime<Utc>> {
+        self.links
+            .iter()
+            .chain(self.deleted.iter())
+            .map(|position| position.at)
+            .max()
+    }
+
+    fn encode(&self) -> String {
+        bs58::encode(serde_json::to_vec(self).unwrap_or_default()).into_string()
+    }
+
+    fn decode(cursor: &str) -> Result<Self> {
+        bs58::decode(cursor)
+            .into_vec()
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .ok_or(Error::InvalidSyncCursor)
+    }
+}
+
+#[tracing::instrument(
+    name = "Getting changes for device",
+    skip(ctx, app_state),
+    fields(
+        user_id = %ctx.user_id(),
+    )
+)]
+async fn get_changes(
+    ctx: Ctx,
+    State(app_state): State<AppState>,
+    Path(device_id): Path<String>,
+    Query(params): Query<ChangesParams>,
+) -> Result<Json<ChangesResponse>> {
+    ctx.require_scope(Scope::LinksRead)?;
+
+    let device_id = parse_device_id(&device_id)?;
+    let device = get_device_for_user(&app_state, device_id, ctx.user_id()).await?;
+    let cursor = SyncCursor::of_device(&device)?;
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_CHANGES_LIMIT)
+        .clamp(1, MAX_CHANGES_LIMIT);
+
+    // Keyset pagination over (updated_at, id) and (deleted_at, id), so changes
+    // sharing a timestamp are never skipped. One extra change is fetched of
+    // each kind to know whether there is more.
+    let user_id = thing(ctx.user_id()).expect("Failed to convert ctx user_id to thing");
+    let links_after = cursor
+        .links
+        .as_ref()
+        .map(ChangePosition::parse)
+        .transpose()?;
+    let deleted_after = cursor
+        .deleted
+        .as_ref()
+        .map(ChangePosition::parse)
+        .transpose()?;
+    let mut links = app_state
+        .links
+        .list_changed(&user_id, links_after.as_ref(), limit + 1)
+        .await?;
+    let mut tombstones = app_state
+        .links
+        .list_deleted(&user_id, deleted_after.as_ref(), limit + 1)
+        .await?;
+
+    let has_more = links.len() > limit || tombstones.len() > limit;
+    links.truncate(limit);
+    tombstones.truncate(limit);
+
+    let next_cursor = SyncCursor {
+        links: links
+            .last()
+            .map(|link| ChangePosition::new(link_changed_at(link), &link.id))
+            .or(cursor.links),
+        deleted: tombstones
+            .last()
+            .map(|tombstone| ChangePosition::new(tombstone.deleted_at, &tombstone.id))
+            .or(cursor.deleted),
+    };
+
+    // Devices can only acknowledge as far as they were served
+    let served_cursor = next_cursor.encode();
+    app_state
+        .db
+        .query("UPDATE $device_id SET served_cursor = $served_cursor;")
+        .bind(("device_id", &device.id))
+        .bind(("served_cursor", &served_cursor))
+        .await
+        .and_then(|response| response.check())
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::GetChangesFail
+        })?;
+
+    let body = Json(ChangesResponse {
+        links: links.into_iter().map(LinkResponse::from).collect(),
+        deleted: tombstones
+            .into_iter()
+            .map(|tombstone| tombstone.link.to_string())
+            .collect(),
+        cursor: served_cursor,
+        has_more,
+    });
+
+    Ok(body)
+}
+
+fn link_changed_at(link: &LinkDBResult) -> DateTime<Utc> {
+    link.updated_at.unwrap_or(link.bookmarked_at)
+}
+
+#[derive(Debug, Deserialize)]
+struct AckPayload {
+    cursor: String,
+}
+
+#[tracing::instrument(
+    name = "Acknowledging changes for device",
+    skip(ctx, app_state),
+    fields(
+        user_id = %ctx.user_id(),
+    )
+)]
+async fn ack_changes(
+    ctx: Ctx,
+    State(app_state): State<AppState>,
+    Path(device_id): Path<String>,
+    Json(payload): Json<AckPayload>,
+) -> Result<Json<DeviceResponse>> {
+    ctx.require_scope(Scope::LinksWrite)?;
+
+    let device_id = parse_device_id(&device_id)?;
+    let device = get_device_for_user(&app_state, device_id.clone(), ctx.user_id()).await?;
+    let now = Utc::now();
+
+    // A cursor past the changes the device was served would skip the ones in
+    // between. Devices that weren't served any can only stay where they are.
+    let cursor = SyncCursor::decode(&payload.cursor)?;
+    let served = match &device.served_cursor {
+        Some(served_cursor) => SyncCursor::decode(served_cursor)?,
+        None => SyncCursor::of_device(&device)?,
+    };
+    if !cursor.is_within(&served) {
+        return Err(Error::InvalidSyncCursor);
+    }
+    for position in cursor.links.iter().chain(cursor.deleted.iter()) {
+        position.parse()?;
+    }
+
+    let mut result = app_state
+        .db
+        .query(
+            "UPDATE $device_id SET cursor = $cursor, sync_cursor = $sync_cursor, \
+                last_synced_at = $now WHERE user = $user_id;",
+        )
+        .bind(("device_id", device_id))
+        .bind(("cursor", cursor.latest().map(Datetime::from)))
+        .bind(("sync_cursor", cursor.encode()))
+        .bind(("now", Datetime::from(now)))
+        .bind(("user_id", thing(ctx.user_id()).unwrap()))
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::AckChangesFail
+        })?;
+
+    let device: Option<DeviceDBResult> = result.take(0).map_err(|e| {
+        error!("Encountered error {:?}", e);
+        Error::AckChangesFail
+    })?;
+    let device = device.ok_or(Error::DeviceNotFound)?;
+
+    prune_tombstones(&app_state, ctx.user_id()).await?;
+
+    Ok(Json(device.into()))
+}
+
+/// Once every device of the user has acknowledged past a tombstone there is
+/// nobody left to tell about the deletion, so it can be dropped.
+async fn prune_tombstones(app_state: &AppState, user_id: &str) -> Result<()> {
+    let mut result = app_state
+        .db
+        .query("SELECT * FROM device WHERE user = $user_id;")
+        .bind(("user_id", thing(user_id).unwrap()))
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::AckChangesFail
+        })?;
+
+    let devices: Vec<DeviceDBResult> = result.take(0).map_err(|e| {
+        error!("Encountered error {:?}", e);
+        Error::AckChangesFail
+    })?;
+
+    // Every device has seen the tombstones up to its own deleted position, and
+    // never gets the ones from before it was registered.
+    let mut positions = Vec::with_capacity(devices.len());
+    for device in &devices {
+        let position = SyncCursor::of_device(device)?
+            .deleted
+            .unwrap_or(ChangePosition::watermark(device.created_at));
+        positions.push(position);
+    }
+    let Some(oldest) = positions
+        .into_iter()
+        .min_by(|a, b| a.sort_key().cmp(&b.sort_key()))
+    else {
+        return Ok(());
+    };
+
+    app_state
+        .links
+        .prune_tombstones(&thing(user_id).unwrap(), &oldest.parse()?)
+        .await
+}
@@ -0,0 +1,449 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Path, State},
+    routing::{get, patch, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{thing, Datetime, Thing};
+use tracing::error;
+
+use crate::{
+    ctx::{Ctx, Scope},
+    error::{Error, Result},
+    types::{AppState, Tag, TagDBResult},
+};
+
+pub fn routes(state: AppState) -> Router {
+    Router::new()
+        .route("/tags", get(get_tags))
+        .route("/tags/:id", patch(rename_tag))
+        .route("/tags/merge", post(merge_tags))
+        .with_state(state)
+}
+
+fn parse_tag_id(tag_id: &str) -> Result<Thing> {
+    match thing(tag_id) {
+        Ok(tag_id) if tag_id.tb == "tag" => Ok(tag_id),
+        _ => Err(Error::InvalidTagId),
+    }
+}
+
+/// Tags are case insensitive and may be written with a leading `#`, so
+/// `#Rust` and `rust` are the same tag.
+pub fn normalize_tag(name: &str) -> Option<String> {
+    let name = name.trim().trim_start_matches('#').trim().to_lowercase();
+
+    match name.is_empty() {
+        true => None,
+        false => Some(name),
+    }
+}
+
+/// The user's tags by normalized name, as returned by `resolve_tag_names`.
+#[derive(Debug, Default)]
+pub struct ResolvedTags {
+    ids: HashMap<String, Thing>,
+    /// Tags that didn't exist yet, which `remove_unused_tags` takes back.
+    created: Vec<Thing>,
+    /// When the last of `created` was created.
+    created_at: Option<DateTime<Utc>>,
+}
+
+impl ResolvedTags {
+    /// Ids of the tags named `names`, in order and without repeats.
+    pub fn ids(&self, names: &[String]) -> Vec<Thing> {
+        let mut ids: Vec<Thing> = Vec::with_capacity(names.len());
+        for name in names.iter().filter_map(|name| normalize_tag(name)) {
+            if let Some(id) = self.ids.get(&name) {
+                if !ids.contains(id) {
+                    ids.push(id.clone());
+                }
+            }
+        }
+
+        ids
+    }
+}
+
+/// How many times tags are looked up and created when other requests create
+/// some of the same ones at the same time.
+const TAG_CREATE_ATTEMPTS: usize = 3;
+
+/// A tag as returned when it was created.
+#[derive(Debug, Deserialize)]
+struct CreatedTag {
+    id: Thing,
+    name: String,
+    resolved_at: DateTime<Utc>,
+}
+
+/// Looks up the user's tags with the given names and creates the ones that
+/// don't exist yet, all in one go. The existing ones are marked as resolved,
+/// so that `remove_unused_tags` of another request leaves them alone.
+pub async fn resolve_tag_names<'a>(
+    app_state: &AppState,
+    user_id: &str,
+    names: impl IntoIterator<Item = &'a String>,
+) -> Result<ResolvedTags> {
+    let mut normalized: Vec<String> = vec![];
+    for name in names.into_iter().filter_map(|name| normalize_tag(name)) {
+        if !normalized.contains(&name) {
+            normalized.push(name);
+        }
+    }
+    let names = normalized;
+
+    let user = thing(user_id).expect("Failed to convert ctx user_id to thing");
+    let mut resolved = ResolvedTags::default();
+    for _ in 0..TAG_CREATE_ATTEMPTS {
+        let missing: Vec<String> = names
+            .iter()
+            .filter(|name| !resolved.ids.contains_key(*name))
+            .cloned()
+            .collect();
+        if missing.is_empty() {
+            return Ok(resolved);
+        }
+
+        let mut result = app_state
+            .db
+            .query(
+                "UPDATE tag SET resolved_at = time::now() WHERE user = $user_id AND name IN $names;",
+            )
+            .bind(("user_id", &user))
+            .bind(("names", &missing))
+            .await
+            .map_err(|e| {
+                error!("Encountered error {:?}", e);
+                Error::ResolveTagsFail
+            })?;
+        let existing: Vec<TagDBResult> = result.take(0).map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::ResolveTagsFail
+        })?;
+        for tag in existing {
+            resolved.ids.insert(tag.name, tag.id);
+        }
+
+        let missing: Vec<Tag> = missing
+            .into_iter()
+            .filter(|name| !resolved.ids.contains_key(name))
+            .map(|name| Tag {
+                name,
+                user: user.clone(),
+            })
+            .collect();
+        if missing.is_empty() {
+            return Ok(resolved);
+        }
+
+        // The unique index rejects the tags another request created in the
+        // meantime, which the next attempt finds instead
+        match create_tags(app_state, missing).await {
+            Ok(created) => {
+                for tag in created {
+                    resolved.created.push(tag.id.clone());
+                    resolved.created_at = resolved.created_at.max(Some(tag.resolved_at));
+                    resolved.ids.insert(tag.name, tag.id);
+                }
+                return Ok(resolved);
+            }
+            Err(e) => error!("Encountered error {:?}", e),
+        }
+    }
+
+    Err(Error::ResolveTagsFail)
+}
+
+async fn create_tags(
+    app_state: &AppState,
+    tags: Vec<Tag>,
+) -> std::result::Result<Vec<CreatedTag>, surrealdb::Error> {
+    let statements: String = (0..tags.len())
+        .map(|index| format!("CREATE tag CONTENT $tag_{index};"))
+        .collect();
+    let count = tags.len();
+    let mut query = app_state.db.query(format!(
+        "BEGIN TRANSACTION; {statements} COMMIT TRANSACTION;"
+    ));
+    for (index, tag) in tags.into_iter().enumerate() {
+        query = query.bind((format!("tag_{index}"), tag));
+    }
+    let mut result = query.await?;
+
+    let mut created = Vec::with_capacity(count);
+    for index in 0..count {
+        let tag: Option<CreatedTag> = result.take(index)?;
+        created.extend(tag);
+    }
+
+    Ok(created)
+}
+
+/// Looks up the user's tags with the given names, creating the ones that don't
+/// exist yet, and returns their ids in the order the names were given.
+pub async fn resolve_tags(
+    app_state: &AppState,
+    user_id: &str,
+    names: &[String],
+) -> Result<Vec<Thing>> {
+    let resolved = resolve_tag_names(app_state, user_id, names).await?;
+
+    Ok(resolved.ids(names))
+}
+
+/// Removes the tags `resolve_tag_names` created that no link uses, like the
+/// ones of links that turned out to be duplicates or failed to be saved.
+/// Tags another request resolved since are kept, as it may be about to save a
+/// link with them. Errors are only logged, since the links themselves are fine.
+pub async fn remove_unused_tags(app_state: &AppState, user_id: &str, resolved: &ResolvedTags) {
+    let Some(created_at) = resolved.created_at else {
+        return;
+    };
+
+    let user_id = thing(user_id).unwrap();
+    let used = match app_state
+        .links
+        .tags_in_use(&user_id, &resolved.created)
+        .await
+    {
+        Ok(used) => used,
+        Err(e) => {
+            error!("Encountered error {:?}", e);
+            return;
+        }
+    };
+    let unused: Vec<&Thing> = resolved
+        .created
+        .iter()
+        .filter(|tag| !used.contains(tag))
+        .collect();
+    if unused.is_empty() {
+        return;
+    }
+
+    let result = app_state
+        .db
+        .query("DELETE tag WHERE user = $user_id AND id IN $unused AND resolved_at <= $created_at;")
+        .bind(("user_id", user_id))
+        .bind(("unused", unused))
+        .bind(("created_at", Datetime::from(created_at)))
+        .await
+        .and_then(|response| response.check());
+    if let Err(e) = result {
+        error!("Encountered error {:?}", e);
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TagResponse {
+    pub id: String,
+    pub name: String,
+    pub count: u64,
+}
+
+impl TagResponse {
+    /// The tag with its count from `LinkRepository::count_by_tag`.
+    fn new(tag: TagDBResult, counts: &HashMap<String, u64>) -> Self {
+        let id = tag.id.to_string();
+        Self {
+            count: counts.get(&id).copied().unwrap_or(0),
+            id,
+            name: tag.name,
+        }
+    }
+}
+
+async fn get_tag_with_count(
+    app_state: &AppState,
+    tag_id: Thing,
+    user_id: &str,
+) -> Result<TagResponse> {
+    let user_id = thing(user_id).unwrap();
+    let mut result = app_state
+        .db
+        .query("SELECT * FROM tag WHERE id = $tag_id AND user = $user_id;")
+        .bind(("tag_id", tag_id))
+        .bind(("user_id", &user_id))
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::GetTagsFail
+        })?;
+
+    let tag: Option<TagDBResult> = result.take(0).map_err(|e| {
+        error!("Encountered error {:?}", e);
+        Error::GetTagsFail
+    })?;
+    let tag = tag.ok_or(Error::TagNotFound)?;
+    let counts = app_state.links.count_by_tag(&user_id).await?;
+
+    Ok(TagResponse::new(tag, &counts))
+}
+
+#[tracing::instrument(
+    name = "Getting tags",
+    skip(ctx, app_state),
+    fields(
+        user_id = %ctx.user_id(),
+    )
+)]
+async fn get_tags(ctx: Ctx, State(app_state): State<AppState>) -> Result<Json<Vec<TagResponse>>> {
+    ctx.require_scope(Scope::LinksRead)?;
+
+    let user_id = thing(ctx.user_id()).unwrap();
+    let mut result = app_state
+        .db
+        .query("SELECT * FROM tag WHERE user = $user_id ORDER BY name ASC;")
+        .bind(("user_id", &user_id))
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::GetTagsFail
+        })?;
+
+    let tags: Vec<TagDBResult> = result.take(0).map_err(|e| {
+        error!("Encountered error {:?}", e);
+        Error::GetTagsFail
+    })?;
+    let counts = app_state.links.count_by_tag(&user_id).await?;
+
+    Ok(Json(
+        tags.into_iter()
+            .map(|tag| TagResponse::new(tag, &counts))
+            .collect(),
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+struct RenameTagPayload {
+    name: String,
+}
+
+#[tracing::instrument(
+    name = "Renaming a tag",
+    skip(ctx, app_state),
+    fields(
+        user_id = %ctx.user_id(),
+    )
+)]
+async fn rename_tag(
+    ctx: Ctx,
+    State(app_state): State<AppState>,
+    Path(tag_id): Path<String>,
+    Json(payload): Json<RenameTagPayload>,
+) -> Result<Json<TagResponse>> {
+    ctx.require_scope(Scope::LinksWrite)?;
+
+    let tag_id = parse_tag_id(&tag_id)?;
+    let name = normalize_tag(&payload.name).ok_or(Error::InvalidTagName)?;
+
+    let mut result = app_state
+        .db
+        .query("SELECT * FROM tag WHERE user = $user_id AND name = $name AND id != $tag_id;")
+        .bind(("user_id", thing(ctx.user_id()).unwrap()))
+        .bind(("name", &name))
+        .bind(("tag_id", &tag_id))
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::UpdateTagFail
+        })?;
+
+    let conflicting: Option<TagDBResult> = result.take(0).map_err(|e| {
+        error!("Encountered error {:?}", e);
+        Error::UpdateTagFail
+    })?;
+
+    if conflicting.is_some() {
+        // Renaming onto an existing tag is a merge, which has its own endpoint
+        return Err(Error::TagExists);
+    }
+
+    let user_id = thing(ctx.user_id()).unwrap();
+    let mut result = app_state
+        .db
+        .query("UPDATE $tag_id SET name = $name WHERE user = $user_id;")
+        .bind(("tag_id", &tag_id))
+        .bind(("name", &name))
+        .bind(("user_id", &user_id))
+        .await
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::UpdateTagFail
+        })?;
+
+    let renamed: Option<TagDBResult> = result.take(0).map_err(|e| {
+        error!("Encountered error {:?}", e);
+        Error::UpdateTagFail
+    })?;
+    renamed.ok_or(Error::TagNotFound)?;
+
+    // Links store the tag id so they don't need to change, but they are
+    // touched so that their `updated_at` moves and devices sync the new name.
+    app_state.links.touch_tagged(&user_id, &tag_id).await?;
+
+    let tag = get_tag_with_count(&app_state, tag_id, ctx.user_id()).await?;
+
+    Ok(Json(tag))
+}
+
+#[derive(Debug, Deserialize)]
+struct MergeTagsPayload {
+    /// The tag that is merged and then deleted.
+    source: String,
+    /// The tag that the source's links are moved to.
+    target: String,
+}
+
+#[tracing::instrument(
+    name = "Merging tags",
+    skip(ctx, app_state),
+    fields(
+        user_id = %ctx.user_id(),
+    )
+)]
+async fn merge_tags(
+    ctx: Ctx,
+    State(app_state): State<AppState>,
+    Json(payload): Json<MergeTagsPayload>,
+) -> Result<Json<TagResponse>> {
+    ctx.require_scope(Scope::LinksWrite)?;
+
+    let source = parse_tag_id(&payload.source)?;
+    let target = parse_tag_id(&payload.target)?;
+
+    if source == target {
+        return Err(Error::InvalidTagId);
+    }
+
+    // Make sure both tags exist and belong to the user before touching links
+    get_tag_with_count(&app_state, source.clone(), ctx.user_id()).await?;
+    get_tag_with_count(&app_state, target.clone(), ctx.user_id()).await?;
+
+    // The source is only deleted once no link has it anymore, so a failed
+    // merge can simply be retried.
+    let user_id = thing(ctx.user_id()).unwrap();
+    app_state
+        .links
+        .replace_tag(&user_id, &source, &target)
+        .await?;
+    app_state
+        .db
+        .query("DELETE $source WHERE user = $user_id;")
+        .bind(("source", source))
+        .bind(("user_id", &user_id))
+        .await
+        .and_then(|response| response.check())
+        .map_err(|e| {
+            error!("Encountered error {:?}", e);
+            Error::MergeTagsFail
+        })?;
+
+    let tag = get_tag_with_count(&app_state, target, ctx.user_id()).await?;
+
+    Ok(Json(tag))
+}
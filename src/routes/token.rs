@@ -2,26 +2,36 @@ use axum::extract::Path;
 use axum::routing::delete;
 use axum::Json;
 use axum::{extract::State, routing::post, Router};
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use surrealdb::sql::{thing, Thing};
-use tracing::error;
+use surrealdb::sql::{thing, Datetime, Thing};
 
-use crate::ctx::Ctx;
+use crate::ctx::{Ctx, Scope};
 use crate::error::{Error, Result};
 use crate::prefixed_api_key::{PrefixedApiKey, PrefixedApiKeyController};
-use crate::types::{AppState, Token};
+use crate::types::{AppState, Token, TokenDBResult};
+
+/// How long a rotated token keeps working by default, so that clients can be
+/// switched over to its replacement.
+const DEFAULT_ROTATION_GRACE_PERIOD_MINUTES: i64 = 60;
+const MAX_ROTATION_GRACE_PERIOD_MINUTES: i64 = 7 * 24 * 60;
 
 pub fn routes(state: AppState) -> Router {
     Router::new()
         .route("/tokens", post(create_token).get(get_tokens))
         .route("/tokens/:id", delete(delete_token))
+        .route("/tokens/:id/rotate", post(rotate_token))
         .with_state(state)
 }
 
 #[derive(Debug, Deserialize)]
 struct CreateTokenPayload {
     name: String,
+    /// Defaults to `Scope::DEFAULT_TOKEN`.
+    scopes: Option<Vec<Scope>>,
+    /// The token never expires when not set.
+    expires_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Serialize)]
@@ -34,24 +44,27 @@ struct TokenResponse {
     token: String,
 }
 
-pub async fn gen_pak(app_state: &AppState, user_id: &str, name: &str) -> Result<PrefixedApiKey> {
+pub async fn gen_pak(
+    app_state: &AppState,
+    user_id: &str,
+    name: &str,
+    scopes: &[Scope],
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<PrefixedApiKey> {
     let controller = PrefixedApiKeyController::new("lshelf".into(), 8, 24);
     let (pak, hash) = controller.generate_key_and_hash();
 
-    let _result: Vec<Token> = app_state
-        .db
-        .create("token")
-        .content(Token {
+    app_state
+        .tokens
+        .create(Token {
             token_hash: hash.clone(),
             name: name.into(),
             short_token: pak.short_token().into(),
             user: thing(user_id).expect("Failed to convert ctx user_id to thing"),
+            scopes: scopes.to_vec(),
+            expires_at: expires_at.map(Datetime::from),
         })
-        .await
-        .map_err(|e| {
-            error!("Encountered error {:?}", e);
-            Error::GenTokenFail
-        })?;
+        .await?;
 
     Ok(pak)
 }
@@ -68,7 +81,35 @@ async fn create_token(
     ctx: Ctx,
     Json(payload): Json<CreateTokenPayload>,
 ) -> Result<Json<TokenResponse>> {
-    let pak = gen_pak(&app_state, ctx.user_id(), &payload.name).await?;
+    ctx.require_scope(Scope::TokensManage)?;
+
+    if payload
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= Utc::now())
+    {
+        return Err(Error::InvalidTokenExpiry);
+    }
+
+    let mut scopes: Vec<Scope> = vec![];
+    for scope in payload
+        .scopes
+        .unwrap_or_else(|| Scope::DEFAULT_TOKEN.to_vec())
+    {
+        // A token can't hand out more than it has itself
+        ctx.require_scope(scope)?;
+        if !scopes.contains(&scope) {
+            scopes.push(scope);
+        }
+    }
+
+    let pak = gen_pak(
+        &app_state,
+        ctx.user_id(),
+        &payload.name,
+        &scopes,
+        payload.expires_at,
+    )
+    .await?;
 
     let body = Json(TokenResponse {
         token: pak.to_string(),
@@ -82,6 +123,26 @@ struct ListTokensItem {
     pub id: Thing,
     pub name: String,
     pub short_token: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub last_used_ip: Option<String>,
+}
+
+impl From<TokenDBResult> for ListTokensItem {
+    fn from(token: TokenDBResult) -> Self {
+        Self {
+            id: token.id,
+            name: token.name,
+            short_token: token.short_token,
+            scopes: token.scopes,
+            created_at: token.created_at,
+            expires_at: token.expires_at,
+            last_used_at: token.last_used_at,
+            last_used_ip: token.last_used_ip,
+        }
+    }
 }
 
 #[tracing::instrument(
@@ -95,20 +156,16 @@ async fn get_tokens(
     ctx: Ctx,
     State(app_state): State<AppState>,
 ) -> Result<Json<Vec<ListTokensItem>>> {
-    let mut result = app_state
-        .db
-        .query("SELECT * FROM token WHERE user.id = $user_id;")
-        .bind(("user_id", ctx.user_id()))
-        .await
-        .map_err(|e| {
-            error!("Encountered error {:?}", e);
-            Error::GetTokensFail
-        })?;
-
-    let tokens: Vec<ListTokensItem> = result.take(0).map_err(|e| {
-        error!("Encountered error {:?}", e);
-        Error::GetTokensFail
-    })?;
+    ctx.require_scope(Scope::TokensManage)?;
+
+    let user_id = thing(ctx.user_id()).expect("Failed to convert ctx user_id to thing");
+    let tokens = app_state
+        .tokens
+        .list_for_user(&user_id)
+        .await?
+        .into_iter()
+        .map(ListTokensItem::from)
+        .collect();
 
     let body = Json(tokens);
 
@@ -127,33 +184,100 @@ async fn delete_token(
     State(app_state): State<AppState>,
     Path(token_id): Path<String>,
 ) -> Result<Json<Value>> {
-    let parts = token_id.split(':').collect::<Vec<&str>>();
+    ctx.require_scope(Scope::TokensManage)?;
 
-    if parts.len() != 2 {
-        return Err(Error::InvalidDeleteToken);
-    }
+    let token_id = match thing(&token_id) {
+        Ok(token_id) if token_id.tb == "token" => token_id,
+        _ => return Err(Error::InvalidDeleteToken),
+    };
 
-    let mut result = app_state
-        .db
-        .query("DELETE token WHERE id = $token_id AND user = $user_id;")
-        .bind(("token_id", token_id))
-        .bind(("user_id", ctx.user_id()))
-        .await
-        .map_err(|e| {
-            error!("Encountered error {:?}", e);
-            Error::DeleteTokenFail
-        })?;
-
-    let deleted: surrealdb::Result<Vec<Token>> = result.take(0);
-
-    match deleted {
-        Ok(_) => {
-            let body = Json(json!({
-                "success": true,
-            }));
-
-            Ok(body)
-        }
-        Err(_) => Err(Error::DeleteTokenFail),
+    let user_id = thing(ctx.user_id()).expect("Failed to convert ctx user_id to thing");
+    app_state
+        .tokens
+        .delete_for_user(&token_id, &user_id)
+        .await?;
+    app_state.token_cache.invalidate(&token_id);
+
+    let body = Json(json!({
+        "success": true,
+    }));
+
+    Ok(body)
+}
+
+#[derive(Debug, Deserialize)]
+struct RotateTokenPayload {
+    /// How long the old token keeps working, in minutes.
+    grace_period_minutes: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct RotateTokenResponse {
+    token: String,
+    /// When the old token stops working.
+    previous_expires_at: DateTime<Utc>,
+}
+
+#[tracing::instrument(
+    name = "Rotating token",
+    skip(ctx, app_state, payload),
+    fields(
+        user_id = %ctx.user_id(),
+    )
+)]
+async fn rotate_token(
+    ctx: Ctx,
+    State(app_state): State<AppState>,
+    Path(token_id): Path<String>,
+    payload: Option<Json<RotateTokenPayload>>,
+) -> Result<Json<RotateTokenResponse>> {
+    ctx.require_scope(Scope::TokensManage)?;
+
+    let token_id = match thing(&token_id) {
+        Ok(token_id) if token_id.tb == "token" => token_id,
+        _ => return Err(Error::InvalidTokenId),
+    };
+    let grace_period = payload
+        .and_then(|Json(payload)| payload.grace_period_minutes)
+        .unwrap_or(DEFAULT_ROTATION_GRACE_PERIOD_MINUTES)
+        .clamp(0, MAX_ROTATION_GRACE_PERIOD_MINUTES);
+
+    let user_id = thing(ctx.user_id()).expect("Failed to convert ctx user_id to thing");
+    let token = app_state.tokens.find_for_user(&token_id, &user_id).await?;
+    let token = token.ok_or(Error::TokenNotFound)?;
+
+    let now = Utc::now();
+    if token.expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err(Error::TokenExpired);
     }
+    for scope in &token.scopes {
+        ctx.require_scope(*scope)?;
+    }
+
+    let pak = gen_pak(
+        &app_state,
+        ctx.user_id(),
+        &token.name,
+        &token.scopes,
+        token.expires_at,
+    )
+    .await?;
+
+    let grace_expires_at = now + Duration::minutes(grace_period);
+    let previous_expires_at = match token.expires_at {
+        Some(expires_at) if expires_at < grace_expires_at => expires_at,
+        _ => grace_expires_at,
+    };
+    app_state
+        .tokens
+        .set_expiry(&token_id, previous_expires_at)
+        .await?;
+    app_state.token_cache.invalidate(&token_id);
+
+    let body = Json(RotateTokenResponse {
+        token: pak.to_string(),
+        previous_expires_at,
+    });
+
+    Ok(body)
 }
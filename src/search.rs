@@ -0,0 +1,203 @@
+//! Module containing the parsing of link search queries and the highlighting
+//! of matches in search results.
+//!
+//! Matching and ranking of plain terms is done by the link repository, with
+//! the SurrealDB full text indexes by default, each term in any of the
+//! searched fields. Phrases (`"rust async"`) and prefixes (`tok*`) aren't
+//! supported by them, so those are checked again here once the candidates are
+//! loaded.
+//!
+//! Everything is case-folded with `str::to_lowercase`.
+
+const SNIPPET_CONTEXT: usize = 60;
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SearchQuery {
+    pub terms: Vec<String>,
+    pub prefixes: Vec<String>,
+    pub phrases: Vec<String>,
+}
+
+impl SearchQuery {
+    pub fn parse(query: &str) -> Self {
+        let mut search_query = SearchQuery::default();
+
+        // Every other piece between double quotes is a phrase
+        for (i, part) in query.split('"').enumerate() {
+            if i % 2 == 1 {
+                let phrase = words(part).join(" ");
+                if !phrase.is_empty() {
+                    search_query.phrases.push(phrase);
+                }
+                continue;
+            }
+
+            for word in part.split_whitespace() {
+                let word = word.to_lowercase();
+                match word.strip_suffix('*') {
+                    Some(prefix) => search_query.prefixes.extend(words(prefix)),
+                    None => search_query.terms.extend(words(&word)),
+                }
+            }
+        }
+
+        search_query
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty() && self.prefixes.is_empty() && self.phrases.is_empty()
+    }
+
+    /// The words that have to be found through the full text indexes, each
+    /// in any of the fields.
+    pub fn index_terms(&self) -> Vec<String> {
+        let mut index_terms: Vec<String> = vec![];
+        for word in self
+            .terms
+            .iter()
+            .cloned()
+            .chain(self.phrases.iter().flat_map(|phrase| words(phrase)))
+        {
+            if !index_terms.contains(&word) {
+                index_terms.push(word);
+            }
+        }
+
+        index_terms
+    }
+
+    /// Checks the phrases and prefixes, which the full text index can't, against
+    /// all of the searched fields of a link.
+    pub fn matches(&self, fields: &[&str]) -> bool {
+        let text = words(&fields.join(" ")).join(" ");
+        let text_words: Vec<&str> = text.split(' ').collect();
+
+        self.phrases
+            .iter()
+            .all(|phrase| format!(" {text} ").contains(&format!(" {phrase} ")))
+            && self
+                .prefixes
+                .iter()
+                .all(|prefix| text_words.iter().any(|word| word.starts_with(prefix)))
+    }
+
+    /// Returns the HTML escaped `text` with every match wrapped in `<mark>`.
+    pub fn highlight(&self, text: &str) -> String {
+        let ranges = self.match_ranges(text);
+        mark(text, &ranges, 0, text.len())
+    }
+
+    /// Like `highlight`, but only returns a short window of `text` around the
+    /// first match. Returns `None` when nothing in `text` matches.
+    pub fn snippet(&self, text: &str) -> Option<String> {
+        let ranges = self.match_ranges(text);
+        let (first_start, first_end) = *ranges.first()?;
+
+        let mut start = first_start.saturating_sub(SNIPPET_CONTEXT);
+        while !text.is_char_boundary(start) {
+            start -= 1;
+        }
+        let mut end = (first_end + SNIPPET_CONTEXT).min(text.len());
+        while !text.is_char_boundary(end) {
+            end += 1;
+        }
+
+        let mut snippet = mark(text, &ranges, start, end);
+        if start > 0 {
+            snippet.insert(0, '…');
+        }
+        if end < text.len() {
+            snippet.push('…');
+        }
+
+        Some(snippet)
+    }
+
+    /// Byte ranges of `text` matching the query, sorted and merged.
+    fn match_ranges(&self, text: &str) -> Vec<(usize, usize)> {
+        let (lowercase, offsets) = fold_case(text);
+        let mut ranges = vec![];
+
+        for needle in self
+            .terms
+            .iter()
+            .chain(self.prefixes.iter())
+            .chain(self.phrases.iter())
+        {
+            for (start, _) in lowercase.match_indices(needle.as_str()) {
+                let at_word_start = !matches!(
+                    lowercase[..start].chars().next_back(),
+                    Some(c) if c.is_alphanumeric()
+                );
+                let (start, end) = (offsets[start], offsets[start + needle.len()]);
+                if at_word_start && start < end {
+                    ranges.push((start, end));
+                }
+            }
+        }
+
+        ranges.sort();
+        let mut merged: Vec<(usize, usize)> = vec![];
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+
+        merged
+    }
+}
+
+/// Lowercased alphanumeric words of `text`.
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+/// Lowercases `text` like `words` does, along with the offset in `text` of
+/// every byte of the result, and of its end.
+fn fold_case(text: &str) -> (String, Vec<usize>) {
+    let mut lowercase = String::with_capacity(text.len());
+    let mut offsets = Vec::with_capacity(text.len() + 1);
+
+    for (offset, c) in text.char_indices() {
+        for lower in c.to_lowercase() {
+            lowercase.push(lower);
+            offsets.resize(lowercase.len(), offset);
+        }
+    }
+    offsets.push(text.len());
+
+    (lowercase, offsets)
+}
+
+fn mark(text: &str, ranges: &[(usize, usize)], start: usize, end: usize) -> String {
+    let mut marked = String::new();
+    let mut position = start;
+
+    for &(range_start, range_end) in ranges {
+        if range_end <= start || range_start >= end {
+            continue;
+        }
+        let range_start = range_start.max(position);
+        let range_end = range_end.min(end);
+        marked.push_str(&escape_html(&text[position..range_start]));
+        marked.push_str("<mark>");
+        marked.push_str(&escape_html(&text[range_start..range_end]));
+        marked.push_str("</mark>");
+        position = range_end;
+    }
+    marked.push_str(&escape_html(&text[position..end]));
+
+    marked
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -1,23 +1,28 @@
 use std::collections::HashMap;
 
+use axum::http::HeaderMap;
 use opentelemetry::{
     propagation::TextMapPropagator,
     sdk::{
         self,
         propagation::{BaggagePropagator, TextMapCompositePropagator, TraceContextPropagator},
-        trace::Tracer,
+        trace::{Sampler, Tracer},
         Resource,
     },
     trace::TraceError,
+    KeyValue,
 };
-use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_otlp::{SpanExporterBuilder, WithExportConfig};
 use opentelemetry_semantic_conventions as semconv;
-use tracing::{info, Subscriber};
+use tonic::metadata::MetadataMap;
+use tracing::{info, warn, Subscriber};
 use tracing_opentelemetry::OpenTelemetryLayer;
 use tracing_subscriber::{
     fmt::format::FmtSpan, layer::SubscriberExt, registry::LookupSpan, EnvFilter, Layer,
 };
 
+use crate::configuration::{SamplerSettings, TelemetryExporter, TelemetrySettings};
+
 pub fn build_logger_text<S>() -> Box<dyn Layer<S> + Send + Sync + 'static>
 where
     S: Subscriber + for<'a> LookupSpan<'a>,
@@ -97,57 +102,141 @@ pub fn init_propagator() -> Result<(), TraceError> {
     Ok(())
 }
 
-pub fn build_otel_layer<S>() -> Result<OpenTelemetryLayer<S, Tracer>, TraceError>
+/// `None` when traces aren't exported.
+pub fn build_otel_layer<S>(
+    settings: &TelemetrySettings,
+) -> Result<Option<OpenTelemetryLayer<S, Tracer>>, TraceError>
 where
     S: Subscriber + for<'a> LookupSpan<'a>,
 {
-    let service_name = std::env::var("OTEL_SERVICE_NAME").unwrap_or("linkstowr-dev".into());
-    let resource = Resource::new(vec![semconv::resource::SERVICE_NAME.string(service_name)]);
-
     init_propagator()?;
+    if settings.exporter == TelemetryExporter::None {
+        return Ok(None);
+    }
+
+    let trace_config = sdk::trace::config()
+        .with_resource(build_resource(settings))
+        .with_sampler(build_sampler(&settings.sampler));
+
+    let otel_tracer = match settings.exporter {
+        TelemetryExporter::Stdout => sdk::export::trace::stdout::new_pipeline()
+            .with_trace_config(trace_config)
+            .install_simple(),
+        _ => opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(build_otlp_exporter(settings)?)
+            .with_trace_config(trace_config)
+            .install_batch(opentelemetry::runtime::Tokio)?,
+    };
+
+    Ok(Some(
+        tracing_opentelemetry::layer()
+            .with_exception_field_propagation(true)
+            .with_tracer(otel_tracer),
+    ))
+}
 
-    let mut map = HashMap::new();
-    map.insert(
-        "authorization".to_string(),
-        std::env::var("HYPERDX_API_KEY").unwrap(),
+fn build_resource(settings: &TelemetrySettings) -> Resource {
+    let mut attributes =
+        vec![semconv::resource::SERVICE_NAME.string(settings.service_name.clone())];
+    attributes.extend(
+        settings
+            .resource_attributes
+            .iter()
+            .map(|(key, value)| KeyValue::new(key.clone(), value.clone())),
     );
 
-    let otel_tracer = opentelemetry_otlp::new_pipeline()
-        .tracing()
-        .with_exporter(
-            opentelemetry_otlp::new_exporter()
+    Resource::new(attributes)
+}
+
+fn build_sampler(settings: &SamplerSettings) -> Sampler {
+    let sampler = if settings.ratio >= 1.0 {
+        Sampler::AlwaysOn
+    } else if settings.ratio <= 0.0 {
+        Sampler::AlwaysOff
+    } else {
+        Sampler::TraceIdRatioBased(settings.ratio)
+    };
+
+    match settings.parent_based {
+        true => Sampler::ParentBased(Box::new(sampler)),
+        false => sampler,
+    }
+}
+
+/// The configured headers along with the ones read from the environment.
+fn build_otlp_headers(settings: &TelemetrySettings) -> Result<HashMap<String, String>, TraceError> {
+    let mut headers = settings.headers.clone();
+    for (name, var) in &settings.headers_from_env {
+        let value = std::env::var(var).map_err(|_| {
+            TraceError::from(format!("missing {var} env var for OTLP header {name}"))
+        })?;
+        headers.insert(name.clone(), value);
+    }
+
+    Ok(headers)
+}
+
+fn build_otlp_exporter(settings: &TelemetrySettings) -> Result<SpanExporterBuilder, TraceError> {
+    let headers = build_otlp_headers(settings)?;
+    let exporter: SpanExporterBuilder = match settings.exporter {
+        TelemetryExporter::OtlpGrpc => {
+            let headers = HeaderMap::try_from(&headers)
+                .map_err(|e| TraceError::from(format!("invalid OTLP header: {e}")))?;
+            let mut exporter = opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_metadata(MetadataMap::from_headers(headers));
+            if let Some(endpoint) = &settings.endpoint {
+                exporter = exporter.with_endpoint(endpoint);
+            }
+            exporter.into()
+        }
+        _ => {
+            let mut exporter = opentelemetry_otlp::new_exporter()
                 .http()
-                .with_endpoint(
-                    std::env::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT")
-                        .expect("Missing OTEL_EXPORTER_OTLP_TRACES_ENDPOINT env var"),
-                )
-                .with_headers(map),
-        )
-        .with_trace_config(
-            sdk::trace::config()
-                .with_resource(resource)
-                .with_sampler(sdk::trace::Sampler::AlwaysOn),
-        )
-        .install_batch(opentelemetry::runtime::Tokio)?;
+                .with_headers(headers);
+            if let Some(endpoint) = &settings.endpoint {
+                exporter = exporter.with_endpoint(endpoint);
+            }
+            exporter.into()
+        }
+    };
 
-    Ok(tracing_opentelemetry::layer()
-        .with_exception_field_propagation(true)
-        .with_tracer(otel_tracer))
+    Ok(exporter)
 }
 
-pub fn init_subscribers() -> Result<(), TraceError> {
+/// Logs locally and, depending on the settings, exports traces. When the
+/// exporter can't be built, like when an env var for its headers is missing,
+/// this only logs locally rather than keeping the app from starting.
+pub fn init_subscribers(settings: &TelemetrySettings) -> Result<(), TraceError> {
     //setup a temporary subscriber to log output during setup
     let subscriber = tracing_subscriber::registry()
         .with(build_loglevel_filter_layer())
         .with(build_logger_text());
-    let _guard = tracing::subscriber::set_default(subscriber);
-    info!("init logging & tracing");
+    let otel_layer = tracing::subscriber::with_default(subscriber, || {
+        info!(
+            "init logging & tracing, exporting traces to {:?}",
+            settings.exporter
+        );
+        build_otel_layer(settings).unwrap_or_else(|e| {
+            warn!("Not exporting traces: {e}");
+            None
+        })
+    });
 
     let subscriber = tracing_subscriber::registry()
-        .with(build_otel_layer()?)
+        .with(otel_layer)
         .with(build_loglevel_filter_layer())
         .with(build_logger_text());
     tracing::subscriber::set_global_default(subscriber)
-        .expect("Unable to set global tracing subscriber");
-    Ok(())
+        .map_err(|e| TraceError::from(format!("unable to set global tracing subscriber: {e}")))
+}
+
+/// Logs to stderr without exporting traces, for the admin commands whose
+/// output goes to stdout. Only warnings and errors unless `RUST_LOG` is set.
+pub fn init_cli_subscriber() {
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| "warn".into()))
+        .init();
 }
@@ -0,0 +1,155 @@
+//! In-process cache of validated API tokens, so that requests carrying an
+//! `X-Api-Token` don't each have to look the token up in the database.
+//!
+//! Entries are dropped explicitly when a token is deleted or rotated on this
+//! instance. The TTL bounds how long other instances can keep using a stale
+//! entry.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use surrealdb::sql::Thing;
+
+use crate::{ctx::Scope, types::TokenDBResult};
+
+/// What is needed to authenticate a request with an API token.
+#[derive(Debug, Clone)]
+pub struct CachedToken {
+    pub id: Thing,
+    pub user: Thing,
+    pub scopes: Vec<Scope>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub last_used_ip: Option<String>,
+}
+
+impl From<TokenDBResult> for CachedToken {
+    fn from(token: TokenDBResult) -> Self {
+        Self {
+            id: token.id,
+            user: token.user,
+            scopes: token.scopes,
+            expires_at: token.expires_at,
+            last_used_at: token.last_used_at,
+            last_used_ip: token.last_used_ip,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+struct Entry {
+    token: CachedToken,
+    inserted_at: Instant,
+}
+
+/// Bounded TTL cache keyed on the hash of the token's long part.
+pub struct TokenCache {
+    entries: Mutex<HashMap<String, Entry>>,
+    capacity: usize,
+    ttl: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl TokenCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            capacity,
+            ttl,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, token_hash: &str) -> Option<CachedToken> {
+        let mut entries = self.entries.lock().unwrap();
+        let token = match entries.get(token_hash) {
+            Some(entry) if entry.inserted_at.elapsed() < self.ttl => Some(entry.token.clone()),
+            Some(_) => {
+                entries.remove(token_hash);
+                None
+            }
+            None => None,
+        };
+
+        match token {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+
+        token
+    }
+
+    pub fn insert(&self, token_hash: String, token: CachedToken) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity && !entries.contains_key(&token_hash) {
+            entries.retain(|_, entry| entry.inserted_at.elapsed() < self.ttl);
+        }
+        if entries.len() >= self.capacity && !entries.contains_key(&token_hash) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.inserted_at)
+                .map(|(hash, _)| hash.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+
+        entries.insert(
+            token_hash,
+            Entry {
+                token,
+                inserted_at: Instant::now(),
+            },
+        );
+    }
+
+    /// Keeps the cached usage in line with what was written to the database,
+    /// without extending the entry's lifetime.
+    pub fn record_use(&self, token_hash: &str, used_at: DateTime<Utc>, ip: Option<String>) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(token_hash) {
+            entry.token.last_used_at = Some(used_at);
+            entry.token.last_used_ip = ip;
+        }
+    }
+
+    /// Drops the entry of the token with the given id, if it is cached.
+    pub fn invalidate(&self, token_id: &Thing) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| &entry.token.id != token_id);
+    }
+
+    /// Drops the entries of all of the user's tokens.
+    pub fn invalidate_user(&self, user_id: &Thing) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| &entry.token.user != user_id);
+    }
+
+    pub fn stats(&self) -> TokenCacheStats {
+        TokenCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.entries.lock().unwrap().len(),
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! Module for RFC 6238 time-based one-time passwords, as generated by
+//! authenticator apps: HMAC-SHA1, 6 digits and 30 second steps.
+
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use sha1::Sha1;
+
+const STEP_SECS: i64 = 30;
+const DIGITS: u32 = 6;
+/// Codes from this many steps before or after the current one are accepted
+/// too, to make up for clock drift and slow typing.
+const ALLOWED_DRIFT_STEPS: i64 = 1;
+const SECRET_LEN: usize = 20;
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Returns a new random secret, base32 encoded as authenticator apps expect.
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; SECRET_LEN];
+    OsRng.fill_bytes(&mut bytes);
+    base32_encode(&bytes)
+}
+
+/// The `otpauth://` URI that authenticator apps read from QR codes.
+pub fn otpauth_uri(issuer: &str, account: &str, secret: &str) -> String {
+    let label = format!("{issuer}:{account}");
+    format!(
+        "otpauth://totp/{}?secret={secret}&issuer={}&algorithm=SHA1&digits={DIGITS}&period={STEP_SECS}",
+        percent_encode(&label),
+        percent_encode(issuer),
+    )
+}
+
+/// The code for `secret` at `unix_time`, or `None` if the secret isn't valid
+/// base32.
+pub fn code_at(secret: &str, unix_time: i64) -> Option<String> {
+    let secret = base32_decode(secret)?;
+    let code = hotp(&secret, (unix_time / STEP_SECS) as u64);
+
+    Some(format!("{code:0width$}", width = DIGITS as usize))
+}
+
+/// Checks `code` against `secret` at `unix_time` and returns the time step it
+/// belongs to. Callers should reject steps that were already used so that a
+/// code can't be replayed.
+pub fn verify(secret: &str, code: &str, unix_time: i64) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let secret = base32_decode(secret)?;
+    let current = unix_time / STEP_SECS;
+
+    (current - ALLOWED_DRIFT_STEPS..=current + ALLOWED_DRIFT_STEPS)
+        .filter(|step| *step >= 0)
+        .find(|step| hotp(&secret, *step as u64) == code)
+}
+
+/// RFC 4226 HMAC-based one-time password.
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let code = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+
+    code % 10u32.pow(DIGITS)
+}
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+
+    encoded
+}
+
+/// Decodes unpadded base32, ignoring case and spaces.
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = vec![];
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for c in encoded.chars().filter(|c| !c.is_whitespace() && *c != '=') {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| *a as char == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(decoded)
+}
+
+/// Percent-encodes everything but unreserved characters.
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
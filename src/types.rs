@@ -1,5 +1,9 @@
-use std::sync::Arc;
+use std::{
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use surrealdb::{
     engine::any::Any,
@@ -7,26 +11,196 @@ use surrealdb::{
     Surreal,
 };
 
+use crate::{
+    canonical_url::UrlCanonicalizer,
+    configuration::{
+        AuthSettings, LinkSettings, MetricsSettings, ProxySettings, RateLimitSettings,
+    },
+    ctx::Scope,
+    mailer::{LogMailer, Mailer},
+    metrics::Metrics,
+    rate_limit::RateLimits,
+    repository::{
+        LinkRepository, MemoryLinkRepository, MemoryTokenRepository, MemoryUserRepository, Metered,
+        SurrealLinkRepository, SurrealTokenRepository, SurrealUserRepository, TokenRepository,
+        UserRepository,
+    },
+    token_cache::TokenCache,
+};
+
 pub type DB = Surreal<Any>;
 
+/// Projection to use when selecting links so that `LinkDBResult::tag_names`
+/// gets filled in from the linked `tag` records.
+pub const LINK_FIELDS: &str = "*, tags.name AS tag_names";
+
 #[derive(Clone)]
 pub struct AppState {
     pub db: Arc<DB>,
+    pub users: Arc<dyn UserRepository>,
+    pub tokens: Arc<dyn TokenRepository>,
+    pub links: Arc<dyn LinkRepository>,
+    pub url_canonicalizer: Arc<UrlCanonicalizer>,
+    pub max_link_batch_size: usize,
+    pub token_cache: Arc<TokenCache>,
+    pub mailer: Arc<dyn Mailer>,
+    pub password_reset_url: Option<String>,
+    pub rate_limits: Arc<RateLimits>,
+    pub proxy: Arc<ProxySettings>,
+    pub metrics: Arc<Metrics>,
+    /// Token `/metrics` requires. The endpoint isn't served without one.
+    pub metrics_token: Option<String>,
+    /// Set once a graceful shutdown started, to fail the readiness probe.
+    pub shutting_down: Arc<AtomicBool>,
+    /// The SQL database users, tokens and links are kept in, if any, for the
+    /// readiness probe to check.
+    #[cfg(feature = "sql")]
+    pub sql_pool: Option<sqlx::AnyPool>,
 }
 
 impl AppState {
     pub fn new(db: DB) -> Self {
-        AppState { db: Arc::new(db) }
+        let db = Arc::new(db);
+        let metrics = Arc::new(Metrics::new());
+
+        AppState {
+            users: Arc::new(Metered::new(
+                Arc::new(SurrealUserRepository::new(db.clone())) as Arc<dyn UserRepository>,
+                metrics.clone(),
+                "users",
+            )),
+            tokens: Arc::new(Metered::new(
+                Arc::new(SurrealTokenRepository::new(db.clone())) as Arc<dyn TokenRepository>,
+                metrics.clone(),
+                "tokens",
+            )),
+            links: Arc::new(Metered::new(
+                Arc::new(SurrealLinkRepository::new(db.clone())) as Arc<dyn LinkRepository>,
+                metrics.clone(),
+                "links",
+            )),
+            db,
+            url_canonicalizer: Arc::new(UrlCanonicalizer::default()),
+            max_link_batch_size: LinkSettings::default().max_batch_size,
+            token_cache: Arc::new(new_token_cache(&AuthSettings::default())),
+            mailer: Arc::new(LogMailer::default()),
+            password_reset_url: None,
+            rate_limits: Arc::new(RateLimits::new(&RateLimitSettings::default())),
+            proxy: Arc::new(ProxySettings::default()),
+            metrics,
+            metrics_token: None,
+            shutting_down: Arc::new(AtomicBool::new(false)),
+            #[cfg(feature = "sql")]
+            sql_pool: None,
+        }
+    }
+
+    /// State that keeps users, tokens and links in memory, for running
+    /// handlers without a database. `db` is never connected, so anything
+    /// else fails.
+    pub fn in_memory() -> Self {
+        Self::new(Surreal::init()).with_repositories(
+            Arc::new(MemoryUserRepository::new()),
+            Arc::new(MemoryTokenRepository::new()),
+            Arc::new(MemoryLinkRepository::new()),
+        )
+    }
+
+    /// Keeps users, tokens and links in the given repositories instead.
+    pub fn with_repositories(
+        mut self,
+        users: Arc<dyn UserRepository>,
+        tokens: Arc<dyn TokenRepository>,
+        links: Arc<dyn LinkRepository>,
+    ) -> Self {
+        self.users = Arc::new(Metered::new(users, self.metrics.clone(), "users"));
+        self.tokens = Arc::new(Metered::new(tokens, self.metrics.clone(), "tokens"));
+        self.links = Arc::new(Metered::new(links, self.metrics.clone(), "links"));
+        self
+    }
+
+    pub fn with_link_settings(mut self, settings: &LinkSettings) -> Self {
+        self.url_canonicalizer = Arc::new(UrlCanonicalizer::new(settings.tracking_params.clone()));
+        self.max_link_batch_size = settings.max_batch_size;
+        self
+    }
+
+    pub fn with_auth_settings(mut self, settings: &AuthSettings) -> Self {
+        self.token_cache = Arc::new(new_token_cache(settings));
+        self.password_reset_url = settings.password_reset_url.clone();
+        self
+    }
+
+    pub fn with_mailer(mut self, mailer: Arc<dyn Mailer>) -> Self {
+        self.mailer = mailer;
+        self
+    }
+
+    pub fn with_rate_limit_settings(mut self, settings: &RateLimitSettings) -> Self {
+        self.rate_limits = Arc::new(RateLimits::new(settings));
+        self
+    }
+
+    pub fn with_proxy_settings(mut self, settings: &ProxySettings) -> Self {
+        self.proxy = Arc::new(settings.clone());
+        self
+    }
+
+    pub fn with_metrics_settings(mut self, settings: &MetricsSettings) -> Self {
+        self.metrics_token = settings.bearer_token.clone();
+        self
+    }
+
+    /// Makes readiness depend on the SQL database the repositories use.
+    #[cfg(feature = "sql")]
+    pub fn with_sql_pool(mut self, pool: sqlx::AnyPool) -> Self {
+        self.sql_pool = Some(pool);
+        self
     }
 }
 
+fn new_token_cache(settings: &AuthSettings) -> TokenCache {
+    TokenCache::new(
+        settings.token_cache_capacity,
+        Duration::from_secs(settings.token_cache_ttl_secs),
+    )
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Link {
     pub url: String,
+    pub canonical_url: String,
     pub title: String,
     pub note: String,
     pub bookmarked_at: Datetime,
     pub user: Thing,
+    pub tags: Vec<Thing>,
+    pub collection: Option<Thing>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkDBResult {
+    pub id: Thing,
+    pub url: String,
+    #[serde(default)]
+    pub canonical_url: String,
+    pub title: String,
+    pub note: String,
+    pub bookmarked_at: DateTime<Utc>,
+    pub updated_at: Option<DateTime<Utc>>,
+    pub user: Thing,
+    pub collection: Option<Thing>,
+    /// Names of the link's tags. Only set when selected with `LINK_FIELDS`.
+    #[serde(default)]
+    pub tag_names: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkTombstone {
+    pub id: Thing,
+    pub link: Thing,
+    pub user: Thing,
+    pub deleted_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -34,12 +208,43 @@ pub struct LinkPayload {
     pub url: String,
     pub title: String,
     pub note: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Id of the collection to save the link in.
+    pub collection: Option<String>,
+    #[serde(default)]
+    pub on_duplicate: OnDuplicate,
+}
+
+/// What to do when a link with the same canonical URL was already saved.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnDuplicate {
+    /// Update the existing link's note, tags and bookmark time.
+    #[default]
+    Merge,
+    /// Fail with the id of the existing link.
+    Reject,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tag {
+    pub name: String,
+    pub user: Thing,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TagDBResult {
+    pub id: Thing,
+    pub name: String,
+    pub user: Thing,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateUserContent {
     pub username: String,
     pub password: String,
+    pub email: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -48,11 +253,21 @@ pub struct User {
     pub username: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserDBResult {
     pub id: Thing,
     pub username: String,
     pub password: String,
+    pub email: Option<String>,
+    /// Base32 TOTP secret, set once two-factor authentication is enabled.
+    pub totp_secret: Option<String>,
+    /// Secret waiting to be confirmed with a first code.
+    pub totp_pending_secret: Option<String>,
+    /// Time step of the last accepted TOTP code.
+    pub totp_last_step: Option<i64>,
+    /// Set when an admin disabled the account, which then can't sign in.
+    #[serde(default)]
+    pub disabled_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -61,6 +276,136 @@ pub struct Token {
     pub name: String,
     pub short_token: String,
     pub user: Thing,
+    pub scopes: Vec<Scope>,
+    pub expires_at: Option<Datetime>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenDBResult {
+    pub id: Thing,
+    pub token_hash: String,
+    pub name: String,
+    pub short_token: String,
+    pub user: Thing,
+    pub scopes: Vec<Scope>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub last_used_ip: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionDBResult {
+    pub id: Thing,
+    pub user: Thing,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefreshToken {
+    pub token_hash: String,
+    pub session: Thing,
+    pub expires_at: Datetime,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefreshTokenDBResult {
+    pub id: Thing,
+    pub token_hash: String,
+    pub session: Thing,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PasswordReset {
+    pub token_hash: String,
+    pub user: Thing,
+    pub expires_at: Datetime,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PasswordResetDBResult {
+    pub id: Thing,
+    pub token_hash: String,
+    pub user: Thing,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MfaChallenge {
+    pub token_hash: String,
+    pub user: Thing,
+    pub expires_at: Datetime,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MfaChallengeDBResult {
+    pub id: Thing,
+    pub token_hash: String,
+    pub user: Thing,
+    pub expires_at: DateTime<Utc>,
+    pub attempts: i64,
+    pub used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecoveryCode {
+    pub code_hash: String,
+    pub user: Thing,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecoveryCodeDBResult {
+    pub id: Thing,
+    pub code_hash: String,
+    pub user: Thing,
+    pub used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Collection {
+    pub name: String,
+    pub user: Thing,
+    pub parent: Option<Thing>,
+    pub position: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CollectionDBResult {
+    pub id: Thing,
+    pub name: String,
+    pub user: Thing,
+    pub parent: Option<Thing>,
+    pub position: i64,
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub link_count: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Device {
+    pub name: String,
+    pub user: Thing,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceDBResult {
+    pub id: Thing,
+    pub name: String,
+    pub user: Thing,
+    pub cursor: Option<DateTime<Utc>>,
+    /// Encoded position of the last acknowledged changes.
+    #[serde(default)]
+    pub sync_cursor: Option<String>,
+    /// Encoded position of the last changes handed to the device, which it
+    /// can't acknowledge past.
+    #[serde(default)]
+    pub served_cursor: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_synced_at: Option<DateTime<Utc>>,
 }
 
 impl From<UserDBResult> for User {
@@ -1,26 +1,54 @@
-use std::{fs, net::TcpListener};
+use std::{
+    fs,
+    net::{IpAddr, SocketAddr, TcpListener},
+    path::PathBuf,
+    sync::{atomic::Ordering, Arc},
+};
 
+use async_trait::async_trait;
+use axum::{extract::ConnectInfo, http::Request};
 use linkstowr::{
     app::get_app,
-    error::Result,
+    canonical_url::UrlCanonicalizer,
+    cli::{self, AdminCommand, TokenCommand, UserCommand},
+    configuration::{
+        DatabaseBackend, MetricsSettings, ProxySettings, TelemetryExporter, TelemetrySettings,
+    },
+    ctx::Scope,
+    error::{Error, Result},
+    mailer::{Email, LogMailer, Mailer},
+    middlewares::auth::get_client_ip,
+    migrations::{self, MigrationError, MIGRATIONS},
     prefixed_api_key::PrefixedApiKey,
     routes::{
-        auth::{create_user, UserResponse},
-        link_routes::LinkResponse,
+        account::{AccountExport, RecoveryCodesResponse, TotpEnrollmentResponse},
+        auth::{create_user, MfaChallengeResponse, RefreshResponse, UserResponse},
+        collection_routes::CollectionResponse,
+        import_routes::ImportReport,
+        link_routes::{BatchItemStatus, BatchResponse, LinkResponse, LinksPage, SearchResults},
+        sync_routes::{ChangesResponse, DeviceResponse},
+        tag_routes::{remove_unused_tags, resolve_tag_names, TagResponse},
         token::gen_pak,
     },
-    types::AppState,
+    search::SearchQuery,
+    telemetry::{build_otel_layer, init_subscribers},
+    totp,
+    types::{AppState, TokenDBResult},
 };
 use serde_json::{json, Value};
 use surrealdb::sql::thing;
+use tracing_subscriber::Registry;
 use uuid::Uuid;
 
 const TEST_USER_PASSWORD: &str = "password";
 const JWT_ENCODING_SECRET: &str = "super-secret";
+const METRICS_TOKEN: &str = "metrics-secret";
 
 pub struct TestApp {
     pub address: String,
     pub state: AppState,
+    /// Where emails sent by the app end up.
+    pub outbox_dir: PathBuf,
 }
 
 async fn spawn_app() -> TestApp {
@@ -29,32 +57,43 @@ async fn spawn_app() -> TestApp {
         .expect("Failed to initialize test db");
     db.use_ns("test").use_db("test").await.unwrap();
 
-    // Initialize schema
-    let schema =
-        fs::read_to_string("db/schema.sql").expect("Failed to read schema file from db/schema.sql");
-
-    db.query(schema)
+    migrations::run(&db, &UrlCanonicalizer::default())
         .await
         .expect("Failed to initialize the DB schema");
 
     // Setup env var for JWT
     std::env::set_var("JWT_ENCODING_SECRET", JWT_ENCODING_SECRET);
 
-    let state = AppState::new(db);
+    let outbox_dir = std::env::temp_dir().join(format!("linkstowr-outbox-{}", Uuid::new_v4()));
+    let state = AppState::new(db)
+        .with_mailer(Arc::new(LogMailer::new(Some(outbox_dir.clone()))))
+        .with_metrics_settings(&MetricsSettings {
+            bearer_token: Some(METRICS_TOKEN.to_string()),
+        });
+    let address = serve_app(&state);
+
+    TestApp {
+        address,
+        state,
+        outbox_dir,
+    }
+}
 
-    let app = get_app(&state);
+/// Serves the app on a random port and returns its address.
+fn serve_app(state: &AppState) -> String {
+    let app = get_app(state);
 
     let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind random port");
     let port = listener.local_addr().unwrap().port();
     let server = axum::Server::from_tcp(listener)
         .expect("Failed to start server from TCP listener")
-        .serve(app.into_make_service());
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>());
 
     let _ = tokio::spawn(server);
     let address = format!("http://127.0.0.1:{}", port);
     println!("->> LISTENING on {address}\n");
 
-    TestApp { address, state }
+    address
 }
 
 struct TestUser {
@@ -67,11 +106,12 @@ async fn create_test_user(app_state: &AppState) -> Result<TestUser> {
     let user = create_user(
         format!("test_user_{}", Uuid::new_v4().to_string()),
         TEST_USER_PASSWORD.into(),
-        app_state.db.clone(),
+        None,
+        app_state.users.as_ref(),
     )
     .await?;
     let user_id = user.id.to_string();
-    let pak = gen_pak(app_state, &user_id, "test_token").await?;
+    let pak = gen_pak(app_state, &user_id, "test_token", &Scope::ALL, None).await?;
 
     Ok(TestUser {
         id: user_id,
@@ -100,6 +140,116 @@ async fn health_check_works() {
     assert_eq!(Some(0), response.content_length());
 }
 
+#[tokio::test]
+async fn health_probes_report_dependencies_and_shutdown() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    // Act
+    let live = client
+        .get(format!("{}/health/live", &app.address))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let ready = client
+        .get(format!("{}/health/ready", &app.address))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let ready_status = ready.status().as_u16();
+    let ready = ready
+        .json::<Value>()
+        .await
+        .expect("Failed to parse json body");
+    app.state.shutting_down.store(true, Ordering::Relaxed);
+    let shutting_down = client
+        .get(format!("{}/health/ready", &app.address))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let shutting_down_status = shutting_down.status().as_u16();
+    let shutting_down = shutting_down
+        .json::<Value>()
+        .await
+        .expect("Failed to parse json body");
+
+    // Assert
+    assert!(live.status().is_success());
+    assert_eq!(ready_status, 200);
+    assert_eq!(ready["status"], "ok");
+    assert_eq!(ready["build"]["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(ready["dependencies"]["surrealdb"]["status"], "ok");
+    assert_eq!(shutting_down_status, 503);
+    assert_eq!(shutting_down["status"], "shutting_down");
+}
+
+#[tokio::test]
+async fn readiness_fails_without_a_database() {
+    // Arrange
+    let state = AppState::in_memory();
+    let address = serve_app(&state);
+
+    // Act
+    let response = reqwest::Client::new()
+        .get(format!("{}/health/ready", &address))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 503);
+    let body = response
+        .json::<Value>()
+        .await
+        .expect("Failed to parse json body");
+    assert_eq!(body["dependencies"]["surrealdb"]["status"], "unavailable");
+    assert_eq!(body["dependencies"]["surrealdb"]["error"], "query failed");
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn readiness_checks_the_sql_database() {
+    use linkstowr::repository::sql::connect;
+
+    // Arrange
+    let app = spawn_app().await;
+    let db_path = std::env::temp_dir().join(format!("linkstowr-{}.db", Uuid::new_v4()));
+    let url = format!("sqlite://{}?mode=rwc", db_path.display());
+    let pool = connect(DatabaseBackend::Sqlite, Some(&url))
+        .await
+        .expect("Failed to set up the SQLite database");
+    let state = app.state.clone().with_sql_pool(pool.clone());
+    let address = serve_app(&state);
+    let client = reqwest::Client::new();
+
+    // Act
+    let ready: Value = client
+        .get(format!("{address}/health/ready"))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json()
+        .await
+        .expect("Failed to parse json body");
+    pool.close().await;
+    let closed = client
+        .get(format!("{address}/health/ready"))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let closed_status = closed.status().as_u16();
+    let closed: Value = closed.json().await.expect("Failed to parse json body");
+
+    // Assert
+    assert_eq!(ready["dependencies"]["sql"]["status"], "ok");
+    assert_eq!(closed_status, 503);
+    assert_eq!(closed["dependencies"]["sql"]["status"], "unavailable");
+    assert_eq!(closed["dependencies"]["sql"]["error"], "query failed");
+
+    let _ = fs::remove_file(db_path);
+}
+
 #[tokio::test]
 async fn sign_up_works() {
     // Arrange
@@ -213,6 +363,106 @@ async fn sign_in_works() {
     assert_eq!(&sign_in_response.id, &test_user.id);
 }
 
+async fn sign_in(app: &TestApp, test_user: &TestUser) -> UserResponse {
+    reqwest::Client::new()
+        .post(format!("{}/signin", &app.address))
+        .json(&json!({
+            "username": test_user.username,
+            "password": TEST_USER_PASSWORD,
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json::<UserResponse>()
+        .await
+        .expect("Failed to parse json body")
+}
+
+async fn refresh(app: &TestApp, refresh_token: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/refresh", &app.address))
+        .json(&json!({ "refresh_token": refresh_token }))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn get_me_status(app: &TestApp, token: &str) -> u16 {
+    reqwest::Client::new()
+        .get(format!("{}/me", &app.address))
+        .bearer_auth(token)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .status()
+        .as_u16()
+}
+
+#[tokio::test]
+async fn refresh_tokens_rotate_and_detect_reuse() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    let session = sign_in(&app, &test_user).await;
+
+    // Act
+    let refreshed = refresh(&app, &session.refresh_token)
+        .await
+        .json::<RefreshResponse>()
+        .await
+        .expect("Failed to parse json body");
+    let me_after_refresh = get_me_status(&app, &refreshed.token).await;
+    let reused = refresh(&app, &session.refresh_token).await;
+
+    // Assert
+    assert_ne!(&refreshed.refresh_token, &session.refresh_token);
+    assert_eq!(me_after_refresh, 200);
+    assert_eq!(reused.status().as_u16(), 401);
+    // Reusing a refresh token revokes the whole session
+    assert_eq!(get_me_status(&app, &refreshed.token).await, 401);
+    assert_eq!(
+        refresh(&app, &refreshed.refresh_token)
+            .await
+            .status()
+            .as_u16(),
+        401
+    );
+}
+
+#[tokio::test]
+async fn logout_revokes_session() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let test_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    let session = sign_in(&app, &test_user).await;
+    let other_session = sign_in(&app, &test_user).await;
+
+    // Act
+    let response = client
+        .post(format!("{}/logout", &app.address))
+        .bearer_auth(&session.token)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert!(response.status().is_success());
+    assert_eq!(get_me_status(&app, &session.token).await, 401);
+    assert_eq!(
+        refresh(&app, &session.refresh_token)
+            .await
+            .status()
+            .as_u16(),
+        401
+    );
+    assert_eq!(get_me_status(&app, &other_session.token).await, 200);
+}
+
 #[tokio::test]
 async fn sign_in_errors() {
     // Arrange
@@ -334,10 +584,14 @@ async fn seed_links_for_user(user_id: &str, app_state: &AppState) {
     let mut values: Vec<String> = vec![];
 
     for (link, title, note) in links {
-        values.push(format!("('{link}', '{title}', '{note}', $user_id)"));
+        let canonical_url = app_state.url_canonicalizer.canonicalize(link);
+        values.push(format!(
+            "('{link}', '{canonical_url}', '{title}', '{note}', $user_id)"
+        ));
     }
     let values = values.join(", ");
-    let query = format!("INSERT INTO link (url, title, note, user) VALUES {values};");
+    let query =
+        format!("INSERT INTO link (url, canonical_url, title, note, user) VALUES {values};");
 
     app_state
         .db
@@ -371,8 +625,2657 @@ async fn get_links_works() {
     // Assert
     assert!(response.status().is_success());
     let links_resp = response
-        .json::<Vec<LinkResponse>>()
+        .json::<LinksPage>()
+        .await
+        .expect("Failed to parse json body");
+    assert_eq!(links_resp.links.len(), 2);
+    assert_eq!(links_resp.next_cursor, None);
+}
+
+async fn get_links_page(app: &TestApp, test_user: &TestUser, query: &[(&str, &str)]) -> LinksPage {
+    let response = reqwest::Client::new()
+        .get(format!("{}/api/links", &app.address))
+        .query(query)
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert!(response.status().is_success());
+    response
+        .json::<LinksPage>()
+        .await
+        .expect("Failed to parse json body")
+}
+
+#[tokio::test]
+async fn get_links_paginates() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    seed_links_for_user(&test_user.id, &app.state).await;
+
+    // Act
+    let first_page = get_links_page(
+        &app,
+        &test_user,
+        &[("limit", "1"), ("sort", "title"), ("order", "asc")],
+    )
+    .await;
+    let next_cursor = first_page.next_cursor.clone().expect("Missing next cursor");
+    let second_page = get_links_page(
+        &app,
+        &test_user,
+        &[
+            ("limit", "1"),
+            ("sort", "title"),
+            ("order", "asc"),
+            ("cursor", &next_cursor),
+        ],
+    )
+    .await;
+
+    // Assert
+    assert_eq!(first_page.links.len(), 1);
+    assert_eq!(&first_page.links[0].title, "Bing");
+    assert_eq!(second_page.links.len(), 1);
+    assert_eq!(&second_page.links[0].title, "Google");
+    assert_eq!(second_page.next_cursor, None);
+}
+
+#[tokio::test]
+async fn get_links_filters() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    seed_links_for_user(&test_user.id, &app.state).await;
+    let test_cases = vec![
+        (vec![("domain", "google.com")], 1, "domain"),
+        (vec![("domain", "example.com")], 0, "unknown domain"),
+        (vec![("has_note", "true")], 2, "has a note"),
+        (vec![("has_note", "false")], 0, "has no note"),
+        (vec![("from", "2000-01-01T00:00:00Z")], 2, "date range"),
+        (
+            vec![("to", "2000-01-01T00:00:00Z")],
+            0,
+            "date range in the past",
+        ),
+    ];
+
+    for (query, expected_count, filter) in test_cases {
+        // Act
+        let page = get_links_page(&app, &test_user, &query).await;
+
+        // Assert
+        assert_eq!(
+            expected_count,
+            page.links.len(),
+            "The API did not return {} links when filtering by {}.",
+            expected_count,
+            filter
+        );
+    }
+}
+
+async fn get_link_ids(app: &TestApp, test_user: &TestUser) -> Vec<String> {
+    reqwest::Client::new()
+        .get(format!("{}/api/links", &app.address))
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json::<LinksPage>()
+        .await
+        .expect("Failed to parse json body")
+        .links
+        .into_iter()
+        .map(|link| link.id)
+        .collect()
+}
+
+#[tokio::test]
+async fn single_link_crud_works() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let test_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    seed_links_for_user(&test_user.id, &app.state).await;
+    let link_id = get_link_ids(&app, &test_user).await.remove(0);
+
+    // Act
+    let get_response = client
+        .get(format!("{}/api/links/{}", &app.address, link_id))
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let update_response = client
+        .patch(format!("{}/api/links/{}", &app.address, link_id))
+        .header("Content-Type", "application/json")
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .body(json!({ "title": "Fixed title" }).to_string())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let updated = update_response
+        .json::<LinkResponse>()
+        .await
+        .expect("Failed to parse json body");
+    let delete_response = client
+        .delete(format!("{}/api/links/{}", &app.address, link_id))
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert!(get_response.status().is_success());
+    let link = get_response
+        .json::<LinkResponse>()
         .await
         .expect("Failed to parse json body");
-    assert_eq!(links_resp.len(), 2);
+    assert_eq!(&link.id, &link_id);
+    assert_eq!(&updated.id, &link_id);
+    assert_eq!(&updated.title, "Fixed title");
+    assert_eq!(&updated.url, &link.url);
+    assert!(delete_response.status().is_success());
+    assert_eq!(get_link_ids(&app, &test_user).await.len(), 1);
+}
+
+#[tokio::test]
+async fn single_link_routes_check_ownership() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let owner = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    let other_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    seed_links_for_user(&owner.id, &app.state).await;
+    let link_id = get_link_ids(&app, &owner).await.remove(0);
+    let url = format!("{}/api/links/{}", &app.address, link_id);
+    let test_cases = vec![
+        (client.get(&url), "get"),
+        (
+            client.patch(&url).json(&json!({ "title": "Hijacked" })),
+            "update",
+        ),
+        (client.delete(&url), "delete"),
+    ];
+
+    // Act
+    for (request, action) in test_cases {
+        let response = request
+            .header("X-Api-Token", &other_user.pak.to_string())
+            .send()
+            .await
+            .expect("Failed to execute request.");
+
+        // Assert
+        assert_eq!(
+            404,
+            response.status().as_u16(),
+            "The API did not fail with status code 404 when trying to {} another user's link.",
+            action
+        );
+    }
+    assert_eq!(get_link_ids(&app, &owner).await.len(), 2);
+}
+
+async fn register_device(app: &TestApp, test_user: &TestUser, name: &str) -> DeviceResponse {
+    reqwest::Client::new()
+        .post(format!("{}/api/devices", &app.address))
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .json(&json!({ "name": name }))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json::<DeviceResponse>()
+        .await
+        .expect("Failed to parse json body")
+}
+
+async fn get_device_changes(
+    app: &TestApp,
+    test_user: &TestUser,
+    device: &DeviceResponse,
+) -> ChangesResponse {
+    reqwest::Client::new()
+        .get(format!(
+            "{}/api/devices/{}/changes",
+            &app.address, device.id
+        ))
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json::<ChangesResponse>()
+        .await
+        .expect("Failed to parse json body")
+}
+
+#[tokio::test]
+async fn device_sync_works() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let test_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    seed_links_for_user(&test_user.id, &app.state).await;
+    let laptop = register_device(&app, &test_user, "laptop").await;
+    let phone = register_device(&app, &test_user, "phone").await;
+
+    // Act
+    let initial_changes = get_device_changes(&app, &test_user, &laptop).await;
+    let ack_response = client
+        .post(format!("{}/api/devices/{}/ack", &app.address, laptop.id))
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .json(&json!({ "cursor": initial_changes.cursor }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let deleted_id = initial_changes.links[0].id.clone();
+    client
+        .delete(format!("{}/api/links/{}", &app.address, deleted_id))
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let laptop_changes = get_device_changes(&app, &test_user, &laptop).await;
+    let phone_changes = get_device_changes(&app, &test_user, &phone).await;
+
+    // Assert
+    assert_eq!(initial_changes.links.len(), 2);
+    assert!(initial_changes.deleted.is_empty());
+    assert!(!initial_changes.has_more);
+    assert!(ack_response.status().is_success());
+    assert!(laptop_changes.links.is_empty());
+    assert_eq!(laptop_changes.deleted, vec![deleted_id]);
+    // The phone never acknowledged anything, so it sees the remaining link
+    assert_eq!(phone_changes.links.len(), 1);
+    assert_eq!(get_link_ids(&app, &test_user).await.len(), 1);
+}
+
+#[tokio::test]
+async fn device_changes_are_paged_by_position() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let test_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    // Both links are inserted by the same statement, so they can share an
+    // `updated_at`.
+    seed_links_for_user(&test_user.id, &app.state).await;
+    let device = register_device(&app, &test_user, "laptop").await;
+    let changes_url = format!("{}/api/devices/{}/changes?limit=1", &app.address, device.id);
+    let ack_url = format!("{}/api/devices/{}/ack", &app.address, device.id);
+
+    // Act
+    let mut pages = vec![];
+    let mut ack_statuses = vec![];
+    for cursor in ["", "", "not-a-cursor"] {
+        let page = client
+            .get(&changes_url)
+            .header("X-Api-Token", &test_user.pak.to_string())
+            .send()
+            .await
+            .expect("Failed to execute request.")
+            .json::<ChangesResponse>()
+            .await
+            .expect("Failed to parse json body");
+        let cursor = match cursor {
+            "" => page.cursor.clone(),
+            cursor => cursor.to_string(),
+        };
+        let ack_response = client
+            .post(&ack_url)
+            .header("X-Api-Token", &test_user.pak.to_string())
+            .json(&json!({ "cursor": cursor }))
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        pages.push(page);
+        ack_statuses.push(ack_response.status().as_u16());
+    }
+
+    // Assert
+    assert_eq!(pages[0].links.len(), 1);
+    assert!(pages[0].has_more);
+    assert_eq!(pages[1].links.len(), 1);
+    assert_ne!(pages[0].links[0].id, pages[1].links[0].id);
+    assert!(!pages[1].has_more);
+    assert!(pages[2].links.is_empty());
+    assert_eq!(ack_statuses, vec![200, 200, 400]);
+}
+
+#[tokio::test]
+async fn device_acks_are_limited_to_served_changes() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let test_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    seed_links_for_user(&test_user.id, &app.state).await;
+    let device = register_device(&app, &test_user, "laptop").await;
+    let ack_url = format!("{}/api/devices/{}/ack", &app.address, device.id);
+    let future_cursor = bs58::encode(
+        serde_json::to_vec(&json!({
+            "links": { "at": "2999-01-01T00:00:00Z", "id": null },
+            "deleted": null,
+        }))
+        .unwrap(),
+    )
+    .into_string();
+
+    // Act
+    let unserved_ack = client
+        .post(&ack_url)
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .json(&json!({ "cursor": future_cursor }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let changes = get_device_changes(&app, &test_user, &device).await;
+    let past_served_ack = client
+        .post(&ack_url)
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .json(&json!({ "cursor": future_cursor }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let served_ack = client
+        .post(&ack_url)
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .json(&json!({ "cursor": changes.cursor }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(unserved_ack.status().as_u16(), 400);
+    assert_eq!(past_served_ack.status().as_u16(), 400);
+    assert!(served_ack.status().is_success());
+}
+
+async fn create_link(app: &TestApp, test_user: &TestUser, body: Value) -> Value {
+    reqwest::Client::new()
+        .post(format!("{}/api/links", &app.address))
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .json(&body)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json::<Value>()
+        .await
+        .expect("Failed to parse json body")
+}
+
+async fn get_tags(app: &TestApp, test_user: &TestUser) -> Vec<TagResponse> {
+    reqwest::Client::new()
+        .get(format!("{}/api/tags", &app.address))
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json::<Vec<TagResponse>>()
+        .await
+        .expect("Failed to parse json body")
+}
+
+#[tokio::test]
+async fn link_tags_work() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    seed_links_for_user(&test_user.id, &app.state).await;
+
+    // Act
+    create_link(
+        &app,
+        &test_user,
+        json!({
+            "url": "https://doc.rust-lang.org/book/",
+            "title": "The Rust Book",
+            "note": "",
+            "tags": ["Rust", "#reading", "rust"],
+        }),
+    )
+    .await;
+    let tagged = get_links_page(&app, &test_user, &[("tags", "rust,reading")]).await;
+    let tags = get_tags(&app, &test_user).await;
+
+    // Assert
+    assert_eq!(tagged.links.len(), 1);
+    let mut link_tags = tagged.links[0].tags.clone();
+    link_tags.sort();
+    assert_eq!(link_tags, vec!["reading", "rust"]);
+    assert_eq!(tags.len(), 2);
+    assert!(tags.iter().all(|tag| tag.count == 1));
+}
+
+async fn search_links(app: &TestApp, test_user: &TestUser, q: &str) -> SearchResults {
+    let response = reqwest::Client::new()
+        .get(format!("{}/api/links/search", &app.address))
+        .query(&[("q", q)])
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert!(response.status().is_success());
+    response
+        .json::<SearchResults>()
+        .await
+        .expect("Failed to parse json body")
+}
+
+#[tokio::test]
+async fn search_links_works() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    let other_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    seed_links_for_user(&test_user.id, &app.state).await;
+    seed_links_for_user(&other_user.id, &app.state).await;
+    create_link(
+        &app,
+        &test_user,
+        json!({
+            "url": "https://tokio.rs/tokio/tutorial",
+            "title": "Tokio tutorial",
+            "note": "Asynchronous programming in Rust with the tokio runtime",
+        }),
+    )
+    .await;
+    let test_cases = vec![
+        ("tokio", 1, "a term"),
+        ("asynchronous", 1, "a term in the note"),
+        ("tutorial runtime", 1, "terms in different fields"),
+        ("tutorial google", 0, "terms of different links"),
+        ("tok*", 1, "a prefix"),
+        ("\"programming in rust\"", 1, "a phrase"),
+        ("\"rust in programming\"", 0, "a phrase in the wrong order"),
+        ("google", 1, "a term only this user's link has"),
+        ("nonexistent", 0, "an unknown term"),
+    ];
+
+    for (q, expected_count, query) in test_cases {
+        // Act
+        let search = search_links(&app, &test_user, q).await;
+
+        // Assert
+        assert_eq!(
+            expected_count,
+            search.results.len(),
+            "The API did not return {} links when searching for {}.",
+            expected_count,
+            query
+        );
+    }
+    let search = search_links(&app, &test_user, "tokio").await;
+    assert_eq!(
+        search.results[0].highlights.title,
+        "<mark>Tokio</mark> tutorial"
+    );
+}
+
+#[test]
+fn search_highlights_fold_case_like_the_query() {
+    let search_query = SearchQuery::parse("ÉCOLE straße*");
+
+    assert_eq!(
+        search_query.highlight("L'École de la STRASSE et la Straßenbahn"),
+        "L'<mark>École</mark> de la STRASSE et la <mark>Straße</mark>nbahn"
+    );
+}
+
+#[tokio::test]
+async fn unused_tags_are_removed_after_a_failed_save() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    create_link(
+        &app,
+        &test_user,
+        json!({
+            "url": "https://www.rust-lang.org/",
+            "title": "Rust",
+            "note": "",
+            "tags": ["rust"],
+        }),
+    )
+    .await;
+    let names: Vec<String> = vec!["rust".into(), "#Async".into(), "tokio".into()];
+
+    // Act
+    let resolved = resolve_tag_names(&app.state, &test_user.id, &names)
+        .await
+        .expect("Failed to resolve tags");
+    let ids = resolved.ids(&names);
+    let before = get_tags(&app, &test_user).await;
+    remove_unused_tags(&app.state, &test_user.id, &resolved).await;
+    let after = get_tags(&app, &test_user).await;
+
+    // Assert
+    assert_eq!(ids.len(), 3);
+    assert_eq!(before.len(), 3);
+    let names: Vec<&str> = after.iter().map(|tag| tag.name.as_str()).collect();
+    assert_eq!(names, vec!["rust"]);
+}
+
+#[tokio::test]
+async fn concurrent_saves_create_new_tags_once() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    let names: Vec<String> = vec!["rust".into(), "tokio".into(), "async".into()];
+
+    // Act
+    let (first, second) = tokio::join!(
+        resolve_tag_names(&app.state, &test_user.id, &names),
+        resolve_tag_names(&app.state, &test_user.id, &names),
+    );
+    let first = first.expect("Failed to resolve tags");
+    let second = second.expect("Failed to resolve tags");
+
+    // Assert
+    assert_eq!(first.ids(&names), second.ids(&names));
+    assert_eq!(get_tags(&app, &test_user).await.len(), 3);
+}
+
+#[tokio::test]
+async fn unused_tags_resolved_by_another_save_are_kept() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    let names: Vec<String> = vec!["tokio".into()];
+
+    // Act
+    let failed_save = resolve_tag_names(&app.state, &test_user.id, &names)
+        .await
+        .expect("Failed to resolve tags");
+    resolve_tag_names(&app.state, &test_user.id, &names)
+        .await
+        .expect("Failed to resolve tags");
+    remove_unused_tags(&app.state, &test_user.id, &failed_save).await;
+    let after = get_tags(&app, &test_user).await;
+
+    // Assert
+    let names: Vec<&str> = after.iter().map(|tag| tag.name.as_str()).collect();
+    assert_eq!(names, vec!["tokio"]);
+}
+
+#[tokio::test]
+async fn rename_and_merge_tags_work() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let test_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    for (url, tag) in [
+        ("https://www.rust-lang.org/", "rust"),
+        ("https://crates.io/", "rustlang"),
+    ] {
+        create_link(
+            &app,
+            &test_user,
+            json!({ "url": url, "title": url, "note": "", "tags": [tag] }),
+        )
+        .await;
+    }
+    let tags = get_tags(&app, &test_user).await;
+    let rust = tags.iter().find(|tag| tag.name == "rust").unwrap();
+    let rustlang = tags.iter().find(|tag| tag.name == "rustlang").unwrap();
+
+    // Act
+    let conflicting_rename = client
+        .patch(format!("{}/api/tags/{}", &app.address, rustlang.id))
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .json(&json!({ "name": "Rust" }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let merged = client
+        .post(format!("{}/api/tags/merge", &app.address))
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .json(&json!({ "source": rustlang.id, "target": rust.id }))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json::<TagResponse>()
+        .await
+        .expect("Failed to parse json body");
+    let renamed = client
+        .patch(format!("{}/api/tags/{}", &app.address, rust.id))
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .json(&json!({ "name": "rust-lang" }))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json::<TagResponse>()
+        .await
+        .expect("Failed to parse json body");
+    let tagged = get_links_page(&app, &test_user, &[("tags", "rust-lang")]).await;
+
+    // Assert
+    assert_eq!(conflicting_rename.status().as_u16(), 409);
+    assert_eq!(merged.count, 2);
+    assert_eq!(&renamed.name, "rust-lang");
+    assert_eq!(get_tags(&app, &test_user).await.len(), 1);
+    assert_eq!(tagged.links.len(), 2);
+}
+
+async fn create_collection(
+    app: &TestApp,
+    test_user: &TestUser,
+    name: &str,
+    parent: Option<&str>,
+) -> CollectionResponse {
+    reqwest::Client::new()
+        .post(format!("{}/api/collections", &app.address))
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .json(&json!({ "name": name, "parent": parent }))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json::<CollectionResponse>()
+        .await
+        .expect("Failed to parse json body")
+}
+
+async fn get_collections(app: &TestApp, test_user: &TestUser) -> Vec<CollectionResponse> {
+    reqwest::Client::new()
+        .get(format!("{}/api/collections", &app.address))
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json::<Vec<CollectionResponse>>()
+        .await
+        .expect("Failed to parse json body")
+}
+
+#[tokio::test]
+async fn nested_collections_work() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let test_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    let work = create_collection(&app, &test_user, "Work", None).await;
+    let onboarding = create_collection(&app, &test_user, "Onboarding", Some(&work.id)).await;
+    let created = create_link(
+        &app,
+        &test_user,
+        json!({
+            "url": "https://example.com/handbook",
+            "title": "Handbook",
+            "note": "",
+            "collection": onboarding.id,
+        }),
+    )
+    .await;
+
+    // Act
+    let tree = get_collections(&app, &test_user).await;
+    let in_onboarding =
+        get_links_page(&app, &test_user, &[("collection", onboarding.id.as_str())]).await;
+    let cyclic_move = client
+        .patch(format!("{}/api/collections/{}", &app.address, work.id))
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .json(&json!({ "parent": onboarding.id }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(tree.len(), 1);
+    assert_eq!(&tree[0].name, "Work");
+    assert_eq!(tree[0].children.len(), 1);
+    assert_eq!(&tree[0].children[0].id, &onboarding.id);
+    assert_eq!(tree[0].children[0].link_count, 1);
+    assert_eq!(in_onboarding.links.len(), 1);
+    assert_eq!(in_onboarding.links[0].id, created["result"]["id"]);
+    assert_eq!(cyclic_move.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn delete_collection_moves_or_cascades() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let test_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    let reading = create_collection(&app, &test_user, "Reading", None).await;
+    let rust = create_collection(&app, &test_user, "Rust", Some(&reading.id)).await;
+    let archive = create_collection(&app, &test_user, "Archive", None).await;
+    for (url, collection) in [
+        ("https://example.com/reading", &reading.id),
+        ("https://example.com/rust", &rust.id),
+        ("https://example.com/archive", &archive.id),
+    ] {
+        create_link(
+            &app,
+            &test_user,
+            json!({ "url": url, "title": url, "note": "", "collection": collection }),
+        )
+        .await;
+    }
+
+    // Act
+    let moved = client
+        .delete(format!("{}/api/collections/{}", &app.address, reading.id))
+        .query(&[("mode", "move"), ("target", archive.id.as_str())])
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let tree_after_move = get_collections(&app, &test_user).await;
+    let in_archive = get_links_page(&app, &test_user, &[("collection", archive.id.as_str())]).await;
+    let cascaded = client
+        .delete(format!("{}/api/collections/{}", &app.address, archive.id))
+        .query(&[("mode", "cascade")])
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert!(moved.status().is_success());
+    // The child collection moves up to the top level
+    assert_eq!(tree_after_move.len(), 2);
+    assert_eq!(in_archive.links.len(), 2);
+    assert!(cascaded.status().is_success());
+    assert_eq!(get_collections(&app, &test_user).await.len(), 1);
+    assert_eq!(get_link_ids(&app, &test_user).await.len(), 1);
+}
+
+#[tokio::test]
+async fn duplicate_links_merge_or_conflict() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let test_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    let created = create_link(
+        &app,
+        &test_user,
+        json!({ "url": "https://www.example.com/article/?b=2&a=1", "title": "Article", "note": "" }),
+    )
+    .await;
+
+    // Act
+    let merged = create_link(
+        &app,
+        &test_user,
+        json!({
+            "url": "http://example.com/article?utm_source=feed&a=1&b=2#comments",
+            "title": "Article",
+            "note": "Read later",
+        }),
+    )
+    .await;
+    let rejected = client
+        .post(format!("{}/api/links", &app.address))
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .json(&json!({
+            "url": "https://example.com/article?a=1&b=2&fbclid=abc",
+            "title": "Article",
+            "note": "",
+            "on_duplicate": "reject",
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let rejected_status = rejected.status().as_u16();
+    let rejected_body = rejected
+        .json::<Value>()
+        .await
+        .expect("Failed to parse json body");
+    let links = get_links_page(&app, &test_user, &[]).await;
+
+    // Assert
+    assert_eq!(merged["result"]["id"], created["result"]["id"]);
+    assert_eq!(merged["result"]["duplicate"], true);
+    assert_eq!(rejected_status, 409);
+    assert_eq!(
+        rejected_body["error"]["data"]["id"],
+        created["result"]["id"]
+    );
+    assert_eq!(links.links.len(), 1);
+    assert_eq!(&links.links[0].note, "Read later");
+}
+
+#[tokio::test]
+async fn batch_create_links_works() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let test_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    let saved = create_link(
+        &app,
+        &test_user,
+        json!({ "url": "https://example.com/saved", "title": "Saved", "note": "" }),
+    )
+    .await;
+    let too_many: Vec<Value> = (0..=app.state.max_link_batch_size)
+        .map(|i| json!({ "url": format!("https://example.com/{i}"), "title": "", "note": "" }))
+        .collect();
+
+    // Act
+    let response = client
+        .post(format!("{}/api/links/batch", &app.address))
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .json(&json!([
+            { "url": "https://example.com/one", "title": "One", "note": "", "tags": ["rust"] },
+            { "url": "https://example.com/saved?utm_source=tab", "title": "Saved", "note": "" },
+            { "url": "https://example.com/two", "note": "missing title" },
+            { "url": "http://www.example.com/one/", "title": "One again", "note": "" },
+            { "url": "https://example.com/two", "title": "Two", "note": "" },
+        ]))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let status = response.status();
+    let batch = response
+        .json::<BatchResponse>()
+        .await
+        .expect("Failed to parse json body");
+    let too_large = client
+        .post(format!("{}/api/links/batch", &app.address))
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .json(&too_many)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert!(status.is_success());
+    let statuses: Vec<BatchItemStatus> = batch.results.iter().map(|r| r.status).collect();
+    assert_eq!(
+        statuses,
+        vec![
+            BatchItemStatus::Created,
+            BatchItemStatus::Duplicate,
+            BatchItemStatus::Invalid,
+            BatchItemStatus::Duplicate,
+            BatchItemStatus::Created,
+        ]
+    );
+    assert_eq!(
+        batch.results[1].id.as_deref(),
+        saved["result"]["id"].as_str()
+    );
+    assert_eq!(batch.results[2].error.as_deref(), Some("INVALID_PARAMS"));
+    assert_eq!(batch.results[3].id, batch.results[0].id);
+    assert_eq!(get_link_ids(&app, &test_user).await.len(), 3);
+    assert_eq!(&get_tags(&app, &test_user).await[0].name, "rust");
+    assert_eq!(too_large.status().as_u16(), 400);
+}
+
+async fn import_links(
+    app: &TestApp,
+    test_user: &TestUser,
+    format: &str,
+    body: &str,
+) -> ImportReport {
+    reqwest::Client::new()
+        .post(format!("{}/api/import", &app.address))
+        .query(&[("format", format)])
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .body(body.to_string())
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json::<ImportReport>()
+        .await
+        .expect("Failed to parse json body")
+}
+
+#[tokio::test]
+async fn import_netscape_bookmarks_works() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    create_link(
+        &app,
+        &test_user,
+        json!({ "url": "https://example.com/saved", "title": "Saved", "note": "" }),
+    )
+    .await;
+    let export = r#"<!DOCTYPE NETSCAPE-Bookmark-file-1>
+<META HTTP-EQUIV="Content-Type" CONTENT="text/html; charset=UTF-8">
+<TITLE>Bookmarks</TITLE>
+<H1>Bookmarks</H1>
+<DL><p>
+    <DT><H3 ADD_DATE="1600000000">Reading</H3>
+    <DL><p>
+        <DT><H3>Rust</H3>
+        <DL><p>
+            <DT><A HREF="https://doc.rust-lang.org/book/" ADD_DATE="1577836800" TAGS="rust,books">The Rust &amp; Book</A>
+            <DD>Read chapter 4
+        </DL><p>
+        <DT><H3>Archive</H3>
+        <DL><p>
+            <DT><A HREF="https://www.example.com/saved/" TAGS="skipped">Saved again</A>
+        </DL><p>
+    </DL><p>
+    <DT><A HREF="">No URL</A>
+    <DT><A HREF="https://example.com/top">Top level</A>
+</DL><p>
+"#;
+
+    // Act
+    let report = import_links(&app, &test_user, "netscape", export).await;
+    let links = get_links_page(
+        &app,
+        &test_user,
+        &[("sort", "bookmarked_at"), ("order", "asc")],
+    )
+    .await;
+    let collections = get_collections(&app, &test_user).await;
+    let tags = get_tags(&app, &test_user).await;
+
+    // Assert
+    assert_eq!(report.imported, 2);
+    assert_eq!(report.skipped, 1);
+    assert_eq!(report.failed, 1);
+    assert_eq!(links.links.len(), 3);
+    // The tag of the skipped entry isn't kept
+    assert_eq!(tags.len(), 2);
+    let book = &links.links[0];
+    assert_eq!(&book.title, "The Rust & Book");
+    assert_eq!(&book.note, "Read chapter 4");
+    assert_eq!(book.tags.len(), 2);
+    assert_eq!(
+        &book.bookmarked_at.to_rfc3339(),
+        "2020-01-01T00:00:00+00:00"
+    );
+    // Only folders with imported links become collections
+    assert_eq!(collections.len(), 1);
+    assert_eq!(&collections[0].name, "Reading");
+    assert_eq!(collections[0].children.len(), 1);
+    assert_eq!(&collections[0].children[0].name, "Rust");
+    assert_eq!(
+        book.collection.as_ref(),
+        Some(&collections[0].children[0].id)
+    );
+}
+
+#[tokio::test]
+async fn import_csv_and_json_exports_works() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    let pocket = "title,url,time_added,tags,status\n\
+        Pocket,https://example.com/pocket,1577836800,read|later,unread\n\
+        Broken,,1577836800,,unread\n";
+    let pinboard = json!([
+        {
+            "href": "https://example.com/pinboard",
+            "description": "Pinboard",
+            "extended": "A note",
+            "time": "2020-01-01T00:00:00Z",
+            "tags": "one two",
+        },
+        { "description": "No href" },
+    ])
+    .to_string();
+    let raindrop = "id,title,note,excerpt,url,folder,tags,created,cover,highlights,favorite\n\
+        1,Raindrop,Note,,https://example.com/raindrop,Work/Docs,\"a, b\",2020-01-01T00:00:00.000Z,,,false\n";
+
+    // Act
+    let pocket_report = import_links(&app, &test_user, "pocket_csv", pocket).await;
+    let pinboard_report = import_links(&app, &test_user, "pinboard", &pinboard).await;
+    let raindrop_report = import_links(&app, &test_user, "raindrop", raindrop).await;
+    let invalid = reqwest::Client::new()
+        .post(format!("{}/api/import", &app.address))
+        .query(&[("format", "pinboard")])
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .body("not json")
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let collections = get_collections(&app, &test_user).await;
+
+    // Assert
+    assert_eq!((pocket_report.imported, pocket_report.failed), (1, 1));
+    assert_eq!((pinboard_report.imported, pinboard_report.failed), (1, 1));
+    assert_eq!((raindrop_report.imported, raindrop_report.failed), (1, 0));
+    assert_eq!(get_link_ids(&app, &test_user).await.len(), 3);
+    assert_eq!(&collections[0].name, "Work");
+    assert_eq!(&collections[0].children[0].name, "Docs");
+    assert_eq!(invalid.status().as_u16(), 400);
+}
+
+async fn export_links(app: &TestApp, test_user: &TestUser, format: &str) -> String {
+    reqwest::Client::new()
+        .get(format!("{}/api/links/export", &app.address))
+        .query(&[("format", format)])
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .text()
+        .await
+        .expect("Failed to read body")
+}
+
+#[tokio::test]
+async fn export_links_works() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    create_link(
+        &app,
+        &test_user,
+        json!({
+            "url": "https://example.com/a",
+            "title": "A & [B]",
+            "note": "Some note",
+            "tags": ["rust lang"],
+        }),
+    )
+    .await;
+    create_link(
+        &app,
+        &test_user,
+        json!({ "url": "https://example.com/b", "title": "Second", "note": "" }),
+    )
+    .await;
+
+    // Act
+    let netscape = export_links(&app, &test_user, "netscape").await;
+    let jsonl = export_links(&app, &test_user, "jsonl").await;
+    let csv = export_links(&app, &test_user, "csv").await;
+    let markdown = export_links(&app, &test_user, "markdown").await;
+
+    // Assert
+    assert!(netscape.starts_with("<!DOCTYPE NETSCAPE-Bookmark-file-1>"));
+    assert!(netscape.contains(">A &amp; [B]</A>"));
+    assert!(netscape.contains("<DD>Some note"));
+    let lines: Vec<LinkResponse> = jsonl
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("Failed to parse json line"))
+        .collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(csv.lines().count(), 3);
+    assert!(csv.starts_with("url,title,note,tags,collection,bookmarked_at,updated_at"));
+    assert!(markdown.contains("- [A & \\[B\\]](https://example.com/a) #rust-lang"));
+    assert!(markdown.contains("    Some note"));
+}
+
+async fn create_token(app: &TestApp, api_token: &str, body: Value) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/api/tokens", &app.address))
+        .header("X-Api-Token", api_token)
+        .json(&body)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn token_scopes_are_enforced() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let test_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    let read_only = create_token(
+        &app,
+        &test_user.pak.to_string(),
+        json!({ "name": "obsidian", "scopes": ["links:read"] }),
+    )
+    .await
+    .json::<Value>()
+    .await
+    .expect("Failed to parse json body");
+    let read_only = read_only["token"].as_str().unwrap().to_string();
+    let default_scopes = create_token(&app, &test_user.pak.to_string(), json!({ "name": "ext" }))
+        .await
+        .json::<Value>()
+        .await
+        .expect("Failed to parse json body");
+    let default_scopes = default_scopes["token"].as_str().unwrap().to_string();
+
+    // Act
+    let read = client
+        .get(format!("{}/api/links", &app.address))
+        .header("X-Api-Token", &read_only)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let write = client
+        .post(format!("{}/api/links", &app.address))
+        .header("X-Api-Token", &read_only)
+        .json(&json!({ "url": "https://example.com", "title": "", "note": "" }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let write_status = write.status().as_u16();
+    let write_body = write
+        .json::<Value>()
+        .await
+        .expect("Failed to parse json body");
+    let register_device = client
+        .post(format!("{}/api/devices", &app.address))
+        .header("X-Api-Token", &read_only)
+        .json(&json!({ "name": "reader" }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let mint_from_default = create_token(&app, &default_scopes, json!({ "name": "more" })).await;
+    let escalate = create_token(
+        &app,
+        &test_user.pak.to_string(),
+        json!({ "name": "bad", "scopes": ["links:read", "admin"] }),
+    )
+    .await;
+
+    // Assert
+    assert!(read.status().is_success());
+    assert_eq!(write_status, 403);
+    assert_eq!(write_body["error"]["type"], "MISSING_SCOPE");
+    assert_eq!(write_body["error"]["data"]["scope"], "links:write");
+    // Registering a device and acknowledging changes are writes too
+    assert_eq!(register_device.status().as_u16(), 403);
+    assert_eq!(mint_from_default.status().as_u16(), 403);
+    assert!(escalate.status().is_client_error());
+}
+
+#[test]
+fn client_ip_is_only_taken_from_trusted_proxies() {
+    let peer: SocketAddr = "10.0.0.2:4000".parse().unwrap();
+    let request = |header: &str| {
+        let mut request = Request::builder()
+            .header(header, "203.0.113.7, 198.51.100.1")
+            .body(())
+            .unwrap();
+        request.extensions_mut().insert(ConnectInfo(peer));
+        request
+    };
+    let behind = |trusted_proxies: Vec<IpAddr>| ProxySettings {
+        client_ip_header: Some("X-Forwarded-For".into()),
+        trusted_proxies,
+    };
+
+    let direct = get_client_ip(&ProxySettings::default(), &request("X-Forwarded-For"));
+    let any_proxy = get_client_ip(&behind(vec![]), &request("X-Forwarded-For"));
+    let trusted = get_client_ip(&behind(vec![peer.ip()]), &request("X-Forwarded-For"));
+    let untrusted = get_client_ip(
+        &behind(vec!["10.0.0.3".parse().unwrap()]),
+        &request("X-Forwarded-For"),
+    );
+    let other_header = get_client_ip(&behind(vec![]), &request("Fly-Client-IP"));
+
+    assert_eq!(direct.as_deref(), Some("10.0.0.2"));
+    // The last entry is the one the proxy added
+    assert_eq!(any_proxy.as_deref(), Some("198.51.100.1"));
+    assert_eq!(trusted.as_deref(), Some("198.51.100.1"));
+    assert_eq!(untrusted.as_deref(), Some("10.0.0.2"));
+    assert_eq!(other_header.as_deref(), Some("10.0.0.2"));
+}
+
+#[tokio::test]
+async fn token_expiry_usage_and_rotation() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let test_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    let token = create_token(&app, &test_user.pak.to_string(), json!({ "name": "ext" }))
+        .await
+        .json::<Value>()
+        .await
+        .expect("Failed to parse json body");
+    let token = token["token"].as_str().unwrap().to_string();
+    let expired = gen_pak(
+        &app.state,
+        &test_user.id,
+        "expired",
+        &Scope::DEFAULT_TOKEN,
+        Some(chrono::Utc::now() - chrono::Duration::minutes(1)),
+    )
+    .await
+    .expect("Failed to create expired token");
+    let past_expiry = create_token(
+        &app,
+        &test_user.pak.to_string(),
+        json!({ "name": "past", "expires_at": "2020-01-01T00:00:00Z" }),
+    )
+    .await;
+
+    // Act
+    let used = client
+        .get(format!("{}/api/links", &app.address))
+        .header("X-Api-Token", &token)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let mut result = app
+        .state
+        .db
+        .query("SELECT * FROM token WHERE name = 'ext';")
+        .await
+        .expect("Failed to query tokens");
+    let stored: Option<TokenDBResult> = result.take(0).expect("Failed to get token");
+    let stored = stored.expect("Token not stored");
+    let rotated = client
+        .post(format!("{}/api/tokens/{}/rotate", &app.address, stored.id))
+        .header("X-Api-Token", test_user.pak.to_string())
+        .json(&json!({ "grace_period_minutes": 0 }))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json::<Value>()
+        .await
+        .expect("Failed to parse json body");
+    let new_token = rotated["token"].as_str().unwrap();
+    let with_old = client
+        .get(format!("{}/api/links", &app.address))
+        .header("X-Api-Token", &token)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let with_new = client
+        .get(format!("{}/api/links", &app.address))
+        .header("X-Api-Token", new_token)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let with_expired = client
+        .get(format!("{}/api/links", &app.address))
+        .header("X-Api-Token", expired.to_string())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert!(used.status().is_success());
+    assert!(stored.last_used_at.is_some());
+    assert_eq!(past_expiry.status().as_u16(), 400);
+    assert_ne!(new_token, token);
+    assert_eq!(with_old.status().as_u16(), 401);
+    assert!(with_new.status().is_success());
+    assert_eq!(with_expired.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn api_token_validation_is_cached() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let test_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    let token = create_token(&app, &test_user.pak.to_string(), json!({ "name": "ext" }))
+        .await
+        .json::<Value>()
+        .await
+        .expect("Failed to parse json body");
+    let token = token["token"].as_str().unwrap().to_string();
+    let mut result = app
+        .state
+        .db
+        .query("SELECT * FROM token WHERE name = 'ext';")
+        .await
+        .expect("Failed to query tokens");
+    let stored: Option<TokenDBResult> = result.take(0).expect("Failed to get token");
+    let stored = stored.expect("Token not stored");
+    let before = app.state.token_cache.stats();
+
+    // Act
+    for _ in 0..3 {
+        let response = client
+            .get(format!("{}/api/links", &app.address))
+            .header("X-Api-Token", &token)
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        assert!(response.status().is_success());
+    }
+    let after = app.state.token_cache.stats();
+    let deleted = client
+        .delete(format!("{}/api/tokens/{}", &app.address, stored.id))
+        .header("X-Api-Token", test_user.pak.to_string())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let with_deleted = client
+        .get(format!("{}/api/links", &app.address))
+        .header("X-Api-Token", &token)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let metrics = client
+        .get(format!("{}/metrics", &app.address))
+        .bearer_auth(METRICS_TOKEN)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .text()
+        .await
+        .expect("Failed to read body");
+
+    // Assert
+    assert_eq!(after.misses - before.misses, 1);
+    assert_eq!(after.hits - before.hits, 2);
+    assert!(deleted.status().is_success());
+    assert_eq!(with_deleted.status().as_u16(), 401);
+    assert!(metrics.contains(&format!("token_cache_hits {}", after.hits)));
+}
+
+#[tokio::test]
+async fn metrics_record_requests_errors_and_db_latency() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let test_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    create_link(
+        &app,
+        &test_user,
+        json!({ "url": "http://www.example.com/", "title": "Example" }),
+    )
+    .await;
+    let unauthorized = client
+        .get(format!("{}/api/links", &app.address))
+        .header("X-Api-Token", "not-a-token")
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Act
+    let without_token = client
+        .get(format!("{}/metrics", &app.address))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let wrong_token = client
+        .get(format!("{}/metrics", &app.address))
+        .bearer_auth("not-the-token")
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let response = client
+        .get(format!("{}/metrics", &app.address))
+        .bearer_auth(METRICS_TOKEN)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let content_type = response.headers()["content-type"].clone();
+    let metrics = response.text().await.expect("Failed to read body");
+
+    // Assert
+    assert_eq!(without_token.status().as_u16(), 401);
+    assert_eq!(wrong_token.status().as_u16(), 401);
+    assert_eq!(unauthorized.status().as_u16(), 401);
+    assert!(content_type.to_str().unwrap().starts_with("text/plain"));
+    assert!(
+        metrics.contains(r#"http_requests_total{method="POST",route="/api/links",status="200"} 1"#)
+    );
+    assert!(
+        metrics.contains(r#"http_requests_total{method="GET",route="/api/links",status="401"} 1"#)
+    );
+    assert!(metrics.contains(r#"app_errors_total{error="InvalidToken"} 1"#));
+    assert!(metrics.contains(r#"api_tokens_validated_total{result="invalid"} 1"#));
+    assert!(metrics.contains(r#"api_tokens_validated_total{result="valid"} 1"#));
+    assert!(metrics
+        .contains(r#"db_query_duration_seconds_count{operation="create",repository="links"} 1"#));
+    assert!(metrics.contains("links_created_total 1"));
+}
+
+/// The first email in the outbox, which is sent after the response.
+async fn wait_for_email(app: &TestApp) -> String {
+    for _ in 0..50 {
+        let email = fs::read_dir(&app.outbox_dir)
+            .into_iter()
+            .flatten()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().map_or(false, |ext| ext == "eml"))
+            .map(|path| fs::read_to_string(path).unwrap());
+        if let Some(email) = email {
+            return email;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    panic!("No email was sent");
+}
+
+async fn post_json(
+    app: &TestApp,
+    path: &str,
+    bearer: Option<&str>,
+    body: Value,
+) -> reqwest::Response {
+    let mut request = reqwest::Client::new()
+        .post(format!("{}{}", &app.address, path))
+        .json(&body);
+    if let Some(bearer) = bearer {
+        request = request.bearer_auth(bearer);
+    }
+
+    request.send().await.expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn password_change_and_reset_work() {
+    // Arrange
+    let app = spawn_app().await;
+    let username = format!("test_user_{}", Uuid::new_v4());
+    let signup = json!({
+        "username": username,
+        "password": "first-password",
+        "password_confirm": "first-password",
+        "email": "reader@example.com",
+    });
+    let current = post_json(&app, "/signup", None, signup)
+        .await
+        .json::<UserResponse>()
+        .await
+        .expect("Failed to parse json body");
+    let other = post_json(
+        &app,
+        "/signin",
+        None,
+        json!({ "username": username, "password": "first-password" }),
+    )
+    .await
+    .json::<UserResponse>()
+    .await
+    .expect("Failed to parse json body");
+
+    // Act
+    let wrong_password = post_json(
+        &app,
+        "/api/account/password",
+        Some(&current.token),
+        json!({
+            "current_password": "not-it",
+            "password": "second-password",
+            "password_confirm": "second-password",
+        }),
+    )
+    .await;
+    let changed = post_json(
+        &app,
+        "/api/account/password",
+        Some(&current.token),
+        json!({
+            "current_password": "first-password",
+            "password": "second-password",
+            "password_confirm": "second-password",
+        }),
+    )
+    .await;
+    let other_refresh = post_json(
+        &app,
+        "/refresh",
+        None,
+        json!({ "refresh_token": other.refresh_token }),
+    )
+    .await;
+    let current_me = reqwest::Client::new()
+        .get(format!("{}/me", &app.address))
+        .bearer_auth(&current.token)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let unknown_forgot = post_json(
+        &app,
+        "/password/forgot",
+        None,
+        json!({ "username": "nobody" }),
+    )
+    .await;
+    let forgot = post_json(
+        &app,
+        "/password/forgot",
+        None,
+        json!({ "username": username }),
+    )
+    .await;
+    let email = wait_for_email(&app).await;
+    let reset_token = email
+        .lines()
+        .find(|line| line.starts_with("Use this token"))
+        .and_then(|line| line.rsplit(": ").next())
+        .expect("Email has no reset token")
+        .to_string();
+    let reset_body = json!({
+        "token": reset_token,
+        "password": "third-password",
+        "password_confirm": "third-password",
+    });
+    let reset = post_json(&app, "/password/reset", None, reset_body.clone()).await;
+    let reset_again = post_json(&app, "/password/reset", None, reset_body).await;
+    let signin_old = post_json(
+        &app,
+        "/signin",
+        None,
+        json!({ "username": username, "password": "second-password" }),
+    )
+    .await;
+    let signin_new = post_json(
+        &app,
+        "/signin",
+        None,
+        json!({ "username": username, "password": "third-password" }),
+    )
+    .await;
+    let current_me_after_reset = reqwest::Client::new()
+        .get(format!("{}/me", &app.address))
+        .bearer_auth(&current.token)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(wrong_password.status().as_u16(), 400);
+    assert!(changed.status().is_success());
+    assert_eq!(other_refresh.status().as_u16(), 401);
+    assert!(current_me.status().is_success());
+    assert!(unknown_forgot.status().is_success());
+    assert!(forgot.status().is_success());
+    assert!(email.contains("To: reader@example.com"));
+    assert!(reset.status().is_success());
+    assert_eq!(reset_again.status().as_u16(), 400);
+    assert!(signin_old.status().is_client_error());
+    assert!(signin_new.status().is_success());
+    assert_eq!(current_me_after_reset.status().as_u16(), 401);
+}
+
+/// Fails to send every email.
+struct FailingMailer;
+
+#[async_trait]
+impl Mailer for FailingMailer {
+    async fn send(&self, _email: Email) -> Result<()> {
+        Err(Error::SendEmailFail)
+    }
+}
+
+#[tokio::test]
+async fn forgot_password_succeeds_when_the_email_fails() {
+    // Arrange
+    let mut app = spawn_app().await;
+    app.state = app.state.clone().with_mailer(Arc::new(FailingMailer));
+    app.address = serve_app(&app.state);
+    let username = format!("test_user_{}", Uuid::new_v4());
+    let signup = json!({
+        "username": username,
+        "password": "first-password",
+        "password_confirm": "first-password",
+        "email": "reader@example.com",
+    });
+    post_json(&app, "/signup", None, signup).await;
+
+    // Act
+    let forgot = post_json(
+        &app,
+        "/password/forgot",
+        None,
+        json!({ "username": username }),
+    )
+    .await;
+
+    // Assert
+    assert!(forgot.status().is_success());
+}
+
+#[tokio::test]
+async fn account_export_and_deletion_work() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let test_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    create_link(
+        &app,
+        &test_user,
+        json!({ "url": "https://example.com", "title": "Example", "note": "", "tags": ["rust"] }),
+    )
+    .await;
+    let removed = create_link(
+        &app,
+        &test_user,
+        json!({ "url": "https://example.com/removed", "title": "Removed", "note": "" }),
+    )
+    .await;
+    client
+        .delete(format!(
+            "{}/api/links/{}",
+            &app.address,
+            removed["result"]["id"].as_str().unwrap()
+        ))
+        .header("X-Api-Token", test_user.pak.to_string())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    post_json(
+        &app,
+        "/signin",
+        None,
+        json!({ "username": test_user.username, "password": TEST_USER_PASSWORD }),
+    )
+    .await;
+
+    // Act
+    let export = client
+        .get(format!("{}/api/account/export", &app.address))
+        .header("X-Api-Token", test_user.pak.to_string())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let disposition = export.headers()["content-disposition"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    let export = export
+        .json::<AccountExport>()
+        .await
+        .expect("Failed to parse json body");
+    let wrong_password = client
+        .delete(format!("{}/api/account", &app.address))
+        .header("X-Api-Token", test_user.pak.to_string())
+        .json(&json!({ "password": "not-it" }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let deleted = client
+        .delete(format!("{}/api/account", &app.address))
+        .header("X-Api-Token", test_user.pak.to_string())
+        .json(&json!({ "password": TEST_USER_PASSWORD }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let after_delete = client
+        .get(format!("{}/api/links", &app.address))
+        .header("X-Api-Token", test_user.pak.to_string())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let mut result = app
+        .state
+        .db
+        .query("SELECT VALUE id FROM link WHERE user = $user_id;")
+        .query("SELECT VALUE id FROM tag WHERE user = $user_id;")
+        .query("SELECT VALUE id FROM token WHERE user = $user_id;")
+        .query("SELECT VALUE id FROM user WHERE username = $username;")
+        .query("SELECT VALUE id FROM link_tombstone WHERE user = $user_id;")
+        .query("SELECT VALUE id FROM session WHERE user = $user_id;")
+        // Only the deleted user signed in
+        .query("SELECT VALUE id FROM refresh_token;")
+        .bind(("user_id", thing(&test_user.id).unwrap()))
+        .bind(("username", &test_user.username))
+        .await
+        .expect("Failed to query leftovers");
+    let leftovers: Vec<Vec<surrealdb::sql::Thing>> = (0..7)
+        .map(|index| result.take(index).expect("Failed to get leftovers"))
+        .collect();
+
+    // Assert
+    assert!(disposition.contains("linkstowr-account.json"));
+    assert_eq!(export.account.username, test_user.username);
+    assert_eq!(export.links.len(), 1);
+    assert_eq!(export.tags.len(), 1);
+    assert_eq!(export.tokens.len(), 1);
+    assert_eq!(wrong_password.status().as_u16(), 400);
+    assert!(deleted.status().is_success());
+    assert_eq!(after_delete.status().as_u16(), 400);
+    assert!(leftovers.iter().all(|ids| ids.is_empty()));
+}
+
+async fn start_mfa_sign_in(app: &TestApp, test_user: &TestUser) -> String {
+    post_json(
+        app,
+        "/signin",
+        None,
+        json!({ "username": test_user.username, "password": TEST_USER_PASSWORD }),
+    )
+    .await
+    .json::<MfaChallengeResponse>()
+    .await
+    .expect("Failed to parse json body")
+    .challenge_token
+}
+
+#[tokio::test]
+async fn totp_sign_in_works() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let test_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    let enrollment = client
+        .post(format!("{}/api/account/totp", &app.address))
+        .header("X-Api-Token", test_user.pak.to_string())
+        .json(&json!({ "password": TEST_USER_PASSWORD }))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json::<TotpEnrollmentResponse>()
+        .await
+        .expect("Failed to parse json body");
+    let now = chrono::Utc::now().timestamp();
+    let recovery_codes = client
+        .post(format!("{}/api/account/totp/confirm", &app.address))
+        .header("X-Api-Token", test_user.pak.to_string())
+        .json(&json!({ "code": totp::code_at(&enrollment.secret, now).unwrap() }))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json::<RecoveryCodesResponse>()
+        .await
+        .expect("Failed to parse json body")
+        .recovery_codes;
+
+    // Act
+    let challenge_token = start_mfa_sign_in(&app, &test_user).await;
+    let wrong_code = post_json(
+        &app,
+        "/signin/mfa",
+        None,
+        json!({ "challenge_token": challenge_token, "code": "12345" }),
+    )
+    .await;
+    // The code used to confirm can't be used again, so use the next one
+    let next_code = totp::code_at(&enrollment.secret, now + 30).unwrap();
+    let with_code = post_json(
+        &app,
+        "/signin/mfa",
+        None,
+        json!({ "challenge_token": challenge_token, "code": next_code }),
+    )
+    .await;
+    let with_code_status = with_code.status().as_u16();
+    let session = with_code
+        .json::<UserResponse>()
+        .await
+        .expect("Failed to parse json body");
+    let challenge_reused = post_json(
+        &app,
+        "/signin/mfa",
+        None,
+        json!({ "challenge_token": challenge_token, "code": next_code }),
+    )
+    .await;
+    let challenge_token = start_mfa_sign_in(&app, &test_user).await;
+    let with_recovery_code = post_json(
+        &app,
+        "/signin/mfa",
+        None,
+        json!({ "challenge_token": challenge_token, "recovery_code": recovery_codes[0] }),
+    )
+    .await;
+    let challenge_token = start_mfa_sign_in(&app, &test_user).await;
+    let recovery_code_reused = post_json(
+        &app,
+        "/signin/mfa",
+        None,
+        json!({ "challenge_token": challenge_token, "recovery_code": recovery_codes[0] }),
+    )
+    .await;
+
+    // Assert
+    assert!(enrollment
+        .otpauth_uri
+        .starts_with("otpauth://totp/LinkStowr"));
+    assert_eq!(recovery_codes.len(), 10);
+    assert_eq!(wrong_code.status().as_u16(), 400);
+    assert_eq!(with_code_status, 200);
+    assert_eq!(get_me_status(&app, &session.token).await, 200);
+    assert_eq!(challenge_reused.status().as_u16(), 400);
+    assert!(with_recovery_code.status().is_success());
+    assert_eq!(recovery_code_reused.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn concurrent_mfa_attempts_are_limited() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let test_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    let enrollment = client
+        .post(format!("{}/api/account/totp", &app.address))
+        .header("X-Api-Token", test_user.pak.to_string())
+        .json(&json!({ "password": TEST_USER_PASSWORD }))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json::<TotpEnrollmentResponse>()
+        .await
+        .expect("Failed to parse json body");
+    let now = chrono::Utc::now().timestamp();
+    client
+        .post(format!("{}/api/account/totp/confirm", &app.address))
+        .header("X-Api-Token", test_user.pak.to_string())
+        .json(&json!({ "code": totp::code_at(&enrollment.secret, now).unwrap() }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let challenge_token = start_mfa_sign_in(&app, &test_user).await;
+
+    // Act
+    let attempts: Vec<_> = (0..10)
+        .map(|_| {
+            let request = client
+                .post(format!("{}/signin/mfa", &app.address))
+                .json(&json!({ "challenge_token": challenge_token, "code": "000000" }));
+            tokio::spawn(request.send())
+        })
+        .collect();
+    for attempt in attempts {
+        attempt.await.unwrap().expect("Failed to execute request.");
+    }
+    let mut result = app
+        .state
+        .db
+        .query("SELECT VALUE attempts FROM mfa_challenge;")
+        .await
+        .expect("Failed to query the challenge");
+    let used_attempts: Vec<i64> = result.take(0).expect("Failed to get the attempts");
+    let next_code = totp::code_at(&enrollment.secret, now + 30).unwrap();
+    let with_code = post_json(
+        &app,
+        "/signin/mfa",
+        None,
+        json!({ "challenge_token": challenge_token, "code": next_code }),
+    )
+    .await;
+
+    // Assert
+    assert_eq!(used_attempts, vec![5]);
+    assert_eq!(with_code.status().as_u16(), 400);
+}
+
+#[test]
+fn totp_codes_match_the_rfc_6238_test_vectors() {
+    // Arrange
+    // The SHA-1 secret of RFC 6238, "12345678901234567890" in base32
+    let secret = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+    // The RFC has 8 digit codes, which end with the 6 digit ones
+    let vectors = [
+        (59, "94287082"),
+        (1111111109, "07081804"),
+        (1111111111, "14050471"),
+        (1234567890, "89005924"),
+        (2000000000, "69279037"),
+        (20000000000, "65353130"),
+    ];
+
+    for (unix_time, expected) in vectors {
+        // Act
+        let code = totp::code_at(secret, unix_time);
+
+        // Assert
+        assert_eq!(code.as_deref(), Some(&expected[2..]));
+        assert_eq!(
+            totp::verify(secret, &expected[2..], unix_time),
+            Some(unix_time / 30)
+        );
+    }
+}
+
+#[tokio::test]
+async fn repeated_sign_in_failures_lock_the_account() {
+    // Arrange
+    let mut app = spawn_app().await;
+    app.state = app.state.clone().with_proxy_settings(&ProxySettings {
+        client_ip_header: Some("X-Forwarded-For".to_string()),
+        trusted_proxies: vec![],
+    });
+    app.address = serve_app(&app.state);
+    let test_user = create_test_user(&app.state)
+        .await
+        .expect("Failed to create test user");
+    let wrong_password = json!({ "username": test_user.username, "password": "not-it" });
+
+    // Act
+    let mut failures = vec![];
+    for _ in 0..5 {
+        failures.push(post_json(&app, "/signin", None, wrong_password.clone()).await);
+    }
+    let locked = post_json(
+        &app,
+        "/signin",
+        None,
+        json!({ "username": test_user.username, "password": TEST_USER_PASSWORD }),
+    )
+    .await;
+    let locked_status = locked.status().as_u16();
+    let retry_after = locked
+        .headers()
+        .get("retry-after")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    let locked_body = locked
+        .json::<Value>()
+        .await
+        .expect("Failed to parse json body");
+    let elsewhere = reqwest::Client::new()
+        .post(format!("{}/signin", &app.address))
+        .header("X-Forwarded-For", "203.0.113.7")
+        .json(&json!({ "username": test_user.username, "password": TEST_USER_PASSWORD }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert!(failures
+        .iter()
+        .all(|response| response.status().as_u16() == 400));
+    assert_eq!(locked_status, 429);
+    assert!(retry_after.is_some_and(|retry_after| retry_after > 0));
+    assert_eq!(locked_body["error"]["type"], "RATE_LIMITED");
+    // Only the address the failures came from is locked out
+    assert!(elsewhere.status().is_success());
+}
+
+#[tokio::test]
+async fn links_work_with_in_memory_repositories() {
+    // Arrange
+    let state = AppState::in_memory();
+    let address = serve_app(&state);
+    let test_user = create_test_user(&state).await.unwrap();
+    let client = reqwest::Client::new();
+    let token = test_user.pak.to_string();
+
+    // Act
+    for (url, title) in [
+        ("https://example.com/a", "B title"),
+        ("https://www.example.com/b", "A title"),
+        ("https://other.com/c", "C title"),
+    ] {
+        let response = client
+            .post(format!("{address}/api/links"))
+            .header("X-Api-Token", &token)
+            .json(&json!({ "url": url, "title": title, "note": "" }))
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        assert!(response.status().is_success());
+    }
+    let duplicate: Value = client
+        .post(format!("{address}/api/links"))
+        .header("X-Api-Token", &token)
+        .json(&json!({ "url": "https://example.com/a?utm_source=x", "title": "B title", "note": "Again" }))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json()
+        .await
+        .unwrap();
+    let first_page: LinksPage = client
+        .get(format!(
+            "{address}/api/links?domain=example.com&sort=title&order=asc&limit=1"
+        ))
+        .header("X-Api-Token", &token)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json()
+        .await
+        .unwrap();
+    let second_page: LinksPage = client
+        .get(format!(
+            "{address}/api/links?domain=example.com&sort=title&order=asc&limit=1&cursor={}",
+            first_page.next_cursor.clone().unwrap()
+        ))
+        .header("X-Api-Token", &token)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json()
+        .await
+        .unwrap();
+    let cleared = client
+        .post(format!("{address}/api/links/clear"))
+        .header("X-Api-Token", &token)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let after_clear: LinksPage = client
+        .get(format!("{address}/api/links"))
+        .header("X-Api-Token", &token)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(duplicate["result"]["duplicate"], true);
+    assert_eq!(first_page.links.len(), 1);
+    assert_eq!(first_page.links[0].title, "A title");
+    assert_eq!(second_page.links.len(), 1);
+    assert_eq!(second_page.links[0].title, "B title");
+    assert_eq!(second_page.links[0].note, "Again");
+    assert!(second_page.next_cursor.is_none());
+    assert!(cleared.status().is_success());
+    assert!(after_clear.links.is_empty());
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn links_and_tokens_work_with_sqlite_backend() {
+    use linkstowr::repository::sql::{
+        connect, SqlLinkRepository, SqlTokenRepository, SqlUserRepository,
+    };
+
+    // Arrange
+    let app = spawn_app().await;
+    let db_path = std::env::temp_dir().join(format!("linkstowr-{}.db", Uuid::new_v4()));
+    let url = format!("sqlite://{}?mode=rwc", db_path.display());
+    let pool = connect(DatabaseBackend::Sqlite, Some(&url))
+        .await
+        .expect("Failed to set up the SQLite database");
+    let state = app.state.clone().with_repositories(
+        Arc::new(SqlUserRepository::new(pool.clone())),
+        Arc::new(SqlTokenRepository::new(pool.clone())),
+        Arc::new(SqlLinkRepository::new(pool, app.state.db.clone())),
+    );
+    let address = serve_app(&state);
+    let test_user = create_test_user(&state).await.unwrap();
+    let client = reqwest::Client::new();
+    let token = test_user.pak.to_string();
+
+    // Act
+    for (url, tags) in [
+        ("https://example.com/a", vec!["rust"]),
+        ("https://example.com/b", vec!["rust", "web"]),
+        ("https://other.com/c", vec![]),
+    ] {
+        let response = client
+            .post(format!("{address}/api/links"))
+            .header("X-Api-Token", &token)
+            .json(&json!({ "url": url, "title": url, "note": "", "tags": tags }))
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        assert!(response.status().is_success());
+    }
+    let tagged: LinksPage = client
+        .get(format!("{address}/api/links?tags=rust,web"))
+        .header("X-Api-Token", &token)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json()
+        .await
+        .unwrap();
+    let link_id = &tagged.links[0].id;
+    let updated = client
+        .patch(format!("{address}/api/links/{link_id}"))
+        .header("X-Api-Token", &token)
+        .json(&json!({ "title": "Updated" }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let fetched: LinkResponse = client
+        .get(format!("{address}/api/links/{link_id}"))
+        .header("X-Api-Token", &token)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json()
+        .await
+        .unwrap();
+    let deleted = client
+        .delete(format!("{address}/api/links/{link_id}"))
+        .header("X-Api-Token", &token)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let on_domain: LinksPage = client
+        .get(format!("{address}/api/links?domain=example.com"))
+        .header("X-Api-Token", &token)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json()
+        .await
+        .unwrap();
+    let tokens: Value = client
+        .get(format!("{address}/api/tokens"))
+        .header("X-Api-Token", &token)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(tagged.links.len(), 1);
+    assert_eq!(tagged.links[0].url, "https://example.com/b");
+    assert_eq!(tagged.links[0].tags, vec!["rust", "web"]);
+    assert!(updated.status().is_success());
+    assert_eq!(fetched.title, "Updated");
+    assert!(deleted.status().is_success());
+    assert_eq!(on_domain.links.len(), 1);
+    assert_eq!(on_domain.links[0].url, "https://example.com/a");
+    assert_eq!(tokens.as_array().unwrap().len(), 1);
+
+    let _ = fs::remove_file(db_path);
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn link_changes_tags_and_search_work_with_sqlite_backend() {
+    use linkstowr::repository::sql::{
+        connect, SqlLinkRepository, SqlTokenRepository, SqlUserRepository,
+    };
+
+    // Arrange
+    let surreal_app = spawn_app().await;
+    let db_path = std::env::temp_dir().join(format!("linkstowr-{}.db", Uuid::new_v4()));
+    let url = format!("sqlite://{}?mode=rwc", db_path.display());
+    let pool = connect(DatabaseBackend::Sqlite, Some(&url))
+        .await
+        .expect("Failed to set up the SQLite database");
+    let state = surreal_app.state.clone().with_repositories(
+        Arc::new(SqlUserRepository::new(pool.clone())),
+        Arc::new(SqlTokenRepository::new(pool.clone())),
+        Arc::new(SqlLinkRepository::new(pool, surreal_app.state.db.clone())),
+    );
+    let app = TestApp {
+        address: serve_app(&state),
+        state,
+        outbox_dir: surreal_app.outbox_dir.clone(),
+    };
+    let test_user = create_test_user(&app.state).await.unwrap();
+    let device = register_device(&app, &test_user, "Laptop").await;
+
+    // Act
+    let deleted = create_link(
+        &app,
+        &test_user,
+        json!({ "url": "https://example.com/a", "title": "Async Rust", "note": "", "tags": ["rust"] }),
+    )
+    .await;
+    let kept = create_link(
+        &app,
+        &test_user,
+        json!({ "url": "https://example.com/b", "title": "Web servers", "note": "", "tags": ["rust", "web"] }),
+    )
+    .await;
+    let deleted_id = deleted["result"]["id"].as_str().unwrap().to_string();
+    reqwest::Client::new()
+        .delete(format!("{}/api/links/{deleted_id}", &app.address))
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let changes = get_device_changes(&app, &test_user, &device).await;
+    let tags = get_tags(&app, &test_user).await;
+    let search: SearchResults = reqwest::Client::new()
+        .get(format!("{}/api/links/search?q=serv*", &app.address))
+        .header("X-Api-Token", &test_user.pak.to_string())
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(changes.links.len(), 1);
+    assert_eq!(changes.links[0].id, kept["result"]["id"].as_str().unwrap());
+    assert_eq!(changes.deleted, vec![deleted_id]);
+    let counts: Vec<(&str, u64)> = tags
+        .iter()
+        .map(|tag| (tag.name.as_str(), tag.count))
+        .collect();
+    assert_eq!(counts, vec![("rust", 1), ("web", 1)]);
+    assert_eq!(search.results.len(), 1);
+    assert_eq!(search.results[0].link.title, "Web servers");
+
+    let _ = fs::remove_file(db_path);
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn sessions_work_with_sqlite_backend() {
+    use linkstowr::repository::sql::{
+        connect, SqlLinkRepository, SqlTokenRepository, SqlUserRepository,
+    };
+
+    // Arrange
+    let surreal_app = spawn_app().await;
+    let db_path = std::env::temp_dir().join(format!("linkstowr-{}.db", Uuid::new_v4()));
+    let url = format!("sqlite://{}?mode=rwc", db_path.display());
+    let pool = connect(DatabaseBackend::Sqlite, Some(&url))
+        .await
+        .expect("Failed to set up the SQLite database");
+    let state = surreal_app.state.clone().with_repositories(
+        Arc::new(SqlUserRepository::new(pool.clone())),
+        Arc::new(SqlTokenRepository::new(pool.clone())),
+        Arc::new(SqlLinkRepository::new(pool, surreal_app.state.db.clone())),
+    );
+    let app = TestApp {
+        address: serve_app(&state),
+        state,
+        outbox_dir: surreal_app.outbox_dir.clone(),
+    };
+    let test_user = create_test_user(&app.state).await.unwrap();
+
+    // Act
+    let session = sign_in(&app, &test_user).await;
+    let refreshed = refresh(&app, &session.refresh_token).await;
+    let refreshed_status = refreshed.status().as_u16();
+    let refreshed: RefreshResponse = refreshed.json().await.expect("Failed to parse json body");
+    let me_after_refresh = get_me_status(&app, &refreshed.token).await;
+    let logout = reqwest::Client::new()
+        .post(format!("{}/logout", &app.address))
+        .bearer_auth(&refreshed.token)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(&session.id, &test_user.id);
+    assert_eq!(refreshed_status, 200);
+    assert_eq!(me_after_refresh, 200);
+    assert!(logout.status().is_success());
+    assert_eq!(get_me_status(&app, &refreshed.token).await, 401);
+    assert_eq!(
+        refresh(&app, &refreshed.refresh_token)
+            .await
+            .status()
+            .as_u16(),
+        401
+    );
+
+    let _ = fs::remove_file(db_path);
+}
+
+#[tokio::test]
+async fn migrations_are_recorded_and_applied_once() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let reapplied = migrations::run(&app.state.db, &app.state.url_canonicalizer)
+        .await
+        .unwrap();
+    let applied = migrations::applied(&app.state.db).await.unwrap();
+
+    // Assert
+    assert!(reapplied.is_empty());
+    assert_eq!(applied.len(), MIGRATIONS.len());
+    for (applied, migration) in applied.iter().zip(MIGRATIONS) {
+        assert_eq!(applied.version, migration.version);
+        assert_eq!(applied.checksum, migration.checksum());
+    }
+}
+
+#[tokio::test]
+async fn migrations_canonicalize_and_merge_existing_links() {
+    // Arrange
+    let db = surrealdb::engine::any::connect("mem://")
+        .await
+        .expect("Failed to initialize test db");
+    db.use_ns("test").use_db("test").await.unwrap();
+    // Links as they were saved with the schema from before migrations, which
+    // had no canonical URLs, tags or collections
+    db.query(
+        "DEFINE TABLE link SCHEMAFULL;
+        DEFINE FIELD url ON TABLE link TYPE string;
+        DEFINE FIELD title ON TABLE link TYPE string;
+        DEFINE FIELD note ON TABLE link TYPE string;
+        DEFINE FIELD user ON TABLE link TYPE record (user);
+        DEFINE FIELD bookmarked_at ON TABLE link TYPE datetime DEFAULT time::now();
+        DEFINE INDEX idx_user ON TABLE link COLUMNS user;
+        CREATE link:first SET url = 'http://www.example.com/?utm_source=feed', title = 'Example', \
+            note = 'First', user = user:alice, bookmarked_at = d'2020-01-01T00:00:00Z';
+        CREATE link:second SET url = 'https://example.com/', title = 'Example', \
+            note = 'Second', user = user:alice, bookmarked_at = d'2021-01-01T00:00:00Z';
+        CREATE link:other SET url = 'https://example.com/', title = 'Example', \
+            note = '', user = user:bob, bookmarked_at = d'2021-01-01T00:00:00Z';",
+    )
+    .await
+    .unwrap()
+    .check()
+    .unwrap();
+
+    // Act
+    let applied = migrations::run(&db, &UrlCanonicalizer::default())
+        .await
+        .expect("Failed to migrate");
+    let mut result = db
+        .query("SELECT meta::id(id) AS id, canonical_url, note, array::len(tags) AS tags FROM link ORDER BY id;")
+        .await
+        .unwrap();
+    let links: Vec<Value> = result.take(0).unwrap();
+    let duplicate = db
+        .query(
+            "CREATE link SET url = 'https://example.com', canonical_url = 'https://example.com/', \
+            title = 'Example', note = '', user = user:alice;",
+        )
+        .await
+        .unwrap()
+        .check();
+
+    // Assert
+    assert_eq!(applied.len(), MIGRATIONS.len());
+    assert_eq!(
+        links,
+        vec![
+            json!({
+                "id": "first",
+                "canonical_url": "https://example.com/",
+                "note": "First\n\nSecond",
+                "tags": 0,
+            }),
+            json!({
+                "id": "other",
+                "canonical_url": "https://example.com/",
+                "note": "",
+                "tags": 0,
+            }),
+        ]
+    );
+    assert!(duplicate.is_err());
+}
+
+#[tokio::test]
+async fn migrations_refuse_to_run_after_checksum_drift() {
+    // Arrange
+    let app = spawn_app().await;
+    app.state
+        .db
+        .query("UPDATE migration SET checksum = \"changed\" WHERE version = 1;")
+        .await
+        .unwrap();
+
+    // Act
+    let result = migrations::run(&app.state.db, &app.state.url_canonicalizer).await;
+
+    // Assert
+    assert!(matches!(
+        result,
+        Err(MigrationError::ChecksumMismatch { version: 1, .. })
+    ));
+}
+
+#[test]
+fn every_migration_file_is_embedded() {
+    let mut files: Vec<String> = fs::read_dir("db/surreal")
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    files.sort();
+
+    let embedded: Vec<String> = MIGRATIONS
+        .iter()
+        .map(|migration| format!("{:04}_{}.sql", migration.version, migration.name))
+        .collect();
+
+    assert_eq!(files, embedded);
+}
+
+#[tokio::test]
+async fn disabling_a_user_from_the_cli_locks_them_out() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let test_user = create_test_user(&app.state).await.unwrap();
+    let session = sign_in(&app, &test_user).await;
+
+    // Act
+    cli::run(
+        AdminCommand::User(UserCommand::Disable {
+            username: test_user.username.clone(),
+        }),
+        &app.state,
+        DatabaseBackend::Surreal,
+    )
+    .await
+    .unwrap();
+    let signin = client
+        .post(format!("{}/signin", &app.address))
+        .json(&json!({
+            "username": test_user.username,
+            "password": TEST_USER_PASSWORD,
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let refreshed = refresh(&app, &session.refresh_token).await;
+    let with_token = client
+        .get(format!("{}/api/links", &app.address))
+        .header("X-Api-Token", test_user.pak.to_string())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    cli::run(
+        AdminCommand::User(UserCommand::Enable {
+            username: test_user.username.clone(),
+        }),
+        &app.state,
+        DatabaseBackend::Surreal,
+    )
+    .await
+    .unwrap();
+    let signin_after_enable = client
+        .post(format!("{}/signin", &app.address))
+        .json(&json!({
+            "username": test_user.username,
+            "password": TEST_USER_PASSWORD,
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let with_token_after_enable = client
+        .get(format!("{}/api/links", &app.address))
+        .header("X-Api-Token", test_user.pak.to_string())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(signin.status().as_u16(), 403);
+    assert_eq!(refreshed.status().as_u16(), 401);
+    assert_eq!(with_token.status().as_u16(), 403);
+    assert!(signin_after_enable.status().is_success());
+    // The tokens were only refused while the user was disabled
+    assert!(with_token_after_enable.status().is_success());
+}
+
+#[tokio::test]
+async fn api_tokens_of_disabled_accounts_are_refused() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_user = create_test_user(&app.state).await.unwrap();
+    let user_id = thing(&test_user.id).unwrap();
+
+    // Act
+    app.state
+        .users
+        .set_disabled_at(&user_id, Some(chrono::Utc::now()))
+        .await
+        .unwrap();
+    let with_token = reqwest::Client::new()
+        .get(format!("{}/api/links", &app.address))
+        .header("X-Api-Token", test_user.pak.to_string())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(with_token.status().as_u16(), 403);
+}
+
+#[tokio::test]
+async fn cli_issues_and_revokes_tokens() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_user = create_test_user(&app.state).await.unwrap();
+    let user_id = thing(&test_user.id).unwrap();
+
+    // Act
+    cli::run(
+        AdminCommand::Token(TokenCommand::Issue {
+            user: test_user.username.clone(),
+            name: "cli".into(),
+            scopes: vec![Scope::LinksRead],
+            expires_at: None,
+        }),
+        &app.state,
+        DatabaseBackend::Surreal,
+    )
+    .await
+    .unwrap();
+    let tokens = app.state.tokens.list_for_user(&user_id).await.unwrap();
+    let issued = tokens.iter().find(|token| token.name == "cli").unwrap();
+    cli::run(
+        AdminCommand::Token(TokenCommand::Revoke {
+            user: test_user.username.clone(),
+            id: issued.id.to_string(),
+        }),
+        &app.state,
+        DatabaseBackend::Surreal,
+    )
+    .await
+    .unwrap();
+    let after_revoke = app.state.tokens.list_for_user(&user_id).await.unwrap();
+    let unknown_user = cli::run(
+        AdminCommand::Token(TokenCommand::Revoke {
+            user: "nobody".into(),
+            id: issued.id.to_string(),
+        }),
+        &app.state,
+        DatabaseBackend::Surreal,
+    )
+    .await;
+
+    // Assert
+    assert_eq!(issued.scopes, vec![Scope::LinksRead]);
+    assert!(after_revoke.iter().all(|token| token.name != "cli"));
+    assert!(unknown_user.is_err());
+}
+
+#[tokio::test]
+async fn cli_backup_refuses_sql_backends() {
+    // Arrange
+    let app = spawn_app().await;
+    let path = std::env::temp_dir().join(format!("linkstowr-{}.surql", Uuid::new_v4()));
+
+    // Act
+    let backup = cli::run(
+        AdminCommand::Backup { path: path.clone() },
+        &app.state,
+        DatabaseBackend::Sqlite,
+    )
+    .await;
+    let restore = cli::run(
+        AdminCommand::Restore { path: path.clone() },
+        &app.state,
+        DatabaseBackend::Postgres,
+    )
+    .await;
+
+    // Assert
+    assert!(backup.is_err());
+    assert!(restore.is_err());
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn telemetry_exporters_are_optional() {
+    // Arrange
+    let mut settings = TelemetrySettings::default();
+
+    // Act
+    let disabled = build_otel_layer::<Registry>(&settings).expect("Failed to build layer");
+    settings.exporter = TelemetryExporter::Stdout;
+    settings.sampler.ratio = 0.5;
+    settings
+        .resource_attributes
+        .insert("deployment.environment".into(), "test".into());
+    let stdout = build_otel_layer::<Registry>(&settings).expect("Failed to build layer");
+    settings.exporter = TelemetryExporter::OtlpGrpc;
+    settings
+        .headers
+        .insert("not a header".into(), "secret".into());
+    let invalid_header = build_otel_layer::<Registry>(&settings);
+    settings.headers.clear();
+    settings.exporter = TelemetryExporter::OtlpHttp;
+    settings.headers_from_env.insert(
+        "authorization".into(),
+        "LINKSTOWR_TEST_UNSET_API_KEY".into(),
+    );
+    let missing_env_header = build_otel_layer::<Registry>(&settings);
+
+    // Assert
+    assert_eq!(
+        TelemetrySettings::default().exporter,
+        TelemetryExporter::None
+    );
+    assert!(disabled.is_none());
+    assert!(stdout.is_some());
+    assert!(invalid_header.is_err());
+    assert!(missing_env_header.is_err());
+}
+
+#[tokio::test]
+async fn telemetry_init_falls_back_to_local_logs_without_exporter() {
+    // Arrange
+    let mut settings = TelemetrySettings::default();
+    settings.exporter = TelemetryExporter::OtlpHttp;
+    settings.headers_from_env.insert(
+        "authorization".into(),
+        "LINKSTOWR_TEST_UNSET_API_KEY".into(),
+    );
+
+    // Act
+    let init = init_subscribers(&settings);
+
+    // Assert
+    assert!(init.is_ok());
 }